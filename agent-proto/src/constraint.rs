@@ -0,0 +1,121 @@
+//! The constraints on a key's use that follow it in `SSH_AGENTC_ADD_ID_CONSTRAINED` (as set with
+//! `ssh-add -t`, `-c` and `-h`).
+
+use crate::key::PrivateKey;
+use crate::wire::Reader;
+use crate::Error;
+
+const LIFETIME: u8 = 1;
+const CONFIRM: u8 = 2;
+const EXTENSION: u8 = 255;
+
+/// The extension carrying `ssh-add -h`'s destination constraints.
+pub const RESTRICT_DESTINATION: &str = "restrict-destination-v00@openssh.com";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Constraint {
+    /// Forget the key after this many seconds.
+    Lifetime(u32),
+    /// Ask the user before each use of the key.
+    Confirm,
+    /// Only use the key on the given hops.
+    RestrictDestination(Vec<Destination>),
+    /// Any other extension. Its contents aren't delimited, so they're everything left in the
+    /// message and this is always the last constraint.
+    Extension { name: String, contents: Vec<u8> },
+}
+
+/// A hop (from one host to another) that a key may be used for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Destination {
+    /// The host the connection is made from, with an empty hostname meaning the agent's own host.
+    pub from: Hop,
+    pub to: Hop,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hop {
+    pub user: String,
+    pub hostname: String,
+    /// The host's keys (blob and whether it's a CA key).
+    pub host_keys: Vec<(Vec<u8>, bool)>,
+}
+
+/// Parse the contents of `SSH_AGENTC_ADD_ID_CONSTRAINED` into the key and its constraints.
+pub fn parse(contents: &[u8]) -> Result<(PrivateKey<'_>, Vec<Constraint>), Error> {
+    let mut reader = Reader::new(contents);
+    let key = crate::key::read(&mut reader)?;
+    let mut constraints = Vec::new();
+    while !reader.is_empty() {
+        let constraint = match reader.u8()? {
+            LIFETIME => Constraint::Lifetime(reader.u32()?),
+            CONFIRM => Constraint::Confirm,
+            EXTENSION => {
+                let name = reader.text()?;
+                if name == RESTRICT_DESTINATION {
+                    Constraint::RestrictDestination(destinations(reader.string()?)?)
+                } else {
+                    let contents = reader.bytes(reader.remaining().len())?;
+                    Constraint::Extension {
+                        name,
+                        contents: contents.to_vec(),
+                    }
+                }
+            }
+            other => return Err(Error::UnknownConstraint(other)),
+        };
+        constraints.push(constraint);
+    }
+    Ok((key, constraints))
+}
+
+/// A sequence of destination constraints, each a `string` holding the from and to hops (each
+/// also a `string`) and a reserved `string`.
+fn destinations(buf: &[u8]) -> Result<Vec<Destination>, Error> {
+    let mut reader = Reader::new(buf);
+    let mut destinations = Vec::new();
+    while !reader.is_empty() {
+        let mut destination = Reader::new(reader.string()?);
+        let from = hop(destination.string()?)?;
+        let to = hop(destination.string()?)?;
+        let _reserved = destination.string()?;
+        destinations.push(Destination { from, to });
+    }
+    Ok(destinations)
+}
+
+/// A user, hostname, reserved `string` and then any number of host keys.
+fn hop(buf: &[u8]) -> Result<Hop, Error> {
+    let mut reader = Reader::new(buf);
+    let user = reader.text()?;
+    let hostname = reader.text()?;
+    let _reserved = reader.string()?;
+    let mut host_keys = Vec::new();
+    while !reader.is_empty() {
+        host_keys.push((reader.string()?.to_vec(), reader.u8()? != 0));
+    }
+    Ok(Hop {
+        user,
+        hostname,
+        host_keys,
+    })
+}
+
+impl std::fmt::Display for Hop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.user.is_empty() {
+            write!(f, "{}@", self.user)?;
+        }
+        f.write_str(&self.hostname)
+    }
+}
+
+/// In `ssh-add -h` syntax, i.e. `from>to` or just `to` for connections from the agent's host.
+impl std::fmt::Display for Destination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.from.hostname.is_empty() {
+            write!(f, "{}>", self.from)?;
+        }
+        write!(f, "{}", self.to)
+    }
+}
@@ -0,0 +1,76 @@
+//! Hex dumps of agent messages for tracing, with the secrets left out.
+//!
+//! Private keys (`SSH_AGENTC_ADD_IDENTITY`), smartcard PINs and lock passphrases are redacted
+//! unless asked for. The rest of the protocol (public keys, the data being signed, signatures)
+//! isn't secret, so is dumped as-is.
+
+use std::fmt::Write as _;
+
+use crate::{wire, MessageType};
+
+/// Where the secret material in the message with `body` starts, if it has any.
+///
+/// What comes before (e.g. the type of a key being added, or a smartcard reader's name) is left
+/// in, as it's useful when debugging.
+pub fn secret_from(body: &[u8]) -> Option<usize> {
+    let message_type = MessageType::of(body).ok()?;
+    // The offset past a leading string, or everything after the message type if it's malformed.
+    let after_string = || {
+        let mut reader = wire::Reader::new(&body[1..]);
+        match reader.string() {
+            Ok(_) => body.len() - reader.remaining().len(),
+            Err(_) => 1,
+        }
+    };
+    match message_type {
+        MessageType::ADD_IDENTITY
+        | MessageType::ADD_ID_CONSTRAINED
+        | MessageType::ADD_SMARTCARD_KEY
+        | MessageType::ADD_SMARTCARD_KEY_CONSTRAINED => Some(after_string()),
+        MessageType::LOCK | MessageType::UNLOCK => Some(1),
+        _ => None,
+    }
+}
+
+/// A dump of the message with `body` (without the length prefix), redacting any secrets unless
+/// `redact` is `false`.
+pub fn message(body: &[u8], redact: bool) -> String {
+    match secret_from(body).filter(|_| redact) {
+        Some(offset) if offset < body.len() => {
+            let mut dump = hex(&body[..offset]);
+            let _ = write!(dump, "\n[{} bytes redacted]", body.len() - offset);
+            dump
+        }
+        _ => hex(body),
+    }
+}
+
+/// `data` as lines of an offset, 16 bytes in hex and those bytes as ASCII, like `hexdump -C`.
+pub fn hex(data: &[u8]) -> String {
+    let mut dump = String::new();
+    for (line, chunk) in data.chunks(16).enumerate() {
+        if line > 0 {
+            dump.push('\n');
+        }
+        let _ = write!(dump, "{:08x} ", line * 16);
+        for (i, byte) in chunk.iter().enumerate() {
+            if i == 8 {
+                dump.push(' ');
+            }
+            let _ = write!(dump, " {:02x}", byte);
+        }
+        // Line the ASCII column up on a short last line.
+        let missing = 16 - chunk.len();
+        let padding = missing * 3 + usize::from(chunk.len() <= 8);
+        let _ = write!(dump, "{:padding$}  |", "");
+        dump.extend(chunk.iter().map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        }));
+        dump.push('|');
+    }
+    dump
+}
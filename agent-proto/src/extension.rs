@@ -0,0 +1,53 @@
+//! `SSH_AGENTC_EXTENSION` requests, as defined by OpenSSH (see PROTOCOL.agent in OpenSSH).
+//!
+//! Extensions are identified by name, and their contents follow it in the request. Pageant
+//! doesn't implement OpenSSH's, but they're still worth recognising: OpenSSH 8.9+ binds every
+//! agent connection to the SSH session it's being used for, which is what any policy about where
+//! keys may be used has to go on.
+
+use crate::wire::{Reader, Writer};
+use crate::Error;
+
+/// Lists the extensions the agent supports.
+pub const QUERY: &str = "query";
+
+/// Binds the connection to an SSH session, see [`SessionBind`].
+pub const SESSION_BIND: &str = "session-bind@openssh.com";
+
+/// `session-bind@openssh.com`, sent by `ssh` once it's authenticated the server and before it
+/// uses the agent for that connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionBind {
+    /// The server's host key.
+    pub host_key: Vec<u8>,
+    /// The SSH session's exchange hash.
+    pub session_id: Vec<u8>,
+    /// The server's signature of `session_id`, made with `host_key`.
+    pub signature: Vec<u8>,
+    /// Whether the agent is being forwarded to the server, rather than only used to log in to it.
+    pub forwarding: bool,
+}
+
+impl SessionBind {
+    /// Parse the contents of a `session-bind@openssh.com` extension request.
+    pub fn parse(contents: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(contents);
+        Ok(Self {
+            host_key: reader.string()?.to_vec(),
+            session_id: reader.string()?.to_vec(),
+            signature: reader.string()?.to_vec(),
+            forwarding: reader.u8()? != 0,
+        })
+    }
+
+    /// The extension request's contents.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut writer = Writer::new();
+        writer
+            .string(&self.host_key)
+            .string(&self.session_id)
+            .string(&self.signature)
+            .u8(self.forwarding.into());
+        writer.into_inner()
+    }
+}
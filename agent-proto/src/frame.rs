@@ -0,0 +1,205 @@
+//! Splitting a byte stream into agent messages, each a `uint32` length followed by the body.
+
+use std::io::Read as _;
+
+/// A message read from the stream.
+#[derive(Debug)]
+pub enum Frame {
+    /// A complete message body (without the length prefix).
+    Message(Vec<u8>),
+    /// A message longer than the reader's limit, which has been discarded.
+    Oversized(usize),
+}
+
+/// Read the next message, or `None` if the stream ended cleanly between messages.
+///
+/// Messages whose body is longer than `max_len` bytes are consumed in full and reported as
+/// [`Frame::Oversized`], so the next read still starts at a message boundary. A stream that ends
+/// part way through a message is an `UnexpectedEof` error.
+pub fn read_frame(
+    reader: &mut impl std::io::Read,
+    max_len: usize,
+) -> std::io::Result<Option<Frame>> {
+    let mut len_buf = [0; 4];
+    let mut filled = 0;
+    while filled < len_buf.len() {
+        match reader.read(&mut len_buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(len) => filled += len,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let len = u32::from_be_bytes(len_buf) as usize;
+
+    let mut body = reader.take(len as u64);
+    let frame = if len > max_len {
+        std::io::copy(&mut body, &mut std::io::sink())?;
+        Frame::Oversized(len)
+    } else {
+        let mut message = Vec::with_capacity(len);
+        body.read_to_end(&mut message)?;
+        Frame::Message(message)
+    };
+
+    if body.limit() != 0 {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some(frame))
+}
+
+/// Split the message at the start of `buf` (length prefix and all) from whatever follows it, e.g.
+/// requests a client has pipelined behind it. `None` if the length claims more than `buf` holds.
+pub fn split_frame(buf: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len = u32::from_be_bytes(buf.get(..4)?.try_into().expect("4 bytes"));
+    let end = (len as usize)
+        .checked_add(4)
+        .filter(|&end| end <= buf.len())?;
+    Some(buf.split_at(end))
+}
+
+/// Prefix `body` with its length, ready to be written to the stream.
+pub fn frame(body: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(body.len() + 4);
+    message.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    message.extend_from_slice(body);
+    message
+}
+
+/// OpenSSH's limit on message length, anything longer isn't the agent protocol.
+pub const MAX_MESSAGE_LEN: usize = 256 * 1024;
+
+/// Where a stream of messages is up to, for following a stream that's passing through without
+/// buffering it.
+#[derive(Debug, Default)]
+pub struct Boundaries {
+    /// The length and message type of the next message, as far as they've been seen.
+    header: Vec<u8>,
+    /// How much of the current message's body is still to come.
+    remaining: usize,
+}
+
+impl Boundaries {
+    /// Follow `data`, calling `message` with the type of each message as it starts. Returns
+    /// `false` if the data can't be agent messages (an empty message, or one longer than
+    /// [`MAX_MESSAGE_LEN`]).
+    pub fn feed(&mut self, mut data: &[u8], mut message: impl FnMut(u8)) -> bool {
+        while !data.is_empty() {
+            if self.remaining > 0 {
+                let skipped = self.remaining.min(data.len());
+                self.remaining -= skipped;
+                data = &data[skipped..];
+                continue;
+            }
+            let wanted = (5 - self.header.len()).min(data.len());
+            self.header.extend_from_slice(&data[..wanted]);
+            data = &data[wanted..];
+            if self.header.len() == 5 {
+                let len = u32::from_be_bytes(self.header[..4].try_into().expect("4 bytes"));
+                if len == 0 || len as usize > MAX_MESSAGE_LEN {
+                    return false;
+                }
+                message(self.header[4]);
+                self.remaining = len as usize - 1;
+                self.header.clear();
+            }
+        }
+        true
+    }
+
+    /// Whether the stream is between messages.
+    pub fn at_boundary(&self) -> bool {
+        self.header.is_empty() && self.remaining == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(frame: Option<Frame>) -> Vec<u8> {
+        match frame {
+            Some(Frame::Message(body)) => body,
+            other => panic!("expected a message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reads_messages_until_the_stream_ends() {
+        let stream = [frame(b"\x0b"), frame(b""), frame(b"\x0dbody")].concat();
+        let mut reader = &stream[..];
+        assert_eq!(message(read_frame(&mut reader, 16).unwrap()), b"\x0b");
+        assert_eq!(message(read_frame(&mut reader, 16).unwrap()), b"");
+        assert_eq!(message(read_frame(&mut reader, 16).unwrap()), b"\x0dbody");
+        assert!(read_frame(&mut reader, 16).unwrap().is_none());
+    }
+
+    #[test]
+    fn drains_oversized_messages() {
+        let stream = [frame(&[0x11; 100]), frame(b"\x0b")].concat();
+        let mut reader = &stream[..];
+        assert!(matches!(
+            read_frame(&mut reader, 99).unwrap(),
+            Some(Frame::Oversized(100))
+        ));
+        // The next read starts at the following message.
+        assert_eq!(message(read_frame(&mut reader, 99).unwrap()), b"\x0b");
+    }
+
+    #[test]
+    fn a_stream_ending_part_way_through_a_message_is_an_error() {
+        let stream = frame(b"\x0dbody");
+        for len in 1..stream.len() {
+            for max_len in [1, 16] {
+                let e = read_frame(&mut &stream[..len], max_len).unwrap_err();
+                assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+            }
+        }
+    }
+
+    #[test]
+    fn splits_off_the_first_message() {
+        let stream = [frame(b"\x0b"), frame(b"\x0d")].concat();
+        let (first, rest) = split_frame(&stream).unwrap();
+        assert_eq!(first, frame(b"\x0b"));
+        assert_eq!(rest, frame(b"\x0d"));
+        assert_eq!(split_frame(rest).unwrap(), (rest, &[][..]));
+
+        for len in 0..5 {
+            assert!(split_frame(&stream[..len]).is_none());
+        }
+        assert!(split_frame(b"\xff\xff\xff\xff\x0b").is_none());
+    }
+
+    #[test]
+    fn follows_boundaries_across_chunks() {
+        let stream = [frame(b"\x0b"), frame(b"\x0cabc"), frame(b"\x0e")].concat();
+        for chunk in 1..stream.len() {
+            let mut boundaries = Boundaries::default();
+            let mut types = Vec::new();
+            let mut boundaries_seen = 0;
+            for data in stream.chunks(chunk) {
+                assert!(boundaries.feed(data, |message_type| types.push(message_type)));
+                boundaries_seen += usize::from(boundaries.at_boundary());
+            }
+            assert_eq!(types, [0x0b, 0x0c, 0x0e]);
+            assert!(boundaries.at_boundary());
+            assert!(boundaries_seen > 0);
+        }
+
+        let mut boundaries = Boundaries::default();
+        assert!(boundaries.feed(&frame(b"\x0cabc")[..6], |_| {}));
+        assert!(!boundaries.at_boundary());
+    }
+
+    #[test]
+    fn rejects_streams_that_arent_agent_messages() {
+        let mut types = Vec::new();
+        let empty = [frame(b""), frame(b"\x0b")].concat();
+        assert!(!Boundaries::default().feed(&empty, |t| types.push(t)));
+        let oversized = ((MAX_MESSAGE_LEN + 1) as u32).to_be_bytes();
+        assert!(!Boundaries::default().feed(&[&oversized[..], b"\x0b"].concat(), |t| types.push(t)));
+        assert!(types.is_empty());
+    }
+}
@@ -0,0 +1,66 @@
+//! Private keys, as carried by `SSH_AGENTC_ADD_IDENTITY` (and stored in OpenSSH's key files).
+//!
+//! A private key is its type (e.g. `ssh-ed25519`), a type-specific list of fields and a comment.
+//! Nothing marks where it ends, so finding the constraints that follow it in
+//! `SSH_AGENTC_ADD_ID_CONSTRAINED` means knowing how many fields each type of key has.
+
+use crate::wire::Reader;
+use crate::Error;
+
+/// A private key read from a message.
+pub struct PrivateKey<'a> {
+    /// e.g. `ssh-ed25519`.
+    pub key_type: String,
+    pub comment: String,
+    /// The whole key (type, fields and comment) as it was encoded.
+    pub encoded: &'a [u8],
+}
+
+/// Read a private key from the front of `reader`.
+pub fn read<'a>(reader: &mut Reader<'a>) -> Result<PrivateKey<'a>, Error> {
+    let start = reader.remaining();
+    let key_type = reader.text()?;
+    let fields = fields(&key_type).ok_or_else(|| Error::UnknownKeyType(key_type.clone()))?;
+    for field in fields {
+        match field {
+            Field::String => reader.string().map(drop),
+            Field::Byte => reader.u8().map(drop),
+        }?;
+    }
+    let comment = reader.text()?;
+    let len = start.len() - reader.remaining().len();
+    Ok(PrivateKey {
+        key_type,
+        comment,
+        encoded: &start[..len],
+    })
+}
+
+/// How a field of a private key is encoded, `mpint`s being `string`s as far as that goes.
+enum Field {
+    String,
+    Byte,
+}
+
+/// The fields (after the type) of each type of private key.
+fn fields(key_type: &str) -> Option<&'static [Field]> {
+    use Field::{Byte, String};
+
+    Some(match key_type {
+        // public key, private key
+        "ssh-ed25519" => &[String, String],
+        // n, e, d, iqmp, p, q
+        "ssh-rsa" => &[String, String, String, String, String, String],
+        // p, q, g, y, x
+        "ssh-dss" => &[String, String, String, String, String],
+        // curve, public point, private scalar
+        "ecdsa-sha2-nistp256" | "ecdsa-sha2-nistp384" | "ecdsa-sha2-nistp521" => {
+            &[String, String, String]
+        }
+        // public key, application, flags, key handle, reserved
+        "sk-ssh-ed25519@openssh.com" => &[String, String, Byte, String, String],
+        // curve, public point, application, flags, key handle, reserved
+        "sk-ecdsa-sha2-nistp256@openssh.com" => &[String, String, String, Byte, String, String],
+        _ => return None,
+    })
+}
@@ -0,0 +1,542 @@
+//! The ssh-agent protocol, as spoken by OpenSSH and Pageant.
+//!
+//! See https://datatracker.ietf.org/doc/html/draft-miller-ssh-agent for the message formats.
+//! Messages are parsed from (and encoded to) their body, i.e. without the length prefix, which is
+//! handled by the [`frame`] module.
+
+pub mod constraint;
+pub mod dump;
+pub mod extension;
+pub mod frame;
+pub mod key;
+pub mod report;
+pub mod wire;
+
+pub use frame::{read_frame, Frame};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Message is empty")]
+    Empty,
+    #[error("Message is truncated")]
+    Truncated,
+    #[error("Unknown key type {0:?}")]
+    UnknownKeyType(String),
+    #[error("Unknown key constraint {0}")]
+    UnknownConstraint(u8),
+}
+
+/// The first byte of every agent message.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MessageType(pub u8);
+
+impl MessageType {
+    pub const FAILURE: Self = Self(5);
+    pub const SUCCESS: Self = Self(6);
+    pub const REQUEST_IDENTITIES: Self = Self(11);
+    pub const IDENTITIES_ANSWER: Self = Self(12);
+    pub const SIGN_REQUEST: Self = Self(13);
+    pub const SIGN_RESPONSE: Self = Self(14);
+    pub const ADD_IDENTITY: Self = Self(17);
+    pub const REMOVE_IDENTITY: Self = Self(18);
+    pub const REMOVE_ALL_IDENTITIES: Self = Self(19);
+    pub const ADD_SMARTCARD_KEY: Self = Self(20);
+    pub const REMOVE_SMARTCARD_KEY: Self = Self(21);
+    pub const LOCK: Self = Self(22);
+    pub const UNLOCK: Self = Self(23);
+    pub const ADD_ID_CONSTRAINED: Self = Self(25);
+    pub const ADD_SMARTCARD_KEY_CONSTRAINED: Self = Self(26);
+    pub const EXTENSION: Self = Self(27);
+    pub const EXTENSION_FAILURE: Self = Self(28);
+
+    pub fn name(self) -> Option<&'static str> {
+        let name = match self {
+            Self::FAILURE => "SSH_AGENT_FAILURE",
+            Self::SUCCESS => "SSH_AGENT_SUCCESS",
+            Self::REQUEST_IDENTITIES => "SSH_AGENTC_REQUEST_IDENTITIES",
+            Self::IDENTITIES_ANSWER => "SSH_AGENT_IDENTITIES_ANSWER",
+            Self::SIGN_REQUEST => "SSH_AGENTC_SIGN_REQUEST",
+            Self::SIGN_RESPONSE => "SSH_AGENT_SIGN_RESPONSE",
+            Self::ADD_IDENTITY => "SSH_AGENTC_ADD_IDENTITY",
+            Self::REMOVE_IDENTITY => "SSH_AGENTC_REMOVE_IDENTITY",
+            Self::REMOVE_ALL_IDENTITIES => "SSH_AGENTC_REMOVE_ALL_IDENTITIES",
+            Self::ADD_SMARTCARD_KEY => "SSH_AGENTC_ADD_SMARTCARD_KEY",
+            Self::REMOVE_SMARTCARD_KEY => "SSH_AGENTC_REMOVE_SMARTCARD_KEY",
+            Self::LOCK => "SSH_AGENTC_LOCK",
+            Self::UNLOCK => "SSH_AGENTC_UNLOCK",
+            Self::ADD_ID_CONSTRAINED => "SSH_AGENTC_ADD_ID_CONSTRAINED",
+            Self::ADD_SMARTCARD_KEY_CONSTRAINED => "SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED",
+            Self::EXTENSION => "SSH_AGENTC_EXTENSION",
+            Self::EXTENSION_FAILURE => "SSH_AGENT_EXTENSION_FAILURE",
+            _ => return None,
+        };
+        Some(name)
+    }
+
+    /// The type of the message with the given body.
+    pub fn of(body: &[u8]) -> Result<Self, Error> {
+        body.first().copied().map(Self).ok_or(Error::Empty)
+    }
+
+    /// Whether a response of this type could be an answer to a request of type `request`.
+    ///
+    /// Any request can fail, but otherwise only the documented responses are plausible. Requests
+    /// this crate doesn't know could be answered with anything.
+    pub fn answers(self, request: Self) -> bool {
+        let expected = match request {
+            Self::REQUEST_IDENTITIES => Self::IDENTITIES_ANSWER,
+            Self::SIGN_REQUEST => Self::SIGN_RESPONSE,
+            Self::EXTENSION => {
+                return matches!(
+                    self,
+                    Self::FAILURE | Self::SUCCESS | Self::EXTENSION_FAILURE
+                )
+            }
+            Self::ADD_IDENTITY
+            | Self::REMOVE_IDENTITY
+            | Self::REMOVE_ALL_IDENTITIES
+            | Self::ADD_SMARTCARD_KEY
+            | Self::REMOVE_SMARTCARD_KEY
+            | Self::LOCK
+            | Self::UNLOCK
+            | Self::ADD_ID_CONSTRAINED
+            | Self::ADD_SMARTCARD_KEY_CONSTRAINED => Self::SUCCESS,
+            _ => return true,
+        };
+        self == expected || self == Self::FAILURE
+    }
+}
+
+impl std::fmt::Debug for MessageType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "UNKNOWN({})", self.0),
+        }
+    }
+}
+
+impl std::fmt::Display for MessageType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+/// A message from a client to the agent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    RequestIdentities,
+    SignRequest {
+        key_blob: Vec<u8>,
+        data: Vec<u8>,
+        flags: u32,
+    },
+    /// `SSH_AGENTC_ADD_IDENTITY` or `SSH_AGENTC_ADD_ID_CONSTRAINED`, `contents` is the key type,
+    /// key material, comment and (if constrained) the constraints.
+    AddIdentity {
+        constrained: bool,
+        contents: Vec<u8>,
+    },
+    RemoveIdentity {
+        key_blob: Vec<u8>,
+    },
+    RemoveAllIdentities,
+    Lock {
+        passphrase: Vec<u8>,
+    },
+    Unlock {
+        passphrase: Vec<u8>,
+    },
+    /// `SSH_AGENTC_EXTENSION`, see the [`extension`] module for the ones OpenSSH defines.
+    Extension {
+        name: String,
+        contents: Vec<u8>,
+    },
+    /// Any other message, passed through uninterpreted.
+    Other {
+        message_type: MessageType,
+        contents: Vec<u8>,
+    },
+}
+
+impl Request {
+    pub fn parse(body: &[u8]) -> Result<Self, Error> {
+        let message_type = MessageType::of(body)?;
+        let mut reader = wire::Reader::new(&body[1..]);
+        let request = match message_type {
+            MessageType::REQUEST_IDENTITIES => Self::RequestIdentities,
+            MessageType::SIGN_REQUEST => Self::SignRequest {
+                key_blob: reader.string()?.to_vec(),
+                data: reader.string()?.to_vec(),
+                flags: reader.u32()?,
+            },
+            MessageType::ADD_IDENTITY | MessageType::ADD_ID_CONSTRAINED => Self::AddIdentity {
+                constrained: message_type == MessageType::ADD_ID_CONSTRAINED,
+                contents: reader.remaining().to_vec(),
+            },
+            MessageType::REMOVE_IDENTITY => Self::RemoveIdentity {
+                key_blob: reader.string()?.to_vec(),
+            },
+            MessageType::REMOVE_ALL_IDENTITIES => Self::RemoveAllIdentities,
+            MessageType::LOCK => Self::Lock {
+                passphrase: reader.string()?.to_vec(),
+            },
+            MessageType::UNLOCK => Self::Unlock {
+                passphrase: reader.string()?.to_vec(),
+            },
+            MessageType::EXTENSION => Self::Extension {
+                name: reader.text()?,
+                contents: reader.remaining().to_vec(),
+            },
+            message_type => Self::Other {
+                message_type,
+                contents: reader.remaining().to_vec(),
+            },
+        };
+        Ok(request)
+    }
+
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            Self::RequestIdentities => MessageType::REQUEST_IDENTITIES,
+            Self::SignRequest { .. } => MessageType::SIGN_REQUEST,
+            Self::AddIdentity {
+                constrained: false, ..
+            } => MessageType::ADD_IDENTITY,
+            Self::AddIdentity {
+                constrained: true, ..
+            } => MessageType::ADD_ID_CONSTRAINED,
+            Self::RemoveIdentity { .. } => MessageType::REMOVE_IDENTITY,
+            Self::RemoveAllIdentities => MessageType::REMOVE_ALL_IDENTITIES,
+            Self::Lock { .. } => MessageType::LOCK,
+            Self::Unlock { .. } => MessageType::UNLOCK,
+            Self::Extension { .. } => MessageType::EXTENSION,
+            Self::Other { message_type, .. } => *message_type,
+        }
+    }
+
+    /// The message body, ready to be [framed](frame::frame).
+    pub fn encode(&self) -> Vec<u8> {
+        let mut writer = wire::Writer::new();
+        writer.u8(self.message_type().0);
+        match self {
+            Self::RequestIdentities | Self::RemoveAllIdentities => {}
+            Self::SignRequest {
+                key_blob,
+                data,
+                flags,
+            } => {
+                writer.string(key_blob).string(data).u32(*flags);
+            }
+            Self::AddIdentity { contents, .. } | Self::Other { contents, .. } => {
+                writer.bytes(contents);
+            }
+            Self::RemoveIdentity { key_blob } => {
+                writer.string(key_blob);
+            }
+            Self::Lock { passphrase } | Self::Unlock { passphrase } => {
+                writer.string(passphrase);
+            }
+            Self::Extension { name, contents } => {
+                writer.string(name.as_bytes()).bytes(contents);
+            }
+        }
+        writer.into_inner()
+    }
+}
+
+/// A key held by the agent, as listed in `SSH_AGENT_IDENTITIES_ANSWER`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    /// The public key, in SSH wire format.
+    pub key_blob: Vec<u8>,
+    pub comment: String,
+}
+
+impl Identity {
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.key_blob)
+    }
+
+    /// The key's algorithm, e.g. `ssh-ed25519`, which is the first field of the key blob.
+    pub fn key_type(&self) -> Result<String, Error> {
+        wire::Reader::new(&self.key_blob).text()
+    }
+}
+
+/// The OpenSSH-style fingerprint of a public key blob, e.g. `SHA256:uNiVztk...j3tD2s`.
+pub fn fingerprint(key_blob: &[u8]) -> String {
+    use base64::Engine as _;
+    use sha2::Digest as _;
+
+    let digest = sha2::Sha256::digest(key_blob);
+    format!(
+        "SHA256:{}",
+        base64::engine::general_purpose::STANDARD_NO_PAD.encode(digest)
+    )
+}
+
+/// A message from the agent to a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Failure,
+    Success,
+    IdentitiesAnswer(Vec<Identity>),
+    SignResponse {
+        signature: Vec<u8>,
+    },
+    ExtensionFailure,
+    /// Any other message (e.g. an extension's response), passed through uninterpreted.
+    Other {
+        message_type: MessageType,
+        contents: Vec<u8>,
+    },
+}
+
+impl Response {
+    pub fn parse(body: &[u8]) -> Result<Self, Error> {
+        let message_type = MessageType::of(body)?;
+        let mut reader = wire::Reader::new(&body[1..]);
+        let response = match message_type {
+            MessageType::FAILURE => Self::Failure,
+            MessageType::SUCCESS => Self::Success,
+            MessageType::IDENTITIES_ANSWER => {
+                let count = reader.u32()?;
+                // Don't trust `count` for the allocation, each identity is at least 8 bytes.
+                let mut identities = Vec::with_capacity((count as usize).min(body.len() / 8));
+                for _ in 0..count {
+                    identities.push(Identity {
+                        key_blob: reader.string()?.to_vec(),
+                        comment: reader.text()?,
+                    });
+                }
+                Self::IdentitiesAnswer(identities)
+            }
+            MessageType::SIGN_RESPONSE => Self::SignResponse {
+                signature: reader.string()?.to_vec(),
+            },
+            MessageType::EXTENSION_FAILURE => Self::ExtensionFailure,
+            message_type => Self::Other {
+                message_type,
+                contents: reader.remaining().to_vec(),
+            },
+        };
+        Ok(response)
+    }
+
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            Self::Failure => MessageType::FAILURE,
+            Self::Success => MessageType::SUCCESS,
+            Self::IdentitiesAnswer(_) => MessageType::IDENTITIES_ANSWER,
+            Self::SignResponse { .. } => MessageType::SIGN_RESPONSE,
+            Self::ExtensionFailure => MessageType::EXTENSION_FAILURE,
+            Self::Other { message_type, .. } => *message_type,
+        }
+    }
+
+    /// The message body, ready to be [framed](frame::frame).
+    pub fn encode(&self) -> Vec<u8> {
+        let mut writer = wire::Writer::new();
+        writer.u8(self.message_type().0);
+        match self {
+            Self::Failure | Self::Success | Self::ExtensionFailure => {}
+            Self::IdentitiesAnswer(identities) => {
+                writer.u32(identities.len() as u32);
+                for identity in identities {
+                    writer
+                        .string(&identity.key_blob)
+                        .string(identity.comment.as_bytes());
+                }
+            }
+            Self::SignResponse { signature } => {
+                writer.string(signature);
+            }
+            Self::Other { contents, .. } => {
+                writer.bytes(contents);
+            }
+        }
+        writer.into_inner()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requests() -> Vec<Request> {
+        vec![
+            Request::RequestIdentities,
+            Request::SignRequest {
+                key_blob: b"key".to_vec(),
+                data: b"session".to_vec(),
+                flags: 4,
+            },
+            Request::AddIdentity {
+                constrained: false,
+                contents: b"\0\0\0\x0bssh-ed25519".to_vec(),
+            },
+            Request::AddIdentity {
+                constrained: true,
+                contents: b"\0\0\0\x0bssh-ed25519\x01\0\0\0\x3c".to_vec(),
+            },
+            Request::RemoveIdentity {
+                key_blob: b"key".to_vec(),
+            },
+            Request::RemoveAllIdentities,
+            Request::Lock {
+                passphrase: b"secret".to_vec(),
+            },
+            Request::Unlock {
+                passphrase: b"secret".to_vec(),
+            },
+            Request::Extension {
+                name: "query".to_owned(),
+                contents: Vec::new(),
+            },
+            Request::Other {
+                message_type: MessageType(99),
+                contents: b"anything".to_vec(),
+            },
+        ]
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        for request in requests() {
+            let body = request.encode();
+            assert_eq!(MessageType::of(&body).unwrap(), request.message_type());
+            assert_eq!(Request::parse(&body).unwrap(), request);
+        }
+    }
+
+    #[test]
+    fn parses_a_sign_request() {
+        let body = b"\x0d\0\0\0\x03key\0\0\0\x04data\0\0\0\x02";
+        assert_eq!(
+            Request::parse(body).unwrap(),
+            Request::SignRequest {
+                key_blob: b"key".to_vec(),
+                data: b"data".to_vec(),
+                flags: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn truncated_requests_are_errors() {
+        assert!(matches!(Request::parse(&[]), Err(Error::Empty)));
+        for request in requests() {
+            let body = request.encode();
+            // Requests ending in uninterpreted contents can be cut short anywhere.
+            if matches!(
+                request,
+                Request::AddIdentity { .. } | Request::Extension { .. } | Request::Other { .. }
+            ) {
+                continue;
+            }
+            for len in 1..body.len() {
+                assert!(
+                    matches!(Request::parse(&body[..len]), Err(Error::Truncated)),
+                    "{:?} cut to {} bytes",
+                    request,
+                    len
+                );
+            }
+        }
+        assert!(matches!(
+            Request::parse(b"\x1b\0\0\0\x05que"),
+            Err(Error::Truncated)
+        ));
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        for response in [
+            Response::Failure,
+            Response::Success,
+            Response::IdentitiesAnswer(Vec::new()),
+            Response::IdentitiesAnswer(vec![
+                Identity {
+                    key_blob: b"first".to_vec(),
+                    comment: "me@laptop".to_owned(),
+                },
+                Identity {
+                    key_blob: b"second".to_vec(),
+                    comment: String::new(),
+                },
+            ]),
+            Response::SignResponse {
+                signature: b"signature".to_vec(),
+            },
+            Response::ExtensionFailure,
+            Response::Other {
+                message_type: MessageType(99),
+                contents: b"anything".to_vec(),
+            },
+        ] {
+            let body = response.encode();
+            assert_eq!(MessageType::of(&body).unwrap(), response.message_type());
+            assert_eq!(Response::parse(&body).unwrap(), response);
+        }
+    }
+
+    #[test]
+    fn an_identities_answer_claiming_more_keys_than_it_holds_is_truncated() {
+        let body = b"\x0c\xff\xff\xff\xff\0\0\0\x03key\0\0\0\0";
+        assert!(matches!(Response::parse(body), Err(Error::Truncated)));
+    }
+
+    #[test]
+    fn responses_answer_their_requests() {
+        use MessageType as T;
+
+        assert!(T::IDENTITIES_ANSWER.answers(T::REQUEST_IDENTITIES));
+        assert!(!T::SIGN_RESPONSE.answers(T::REQUEST_IDENTITIES));
+        assert!(T::SIGN_RESPONSE.answers(T::SIGN_REQUEST));
+        assert!(!T::SUCCESS.answers(T::SIGN_REQUEST));
+        assert!(T::SUCCESS.answers(T::LOCK));
+        assert!(!T::IDENTITIES_ANSWER.answers(T::ADD_IDENTITY));
+        assert!(T::EXTENSION_FAILURE.answers(T::EXTENSION));
+        assert!(!T::SIGN_RESPONSE.answers(T::EXTENSION));
+        // Anything can fail.
+        for request in [
+            T::REQUEST_IDENTITIES,
+            T::SIGN_REQUEST,
+            T::UNLOCK,
+            T::EXTENSION,
+        ] {
+            assert!(T::FAILURE.answers(request));
+        }
+        // Unknown requests could be answered with anything.
+        assert!(T(99).answers(T(98)));
+    }
+
+    #[test]
+    fn names_message_types() {
+        assert_eq!(
+            MessageType::SIGN_REQUEST.to_string(),
+            "SSH_AGENTC_SIGN_REQUEST"
+        );
+        assert_eq!(format!("{:?}", MessageType(99)), "UNKNOWN(99)");
+    }
+
+    #[test]
+    fn fingerprints_like_openssh() {
+        use base64::Engine as _;
+
+        let key_blob = base64::engine::general_purpose::STANDARD
+            .decode("AAAAC3NzaC1lZDI1NTE5AAAAICBgwMp6/nJnnIkczX/IqtCj8/CimCFiaU5xEqfXIfYC")
+            .unwrap();
+        let identity = Identity {
+            key_blob,
+            comment: String::new(),
+        };
+        // As `ssh-keygen -l` shows it.
+        assert_eq!(
+            identity.fingerprint(),
+            "SHA256:5Dp1CrF8uktTMV0Mha1N8gGxR7KCtGqwrEcZhma2r4M"
+        );
+        assert_eq!(identity.key_type().unwrap(), "ssh-ed25519");
+    }
+}
@@ -0,0 +1,179 @@
+//! Errors reported by a helper (e.g. `pageant.exe`) to the bridge that spawned it.
+//!
+//! A helper relaying the agent protocol over its stdin/stdout can only answer a request it
+//! couldn't forward with `SSH_AGENT_FAILURE`, and if it gives up altogether the bridge just sees
+//! the pipe close. So, when the bridge sets [`ENV`], the helper also writes a [`Report`] of each
+//! error to stderr, as a line of JSON, which the bridge picks out from the helper's other output
+//! to log the cause (and whether it's worth retrying).
+
+use serde::{Deserialize, Serialize};
+
+/// Set (to anything) in a helper's environment to have it report errors on stderr.
+pub const ENV: &str = "WSL_SYSTEMD_REPORT_ERRORS";
+
+/// What went wrong, broadly.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Kind {
+    /// The agent isn't running.
+    AgentNotRunning,
+    /// The agent didn't answer in time.
+    AgentTimeout,
+    /// The agent wouldn't take the request.
+    AgentRefused,
+    /// The agent's response was malformed or didn't fit the request.
+    BadResponse,
+    /// The client's connection failed.
+    Io,
+    /// Anything else, e.g. an unexpected Windows API failure.
+    Other,
+}
+
+impl Kind {
+    /// Whether the same request may well succeed if tried again later.
+    pub fn is_transient(self) -> bool {
+        matches!(self, Self::AgentNotRunning | Self::AgentTimeout)
+    }
+}
+
+impl std::fmt::Display for Kind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::AgentNotRunning => "agent-not-running",
+            Self::AgentTimeout => "agent-timeout",
+            Self::AgentRefused => "agent-refused",
+            Self::BadResponse => "bad-response",
+            Self::Io => "io",
+            Self::Other => "other",
+        };
+        f.write_str(name)
+    }
+}
+
+/// An error reported by a helper.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// Named so the line can't be mistaken for a JSON log message.
+    #[serde(rename = "wsl-systemd-error")]
+    pub kind: Kind,
+    /// The error, with its causes.
+    pub message: String,
+    /// The helper is exiting, rather than answering the request with `SSH_AGENT_FAILURE` and
+    /// carrying on.
+    pub fatal: bool,
+}
+
+impl Report {
+    /// A report of `error`, including its chain of causes.
+    pub fn new(kind: Kind, error: &dyn std::error::Error, fatal: bool) -> Self {
+        let mut message = error.to_string();
+        let mut source = error.source();
+        while let Some(e) = source {
+            message.push_str(": ");
+            message.push_str(&e.to_string());
+            source = e.source();
+        }
+        Self {
+            kind,
+            message,
+            fatal,
+        }
+    }
+
+    /// Whether the helper was asked to report errors.
+    pub fn enabled() -> bool {
+        std::env::var_os(ENV).is_some()
+    }
+
+    /// The report as a line for stderr (without the newline).
+    pub fn to_line(&self) -> String {
+        serde_json::to_string(self).expect("reports can be serialized")
+    }
+
+    /// The report on `line` of a helper's stderr, if it is one.
+    pub fn parse(line: &str) -> Option<Self> {
+        serde_json::from_str(line.trim_end()).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Failed(Option<std::io::Error>);
+
+    impl std::fmt::Display for Failed {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str("Failed to send the request")
+        }
+    }
+
+    impl std::error::Error for Failed {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            self.0.as_ref().map(|e| e as _)
+        }
+    }
+
+    #[test]
+    fn includes_the_causes() {
+        let error = Failed(Some(std::io::Error::other("window not found")));
+        let report = Report::new(Kind::AgentNotRunning, &error, false);
+        assert_eq!(
+            report.message,
+            "Failed to send the request: window not found"
+        );
+        assert_eq!(
+            Report::new(Kind::Other, &Failed(None), true).message,
+            "Failed to send the request"
+        );
+    }
+
+    #[test]
+    fn round_trips_through_a_line() {
+        let report = Report::new(Kind::AgentTimeout, &Failed(None), true);
+        let line = report.to_line();
+        assert_eq!(
+            line,
+            r#"{"wsl-systemd-error":"agent-timeout","message":"Failed to send the request","fatal":true}"#
+        );
+        assert_eq!(Report::parse(&format!("{}\r\n", line)), Some(report));
+    }
+
+    #[test]
+    fn ignores_other_output() {
+        for line in [
+            "",
+            "Error: Pageant isn't running",
+            r#"{"level":"ERROR","message":"Pageant isn't running"}"#,
+            r#"{"wsl-systemd-error":"unheard-of","message":"","fatal":false}"#,
+        ] {
+            assert_eq!(Report::parse(line), None, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn retries_only_while_the_agent_is_unavailable() {
+        assert!(Kind::AgentNotRunning.is_transient());
+        assert!(Kind::AgentTimeout.is_transient());
+        assert!(!Kind::AgentRefused.is_transient());
+        assert!(!Kind::BadResponse.is_transient());
+    }
+
+    #[test]
+    fn displays_kinds_as_serialized() {
+        for kind in [
+            Kind::AgentNotRunning,
+            Kind::AgentTimeout,
+            Kind::AgentRefused,
+            Kind::BadResponse,
+            Kind::Io,
+            Kind::Other,
+        ] {
+            assert_eq!(
+                serde_json::to_string(&kind).unwrap(),
+                format!("\"{}\"", kind)
+            );
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! The primitive encodings used by the agent protocol (RFC 4251 section 5).
+
+use crate::Error;
+
+/// Reads primitive values from the front of a message body.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    /// The bytes that haven't been read yet.
+    pub fn remaining(&self) -> &'a [u8] {
+        self.buf
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.buf.len() < len {
+            return Err(Error::Truncated);
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes(bytes.try_into().expect("read 4 bytes")))
+    }
+
+    /// A `string`: a `uint32` length followed by that many bytes.
+    pub fn string(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.u32()? as usize;
+        self.bytes(len)
+    }
+
+    /// A `string` that's expected to contain UTF-8 text, invalid sequences are replaced.
+    pub fn text(&mut self) -> Result<String, Error> {
+        Ok(String::from_utf8_lossy(self.string()?).into_owned())
+    }
+}
+
+/// Appends primitive values to a message body.
+#[derive(Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.buf.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn u8(&mut self, value: u8) -> &mut Self {
+        self.buf.push(value);
+        self
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    pub fn string(&mut self, bytes: &[u8]) -> &mut Self {
+        self.u32(bytes.len() as u32).bytes(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_what_was_written() {
+        let mut writer = Writer::new();
+        writer.u8(7).u32(0x0102_0304).string(b"text").bytes(b"rest");
+        let buf = writer.into_inner();
+        assert_eq!(buf, b"\x07\x01\x02\x03\x04\0\0\0\x04textrest");
+
+        let mut reader = Reader::new(&buf);
+        assert_eq!(reader.u8().unwrap(), 7);
+        assert_eq!(reader.u32().unwrap(), 0x0102_0304);
+        assert_eq!(reader.text().unwrap(), "text");
+        assert_eq!(reader.remaining(), b"rest");
+        assert_eq!(reader.bytes(4).unwrap(), b"rest");
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn reading_past_the_end_is_an_error() {
+        assert!(matches!(
+            Reader::new(b"\0\0\0").u32(),
+            Err(Error::Truncated)
+        ));
+        // A string claiming more than is left is left unread.
+        let mut reader = Reader::new(b"\0\0\0\x05four");
+        assert!(matches!(reader.string(), Err(Error::Truncated)));
+        assert_eq!(reader.remaining(), b"four");
+        assert!(matches!(Reader::new(&[]).u8(), Err(Error::Truncated)));
+    }
+
+    #[test]
+    fn invalid_text_is_replaced() {
+        let mut reader = Reader::new(b"\0\0\0\x02\xffa");
+        assert_eq!(reader.text().unwrap(), "\u{fffd}a");
+    }
+}
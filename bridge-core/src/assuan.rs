@@ -0,0 +1,477 @@
+//! Connecting to gpg-agent's emulated Unix socket on Windows.
+//!
+//! The "socket" is a file holding a localhost TCP port and a nonce that must be sent to
+//! authenticate the connection. Both change every time gpg-agent restarts, which would leave a
+//! long-lived client talking to a dead connection, so if the agent goes away while the client is
+//! idle (i.e. not waiting for a response), or before any of the client's latest command reached
+//! it, [`Assuan::relay`] holds on to the client and transparently connects to the new agent once
+//! there's a command to send it, retrying with backoff while the agent starts up. Only a command
+//! the old agent was part way through handling is lost, and the client with it. Per-session state
+//! (e.g. `OPTION`s the client set) doesn't survive this, any more than it would survive the
+//! restart.
+
+use std::io::Read as _;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio_util::sync::CancellationToken;
+
+use crate::outbox::{earliest, Outbox};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("An IO Error occurred opening the Assuan file")]
+    IO(#[from] std::io::Error),
+    #[error("Failed to parse port from the Assan file")]
+    PortParse(#[from] std::num::ParseIntError),
+    #[error("Failed to parse nonce from the Assuan file")]
+    NonceParse,
+    #[error("gpg-agent hung up without greeting us (is the nonce in the Assuan file stale?)")]
+    NoGreeting,
+    #[error("Timed out waiting for gpg-agent to greet us")]
+    GreetingTimeout,
+    #[error("gpg-agent refused the connection: {0}")]
+    Refused(AgentError),
+    #[error("Unexpected greeting from gpg-agent: {0:?}")]
+    UnexpectedGreeting(String),
+}
+
+/// How long to wait for the agent's greeting before giving up on it.
+const GREETING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to keep trying to reach the agent again once it's gone away.
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The longest line the Assuan protocol allows, including the newline.
+const MAX_LINE_LEN: usize = 1002;
+
+/// The most of the socket file that's read, far more than the port and nonce need.
+const MAX_SOCKET_FILE_LEN: u64 = 1024;
+
+/// The contents of gpg-agent's socket file.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SocketFile {
+    /// The port on localhost that the agent's listening on.
+    pub port: u16,
+    /// Sent to the agent first, to authenticate the connection.
+    pub nonce: [u8; 16],
+}
+
+impl SocketFile {
+    /// Parse the socket file, which is the port in decimal, a newline and the 16-byte nonce.
+    pub fn parse(contents: &[u8]) -> Result<Self, Error> {
+        let newline = contents.iter().position(|&byte| byte == b'\n');
+        let (port, nonce) = match newline {
+            Some(newline) => (&contents[..newline], &contents[newline + 1..]),
+            None => (contents, &[][..]),
+        };
+        let nonce = nonce
+            .get(..16)
+            .and_then(|nonce| nonce.try_into().ok())
+            .ok_or(Error::NonceParse)?;
+        let port = String::from_utf8_lossy(port).trim().parse()?;
+        Ok(Self { port, nonce })
+    }
+}
+
+/// A connection to gpg-agent, ready to be relayed to a client.
+pub struct Assuan {
+    path: PathBuf,
+    backend: Backend,
+}
+
+struct Backend {
+    reader: OwnedReadHalf,
+    writer: OwnedWriteHalf,
+    greeting: Vec<u8>,
+    /// When the socket file was last modified, as of connecting. A change means the agent has
+    /// restarted.
+    modified: Option<SystemTime>,
+}
+
+impl Backend {
+    async fn connect(path: &Path) -> Result<Self, Error> {
+        tracing::debug!(path = %path.display(), "Opening Assuan file");
+        let modified = modified(path);
+        let mut contents = Vec::new();
+        std::fs::File::open(path)?
+            .take(MAX_SOCKET_FILE_LEN)
+            .read_to_end(&mut contents)?;
+        let SocketFile { port, nonce } = SocketFile::parse(&contents)?;
+
+        tracing::debug!(port, "Discovered Assuan socket");
+        trace_secret!(?nonce, "Assuan nonce");
+
+        let mut sock = TcpStream::connect(("127.0.0.1", port)).await?;
+        crate::endpoint::enable_keepalive(&sock);
+        sock.write_all(&nonce[..]).await?;
+        let greeting = tokio::time::timeout(GREETING_TIMEOUT, read_greeting(&mut sock))
+            .await
+            .map_err(|_| Error::GreetingTimeout)??;
+
+        let (reader, writer) = sock.into_split();
+        Ok(Self {
+            reader,
+            writer,
+            greeting,
+            modified,
+        })
+    }
+
+    /// Whether the agent has exited (and removed the socket file) or restarted since connecting.
+    fn is_stale(&self, path: &Path) -> bool {
+        match std::fs::metadata(path) {
+            Err(e) => e.kind() == std::io::ErrorKind::NotFound,
+            Ok(metadata) => {
+                let modified = metadata.modified().ok();
+                modified.is_some() && modified != self.modified
+            }
+        }
+    }
+}
+
+/// Read the agent's first line, which should be `OK Pleased to meet you`. If the nonce was wrong
+/// the agent just hangs up, and if it's unwilling to serve us it says why with an `ERR`.
+async fn read_greeting(sock: &mut TcpStream) -> Result<Vec<u8>, Error> {
+    let mut greeting = Vec::new();
+    while greeting.last() != Some(&b'\n') && greeting.len() < MAX_LINE_LEN {
+        match sock.read_u8().await {
+            Ok(byte) => greeting.push(byte),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Err(Error::NoGreeting)
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let line = String::from_utf8_lossy(&greeting);
+    let line = line.trim_end();
+    tracing::debug!(greeting = %line, "Connected to gpg-agent");
+    if line == "OK" || line.starts_with("OK ") {
+        Ok(greeting)
+    } else if let Some(error) = AgentError::parse(line) {
+        Err(Error::Refused(error))
+    } else {
+        Err(Error::UnexpectedGreeting(line.to_owned()))
+    }
+}
+
+/// An `ERR <code> <description>` line from the agent.
+#[derive(Debug)]
+pub struct AgentError {
+    /// A libgpg-error code, the component that raised the error in the top bits and the error
+    /// itself in the bottom 16.
+    code: u32,
+    description: String,
+}
+
+impl AgentError {
+    fn parse(line: &str) -> Option<Self> {
+        let rest = line.strip_prefix("ERR ")?;
+        let (code, description) = rest.split_once(' ').unwrap_or((rest, ""));
+        Some(Self {
+            code: code.parse().ok()?,
+            description: description.to_owned(),
+        })
+    }
+
+    /// The component the error came from, as named by libgpg-error.
+    fn source(&self) -> &'static str {
+        match self.code >> 24 {
+            1 => "gcrypt",
+            2 => "gpg",
+            3 => "gpgsm",
+            4 => "gpg-agent",
+            5 => "pinentry",
+            6 => "scdaemon",
+            7 => "gpgme",
+            8 => "keybox",
+            9 => "ksba",
+            10 => "dirmngr",
+            11 => "gsti",
+            12 => "gpa",
+            13 => "kleopatra",
+            14 => "g13",
+            15 => "assuan",
+            _ => "unknown",
+        }
+    }
+}
+
+impl std::fmt::Display for AgentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = match self.description.trim() {
+            "" => "no description",
+            description => description,
+        };
+        write!(
+            f,
+            "{} (error {} from {})",
+            description,
+            self.code & 0xffff,
+            self.source()
+        )
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+impl Assuan {
+    pub async fn connect(path: &Path) -> Result<Self, Error> {
+        Ok(Self {
+            path: path.to_owned(),
+            backend: Backend::connect(path).await?,
+        })
+    }
+
+    /// Relay between `client` and the agent until both are finished, returning the number of
+    /// bytes sent to the agent and to the client.
+    ///
+    /// Once `stop` is cancelled, the client is treated as having closed the connection as soon as
+    /// the agent has finished responding to its current command. The idle timeout only applies
+    /// between commands, the agent may legitimately take a long time to respond (e.g. while
+    /// pinentry waits for a passphrase).
+    pub async fn relay<C>(
+        self,
+        client: &mut C,
+        options: crate::relay::Options,
+        stop: &CancellationToken,
+        hooks: &dyn crate::relay::Hooks,
+    ) -> std::io::Result<(u64, u64)>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+    {
+        let Self { path, backend } = self;
+        let (mut client_reader, mut client_writer) = tokio::io::split(client);
+        client_writer.write_all(&backend.greeting).await?;
+        hooks.to_client(&backend.greeting);
+        let mut sent_to_client = backend.greeting.len() as u64;
+        let mut sent_to_backend = 0;
+
+        let mut backend = Some(backend);
+        let mut session = Session::default();
+        // Some of the client's unanswered commands have been written to the agent, so they'd be
+        // lost with it.
+        let mut delivered = false;
+        let mut client_open = true;
+        let mut backend_shut = false;
+        let mut to_backend = Outbox::new(options.buffer_size, "gpg-agent");
+        let mut to_client = Outbox::new(options.buffer_size, "client");
+        let idle = tokio::time::sleep(options.idle_timeout.unwrap_or_default());
+        tokio::pin!(idle);
+        loop {
+            if backend.is_none() && !to_backend.is_empty() {
+                backend = Some(reconnect(&path).await?);
+                backend_shut = false;
+                hooks.reconnected();
+            }
+            // Once the client's finished, pass that on as soon as the agent has all it sent.
+            if !client_open && !backend_shut && to_backend.is_empty() {
+                match &mut backend {
+                    Some(backend) => backend.writer.shutdown().await?,
+                    None => break,
+                }
+                backend_shut = true;
+            }
+            let stall = earliest(to_backend.stall_deadline(), to_client.stall_deadline());
+            let (from_client, for_backend) = to_backend.buffers();
+            let (from_backend, for_client) = to_client.buffers();
+            let connected = backend.is_some();
+            let (backend_reader, backend_writer) = match &mut backend {
+                Some(backend) => (Some(&mut backend.reader), Some(&mut backend.writer)),
+                None => (None, None),
+            };
+            tokio::select! {
+                // If the agent's hung up, see that before sending it anything more, so a command
+                // isn't lost with it when it could be sent to the new agent.
+                biased;
+                read = client_reader.read(from_client),
+                    if client_open && !from_client.is_empty() =>
+                {
+                    let len = read?;
+                    if len == 0 {
+                        client_open = false;
+                        continue;
+                    }
+                    let data = to_backend.filled(len);
+                    hooks.to_backend(data);
+                    session.observe_command(data);
+                    if backend.as_ref().is_some_and(|current| current.is_stale(&path)) {
+                        tracing::info!("gpg-agent has exited or restarted");
+                        backend = None;
+                    }
+                    if let Some(timeout) = options.idle_timeout {
+                        idle.as_mut().reset(tokio::time::Instant::now() + timeout);
+                    }
+                }
+                read = async move {
+                    backend_reader
+                        .expect("branch is disabled without a backend")
+                        .read(from_backend)
+                        .await
+                }, if connected && !from_backend.is_empty() => {
+                    match read {
+                        Ok(len) if len > 0 => {
+                            let data = to_client.filled(len);
+                            session.observe_response(data);
+                            delivered &= session.awaiting_response;
+                            hooks.to_client(data);
+                            if let Some(timeout) = options.idle_timeout {
+                                idle.as_mut().reset(tokio::time::Instant::now() + timeout);
+                            }
+                        }
+                        _ if !client_open || session.said_bye || delivered => {
+                            read?;
+                            break;
+                        }
+                        // The agent went away between commands, reconnect once there's a command
+                        // for it.
+                        _ => {
+                            tracing::info!("gpg-agent closed the connection while idle");
+                            backend = None;
+                        }
+                    }
+                }
+                written = async move {
+                    backend_writer
+                        .expect("branch is disabled without a backend")
+                        .write(for_backend)
+                        .await
+                }, if connected && !for_backend.is_empty() => {
+                    match written {
+                        Ok(len) => {
+                            to_backend.consumed(len);
+                            sent_to_backend += len as u64;
+                            delivered = true;
+                        }
+                        // Nothing's lost, the command's still waiting to be sent to the new agent.
+                        Err(e) if !delivered => {
+                            tracing::info!(error = %e, "gpg-agent went away before the command");
+                            backend = None;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                written = client_writer.write(for_client), if !for_client.is_empty() => {
+                    let len = written?;
+                    to_client.consumed(len);
+                    sent_to_client += len as u64;
+                    if to_client.is_empty() {
+                        client_writer.flush().await?;
+                    }
+                }
+                () = &mut idle, if options.idle_timeout.is_some() && !session.awaiting_response => {
+                    let timeout = options.idle_timeout.unwrap_or_default();
+                    tracing::info!(?timeout, "Closing idle connection");
+                    break;
+                }
+                () = stop.cancelled(), if client_open && !session.awaiting_response => {
+                    tracing::debug!("Ending the session to shut down");
+                    client_open = false;
+                }
+                () = tokio::time::sleep_until(stall.unwrap_or_else(tokio::time::Instant::now)),
+                    if stall.is_some() =>
+                {
+                    to_backend.check_stall();
+                    to_client.check_stall();
+                }
+            }
+        }
+
+        // Pass on the rest of the agent's response.
+        client_writer.write_all(to_client.pending()).await?;
+        sent_to_client += to_client.pending().len() as u64;
+        client_writer.shutdown().await?;
+        Ok((sent_to_backend, sent_to_client))
+    }
+}
+
+/// Connect to the restarted agent, re-reading the socket file and retrying with backoff for up to
+/// [`RECONNECT_TIMEOUT`] while it starts up.
+///
+/// The new agent's greeting isn't passed on, the client has already had one from the old agent.
+async fn reconnect(path: &Path) -> std::io::Result<Backend> {
+    tracing::info!(path = %path.display(), "Reconnecting to gpg-agent");
+    let deadline = tokio::time::Instant::now() + RECONNECT_TIMEOUT;
+    let mut delay = Duration::from_millis(100);
+    loop {
+        let e = match Backend::connect(path).await {
+            Ok(backend) => return Ok(backend),
+            Err(e) => e,
+        };
+        if tokio::time::Instant::now() + delay >= deadline {
+            return Err(match e {
+                Error::IO(e) => e,
+                e => std::io::Error::new(std::io::ErrorKind::ConnectionRefused, e),
+            });
+        }
+        tracing::debug!(error = %e, ?delay, "gpg-agent isn't back yet");
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(Duration::from_secs(1));
+    }
+}
+
+/// What's been seen of the conversation between the client and the agent.
+#[derive(Default)]
+struct Session {
+    /// The client has sent a command that the agent hasn't finished responding to.
+    awaiting_response: bool,
+    /// The agent's current response line so far (truncated, but enough to decode an `ERR`).
+    response_line: Vec<u8>,
+    /// The start of the client's current command line (enough to spot `BYE`).
+    command_start: Vec<u8>,
+    /// The client has ended the session, so the agent closing the connection is expected.
+    said_bye: bool,
+}
+
+impl Session {
+    /// Track the agent's response lines, to know when it's finished responding to a command, and
+    /// log any errors it reports.
+    fn observe_response(&mut self, data: &[u8]) {
+        /// Enough to hold the code and (the start of) the description of an `ERR`.
+        const MAX_TRACKED: usize = 256;
+
+        for &byte in data {
+            if byte != b'\n' {
+                if self.response_line.len() < MAX_TRACKED {
+                    self.response_line.push(byte);
+                }
+                continue;
+            }
+            let line = String::from_utf8_lossy(&self.response_line);
+            if line == "OK" || line.starts_with("OK ") {
+                self.awaiting_response = false;
+            } else if line.starts_with("ERR ") {
+                self.awaiting_response = false;
+                match AgentError::parse(&line) {
+                    Some(error) => tracing::info!(%error, "gpg-agent reported an error"),
+                    None => tracing::info!(line = %line, "gpg-agent reported a malformed error"),
+                }
+            }
+            self.response_line.clear();
+        }
+    }
+
+    /// Track the client's command lines, to know when it's waiting for a response and when it's
+    /// ended the session.
+    fn observe_command(&mut self, data: &[u8]) {
+        self.awaiting_response = true;
+        for &byte in data {
+            if byte == b'\n' {
+                self.command_start.clear();
+            } else if self.command_start.len() < 3 {
+                self.command_start.push(byte);
+                if self.command_start == b"BYE" {
+                    self.said_bye = true;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! Connections to the targets a bridge can forward to.
+
+use std::path::Path;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// How long a TCP connection can be silent before keepalive probes are sent, and then the
+/// interval between probes.
+const KEEPALIVE_TIME: Duration = Duration::from_secs(60);
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A byte stream that can be relayed.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// An open connection to a bridge's target.
+pub enum Endpoint {
+    /// gpg-agent, which needs to be relayed with some knowledge of the protocol.
+    Assuan(crate::assuan::Assuan),
+    /// Anything else, which is relayed byte for byte.
+    Stream(Box<dyn Stream>),
+}
+
+impl Endpoint {
+    /// Relay `stream` byte for byte.
+    pub fn stream(stream: impl Stream + 'static) -> Self {
+        Self::Stream(Box::new(stream))
+    }
+
+    /// Connect to the gpg-agent whose Assuan socket file is `path`.
+    pub async fn assuan(path: &Path) -> Result<Self, crate::assuan::Error> {
+        Ok(Self::Assuan(crate::assuan::Assuan::connect(path).await?))
+    }
+
+    /// Connect to a TCP port, see [`connect_tcp`].
+    pub async fn tcp(address: &str) -> std::io::Result<Self> {
+        Ok(Self::stream(connect_tcp(address).await?))
+    }
+
+    /// Connect to a Unix socket.
+    #[cfg(unix)]
+    pub async fn unix(path: &Path) -> std::io::Result<Self> {
+        Ok(Self::stream(tokio::net::UnixStream::connect(path).await?))
+    }
+}
+
+/// Connect to a TCP port, with keepalives enabled.
+pub async fn connect_tcp(address: &str) -> std::io::Result<tokio::net::TcpStream> {
+    let sock = tokio::net::TcpStream::connect(address).await?;
+    enable_keepalive(&sock);
+    Ok(sock)
+}
+
+/// Have the OS probe `sock` while it's quiet, so a peer that's vanished without closing the
+/// connection (e.g. across a sleep/resume) is noticed.
+pub fn enable_keepalive(sock: &tokio::net::TcpStream) {
+    let keepalive = socket2::TcpKeepalive::new()
+        .with_time(KEEPALIVE_TIME)
+        .with_interval(KEEPALIVE_INTERVAL);
+    if let Err(e) = socket2::SockRef::from(sock).set_tcp_keepalive(&keepalive) {
+        tracing::warn!(error = %e, "Failed to enable TCP keepalive");
+    }
+}
@@ -0,0 +1,35 @@
+//! The plumbing for bridging a stream between two places, e.g. a Unix socket inside WSL and a
+//! service on the Windows host.
+//!
+//! A bridge accepts (or is handed) a client, connects to an [`endpoint::Endpoint`] and relays
+//! between the two until both sides are done, see [`relay::relay`]. Byte streams are relayed
+//! as-is, while gpg-agent's Assuan sockets get enough protocol awareness to survive the agent
+//! restarting (see [`assuan`]). Either way, memory use is capped by an [`outbox::Outbox`] in each
+//! direction, and a side that stops reading holds the other back. [`shutdown`] lets a bridge
+//! wind its connections down cleanly when asked to stop, and [`sync`] has a simpler,
+//! thread-based relay for programs that don't otherwise need an async runtime.
+
+/// Whether secret material (e.g. Assuan nonces) may be logged, at trace level.
+static LOG_SECRETS: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// `tracing::trace!` for events that include secret material, which are only emitted if
+/// enabled with [`log_secrets`].
+macro_rules! trace_secret {
+    ($($arg:tt)*) => {
+        if crate::LOG_SECRETS.load(std::sync::atomic::Ordering::Relaxed) {
+            tracing::trace!($($arg)*);
+        }
+    };
+}
+
+pub mod assuan;
+pub mod endpoint;
+pub mod outbox;
+pub mod relay;
+pub mod shutdown;
+pub mod sync;
+
+/// Include secret material (e.g. Assuan nonces) in trace-level logs.
+pub fn log_secrets(enabled: bool) {
+    LOG_SECRETS.store(enabled, std::sync::atomic::Ordering::Relaxed);
+}
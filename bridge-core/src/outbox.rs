@@ -0,0 +1,227 @@
+//! Bounded buffering between the two sides of a connection.
+//!
+//! Each direction of a relayed connection has an [`Outbox`]: what's been read from one side and
+//! not yet written to the other. It never holds more than its capacity. Once it's full, reading
+//! from the sending side stops until the receiving side has taken at least half of it, so a side
+//! that stalls (a locked Windows desktop, a pinentry waiting for the user) holds its peer back
+//! rather than having the peer's data pile up in memory. Writing never waits on reading, so one
+//! direction stalling doesn't stop the other. A stall that lasts is logged, rather than looking
+//! like a silent hang.
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// How long a direction can be stalled before it's logged.
+pub const STALL_WARNING: Duration = Duration::from_secs(10);
+
+/// Data read from one side of a connection, waiting to be written to the other.
+pub struct Outbox {
+    buf: Box<[u8]>,
+    /// The waiting data is `buf[start..end]`.
+    start: usize,
+    end: usize,
+    /// Reading is paused until the waiting data drops to the low watermark (half the capacity).
+    paused: bool,
+    /// When the outbox last filled up, if reading's paused.
+    full_since: Option<Instant>,
+    /// Whether the current stall has been logged.
+    warned: bool,
+    /// The side the data's going to, for the log.
+    to: &'static str,
+}
+
+impl Outbox {
+    /// An empty outbox holding up to `capacity` bytes bound for `to` (e.g. `"client"`).
+    pub fn new(capacity: usize, to: &'static str) -> Self {
+        Self {
+            buf: vec![0; capacity.max(1)].into_boxed_slice(),
+            start: 0,
+            end: 0,
+            paused: false,
+            full_since: None,
+            warned: false,
+            to,
+        }
+    }
+
+    /// The space to read more data into (empty while reading is paused), and the data waiting to
+    /// be written.
+    pub fn buffers(&mut self) -> (&mut [u8], &[u8]) {
+        let (waiting, space) = self.buf.split_at_mut(self.end);
+        let space = if self.paused { &mut [][..] } else { space };
+        (space, &waiting[self.start..])
+    }
+
+    /// The data waiting to be written.
+    pub fn pending(&self) -> &[u8] {
+        &self.buf[self.start..self.end]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// `len` bytes were read into the space from [`Outbox::buffers`], returning them.
+    pub fn filled(&mut self, len: usize) -> &[u8] {
+        let read = self.end;
+        self.end += len;
+        if self.end == self.buf.len() {
+            if self.start == 0 {
+                self.paused = true;
+                self.full_since = Some(Instant::now());
+            } else {
+                // Make room at the end by moving the waiting data to the start.
+                self.buf.copy_within(self.start..self.end, 0);
+                let moved = self.start;
+                self.start = 0;
+                self.end -= moved;
+                return &self.buf[read - moved..self.end];
+            }
+        }
+        &self.buf[read..self.end]
+    }
+
+    /// `len` bytes of the waiting data were written.
+    pub fn consumed(&mut self, len: usize) {
+        self.start += len;
+        if self.start == self.end {
+            self.start = 0;
+            self.end = 0;
+        }
+        if self.paused && self.end - self.start <= self.buf.len() / 2 {
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+            self.paused = false;
+            self.full_since = None;
+            if std::mem::take(&mut self.warned) {
+                tracing::info!(to = self.to, "No longer stalled");
+            }
+        }
+    }
+
+    /// When to call [`Outbox::check_stall`], if reading's paused and the stall hasn't been
+    /// logged yet.
+    pub fn stall_deadline(&self) -> Option<Instant> {
+        match self.full_since {
+            Some(since) if !self.warned => Some(since + STALL_WARNING),
+            _ => None,
+        }
+    }
+
+    /// Log the stall if it's lasted [`STALL_WARNING`].
+    pub fn check_stall(&mut self) {
+        if self.stall_deadline().is_some_and(|at| at <= Instant::now()) {
+            self.warned = true;
+            tracing::warn!(
+                to = self.to,
+                waiting = self.end - self.start,
+                "Stalled, the receiving side isn't reading so neither are we"
+            );
+        }
+    }
+}
+
+/// The earlier of two optional deadlines, e.g. from [`Outbox::stall_deadline`].
+pub fn earliest(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read `data` into `outbox`, as a reader would, returning what `filled` gave back.
+    fn fill(outbox: &mut Outbox, data: &[u8]) -> Vec<u8> {
+        let (space, _) = outbox.buffers();
+        space[..data.len()].copy_from_slice(data);
+        outbox.filled(data.len()).to_vec()
+    }
+
+    fn space(outbox: &mut Outbox) -> usize {
+        outbox.buffers().0.len()
+    }
+
+    #[test]
+    fn hands_on_data_in_order() {
+        let mut outbox = Outbox::new(8, "test");
+        assert!(outbox.is_empty());
+        assert_eq!(fill(&mut outbox, b"abc"), b"abc");
+        assert_eq!(fill(&mut outbox, b"de"), b"de");
+        assert_eq!(outbox.pending(), b"abcde");
+        assert_eq!(outbox.buffers().1, b"abcde");
+        outbox.consumed(2);
+        assert_eq!(outbox.pending(), b"cde");
+        outbox.consumed(3);
+        assert!(outbox.is_empty());
+        // Emptying it starts again at the front.
+        assert_eq!(space(&mut outbox), 8);
+    }
+
+    #[test]
+    fn moves_waiting_data_to_the_front_when_it_reaches_the_end() {
+        let mut outbox = Outbox::new(8, "test");
+        fill(&mut outbox, b"abcdef");
+        outbox.consumed(4);
+        assert_eq!(space(&mut outbox), 2);
+        // Filling the end moves what's waiting to the front, and returns just what was read.
+        assert_eq!(fill(&mut outbox, b"gh"), b"gh");
+        assert_eq!(outbox.pending(), b"efgh");
+        assert_eq!(space(&mut outbox), 4);
+        assert!(outbox.stall_deadline().is_none());
+        assert_eq!(fill(&mut outbox, b"ijkl"), b"ijkl");
+        assert_eq!(outbox.pending(), b"efghijkl");
+    }
+
+    #[test]
+    fn pauses_when_full_until_half_empty() {
+        let mut outbox = Outbox::new(8, "test");
+        fill(&mut outbox, b"abcdefgh");
+        assert_eq!(space(&mut outbox), 0);
+        outbox.consumed(3);
+        assert_eq!(space(&mut outbox), 0);
+        outbox.consumed(1);
+        assert_eq!(outbox.pending(), b"efgh");
+        assert_eq!(space(&mut outbox), 4);
+        assert_eq!(fill(&mut outbox, b"ij"), b"ij");
+        assert_eq!(outbox.pending(), b"efghij");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn warns_once_about_a_lasting_stall() {
+        let mut outbox = Outbox::new(4, "test");
+        fill(&mut outbox, b"abcd");
+        let deadline = outbox.stall_deadline().unwrap();
+        assert_eq!(deadline, Instant::now() + STALL_WARNING);
+
+        tokio::time::advance(STALL_WARNING / 2).await;
+        outbox.check_stall();
+        assert_eq!(outbox.stall_deadline(), Some(deadline));
+        tokio::time::advance(STALL_WARNING / 2).await;
+        outbox.check_stall();
+        assert!(outbox.stall_deadline().is_none());
+
+        // Draining it ends the stall, and filling it again starts another.
+        outbox.consumed(4);
+        assert!(outbox.stall_deadline().is_none());
+        fill(&mut outbox, b"efgh");
+        assert_eq!(
+            outbox.stall_deadline(),
+            Some(Instant::now() + STALL_WARNING)
+        );
+    }
+
+    #[test]
+    fn picks_the_earliest_deadline() {
+        let now = Instant::now();
+        let later = now + STALL_WARNING;
+        assert_eq!(earliest(None, None), None);
+        assert_eq!(earliest(Some(later), None), Some(later));
+        assert_eq!(earliest(None, Some(now)), Some(now));
+        assert_eq!(earliest(Some(later), Some(now)), Some(now));
+    }
+}
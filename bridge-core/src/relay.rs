@@ -0,0 +1,501 @@
+//! Shuffling bytes between a client and a bridge's target.
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::endpoint::Endpoint;
+use crate::outbox::{earliest, Outbox};
+use crate::shutdown::UntilStopped;
+
+/// How connections are relayed.
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// The most data held in each direction, waiting for the receiving side to take it (see
+    /// [`crate::outbox`]).
+    pub buffer_size: usize,
+    /// Close connections that have carried no traffic for this long.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Options {
+    /// Large enough that bulk transfers (e.g. exporting keys) don't take a round trip per line.
+    pub const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            buffer_size: Self::DEFAULT_BUFFER_SIZE,
+            idle_timeout: None,
+        }
+    }
+}
+
+/// Callbacks for what happens on a relayed connection, e.g. to keep statistics. By default they
+/// do nothing, and `()` has the defaults.
+pub trait Hooks: Sync {
+    /// `data` was read from the client, to be sent to the backend.
+    fn to_backend(&self, data: &[u8]) {
+        let _ = data;
+    }
+
+    /// `data` is being sent to the client from the backend.
+    fn to_client(&self, data: &[u8]) {
+        let _ = data;
+    }
+
+    /// The backend went away and was reconnected to (only gpg-agent's are, see
+    /// [`crate::assuan`]).
+    fn reconnected(&self) {}
+
+    /// Both directions finished, having carried this many bytes.
+    fn closed(&self, to_backend: u64, to_client: u64) {
+        let _ = (to_backend, to_client);
+    }
+
+    /// The connection was closed for carrying no traffic for [`Options::idle_timeout`].
+    fn idle(&self) {}
+
+    /// Relaying failed part way through.
+    fn failed(&self, error: &std::io::Error) {
+        let _ = error;
+    }
+}
+
+impl Hooks for () {}
+
+/// Both sets of hooks, in turn.
+impl<A: Hooks, B: Hooks> Hooks for (A, B) {
+    fn to_backend(&self, data: &[u8]) {
+        self.0.to_backend(data);
+        self.1.to_backend(data);
+    }
+
+    fn to_client(&self, data: &[u8]) {
+        self.0.to_client(data);
+        self.1.to_client(data);
+    }
+
+    fn reconnected(&self) {
+        self.0.reconnected();
+        self.1.reconnected();
+    }
+
+    fn closed(&self, to_backend: u64, to_client: u64) {
+        self.0.closed(to_backend, to_client);
+        self.1.closed(to_backend, to_client);
+    }
+
+    fn idle(&self) {
+        self.0.idle();
+        self.1.idle();
+    }
+
+    fn failed(&self, error: &std::io::Error) {
+        self.0.failed(error);
+        self.1.failed(error);
+    }
+}
+
+/// The hooks if there are any, e.g. if an optional feature is enabled.
+impl<H: Hooks> Hooks for Option<H> {
+    fn to_backend(&self, data: &[u8]) {
+        if let Some(hooks) = self {
+            hooks.to_backend(data);
+        }
+    }
+
+    fn to_client(&self, data: &[u8]) {
+        if let Some(hooks) = self {
+            hooks.to_client(data);
+        }
+    }
+
+    fn reconnected(&self) {
+        if let Some(hooks) = self {
+            hooks.reconnected();
+        }
+    }
+
+    fn closed(&self, to_backend: u64, to_client: u64) {
+        if let Some(hooks) = self {
+            hooks.closed(to_backend, to_client);
+        }
+    }
+
+    fn idle(&self) {
+        if let Some(hooks) = self {
+            hooks.idle();
+        }
+    }
+
+    fn failed(&self, error: &std::io::Error) {
+        if let Some(hooks) = self {
+            hooks.failed(error);
+        }
+    }
+}
+
+/// Relay between `client` and `endpoint` until both directions have finished.
+///
+/// When one side reaches EOF the write half of the other side is shut down, so the EOF
+/// propagates through and the other direction winds down naturally. Cancelling `stop` stops
+/// reading from the client, as if it had reached EOF.
+pub async fn relay<C>(
+    client: &mut C,
+    endpoint: Endpoint,
+    options: Options,
+    stop: &CancellationToken,
+    hooks: &dyn Hooks,
+) where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    let result = match endpoint {
+        Endpoint::Assuan(assuan) => assuan.relay(client, options, stop, hooks).await,
+        Endpoint::Stream(mut stream) => {
+            let activity = Activity::new();
+            let mut client = Tracked {
+                inner: UntilStopped::new(client, stop),
+                activity: &activity,
+                hooks,
+            };
+            let copy = pump(&mut client, &mut stream, options.buffer_size);
+            match options.idle_timeout {
+                Some(timeout) => tokio::select! {
+                    result = copy => result,
+                    () = activity.idle_for(timeout) => {
+                        tracing::info!(?timeout, "Closing idle connection");
+                        hooks.idle();
+                        return;
+                    }
+                },
+                None => copy.await,
+            }
+        }
+    };
+    match result {
+        Ok((to_backend, to_client)) => {
+            tracing::debug!(to_backend, to_client, "Streams closed");
+            hooks.closed(to_backend, to_client);
+        }
+        Err(e) => {
+            tracing::warn!(error = %e, "Relay failed");
+            hooks.failed(&e);
+        }
+    }
+}
+
+/// Copy between `client` and `target` until both directions have finished, through an
+/// [`Outbox`] of `buffer_size` each way, returning the number of bytes sent to the target and to
+/// the client.
+///
+/// Each writer is flushed whenever everything waiting for it has been written, so a buffered
+/// writer like stdout doesn't hold on to a reply until more comes along. When one side reaches
+/// EOF, the other's write half is shut down once everything read before it has been written.
+async fn pump<C, T>(client: C, target: T, buffer_size: usize) -> std::io::Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut client_reader, mut client_writer) = tokio::io::split(client);
+    let (mut target_reader, mut target_writer) = tokio::io::split(target);
+    let mut to_target = Outbox::new(buffer_size, "target");
+    let mut to_client = Outbox::new(buffer_size, "client");
+    let (mut client_open, mut target_open) = (true, true);
+    let (mut target_shut, mut client_shut) = (false, false);
+    let (mut sent_to_target, mut sent_to_client) = (0, 0);
+    loop {
+        if !client_open && !target_shut && to_target.is_empty() {
+            target_writer.shutdown().await?;
+            target_shut = true;
+        }
+        if !target_open && !client_shut && to_client.is_empty() {
+            client_writer.shutdown().await?;
+            client_shut = true;
+        }
+        if target_shut && client_shut {
+            return Ok((sent_to_target, sent_to_client));
+        }
+        let stall = earliest(to_target.stall_deadline(), to_client.stall_deadline());
+        let (from_client, for_target) = to_target.buffers();
+        let (from_target, for_client) = to_client.buffers();
+        tokio::select! {
+            read = client_reader.read(from_client), if client_open && !from_client.is_empty() => {
+                match read? {
+                    0 => client_open = false,
+                    len => {
+                        to_target.filled(len);
+                    }
+                }
+            }
+            read = target_reader.read(from_target), if target_open && !from_target.is_empty() => {
+                match read? {
+                    0 => target_open = false,
+                    len => {
+                        to_client.filled(len);
+                    }
+                }
+            }
+            written = target_writer.write(for_target), if !for_target.is_empty() => {
+                let len = written?;
+                to_target.consumed(len);
+                sent_to_target += len as u64;
+                if to_target.is_empty() {
+                    target_writer.flush().await?;
+                }
+            }
+            written = client_writer.write(for_client), if !for_client.is_empty() => {
+                let len = written?;
+                to_client.consumed(len);
+                sent_to_client += len as u64;
+                // Don't leave the last of it in a buffer (stdout's, say) until there's more.
+                if to_client.is_empty() {
+                    client_writer.flush().await?;
+                }
+            }
+            () = tokio::time::sleep_until(stall.unwrap_or_else(Instant::now)),
+                if stall.is_some() =>
+            {
+                to_target.check_stall();
+                to_client.check_stall();
+            }
+        }
+    }
+}
+
+/// When a connection last carried any traffic.
+struct Activity {
+    start: Instant,
+    /// Milliseconds since `start`.
+    last: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// Wait until there's been no traffic for `timeout`.
+    async fn idle_for(&self, timeout: Duration) {
+        loop {
+            let last = self.start + Duration::from_millis(self.last.load(Ordering::Relaxed));
+            if last.elapsed() >= timeout {
+                return;
+            }
+            tokio::time::sleep_until(last + timeout).await;
+        }
+    }
+}
+
+/// A client stream that records its traffic in an [`Activity`], and passes it to the [`Hooks`].
+struct Tracked<'a, S> {
+    inner: S,
+    activity: &'a Activity,
+    hooks: &'a dyn Hooks,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            self.activity.touch();
+            self.hooks.to_backend(&buf.filled()[filled..]);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(len)) = result {
+            if len > 0 {
+                self.activity.touch();
+                self.hooks.to_client(&buf[..len]);
+            }
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Relay `endpoint` to stdin/stdout, e.g. when spawned by systemd for an accepted connection.
+pub async fn attach_to_tty(
+    endpoint: Endpoint,
+    options: Options,
+    stop: &CancellationToken,
+    hooks: &dyn Hooks,
+) {
+    relay(&mut Stdio::default(), endpoint, options, stop, hooks).await
+}
+
+/// The process's stdin and stdout, as a single stream.
+pub struct Stdio {
+    stdin: tokio::io::Stdin,
+    stdout: tokio::io::Stdout,
+}
+
+impl Default for Stdio {
+    fn default() -> Self {
+        Self {
+            stdin: tokio::io::stdin(),
+            stdout: tokio::io::stdout(),
+        }
+    }
+}
+
+impl AsyncRead for Stdio {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stdin).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Stdio {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stdout).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        // Writes happen on a blocking thread, and shutting stdout down doesn't wait for the last
+        // one, which would be lost when the runtime is dropped.
+        std::task::ready!(Pin::new(&mut self.stdout).poll_flush(cx))?;
+        Pin::new(&mut self.stdout).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::DuplexStream;
+
+    use super::*;
+
+    /// `pump` between the other ends of the returned client and target streams.
+    fn pumping(
+        buffer_size: usize,
+    ) -> (
+        DuplexStream,
+        DuplexStream,
+        tokio::task::JoinHandle<std::io::Result<(u64, u64)>>,
+    ) {
+        let (client, client_side) = tokio::io::duplex(1024);
+        let (target, target_side) = tokio::io::duplex(1024);
+        let pump = tokio::spawn(pump(client_side, target_side, buffer_size));
+        (client, target, pump)
+    }
+
+    async fn read_to_end(stream: &mut DuplexStream) -> Vec<u8> {
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).await.unwrap();
+        data
+    }
+
+    #[tokio::test]
+    async fn closing_one_side_closes_the_other_after_whats_waiting() {
+        let (mut client, mut target, pump) = pumping(64);
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+        assert_eq!(read_to_end(&mut target).await, b"request");
+
+        // The other direction carries on until the target's done too.
+        target.write_all(b"response").await.unwrap();
+        let mut response = [0; 8];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"response");
+        assert!(!pump.is_finished());
+        target.write_all(b" and more").await.unwrap();
+        target.shutdown().await.unwrap();
+        assert_eq!(read_to_end(&mut client).await, b" and more");
+
+        assert_eq!(pump.await.unwrap().unwrap(), (7, 17));
+    }
+
+    #[tokio::test]
+    async fn the_target_can_close_first() {
+        let (mut client, mut target, pump) = pumping(64);
+        target.write_all(b"banner").await.unwrap();
+        target.shutdown().await.unwrap();
+        assert_eq!(read_to_end(&mut client).await, b"banner");
+        client.write_all(b"bye").await.unwrap();
+        client.shutdown().await.unwrap();
+        assert_eq!(read_to_end(&mut target).await, b"bye");
+        assert_eq!(pump.await.unwrap().unwrap(), (3, 6));
+    }
+
+    #[tokio::test]
+    async fn relays_more_than_the_buffer_holds() {
+        // An outbox much smaller than the data fills, pauses and wraps around many times.
+        let (mut client, mut target, pump) = pumping(7);
+        let data: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+        let (_, received) = tokio::join!(
+            async {
+                client.write_all(&data).await.unwrap();
+                client.shutdown().await.unwrap();
+            },
+            read_to_end(&mut target)
+        );
+        assert_eq!(received, data);
+        target.shutdown().await.unwrap();
+        assert!(read_to_end(&mut client).await.is_empty());
+        assert_eq!(pump.await.unwrap().unwrap(), (10_000, 0));
+    }
+
+    #[tokio::test]
+    async fn a_stalled_direction_doesnt_hold_up_the_other() {
+        let (mut client, mut target, _pump) = pumping(4);
+        // Nothing reads from the target, so this direction fills up.
+        let mut sent = 0;
+        while tokio::time::timeout(Duration::from_millis(50), client.write_all(&[0; 256]))
+            .await
+            .is_ok()
+        {
+            sent += 256;
+            assert!(sent < 64 * 1024, "never stalled");
+        }
+        target.write_all(b"reply").await.unwrap();
+        let mut reply = [0; 5];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"reply");
+    }
+}
@@ -0,0 +1,121 @@
+//! Shutting down cleanly when asked to (SIGTERM/SIGINT, or Ctrl+C/closing the console on
+//! Windows).
+//!
+//! Rather than dropping connections on the floor, which can cut a response off half-way, a bridge
+//! stops reading from its clients (at a command boundary for gpg-agent), so the EOF propagates
+//! through to the target, and relays whatever the target still has to say before closing.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::AsyncRead;
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
+
+/// How long connections get to wind down after a shutdown is requested before they're dropped.
+pub const GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// A token that's cancelled when the process is asked to shut down.
+pub fn on_signal() -> CancellationToken {
+    let token = CancellationToken::new();
+    let stop = token.clone();
+    tokio::spawn(async move {
+        match signal().await {
+            Ok(()) => tracing::info!("Shutting down"),
+            Err(e) => tracing::error!(error = %e, "Failed to listen for shutdown signals"),
+        }
+        stop.cancel();
+    });
+    token
+}
+
+#[cfg(unix)]
+async fn signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = interrupt.recv() => {}
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+async fn signal() -> std::io::Result<()> {
+    use tokio::signal::windows::{ctrl_c, ctrl_close};
+
+    let mut interrupt = ctrl_c()?;
+    let mut close = ctrl_close()?;
+    tokio::select! {
+        _ = interrupt.recv() => {}
+        _ = close.recv() => {}
+    }
+    Ok(())
+}
+
+/// Run `future` to completion, unless it's still going [`GRACE_PERIOD`] after `stop` is
+/// cancelled.
+pub async fn with_grace_period<F: Future>(
+    stop: &CancellationToken,
+    future: F,
+) -> Option<F::Output> {
+    tokio::pin!(future);
+    tokio::select! {
+        output = &mut future => return Some(output),
+        () = stop.cancelled() => {}
+    }
+    let output = tokio::time::timeout(GRACE_PERIOD, future).await.ok();
+    if output.is_none() {
+        tracing::warn!("Gave up waiting for connections to close");
+    }
+    output
+}
+
+/// A reader that reports EOF once a shutdown is requested.
+pub struct UntilStopped<R> {
+    inner: R,
+    stopped: Pin<Box<WaitForCancellationFutureOwned>>,
+}
+
+impl<R> UntilStopped<R> {
+    pub fn new(inner: R, stop: &CancellationToken) -> Self {
+        Self {
+            inner,
+            stopped: Box::pin(stop.clone().cancelled_owned()),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for UntilStopped<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.stopped.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<W: tokio::io::AsyncWrite + Unpin> tokio::io::AsyncWrite for UntilStopped<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
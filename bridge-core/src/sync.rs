@@ -0,0 +1,123 @@
+//! Relaying between blocking streams, with a thread per direction.
+//!
+//! For small tools that would rather not pull in an async runtime. There's no idle timeout or
+//! graceful shutdown here, a relay runs until both directions reach EOF (or fail).
+
+use std::io::{Read, Write};
+
+/// A stream that can be split into halves that are used from different threads.
+///
+/// Dropping the write half must signal EOF to the peer (while leaving the read half usable), as
+/// that's how EOF propagates through the relay.
+pub trait Split {
+    type Reader: Read + Send;
+    type Writer: Write + Send;
+
+    fn split(self) -> std::io::Result<(Self::Reader, Self::Writer)>;
+}
+
+/// The process's stdin and stdout.
+pub struct Stdio;
+
+impl Split for Stdio {
+    type Reader = std::io::Stdin;
+    type Writer = std::io::Stdout;
+
+    fn split(self) -> std::io::Result<(Self::Reader, Self::Writer)> {
+        Ok((std::io::stdin(), std::io::stdout()))
+    }
+}
+
+impl Split for std::net::TcpStream {
+    type Reader = Self;
+    type Writer = WriteHalf<Self>;
+
+    fn split(self) -> std::io::Result<(Self::Reader, Self::Writer)> {
+        Ok((self.try_clone()?, WriteHalf(self)))
+    }
+}
+
+#[cfg(unix)]
+impl Split for std::os::unix::net::UnixStream {
+    type Reader = Self;
+    type Writer = WriteHalf<Self>;
+
+    fn split(self) -> std::io::Result<(Self::Reader, Self::Writer)> {
+        Ok((self.try_clone()?, WriteHalf(self)))
+    }
+}
+
+impl Split for std::process::Child {
+    type Reader = std::process::ChildStdout;
+    type Writer = std::process::ChildStdin;
+
+    /// The child must have been spawned with piped stdin and stdout.
+    fn split(mut self) -> std::io::Result<(Self::Reader, Self::Writer)> {
+        match (self.stdout.take(), self.stdin.take()) {
+            (Some(stdout), Some(stdin)) => Ok((stdout, stdin)),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "child's stdin and stdout aren't piped",
+            )),
+        }
+    }
+}
+
+/// The write half of a socket, which shuts down writing when dropped.
+pub struct WriteHalf<S: Shutdown>(S);
+
+/// A socket whose write half can be shut down independently.
+pub trait Shutdown: Write {
+    fn shutdown_write(&self) -> std::io::Result<()>;
+}
+
+impl Shutdown for std::net::TcpStream {
+    fn shutdown_write(&self) -> std::io::Result<()> {
+        self.shutdown(std::net::Shutdown::Write)
+    }
+}
+
+#[cfg(unix)]
+impl Shutdown for std::os::unix::net::UnixStream {
+    fn shutdown_write(&self) -> std::io::Result<()> {
+        self.shutdown(std::net::Shutdown::Write)
+    }
+}
+
+impl<S: Shutdown> Write for WriteHalf<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<S: Shutdown> std::ops::Drop for WriteHalf<S> {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.shutdown_write() {
+            tracing::debug!(error = %e, "Failed to shut down the write half");
+        }
+    }
+}
+
+/// Relay between `client` and `target` until both directions have finished, returning the number
+/// of bytes sent to the target and to the client.
+pub fn relay<C: Split, T: Split>(client: C, target: T) -> std::io::Result<(u64, u64)> {
+    let (mut client_reader, client_writer) = client.split()?;
+    let (mut target_reader, target_writer) = target.split()?;
+    std::thread::scope(|scope| {
+        let to_target = scope.spawn(move || copy(&mut client_reader, target_writer));
+        let to_client = copy(&mut target_reader, client_writer);
+        let to_target = to_target.join().expect("relay thread panicked");
+        Ok((to_target?, to_client?))
+    })
+}
+
+/// Copy `reader` to `writer` until EOF, then drop `writer` to pass the EOF on.
+fn copy<W: Write>(reader: &mut impl Read, mut writer: W) -> std::io::Result<u64> {
+    let copied = std::io::copy(reader, &mut writer)?;
+    writer.flush()?;
+    Ok(copied)
+}
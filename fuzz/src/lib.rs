@@ -0,0 +1,137 @@
+//! The checks behind each fuzz target, shared with the test that replays the regression corpus.
+//!
+//! Everything here parses data that arrives from outside the process: agent messages from
+//! clients, responses from Pageant (written into shared memory it controls) and gpg-agent's
+//! socket file. None of it may panic whatever it's given, and each check also asserts that what
+//! parses is consistent, e.g. that it encodes back to something that parses the same.
+//!
+//! Run a target with `cargo fuzz run <target>` from this directory. Inputs worth keeping (e.g.
+//! crashes, once fixed) go in `regressions/<target>`, which `cargo test` here replays.
+
+use std::io::Cursor;
+
+use agent_proto::frame::{Boundaries, Frame, MAX_MESSAGE_LEN};
+use agent_proto::{Request, Response};
+
+/// Splitting a stream into messages: [`read_frame`](agent_proto::read_frame) and
+/// [`Boundaries`] agree on where they are however the stream arrives, and each message read is
+/// exactly the bytes it was framed from.
+pub fn frame(data: &[u8]) {
+    // The first byte decides how big a piece of the stream `Boundaries` is fed at a time.
+    let Some((&chunk, stream)) = data.split_first() else {
+        return;
+    };
+
+    let mut reader = Cursor::new(stream);
+    let mut read = Vec::new();
+    let mut start = 0;
+    let clean_end = loop {
+        let frame = agent_proto::read_frame(&mut reader, MAX_MESSAGE_LEN);
+        let end = reader.position() as usize;
+        match frame {
+            Ok(Some(Frame::Message(body))) => {
+                assert_eq!(agent_proto::frame::frame(&body), stream[start..end]);
+                match body.first() {
+                    Some(&message_type) => read.push(message_type),
+                    None => break false,
+                }
+            }
+            Ok(Some(Frame::Oversized(len))) => {
+                assert!(len > MAX_MESSAGE_LEN);
+                assert_eq!(end - start, len + 4);
+                break false;
+            }
+            // The stream ended between messages.
+            Ok(None) => {
+                assert_eq!(end, start);
+                break true;
+            }
+            Err(e) => {
+                assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+                break false;
+            }
+        }
+        start = end;
+    };
+
+    let mut boundaries = Boundaries::default();
+    let mut followed = Vec::new();
+    let accepted = stream
+        .chunks(usize::from(chunk) + 1)
+        .all(|piece| boundaries.feed(piece, |message_type| followed.push(message_type)));
+    assert_eq!(followed[..read.len().min(followed.len())], read[..]);
+    if clean_end {
+        assert!(accepted);
+        assert!(boundaries.at_boundary());
+        assert_eq!(followed, read);
+    } else {
+        // At most a message that's started but not finished.
+        assert!(followed.len() <= read.len() + 1);
+    }
+
+    // A smaller limit only changes which messages are read and which are skipped.
+    let limit = usize::from(chunk);
+    let mut reader = Cursor::new(stream);
+    while let Ok(Some(frame)) = agent_proto::read_frame(&mut reader, limit) {
+        match frame {
+            Frame::Message(body) => assert!(body.len() <= limit),
+            Frame::Oversized(len) => assert!(len > limit),
+        }
+    }
+}
+
+/// Reading Pageant's response out of the shared memory: whatever length it claims, the message
+/// is within the memory and as long as its length says.
+pub fn shared_memory(data: &[u8]) {
+    match agent_proto::frame::split_frame(data) {
+        Some((message, rest)) => {
+            assert_eq!(message.len() + rest.len(), data.len());
+            let len = u32::from_be_bytes(message[..4].try_into().unwrap());
+            assert_eq!(len as usize + 4, message.len());
+            let _ = Response::parse(&message[4..]);
+        }
+        None => {
+            if let Some(len) = data.get(..4) {
+                let len = u32::from_be_bytes(len.try_into().unwrap());
+                assert!(len as usize + 4 > data.len());
+            }
+        }
+    }
+}
+
+/// Parsing agent messages: whatever parses encodes to something that parses back the same.
+pub fn message(data: &[u8]) {
+    if let Ok(request) = Request::parse(data) {
+        assert_eq!(Request::parse(&request.encode()).unwrap(), request);
+        match &request {
+            Request::AddIdentity { contents, .. } => {
+                if let Ok((key, _)) = agent_proto::constraint::parse(contents) {
+                    assert!(contents.starts_with(key.encoded));
+                }
+            }
+            Request::Extension { name, contents }
+                if name == agent_proto::extension::SESSION_BIND =>
+            {
+                use agent_proto::extension::SessionBind;
+                if let Ok(bind) = SessionBind::parse(contents) {
+                    assert_eq!(SessionBind::parse(&bind.encode()).unwrap(), bind);
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Ok(response) = Response::parse(data) {
+        assert_eq!(Response::parse(&response.encode()).unwrap(), response);
+    }
+}
+
+/// Parsing gpg-agent's socket file: the port and nonce that parse are the ones that were written.
+pub fn assuan_file(data: &[u8]) {
+    use bridge_core::assuan::SocketFile;
+
+    if let Ok(file) = SocketFile::parse(data) {
+        let mut written = format!("{}\n", file.port).into_bytes();
+        written.extend_from_slice(&file.nonce);
+        assert_eq!(SocketFile::parse(&written).unwrap(), file);
+    }
+}
@@ -0,0 +1,36 @@
+//! Replaying the inputs in `regressions/<target>` through each target's checks.
+
+use std::path::Path;
+
+fn replay(target: &str, check: fn(&[u8])) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("regressions")
+        .join(target);
+    for entry in std::fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        let input = std::fs::read(&path).unwrap();
+        if std::panic::catch_unwind(|| check(&input)).is_err() {
+            panic!("{} failed its checks", path.display());
+        }
+    }
+}
+
+#[test]
+fn frame() {
+    replay("frame", wsl_systemd_fuzz::frame);
+}
+
+#[test]
+fn shared_memory() {
+    replay("shared_memory", wsl_systemd_fuzz::shared_memory);
+}
+
+#[test]
+fn message() {
+    replay("message", wsl_systemd_fuzz::message);
+}
+
+#[test]
+fn assuan_file() {
+    replay("assuan_file", wsl_systemd_fuzz::assuan_file);
+}
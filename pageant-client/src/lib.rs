@@ -0,0 +1,560 @@
+//! Talking to PuTTY's Pageant from Rust.
+//!
+//! Pageant doesn't listen on a socket or pipe, clients find its (hidden) window and send it a
+//! `WM_COPYDATA` message naming a shared memory mapping that holds the request. Pageant writes its
+//! response over the request in the same mapping. [`PageantClient`] wraps that up: requests and
+//! responses are ssh-agent messages, framed with their length (see `agent_proto::frame`).
+//!
+//! ```no_run
+//! let pageant = pageant_client::PageantClient::connect()?;
+//! let request = agent_proto::Request::RequestIdentities.encode();
+//! let response = pageant.request(&agent_proto::frame::frame(&request))?;
+//! # Ok::<(), pageant_client::Error>(())
+//! ```
+
+use std::ffi::CString;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder as _};
+use windows::core::{s, PCSTR};
+use windows::Win32::Foundation::{HANDLE, HWND, LPARAM, WPARAM};
+
+/// The size of the shared memory used to talk to Pageant, and hence the largest message
+/// (including the length prefix) that can be sent or received.
+///
+/// https://github.com/Yasushi/putty/blob/31a2ad775f393aad1c31a983b0baea205d48e219/windows/winpgntc.c#L12
+pub const AGENT_MAX_MSGLEN: usize = 8192;
+
+/// How long to wait for a freshly launched Pageant's window, even without [`Options::wait`].
+const LAUNCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Windows API error: {0}")]
+    Windows(#[from] windows::core::Error),
+    #[error("No Pageant window found")]
+    NoPageantWindow,
+    #[error("Failed to launch {0}")]
+    Launch(PathBuf, #[source] std::io::Error),
+    #[error("Request too long")]
+    RequestTooLong,
+    #[error("Pageant rejected our request")]
+    SendMessageFailed,
+    #[error("Pageant didn't answer within {0:?} (is it stuck behind a dialog?)")]
+    Timeout(Duration),
+    #[error("Pageant's response claims to be {0} bytes long, more than fits in the shared memory")]
+    ResponseTooLong(usize),
+    #[error("Pageant sent an empty response")]
+    EmptyResponse,
+    #[error("Pageant answered {request} with {response}")]
+    UnexpectedResponse {
+        request: agent_proto::MessageType,
+        response: agent_proto::MessageType,
+    },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// How a [`PageantClient`] finds Pageant and waits for it.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// How long to wait for Pageant's window to appear, `Duration::MAX` to wait forever.
+    pub wait: Duration,
+    /// Pageant to start if its window can't be found.
+    pub launch: Option<PathBuf>,
+    /// How long to wait for Pageant to answer a request, `Duration::MAX` to wait forever.
+    pub timeout: Duration,
+}
+
+impl Options {
+    /// Long enough for Pageant to ask for a passphrase.
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            wait: Duration::ZERO,
+            launch: None,
+            timeout: Self::DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+/// A client of the current user's Pageant.
+///
+/// Pageant's window and the shared memory are set up on the first request and reused for the
+/// rest, as finding and mapping them again for every message adds up in tight loops (e.g. `git`
+/// over SSH). If a request fails they're thrown away, and if it failed in a way that suggests
+/// Pageant's restarted (its window's gone), they're set up afresh and the request retried once,
+/// as long as it can't have reached the old Pageant or is safe to repeat (listing keys, or
+/// signing).
+#[derive(Debug)]
+pub struct PageantClient {
+    options: Options,
+    /// Set once Pageant has been launched, so it's only attempted once.
+    launched: AtomicBool,
+    /// Pageant's window and the mapping to use with it, once found. Requests take turns, as
+    /// Pageant answers them one at a time anyway.
+    channel: Mutex<Option<Channel>>,
+}
+
+/// What a request needs to reach Pageant.
+#[derive(Debug)]
+struct Channel {
+    window: HWND,
+    /// The mapping's name, nul terminated, which is what's actually sent to Pageant.
+    name: CString,
+    view: ViewOfFile,
+    // Declared after `view`, so the view is unmapped before the mapping's closed.
+    _mapping: DroppableHandle,
+}
+
+/// Distinguishes the mappings of the clients in this process.
+static NEXT_MAPPING: AtomicU32 = AtomicU32::new(0);
+
+impl PageantClient {
+    /// A client with the default [`Options`], failing if Pageant isn't running.
+    pub fn connect() -> Result<Self> {
+        let client = Self::new(Options::default());
+        client.find_window()?;
+        Ok(client)
+    }
+
+    /// A client that doesn't look for Pageant until its first request.
+    pub fn new(options: Options) -> Self {
+        Self {
+            options,
+            launched: AtomicBool::new(false),
+            channel: Mutex::new(None),
+        }
+    }
+
+    /// Send a (framed) request to Pageant, returning its (framed) response.
+    ///
+    /// The response is checked to fit in the shared memory and to be a plausible answer to the
+    /// request (see [`agent_proto::MessageType::answers`]), but isn't otherwise parsed.
+    pub fn request(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if data.len() > AGENT_MAX_MSGLEN {
+            return Err(Error::RequestTooLong);
+        }
+
+        let mut channel = self.channel.lock().expect("channel lock poisoned");
+        if let Some(current) = &mut *channel {
+            match self.send(current, data) {
+                Ok(response) => return Ok(response),
+                // Pageant may have been restarted, so look for it again.
+                Err(e) if retryable(&e, data) => {
+                    tracing::debug!("Request failed, looking for Pageant again");
+                }
+                Err(e) => {
+                    // A timed out request may still be answered into the mapping later.
+                    *channel = None;
+                    return Err(e);
+                }
+            }
+        }
+        *channel = None;
+        let opened = channel.insert(self.open()?);
+        let result = self.send(opened, data);
+        if result.is_err() {
+            *channel = None;
+        }
+        result
+    }
+
+    /// Find Pageant's window and create a mapping to talk to it through.
+    fn open(&self) -> Result<Channel> {
+        let window = self.find_window()?;
+
+        tracing::debug!("Found Pageant window: {:x?}", window);
+
+        // Named after the process, as PuTTY names them after the thread.
+        let map_name = format!(
+            "PageantRequest{:08x}{:x}",
+            std::process::id(),
+            NEXT_MAPPING.fetch_add(1, Ordering::Relaxed)
+        );
+
+        tracing::trace!("Map name is: {:?}", map_name);
+
+        let name = CString::new(map_name).expect("map_name doesn't contain nul bytes");
+
+        let mapping = DroppableHandle(unsafe {
+            windows::Win32::System::Memory::CreateFileMappingA(
+                HWND(0),
+                None,
+                windows::Win32::System::Memory::PAGE_READWRITE,
+                0,
+                AGENT_MAX_MSGLEN as u32,
+                PCSTR(name.as_ptr().cast()),
+            )
+        }?);
+
+        tracing::trace!("Created file mapping: {:?}", mapping);
+
+        let view = ViewOfFile(unsafe {
+            windows::Win32::System::Memory::MapViewOfFile(
+                mapping.0,
+                windows::Win32::System::Memory::FILE_MAP_WRITE,
+                0,
+                0,
+                0,
+            )
+        });
+        if view.0.Value.is_null() {
+            return Err(windows::core::Error::from_win32().into());
+        }
+
+        tracing::trace!("Created view of file: {:?}", view);
+
+        Ok(Channel {
+            window,
+            name,
+            view,
+            _mapping: mapping,
+        })
+    }
+
+    /// Send a (framed) request to Pageant through `channel`, returning its (framed) response.
+    fn send(&self, channel: &mut Channel, data: &[u8]) -> Result<Vec<u8>> {
+        let shm = channel.view.as_slice();
+
+        shm[..data.len()].copy_from_slice(data);
+
+        let copy_data = windows::Win32::System::DataExchange::COPYDATASTRUCT {
+            // https://github.com/Yasushi/putty/blob/31a2ad775f393aad1c31a983b0baea205d48e219/windows/winpgntc.c#L14
+            dwData: 0x804e50ba,
+            // Include the nul byte.
+            cbData: channel.name.as_bytes_with_nul().len() as u32,
+            lpData: channel.name.as_ptr().cast_mut().cast(),
+        };
+
+        tracing::trace!("COPYDATASTRUCT: {:?}", copy_data);
+
+        let timeout = self.options.timeout;
+        // The timeout is in milliseconds, "forever" saturates to about 49 days.
+        let timeout_ms = u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX);
+        let mut result = 0;
+        let ret = unsafe {
+            windows::Win32::UI::WindowsAndMessaging::SendMessageTimeoutA(
+                channel.window,
+                windows::Win32::UI::WindowsAndMessaging::WM_COPYDATA,
+                WPARAM(0),
+                LPARAM(&copy_data as *const _ as isize),
+                windows::Win32::UI::WindowsAndMessaging::SMTO_ABORTIFHUNG,
+                timeout_ms,
+                Some(&mut result),
+            )
+        };
+
+        tracing::debug!(
+            "SendMessageTimeout(WM_COPYDATA) returned: {:?} (result {})",
+            ret,
+            result
+        );
+
+        if ret.0 == 0 {
+            let e = windows::core::Error::from_win32();
+            if e.code() == windows::Win32::Foundation::ERROR_TIMEOUT.to_hresult() {
+                return Err(Error::Timeout(timeout));
+            }
+            return Err(e.into());
+        }
+        if result == 0 {
+            return Err(Error::SendMessageFailed);
+        }
+
+        read_response(&shm[..], data)
+    }
+
+    /// Find Pageant's window, launching Pageant if requested and waiting (with exponential
+    /// backoff) for the window to appear.
+    fn find_window(&self) -> Result<HWND> {
+        const MAX_DELAY: Duration = Duration::from_secs(2);
+
+        let options = &self.options;
+        // `None` if waiting forever (or so long it may as well be forever).
+        let mut deadline = Instant::now().checked_add(options.wait);
+        let mut delay = Duration::from_millis(50);
+        loop {
+            let window_handle = unsafe {
+                windows::Win32::UI::WindowsAndMessaging::FindWindowA(s!("Pageant"), s!("Pageant"))
+            };
+            if window_handle.0 != 0 {
+                return Ok(window_handle);
+            }
+
+            if let Some(program) = &options.launch {
+                if !self.launched.swap(true, Ordering::Relaxed) {
+                    tracing::info!(
+                        program = %program.display(),
+                        "Pageant window not found, launching it"
+                    );
+                    // Keep Pageant off stdout, the caller may be using it.
+                    std::process::Command::new(program)
+                        .stdin(std::process::Stdio::null())
+                        .stdout(std::process::Stdio::null())
+                        .spawn()
+                        .map_err(|e| Error::Launch(program.clone(), e))?;
+                    let launch_deadline = Instant::now() + LAUNCH_TIMEOUT;
+                    deadline = deadline.map(|deadline| deadline.max(launch_deadline));
+                }
+            }
+
+            let remaining = match deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                None => MAX_DELAY,
+            };
+            if remaining.is_zero() {
+                return Err(Error::NoPageantWindow);
+            }
+            tracing::info!(?delay, "Pageant window not found, waiting for it to appear");
+            std::thread::sleep(delay.min(remaining));
+            delay = (delay * 2).min(MAX_DELAY);
+        }
+    }
+}
+
+/// Pageant's (framed) response to the (framed) `request`, from the shared memory `shm` it was
+/// written to.
+fn read_response(shm: &[u8], request: &[u8]) -> Result<Vec<u8>> {
+    let rsp_len = BigEndian::read_u32(&shm[0..4]) as usize;
+
+    tracing::debug!(len = rsp_len, "Received response");
+
+    // Pageant could be buggy, or not Pageant at all, so don't trust it to stay in bounds.
+    let (rsp, _) = agent_proto::frame::split_frame(shm).ok_or(Error::ResponseTooLong(rsp_len))?;
+    validate_response(request, rsp)?;
+
+    Ok(rsp.to_vec())
+}
+
+/// Whether the (framed) `request` can be sent again to a Pageant found afresh after failing with
+/// `error`: if the window had already gone, the request never reached Pageant, but if it may
+/// have been acted on, only listing keys and signing are safe to repeat.
+fn retryable(error: &Error, request: &[u8]) -> bool {
+    use agent_proto::MessageType;
+
+    match error {
+        Error::Windows(e)
+            if e.code() == windows::Win32::Foundation::ERROR_INVALID_WINDOW_HANDLE.to_hresult() =>
+        {
+            true
+        }
+        Error::Windows(_) | Error::SendMessageFailed => matches!(
+            request
+                .get(4)
+                .map(|&message_type| MessageType(message_type)),
+            Some(MessageType::REQUEST_IDENTITIES | MessageType::SIGN_REQUEST)
+        ),
+        _ => false,
+    }
+}
+
+/// Check that the (framed) response could be Pageant's answer to the (framed) request.
+fn validate_response(request: &[u8], response: &[u8]) -> Result<()> {
+    let response =
+        agent_proto::MessageType::of(&response[4..]).map_err(|_| Error::EmptyResponse)?;
+    // Requests are passed on uninterpreted, so even an empty one gets sent.
+    let Some(&request) = request.get(4) else {
+        return Ok(());
+    };
+    let request = agent_proto::MessageType(request);
+    if !response.answers(request) {
+        return Err(Error::UnexpectedResponse { request, response });
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct DroppableHandle(HANDLE);
+
+impl std::ops::Drop for DroppableHandle {
+    fn drop(&mut self) {
+        if !self.0.is_invalid() {
+            tracing::trace!("Closing {:?}", self.0);
+            unsafe {
+                windows::Win32::Foundation::CloseHandle(self.0).expect("can close valid handles");
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ViewOfFile(windows::Win32::System::Memory::MEMORY_MAPPED_VIEW_ADDRESS);
+
+// Safety: the view is only used through `&mut self`, and mapped memory can be used from any
+// thread.
+unsafe impl Send for ViewOfFile {}
+
+impl ViewOfFile {
+    /// The mapped memory, which must be a (non-null) view of a mapping at least
+    /// [`AGENT_MAX_MSGLEN`] bytes long.
+    fn as_slice(&mut self) -> &mut [u8; AGENT_MAX_MSGLEN] {
+        // Safety: the view is valid for `AGENT_MAX_MSGLEN` bytes, which are initialised (the
+        // system zeroes new mappings backed by the paging file). Pageant only writes to it while
+        // we're blocked sending it a request (a view is thrown away if a request times out).
+        unsafe { &mut *self.0.Value.cast() }
+    }
+}
+
+impl std::ops::Drop for ViewOfFile {
+    fn drop(&mut self) {
+        if !self.0.Value.is_null() {
+            tracing::trace!("Unmapping {:?}", self.0);
+            unsafe {
+                windows::Win32::System::Memory::UnmapViewOfFile(self.0)
+                    .expect("can unmap view of file");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use agent_proto::frame::frame;
+    use agent_proto::{MessageType, Request, Response};
+
+    use super::*;
+
+    // Results aren't unwrapped or printed, as formatting Windows errors needs Windows.
+
+    /// Shared memory holding `response`, over what's left of `request`.
+    fn shm(request: &[u8], response: &[u8]) -> Vec<u8> {
+        let mut shm = vec![0; AGENT_MAX_MSGLEN];
+        shm[..request.len()].copy_from_slice(request);
+        shm[..response.len()].copy_from_slice(response);
+        shm
+    }
+
+    fn identities_request() -> Vec<u8> {
+        frame(&Request::RequestIdentities.encode())
+    }
+
+    #[test]
+    fn reads_the_response_without_whats_left_of_the_request() {
+        let request = frame(
+            &Request::SignRequest {
+                key_blob: b"key".to_vec(),
+                data: b"data to sign".to_vec(),
+                flags: 0,
+            }
+            .encode(),
+        );
+        let response = frame(
+            &Response::SignResponse {
+                signature: b"sig".to_vec(),
+            }
+            .encode(),
+        );
+        assert!(response.len() < request.len());
+        assert_eq!(
+            read_response(&shm(&request, &response), &request).ok(),
+            Some(response)
+        );
+    }
+
+    #[test]
+    fn a_response_may_fill_the_shared_memory() {
+        let request = frame(&[99]);
+        let mut body = vec![0; AGENT_MAX_MSGLEN - 4];
+        body[0] = 100;
+        let response = frame(&body);
+        assert_eq!(
+            read_response(&shm(&request, &response), &request).ok(),
+            Some(response)
+        );
+    }
+
+    #[test]
+    fn rejects_a_response_longer_than_the_shared_memory() {
+        let request = identities_request();
+        let mut shm = shm(&request, &[]);
+        let len = AGENT_MAX_MSGLEN - 3;
+        shm[..4].copy_from_slice(&(len as u32).to_be_bytes());
+        shm[4] = MessageType::IDENTITIES_ANSWER.0;
+        assert!(matches!(
+            read_response(&shm, &request),
+            Err(Error::ResponseTooLong(claimed)) if claimed == len
+        ));
+        shm[..4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(
+            read_response(&shm, &request),
+            Err(Error::ResponseTooLong(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_an_empty_response() {
+        let request = identities_request();
+        assert!(matches!(
+            read_response(&shm(&request, &frame(&[])), &request),
+            Err(Error::EmptyResponse)
+        ));
+    }
+
+    #[test]
+    fn rejects_a_response_that_doesnt_answer_the_request() {
+        let request = identities_request();
+        let response = frame(&Response::Success.encode());
+        assert!(matches!(
+            read_response(&shm(&request, &response), &request),
+            Err(Error::UnexpectedResponse {
+                request: MessageType::REQUEST_IDENTITIES,
+                response: MessageType::SUCCESS,
+            })
+        ));
+    }
+
+    #[test]
+    fn any_request_can_fail() {
+        let failure = frame(&Response::Failure.encode());
+        for request in [identities_request(), frame(&[99]), frame(&[])] {
+            assert_eq!(
+                read_response(&shm(&request, &failure), &request).ok(),
+                Some(failure.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn an_empty_request_accepts_any_answer() {
+        let request = frame(&[]);
+        let response = frame(&Response::Success.encode());
+        assert_eq!(
+            read_response(&shm(&request, &response), &request).ok(),
+            Some(response)
+        );
+    }
+
+    #[test]
+    fn retries_only_requests_that_are_safe_to_repeat() {
+        let sign = frame(
+            &Request::SignRequest {
+                key_blob: b"key".to_vec(),
+                data: b"data to sign".to_vec(),
+                flags: 0,
+            }
+            .encode(),
+        );
+        assert!(retryable(&Error::SendMessageFailed, &identities_request()));
+        assert!(retryable(&Error::SendMessageFailed, &sign));
+        for request in [
+            frame(&Request::RemoveAllIdentities.encode()),
+            frame(&[MessageType::ADD_IDENTITY.0]),
+            frame(&[]),
+        ] {
+            assert!(!retryable(&Error::SendMessageFailed, &request));
+        }
+        // Nothing suggests Pageant's been restarted.
+        assert!(!retryable(
+            &Error::Timeout(Duration::from_secs(1)),
+            &identities_request()
+        ));
+        assert!(!retryable(&Error::EmptyResponse, &identities_request()));
+    }
+}
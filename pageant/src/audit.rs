@@ -0,0 +1,198 @@
+//! `--audit-log`: a record of every signature asked of Pageant through us.
+//!
+//! Anything that can reach the bridged agent inside WSL (or a container it's mounted into) can
+//! use the keys, so this keeps a trail of what did. Each sign request appends a JSON object to
+//! the file, on a line of its own, e.g.
+//!
+//! ```text
+//! {"time":"2026-10-16T09:30:12.3456Z","pid":4242,"fingerprint":"SHA256:...","result":"signed"}
+//! ```
+//!
+//! `result` is `signed`, `refused`, or `error` if Pageant couldn't be asked (with `error` saying
+//! why). A request refused before reaching Pageant has a `reason`: `filter` (the `--allow-*` and
+//! `--deny-*` options), `confirm` (declined in `--confirm`'s dialog) or `session-bind`
+//! (`--require-session-bind`). Each pageant.exe on stdin/stdout serves a single connection, which its
+//! `pid` identifies, while `connection` tells apart the ones a `--service` serves (as in its log).
+//! `client` is `--client`, if given.
+//!
+//! The file is only ever appended to, a line at a time, so any number of pageant.exe processes
+//! can share it.
+
+use std::io::Write as _;
+use std::path::Path;
+use std::sync::Mutex;
+
+use agent_proto::{MessageType, Request};
+
+/// The open audit log.
+pub struct AuditLog {
+    file: Mutex<std::fs::File>,
+}
+
+/// Why a request was refused without asking Pageant.
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Refusal {
+    /// `--restrict` doesn't allow the request (which is never a sign request).
+    Restrict,
+    /// The key is hidden by the `--allow-*` and `--deny-*` options.
+    Filter,
+    /// The user declined it in `--confirm`'s dialog.
+    Confirm,
+    /// The connection isn't bound to a session, with `--require-session-bind`.
+    SessionBind,
+}
+
+/// What became of a request.
+#[derive(Clone, Copy)]
+pub enum Outcome<'a> {
+    /// Pageant's (framed) response.
+    Answered(&'a [u8]),
+    Refused(Refusal),
+    /// Pageant couldn't be asked.
+    Failed(&'a dyn std::fmt::Display),
+}
+
+/// One sign request, as recorded.
+#[derive(serde::Serialize)]
+struct Entry<'a> {
+    time: String,
+    pid: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connection: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client: Option<&'a str>,
+    fingerprint: String,
+    result: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<Refusal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl AuditLog {
+    /// Open the log at `path`, creating it if needed.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Record the `outcome` of `req` (an unframed request), if it's a sign request.
+    pub fn record(
+        &self,
+        req: &[u8],
+        outcome: Outcome,
+        connection: Option<u64>,
+        client: Option<&str>,
+    ) {
+        let Ok(Request::SignRequest { key_blob, .. }) = Request::parse(req) else {
+            return;
+        };
+        let (result, reason, error) = match outcome {
+            Outcome::Answered(rsp) if rsp.get(4) == Some(&MessageType::SIGN_RESPONSE.0) => {
+                ("signed", None, None)
+            }
+            Outcome::Answered(_) => ("refused", None, None),
+            Outcome::Refused(refusal) => ("refused", Some(refusal), None),
+            Outcome::Failed(e) => ("error", None, Some(e.to_string())),
+        };
+        let entry = Entry {
+            time: time::OffsetDateTime::now_utc()
+                .format(&time::format_description::well_known::Rfc3339)
+                .unwrap_or_default(),
+            pid: std::process::id(),
+            connection,
+            client,
+            fingerprint: agent_proto::fingerprint(&key_blob),
+            result,
+            reason,
+            error,
+        };
+        let mut line = serde_json::to_vec(&entry).expect("audit entries are serializable");
+        line.push(b'\n');
+        // The request's been dealt with either way, so failing to record it is only logged. It's
+        // written all at once, so lines from different processes don't interleave.
+        let mut file = self.file.lock().expect("audit log lock poisoned");
+        if let Err(e) = file.write_all(&line) {
+            tracing::error!(error = %e, "Failed to write to the audit log");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use agent_proto::frame::frame;
+    use agent_proto::{Request, Response};
+
+    use super::*;
+
+    /// The entries recorded for each outcome of a sign request.
+    fn entries(outcomes: &[Outcome]) -> Vec<serde_json::Value> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let log = AuditLog::open(&path).unwrap();
+        let req = Request::SignRequest {
+            key_blob: b"key".to_vec(),
+            data: b"data".to_vec(),
+            flags: 0,
+        }
+        .encode();
+        for &outcome in outcomes {
+            log.record(&req, outcome, Some(7), Some("ssh"));
+        }
+        // Not a sign request, so not recorded.
+        let list = Request::RequestIdentities.encode();
+        log.record(&list, Outcome::Refused(Refusal::Restrict), None, None);
+
+        std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn records_why_a_request_was_refused() {
+        let signed = frame(
+            &Response::SignResponse {
+                signature: b"sig".to_vec(),
+            }
+            .encode(),
+        );
+        let failure = frame(&Response::Failure.encode());
+        let entries = entries(&[
+            Outcome::Answered(&signed),
+            Outcome::Answered(&failure),
+            Outcome::Refused(Refusal::Filter),
+            Outcome::Refused(Refusal::Confirm),
+            Outcome::Refused(Refusal::SessionBind),
+            Outcome::Failed(&"No Pageant window found"),
+        ]);
+        let results: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry["result"].as_str(), entry["reason"].as_str()))
+            .collect();
+        assert_eq!(
+            results,
+            [
+                (Some("signed"), None),
+                (Some("refused"), None),
+                (Some("refused"), Some("filter")),
+                (Some("refused"), Some("confirm")),
+                (Some("refused"), Some("session-bind")),
+                (Some("error"), None),
+            ]
+        );
+        assert_eq!(entries[5]["error"], "No Pageant window found");
+        let entry = &entries[0];
+        assert_eq!(entry["fingerprint"], agent_proto::fingerprint(b"key"));
+        assert_eq!(entry["connection"], 7);
+        assert_eq!(entry["client"], "ssh");
+        assert!(entry.get("error").is_none());
+    }
+}
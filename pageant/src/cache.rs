@@ -0,0 +1,219 @@
+//! Caching Pageant's `SSH_AGENT_IDENTITIES_ANSWER` between connections.
+//!
+//! A new pageant.exe is usually spawned for every connection, so the cache lives in a file in the
+//! user's local cache directory (`%LOCALAPPDATA%\wsl-systemd`) rather than in memory. Only public
+//! keys and comments are cached. The file is removed whenever a request changes (or might have changed) the keys
+//! Pageant holds, and when talking to Pageant fails.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use agent_proto::MessageType;
+
+pub struct IdentityCache {
+    path: PathBuf,
+    ttl: Duration,
+}
+
+impl IdentityCache {
+    /// A cache whose entries expire after `ttl`, or `None` if caching is disabled (a zero TTL) or
+    /// the user has no cache directory.
+    pub fn new(ttl: Duration) -> Option<Self> {
+        if ttl.is_zero() {
+            return None;
+        }
+        let Some(dirs) = directories::BaseDirs::new() else {
+            tracing::warn!("Not caching identities, couldn't find the user's cache directory");
+            return None;
+        };
+        Some(Self::in_dir(&dirs.cache_dir().join("wsl-systemd"), ttl))
+    }
+
+    /// A cache kept in `dir`, which is created when it's first needed.
+    fn in_dir(dir: &Path, ttl: Duration) -> Self {
+        Self {
+            path: dir.join("pageant-identities"),
+            ttl,
+        }
+    }
+
+    /// The cached (framed) response, if there's one that hasn't expired.
+    pub fn get(&self) -> Option<Vec<u8>> {
+        let age = std::fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok()?
+            .elapsed()
+            .ok()?;
+        if age > self.ttl {
+            tracing::debug!(?age, "Cached identities have expired");
+            return None;
+        }
+        let rsp = std::fs::read(&self.path).ok()?;
+        // Don't hand out a partially written or corrupted file.
+        match agent_proto::read_frame(&mut rsp.as_slice(), rsp.len()) {
+            Ok(Some(agent_proto::Frame::Message(body)))
+                if body.len() + 4 == rsp.len()
+                    && MessageType::of(&body).ok() == Some(MessageType::IDENTITIES_ANSWER) =>
+            {
+                tracing::debug!(?age, "Using cached identities");
+                Some(rsp)
+            }
+            _ => {
+                tracing::warn!(path = %self.path.display(), "Ignoring malformed identity cache");
+                None
+            }
+        }
+    }
+
+    /// Cache a (framed) response from Pageant, if it's an identity list.
+    pub fn put(&self, rsp: &[u8]) {
+        if rsp.get(4).copied() != Some(MessageType::IDENTITIES_ANSWER.0) {
+            return;
+        }
+        // Write then rename, so concurrent connections never see half a file.
+        static WRITES: AtomicU64 = AtomicU64::new(0);
+        let write = WRITES.fetch_add(1, Ordering::Relaxed);
+        let tmp = self
+            .path
+            .with_extension(format!("{}.{}.tmp", std::process::id(), write));
+        let result = std::fs::create_dir_all(self.path.parent().expect("in a directory"))
+            .and_then(|()| std::fs::write(&tmp, rsp))
+            .and_then(|()| std::fs::rename(&tmp, &self.path));
+        if let Err(e) = result {
+            tracing::warn!(path = %self.path.display(), error = %e, "Failed to cache identities");
+            let _ = std::fs::remove_file(&tmp);
+        }
+    }
+
+    pub fn invalidate(&self) {
+        match std::fs::remove_file(&self.path) {
+            Ok(()) => tracing::debug!("Invalidated cached identities"),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                tracing::warn!(
+                    path = %self.path.display(),
+                    error = %e,
+                    "Failed to invalidate identity cache"
+                )
+            }
+        }
+    }
+}
+
+/// Whether a request of this type can change the identities Pageant reports.
+pub fn invalidated_by(message_type: MessageType) -> bool {
+    matches!(
+        message_type,
+        MessageType::ADD_IDENTITY
+            | MessageType::ADD_ID_CONSTRAINED
+            | MessageType::REMOVE_IDENTITY
+            | MessageType::REMOVE_ALL_IDENTITIES
+            | MessageType::ADD_SMARTCARD_KEY
+            | MessageType::ADD_SMARTCARD_KEY_CONSTRAINED
+            | MessageType::REMOVE_SMARTCARD_KEY
+            | MessageType::LOCK
+            | MessageType::UNLOCK
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    /// A framed `SSH_AGENT_IDENTITIES_ANSWER` listing one key.
+    fn identities() -> Vec<u8> {
+        let response = agent_proto::Response::IdentitiesAnswer(vec![agent_proto::Identity {
+            key_blob: b"key".to_vec(),
+            comment: "me@laptop".to_owned(),
+        }]);
+        agent_proto::frame::frame(&response.encode())
+    }
+
+    fn cache(dir: &tempfile::TempDir) -> IdentityCache {
+        IdentityCache::in_dir(&dir.path().join("wsl-systemd"), Duration::from_secs(60))
+    }
+
+    #[test]
+    fn is_disabled_by_a_zero_ttl() {
+        assert!(IdentityCache::new(Duration::ZERO).is_none());
+    }
+
+    #[test]
+    fn returns_what_was_put() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir);
+        assert!(cache.get().is_none());
+        cache.put(&identities());
+        assert_eq!(cache.get(), Some(identities()));
+        // Only the cache file is left behind.
+        let files: Vec<_> = std::fs::read_dir(dir.path().join("wsl-systemd"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(files, ["pageant-identities"]);
+    }
+
+    #[test]
+    fn only_caches_identity_lists() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir);
+        let failure = agent_proto::frame::frame(&agent_proto::Response::Failure.encode());
+        cache.put(&failure);
+        assert!(cache.get().is_none());
+    }
+
+    #[test]
+    fn entries_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir);
+        cache.put(&identities());
+        let modified = SystemTime::now() - Duration::from_secs(61);
+        std::fs::File::options()
+            .write(true)
+            .open(&cache.path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        assert!(cache.get().is_none());
+    }
+
+    #[test]
+    fn invalidating_removes_the_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir);
+        // Nothing to invalidate yet.
+        cache.invalidate();
+        cache.put(&identities());
+        cache.invalidate();
+        assert!(cache.get().is_none());
+        assert!(!cache.path.exists());
+    }
+
+    #[test]
+    fn ignores_a_malformed_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir);
+        cache.put(&identities());
+        let mut truncated = identities();
+        truncated.pop();
+        std::fs::write(&cache.path, &truncated).unwrap();
+        assert!(cache.get().is_none());
+        let mut trailing = identities();
+        trailing.push(0);
+        std::fs::write(&cache.path, &trailing).unwrap();
+        assert!(cache.get().is_none());
+    }
+
+    #[test]
+    fn requests_that_change_the_keys_invalidate() {
+        assert!(invalidated_by(MessageType::ADD_IDENTITY));
+        assert!(invalidated_by(MessageType::REMOVE_ALL_IDENTITIES));
+        assert!(invalidated_by(MessageType::UNLOCK));
+        assert!(!invalidated_by(MessageType::REQUEST_IDENTITIES));
+        assert!(!invalidated_by(MessageType::SIGN_REQUEST));
+        assert!(!invalidated_by(MessageType::EXTENSION));
+    }
+}
@@ -0,0 +1,48 @@
+//! Asking the Windows user to approve each signature, like `ssh-add -c` does for OpenSSH's agent.
+
+use windows::core::HSTRING;
+use windows::Win32::Foundation::HWND;
+use windows::Win32::UI::WindowsAndMessaging::{
+    MessageBoxW, IDYES, MB_DEFBUTTON2, MB_ICONQUESTION, MB_SETFOREGROUND, MB_SYSTEMMODAL, MB_YESNO,
+};
+
+/// Show a dialog describing the signature request and return whether the user approved it.
+///
+/// The dialog defaults to "No", so a stray keypress doesn't approve a signature.
+pub fn confirm(key_blob: &[u8], comment: Option<&str>, client: Option<&str>) -> bool {
+    let mut text = String::from("Allow a signature with this key?\n\n");
+    if let Some(comment) = comment {
+        text.push_str(&format!("Key: {}\n", comment));
+    }
+    text.push_str(&format!(
+        "Fingerprint: {}\n",
+        agent_proto::fingerprint(key_blob)
+    ));
+    if let Some(client) = client {
+        text.push_str(&format!("Requested by: {}\n", describe_client(client)));
+    }
+
+    let result = unsafe {
+        MessageBoxW(
+            HWND(0),
+            &HSTRING::from(text),
+            &HSTRING::from("Pageant (WSL)"),
+            MB_YESNO | MB_ICONQUESTION | MB_DEFBUTTON2 | MB_SYSTEMMODAL | MB_SETFOREGROUND,
+        )
+    };
+    tracing::debug!(result = result.0, "Confirmation dialog closed");
+    result == IDYES
+}
+
+/// systemd names the per-connection instances of an `Accept = Yes` unix socket
+/// `<connection>-<pid>-<uid>` after the peer, so turn that into something readable. Anything
+/// else is shown as given.
+pub fn describe_client(client: &str) -> String {
+    let parts: Vec<&str> = client.split('-').collect();
+    match parts[..] {
+        [_, pid, uid] if pid.parse::<u32>().is_ok() && uid.parse::<u32>().is_ok() => {
+            format!("process {} (uid {}) in WSL", pid, uid)
+        }
+        _ => client.to_owned(),
+    }
+}
@@ -0,0 +1,88 @@
+//! Reporting warnings and errors to the Windows Event Log.
+//!
+//! When pageant.exe runs detached (`--service`, or spawned by a systemd unit through interop) its
+//! stderr goes nowhere, so `--event-log` also sends warnings and errors to the Application log,
+//! under the [`SOURCE`] source. Events show up in Event Viewer even if the source isn't
+//! registered, but Windows only stops complaining that it can't find their description once it
+//! is (as an administrator):
+//!
+//! ```text
+//! New-EventLog -LogName Application -Source wsl-systemd-pageant
+//! ```
+
+use windows::core::{HSTRING, PCWSTR};
+use windows::Win32::Foundation::HANDLE;
+use windows::Win32::System::EventLog::{
+    DeregisterEventSource, RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE,
+    EVENTLOG_WARNING_TYPE,
+};
+
+/// The event source that events are reported under.
+pub const SOURCE: &str = "wsl-systemd-pageant";
+
+/// A tracing layer that reports warnings and errors to the Event Log.
+pub struct EventLog(HANDLE);
+
+impl EventLog {
+    pub fn register() -> windows::core::Result<Self> {
+        let handle = unsafe { RegisterEventSourceW(PCWSTR::null(), &HSTRING::from(SOURCE)) }?;
+        Ok(Self(handle))
+    }
+}
+
+impl std::ops::Drop for EventLog {
+    fn drop(&mut self) {
+        let _ = unsafe { DeregisterEventSource(self.0) };
+    }
+}
+
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for EventLog {
+    fn on_event(
+        &self,
+        event: &tracing::Event<'_>,
+        _ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        let kind = match *event.metadata().level() {
+            tracing::Level::ERROR => EVENTLOG_ERROR_TYPE,
+            tracing::Level::WARN => EVENTLOG_WARNING_TYPE,
+            _ => return,
+        };
+        let mut message = Message::default();
+        event.record(&mut message);
+        let text = HSTRING::from(message.0);
+        let strings = [PCWSTR(text.as_ptr())];
+        let reported = unsafe { ReportEventW(self.0, kind, 0, 0, None, 0, Some(&strings), None) };
+        if let Err(e) = reported {
+            // Can't use tracing for this, it would come straight back here.
+            eprintln!("Failed to report an event to the Event Log: {}", e);
+        }
+    }
+}
+
+/// An event's message, followed by its fields as `name=value`.
+#[derive(Default)]
+struct Message(String);
+
+impl Message {
+    fn push(&mut self, field: &tracing::field::Field, value: std::fmt::Arguments) {
+        use std::fmt::Write as _;
+
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+        let _ = match field.name() {
+            "message" => self.0.write_fmt(value),
+            name => write!(self.0, "{}={}", name, value),
+        };
+    }
+}
+
+impl tracing::field::Visit for Message {
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.push(field, format_args!("{}", value));
+    }
+
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        self.push(field, format_args!("{:?}", value));
+    }
+}
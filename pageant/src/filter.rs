@@ -0,0 +1,199 @@
+//! Restricting which of Pageant's keys are visible to (and usable by) the client.
+//!
+//! Keys are matched by their `SHA256:` fingerprint or by a glob (`*` and `?`) on their comment.
+//! If any `--allow-*` rule is given a key must match one of them to be visible, and a key that
+//! matches any `--deny-*` rule is always hidden.
+
+#[derive(structopt::StructOpt, Debug, Default)]
+pub struct Filter {
+    /// Only expose keys with this fingerprint (e.g. `SHA256:...`), may be repeated
+    #[structopt(long = "allow-key", number_of_values = 1)]
+    allow_keys: Vec<String>,
+    /// Hide keys with this fingerprint, may be repeated
+    #[structopt(long = "deny-key", number_of_values = 1)]
+    deny_keys: Vec<String>,
+    /// Only expose keys whose comment matches this glob (e.g. `work-*`), may be repeated
+    #[structopt(long = "allow-comment", number_of_values = 1)]
+    allow_comments: Vec<String>,
+    /// Hide keys whose comment matches this glob, may be repeated
+    #[structopt(long = "deny-comment", number_of_values = 1)]
+    deny_comments: Vec<String>,
+}
+
+impl Filter {
+    pub fn is_empty(&self) -> bool {
+        self.allow_keys.is_empty()
+            && self.deny_keys.is_empty()
+            && self.allow_comments.is_empty()
+            && self.deny_comments.is_empty()
+    }
+
+    /// Whether deciding on a key needs its comment, which isn't included in sign requests.
+    pub fn needs_comment(&self) -> bool {
+        !self.allow_comments.is_empty() || !self.deny_comments.is_empty()
+    }
+
+    /// Whether the key may be used. A `comment` of `None` (i.e. the key isn't one Pageant knows
+    /// about) never matches a comment rule.
+    pub fn allows(&self, key_blob: &[u8], comment: Option<&str>) -> bool {
+        let fingerprint = agent_proto::fingerprint(key_blob);
+        let key_matches = |rules: &[String]| {
+            rules
+                .iter()
+                .any(|rule| normalize_fingerprint(rule) == fingerprint)
+        };
+        let comment_matches = |rules: &[String]| {
+            comment.is_some_and(|comment| rules.iter().any(|rule| glob_matches(rule, comment)))
+        };
+
+        let allowed = (self.allow_keys.is_empty() && self.allow_comments.is_empty())
+            || key_matches(&self.allow_keys)
+            || comment_matches(&self.allow_comments);
+        allowed && !key_matches(&self.deny_keys) && !comment_matches(&self.deny_comments)
+    }
+}
+
+/// `ssh-keygen -l` prints fingerprints with a `SHA256:` prefix, but accept them without it too.
+fn normalize_fingerprint(rule: &str) -> String {
+    let rule = rule.trim().trim_end_matches('=');
+    if rule.starts_with("SHA256:") {
+        rule.to_owned()
+    } else {
+        format!("SHA256:{}", rule)
+    }
+}
+
+/// Match `text` against a glob where `*` matches any run of characters and `?` any one.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where to resume if the most recent `*` needs to swallow another character.
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, start)) => {
+                    p = star + 1;
+                    t = start + 1;
+                    backtrack = Some((star, start + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt as _;
+
+    use super::*;
+
+    const WORK: &[u8] = b"work key";
+    const HOME: &[u8] = b"home key";
+
+    fn rules(args: &[&str]) -> Filter {
+        Filter::from_iter(std::iter::once("pageant").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn allows_everything_without_rules() {
+        let filter = rules(&[]);
+        assert!(filter.is_empty());
+        assert!(!filter.needs_comment());
+        assert!(filter.allows(WORK, Some("work")));
+        assert!(filter.allows(HOME, None));
+    }
+
+    #[test]
+    fn allow_rules_hide_everything_else() {
+        let work = agent_proto::fingerprint(WORK);
+        let filter = rules(&["--allow-key", &work, "--allow-comment", "laptop"]);
+        assert!(!filter.is_empty());
+        assert!(filter.allows(WORK, None));
+        assert!(filter.allows(HOME, Some("laptop")));
+        assert!(!filter.allows(HOME, Some("desktop")));
+    }
+
+    #[test]
+    fn deny_rules_beat_allow_rules() {
+        let work = agent_proto::fingerprint(WORK);
+        let filter = rules(&["--allow-comment", "*", "--deny-key", &work]);
+        assert!(!filter.allows(WORK, Some("work")));
+        assert!(filter.allows(HOME, Some("home")));
+
+        let filter = rules(&["--allow-comment", "work-*", "--deny-comment", "*-old"]);
+        assert!(filter.needs_comment());
+        assert!(filter.allows(WORK, Some("work-laptop")));
+        assert!(!filter.allows(WORK, Some("work-old")));
+    }
+
+    #[test]
+    fn keys_without_a_comment_match_no_comment_rules() {
+        // Allowed only by comment, so hidden.
+        assert!(!rules(&["--allow-comment", "*"]).allows(WORK, None));
+        // Denied only by comment, so shown.
+        assert!(rules(&["--deny-comment", "*"]).allows(WORK, None));
+        // An empty comment is still a comment.
+        assert!(rules(&["--allow-comment", "*"]).allows(WORK, Some("")));
+    }
+
+    #[test]
+    fn fingerprints_may_leave_out_the_prefix_and_padding() {
+        let work = agent_proto::fingerprint(WORK);
+        let bare = format!(" {}= ", work.strip_prefix("SHA256:").unwrap());
+        assert!(rules(&["--allow-key", &bare]).allows(WORK, None));
+        assert!(!rules(&["--allow-key", &bare]).allows(HOME, None));
+    }
+
+    #[test]
+    fn rules_may_be_repeated() {
+        let filter = rules(&["--deny-comment", "work", "--deny-comment", "home"]);
+        assert!(!filter.allows(WORK, Some("work")));
+        assert!(!filter.allows(HOME, Some("home")));
+        assert!(filter.allows(HOME, Some("other")));
+    }
+
+    #[test]
+    fn matches_globs() {
+        for (pattern, text) in [
+            ("", ""),
+            ("*", ""),
+            ("*", "anything"),
+            ("work", "work"),
+            ("work-*", "work-"),
+            ("work-*", "work-laptop"),
+            ("*-laptop", "work-laptop"),
+            ("w?rk", "work"),
+            ("*a*b", "aaab"),
+            ("a**b", "ab"),
+            ("??", "✓✓"),
+            ("*.pem", "x.pem.pem"),
+        ] {
+            assert!(glob_matches(pattern, text), "{:?} vs {:?}", pattern, text);
+        }
+        for (pattern, text) in [
+            ("", "a"),
+            ("work", "Work"),
+            ("work", "work-laptop"),
+            ("work-*", "work"),
+            ("w?rk", "wrk"),
+            ("*a*b", "aaba"),
+            ("?", ""),
+            // Only `*` and `?` are special.
+            ("[wh]ork", "work"),
+        ] {
+            assert!(!glob_matches(pattern, text), "{:?} vs {:?}", pattern, text);
+        }
+    }
+}
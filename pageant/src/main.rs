@@ -1,188 +1,732 @@
-use core::mem::MaybeUninit;
+// Built with the `windowless` feature, pageant.exe is a GUI program, so Windows doesn't give it a
+// console window that flashes up when it's launched through interop. Its stdin/stdout are still
+// the pipes it was started with, but stderr goes nowhere, so use `--log-file` or `--event-log`.
+#![cfg_attr(feature = "windowless", windows_subsystem = "windows")]
+
+use pageant_client::{PageantClient, AGENT_MAX_MSGLEN};
+
+mod audit;
+mod cache;
+mod confirm;
+mod eventlog;
+mod filter;
+mod keyfile;
+mod notify;
+mod ppk;
+mod prompt;
+mod service;
+mod session;
+
+/// Whether agent messages (which can include signatures and private keys) may be logged, at
+/// trace level.
+static LOG_SECRETS: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// `tracing::trace!` for events that include secret material, which are only emitted if
+/// `--log-secrets` was passed.
+macro_rules! trace_secret {
+    ($($arg:tt)*) => {
+        if LOG_SECRETS.load(std::sync::atomic::Ordering::Relaxed) {
+            tracing::trace!($($arg)*);
+        }
+    };
+}
 
-use windows::core::{s, PCSTR};
-use windows::Win32::Foundation::{HANDLE, HWND, LPARAM, WPARAM};
+#[derive(structopt::StructOpt, Debug)]
+struct Args {
+    /// Log filter, e.g. `debug` (overrides `RUST_LOG`) [default: warn]
+    #[structopt(long)]
+    log_level: Option<String>,
+    /// Log output format, `text` or `json`
+    #[structopt(long, default_value = "text")]
+    log_format: LogFormat,
+    /// Include agent messages in trace-level logs (these can contain private keys)
+    #[structopt(long)]
+    log_secrets: bool,
+    /// Log each agent message, with its type and a hex dump (with private keys and passphrases
+    /// redacted)
+    #[structopt(long)]
+    trace_wire: bool,
+    /// Don't redact private keys and passphrases from `--trace-wire`'s dumps
+    #[structopt(long, requires = "trace-wire")]
+    unsafe_trace: bool,
+    /// Also report warnings and errors to the Windows Event Log (Application log, source
+    /// `wsl-systemd-pageant`), for when stderr isn't going anywhere
+    #[structopt(long)]
+    event_log: bool,
+    /// Log to this file rather than stderr, starting a new one each day (named after it, with
+    /// the date appended) and keeping the last week's
+    #[structopt(long, parse(from_os_str))]
+    log_file: Option<std::path::PathBuf>,
+    /// Append a line to this file for every sign request: when, the key's fingerprint, the
+    /// connection and whether it was signed
+    #[structopt(long, parse(from_os_str))]
+    audit_log: Option<std::path::PathBuf>,
+    #[structopt(flatten)]
+    filter: filter::Filter,
+    /// Ask for confirmation (with a Windows dialog) before each signature
+    #[structopt(long)]
+    confirm: bool,
+    /// Show a Windows notification for each signature (which, unlike `--confirm`, doesn't wait
+    /// for the user)
+    #[structopt(long)]
+    notify: bool,
+    /// The AppUserModelID of the app `--notify`'s notifications are shown as coming from
+    /// [default: PowerShell's]
+    #[structopt(long, requires = "notify")]
+    notify_app_id: Option<String>,
+    /// Only let the client list keys and sign with them, answering anything else (adding,
+    /// removing or locking keys, unknown messages) with `SSH_AGENT_FAILURE`, e.g. for an agent
+    /// forwarded into a container
+    #[structopt(long)]
+    restrict: bool,
+    /// Refuse signatures on connections that haven't been bound to an SSH session with OpenSSH
+    /// 8.9+'s `session-bind@openssh.com` extension, signed by the host's key (i.e. from older
+    /// clients and other tools)
+    #[structopt(long)]
+    require_session_bind: bool,
+    /// Description of the client shown in confirmation dialogs, e.g. the systemd instance name
+    /// (`%i`), which identifies the peer process
+    #[structopt(long)]
+    client: Option<String>,
+    /// Reuse Pageant's list of keys for this many seconds, across connections (0 disables)
+    #[structopt(long, default_value = "0")]
+    cache_identities: u64,
+    /// If Pageant isn't running, keep looking for it (for up to SECONDS, or forever if no value
+    /// is given) rather than failing the request
+    #[structopt(long, value_name = "SECONDS")]
+    wait: Option<Option<u64>>,
+    /// Start this program (e.g. `C:\Program Files\PuTTY\pageant.exe`) if Pageant isn't
+    /// running
+    #[structopt(long, parse(from_os_str))]
+    launch: Option<std::path::PathBuf>,
+    /// Give up on a request if Pageant hasn't answered it within SECONDS (0 waits forever)
+    /// [default: 60]
+    #[structopt(long, value_name = "SECONDS")]
+    timeout: Option<u64>,
+    /// Keep running in the background, serving any number of connections on a named pipe
+    /// (see `--pipe`) rather than one on stdin/stdout
+    #[structopt(long)]
+    service: bool,
+    /// The named pipe served with `--service`
+    #[structopt(long, default_value = service::DEFAULT_PIPE)]
+    pipe: String,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+/// Rather than relaying a client, manage Pageant's keys.
+#[derive(structopt::StructOpt, Debug)]
+enum Command {
+    /// Load a private key (an OpenSSH or PuTTY `.ppk` key file, whose passphrase is asked for in a
+    /// Windows dialog) into Pageant
+    Add {
+        #[structopt(parse(from_os_str))]
+        key_file: std::path::PathBuf,
+    },
+    /// Print the fingerprint of a private key file, as `ssh-add -l` would once it's loaded
+    Fingerprint {
+        #[structopt(parse(from_os_str))]
+        key_file: std::path::PathBuf,
+    },
+    /// Convert a private key file between OpenSSH's format and PuTTY's (`.ppk`), as PuTTYgen does
+    Convert {
+        #[structopt(parse(from_os_str))]
+        key_file: std::path::PathBuf,
+        /// Where to write the converted key (without a passphrase), which mustn't already exist
+        #[structopt(parse(from_os_str))]
+        output: std::path::PathBuf,
+        /// The format to convert to, `openssh` or `ppk` [default: `ppk` if OUTPUT ends in `.ppk`,
+        /// otherwise `openssh`]
+        #[structopt(long)]
+        to: Option<keyfile::Format>,
+        /// Required, to acknowledge that the converted key isn't protected by a passphrase (only
+        /// by being readable by the user alone)
+        #[structopt(long)]
+        no_passphrase: bool,
+    },
+    /// Remove a key from Pageant, by fingerprint (`SHA256:...`, as shown by `ssh-add -l`)
+    Remove { fingerprint: String },
+    /// Lock the agent with a passphrase (asked for in a Windows dialog, not the terminal), so its
+    /// keys can't be used until it's unlocked
+    Lock,
+    /// Unlock the agent, with the passphrase it was locked with (asked for in a Windows dialog)
+    Unlock,
+}
 
-use byteorder::{ByteOrder as _, BigEndian};
+#[derive(Debug, Clone, Copy)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "unknown log format {:?} (expected text or json)",
+                s
+            )),
+        }
+    }
+}
 
 #[derive(thiserror::Error, Debug)]
 enum Error {
-    #[error("Windows API error: {0}")]
-    Windows(#[from] windows::core::Error),
-    #[error("No Pageant window found")]
-    NoPageantWindow,
-    #[error("Request too long")]
-    RequestTooLong,
-    #[error("Pageant rejected our request")]
-    SendMessageFailed,
+    #[error(transparent)]
+    Pageant(#[from] pageant_client::Error),
+    #[error("Pageant sent an unexpected {0} response")]
+    UnexpectedResponse(agent_proto::MessageType),
+    #[error("Pageant sent a malformed response: {0}")]
+    MalformedResponse(#[source] agent_proto::Error),
+    #[error("Failed to serve named pipe {0}")]
+    Pipe(String, #[source] windows::core::Error),
+    #[error(transparent)]
+    KeyFile(#[from] keyfile::Error),
+    #[error("Pageant has no key with fingerprint {0}")]
+    UnknownKey(String),
+    #[error("Pageant refused the request")]
+    Refused,
+    #[error("Failed to get a passphrase")]
+    Prompt(#[from] prompt::Error),
+    #[error("The passphrases don't match")]
+    PassphraseMismatch,
+    #[error("The converted key won't have a passphrase, pass --no-passphrase to write it anyway")]
+    NoPassphrase,
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
-#[derive(Debug, Clone)]
-struct DroppableHandle(HANDLE);
-
-impl std::ops::Drop for DroppableHandle {
-    fn drop(&mut self) {
-        if !self.0.is_invalid() {
-            eprintln!("Closing {:?}", self.0);
-            unsafe {
-                windows::Win32::Foundation::CloseHandle(self.0).expect("can close valid handles");
-            }
+impl Error {
+    /// What went wrong, as reported to the bridge that spawned us.
+    fn kind(&self) -> agent_proto::report::Kind {
+        use agent_proto::report::Kind;
+
+        match self {
+            Self::Pageant(e) => match e {
+                pageant_client::Error::NoPageantWindow => Kind::AgentNotRunning,
+                pageant_client::Error::Timeout(_) => Kind::AgentTimeout,
+                pageant_client::Error::SendMessageFailed => Kind::AgentRefused,
+                pageant_client::Error::ResponseTooLong(_)
+                | pageant_client::Error::EmptyResponse
+                | pageant_client::Error::UnexpectedResponse { .. } => Kind::BadResponse,
+                _ => Kind::Other,
+            },
+            Self::UnexpectedResponse(_) | Self::MalformedResponse(_) => Kind::BadResponse,
+            Self::Refused => Kind::AgentRefused,
+            _ => Kind::Other,
         }
     }
 }
 
-#[derive(Debug, Clone)]
-struct ViewOfFile(windows::Win32::System::Memory::MEMORY_MAPPED_VIEW_ADDRESS);
-
-impl ViewOfFile {
-    fn as_slice(&mut self) -> &mut [MaybeUninit<u8>; 8192] {
-        unsafe { &mut *self.0.Value.cast() }
+/// Tell the bridge that spawned us about an error, if it asked (see `agent_proto::report`).
+fn report(kind: agent_proto::report::Kind, error: &dyn std::error::Error, fatal: bool) {
+    if agent_proto::report::Report::enabled() {
+        let report = agent_proto::report::Report::new(kind, error, fatal);
+        eprintln!("{}", report.to_line());
     }
 }
 
-impl std::ops::Drop for ViewOfFile {
-    fn drop(&mut self) {
-        if !self.0.Value.is_null() {
-            eprintln!("Unmapping {:?}", self.0);
-            unsafe {
-                windows::Win32::System::Memory::UnmapViewOfFile(self.0).expect("can unmap view of file");
+/// The framed `SSH_AGENT_FAILURE` message, sent in place of the response when the request
+/// couldn't be forwarded to Pageant.
+fn agent_failure() -> Vec<u8> {
+    agent_proto::frame::frame(&agent_proto::Response::Failure.encode())
+}
+
+/// The framed response to a request, or why it's refused without asking Pageant (and answered
+/// with `SSH_AGENT_FAILURE`).
+type Answer = std::result::Result<Vec<u8>, audit::Refusal>;
+
+/// Forward the request (an unframed message body) to Pageant, applying `args.filter` to the keys
+/// the client can see and use and asking for confirmation of signatures if `args.confirm` is
+/// set, and return the framed response.
+fn handle(
+    pageant: &PageantClient,
+    req: &[u8],
+    args: &Args,
+    cache: Option<&cache::IdentityCache>,
+    session: &mut session::Session,
+) -> Result<Answer> {
+    use agent_proto::{Request, Response};
+
+    let filter = &args.filter;
+    let request = Request::parse(req);
+    if let Ok(request) = &request {
+        session.observe(request);
+    }
+    if args.restrict && !restrict_allows(&request) {
+        let message_type = agent_proto::MessageType::of(req).map_or_else(
+            |_| "(empty)".to_owned(),
+            |message_type| message_type.to_string(),
+        );
+        tracing::warn!(%message_type, "Refused a request not allowed by --restrict");
+        return Ok(Err(audit::Refusal::Restrict));
+    }
+    match request {
+        Ok(Request::RequestIdentities) => {
+            let rsp = request_identities(pageant, cache)?;
+            if filter.is_empty() {
+                return Ok(Ok(rsp));
+            }
+            let identities = parse_identities(&rsp)?;
+            let total = identities.len();
+            let visible: Vec<_> = identities
+                .into_iter()
+                .filter(|identity| filter.allows(&identity.key_blob, Some(&identity.comment)))
+                .collect();
+            tracing::debug!(total, visible = visible.len(), "Filtered identities");
+            Ok(Ok(agent_proto::frame::frame(
+                &Response::IdentitiesAnswer(visible).encode(),
+            )))
+        }
+        Ok(Request::SignRequest { key_blob, .. })
+            if args.require_session_bind && !session.is_bound() =>
+        {
+            let fingerprint = agent_proto::fingerprint(&key_blob);
+            tracing::warn!(%fingerprint, "Refused sign request on a connection with no session");
+            Ok(Err(audit::Refusal::SessionBind))
+        }
+        Ok(Request::SignRequest { key_blob, .. }) if !filter.is_empty() || args.confirm => {
+            let comment = if filter.needs_comment() || args.confirm {
+                parse_identities(&request_identities(pageant, cache)?)?
+                    .into_iter()
+                    .find(|identity| identity.key_blob == key_blob)
+                    .map(|identity| identity.comment)
+            } else {
+                None
+            };
+            let fingerprint = agent_proto::fingerprint(&key_blob);
+            if !filter.allows(&key_blob, comment.as_deref()) {
+                tracing::warn!(%fingerprint, "Refused sign request for a filtered key");
+                return Ok(Err(audit::Refusal::Filter));
+            }
+            let client = args.client.as_deref();
+            if args.confirm && !confirm::confirm(&key_blob, comment.as_deref(), client) {
+                tracing::info!(%fingerprint, "Sign request declined by the user");
+                return Ok(Err(audit::Refusal::Confirm));
             }
+            Ok(Ok(pageant.request(&agent_proto::frame::frame(req))?))
+        }
+        // Anything else (including requests we can't parse) is Pageant's problem.
+        request => {
+            let rsp = pageant.request(&agent_proto::frame::frame(req))?;
+            if let (Some(cache), Ok(request)) = (cache, request) {
+                if cache::invalidated_by(request.message_type()) {
+                    cache.invalidate();
+                }
+            }
+            Ok(Ok(rsp))
         }
     }
 }
 
-fn send_to_pageant(data: &[u8]) -> Result<Vec<u8>> {
-    if data.len() >= 8192 {
-        return Err(Error::RequestTooLong);
+/// Whether `--restrict` lets `request` through: listing keys, signing, and the extensions OpenSSH
+/// uses to describe the session (which don't change anything).
+fn restrict_allows(request: &Result<agent_proto::Request, agent_proto::Error>) -> bool {
+    use agent_proto::extension::{QUERY, SESSION_BIND};
+    use agent_proto::Request;
+
+    match request {
+        Ok(Request::RequestIdentities | Request::SignRequest { .. }) => true,
+        Ok(Request::Extension { name, .. }) => name == SESSION_BIND || name == QUERY,
+        _ => false,
     }
+}
 
-    let window_handle = unsafe {
-        windows::Win32::UI::WindowsAndMessaging::FindWindowA(s!("Pageant"), s!("Pageant"))
-    };
+/// Pageant's (framed, unfiltered) response to `SSH_AGENTC_REQUEST_IDENTITIES`, from the cache if
+/// possible.
+fn request_identities(
+    pageant: &PageantClient,
+    cache: Option<&cache::IdentityCache>,
+) -> Result<Vec<u8>> {
+    if let Some(rsp) = cache.and_then(|cache| cache.get()) {
+        return Ok(rsp);
+    }
+    let req = agent_proto::Request::RequestIdentities.encode();
+    let rsp = pageant.request(&agent_proto::frame::frame(&req))?;
+    if let Some(cache) = cache {
+        cache.put(&rsp);
+    }
+    Ok(rsp)
+}
 
-    if window_handle.0 == 0 {
-        return Err(Error::NoPageantWindow);
+fn parse_identities(rsp: &[u8]) -> Result<Vec<agent_proto::Identity>> {
+    match agent_proto::Response::parse(&rsp[4..]) {
+        Ok(agent_proto::Response::IdentitiesAnswer(identities)) => Ok(identities),
+        Ok(rsp) => Err(Error::UnexpectedResponse(rsp.message_type())),
+        Err(e) => Err(Error::MalformedResponse(e)),
     }
+}
 
-    eprintln!("Found Pagent window: {:x?}", window_handle);
+/// Load the key in `key_file` into Pageant.
+fn add_key(pageant: &PageantClient, key_file: &std::path::Path) -> Result<()> {
+    let key = keyfile::load(key_file, prompt::passphrase)?;
+    let req = agent_proto::Request::AddIdentity {
+        constrained: false,
+        contents: key.contents,
+    };
+    expect_success(&pageant.request(&agent_proto::frame::frame(&req.encode()))?)?;
+    println!(
+        "Identity added: {} {} ({})",
+        key_file.display(),
+        agent_proto::fingerprint(&key.public_key),
+        key.comment
+    );
+    Ok(())
+}
 
-    let tid = unsafe { windows::Win32::System::Threading::GetCurrentThreadId() };
-    let map_name = format!("PageantRequest{:x?}", tid);
+/// Print the fingerprint, comment and type of the key in `key_file`.
+fn fingerprint(key_file: &std::path::Path) -> Result<()> {
+    let key = keyfile::load(key_file, prompt::passphrase)?;
+    let key_type = agent_proto::wire::Reader::new(&key.public_key)
+        .text()
+        .map_err(|e| keyfile::Error::Malformed(key_file.to_owned(), e))?;
+    println!(
+        "{} {} ({})",
+        agent_proto::fingerprint(&key.public_key),
+        key.comment,
+        key_type
+    );
+    Ok(())
+}
 
-    eprintln!("Map name is: {:?}", map_name);
+/// Write the key in `key_file` to `output` in `format` (or the one `output`'s name suggests),
+/// once the user's agreed to it having no passphrase.
+fn convert(
+    key_file: &std::path::Path,
+    output: &std::path::Path,
+    format: Option<keyfile::Format>,
+    no_passphrase: bool,
+) -> Result<()> {
+    if !no_passphrase {
+        return Err(Error::NoPassphrase);
+    }
+    let format = format.unwrap_or_else(|| match output.extension() {
+        Some(extension) if extension.eq_ignore_ascii_case("ppk") => keyfile::Format::Ppk,
+        _ => keyfile::Format::OpenSsh,
+    });
+    let key = keyfile::load(key_file, prompt::passphrase)?;
+    keyfile::save(key_file, &key, output, format)?;
+    println!(
+        "Converted {} to {} ({:?})",
+        key_file.display(),
+        output.display(),
+        format
+    );
+    Ok(())
+}
 
-    let map_pcstr_len = map_name.len() as u32 + 1; // Include nul-bytes
-    let map_pcstr = std::ffi::CString::new(map_name).expect("map_name doesn't contain nul bytes");
-    let map_pcstr = PCSTR(map_pcstr.as_ptr().cast());
+/// Remove the key with `fingerprint` (with or without the `SHA256:` prefix) from Pageant.
+fn remove_key(pageant: &PageantClient, fingerprint: &str) -> Result<()> {
+    let fingerprint = if fingerprint.starts_with("SHA256:") {
+        fingerprint.to_owned()
+    } else {
+        format!("SHA256:{}", fingerprint)
+    };
+    let identity = parse_identities(&request_identities(pageant, None)?)?
+        .into_iter()
+        .find(|identity| identity.fingerprint() == fingerprint)
+        .ok_or_else(|| Error::UnknownKey(fingerprint.clone()))?;
+    let req = agent_proto::Request::RemoveIdentity {
+        key_blob: identity.key_blob,
+    };
+    expect_success(&pageant.request(&agent_proto::frame::frame(&req.encode()))?)?;
+    println!("Identity removed: {} ({})", fingerprint, identity.comment);
+    Ok(())
+}
 
-    let file_mapping_handle = DroppableHandle(unsafe {
-        windows::Win32::System::Memory::CreateFileMappingA(
-            HWND(0),
-            None,
-            windows::Win32::System::Memory::PAGE_READWRITE,
-            0,
-            8192,
-            map_pcstr,
-        )
-    }?);
+/// Lock (or unlock) Pageant with a passphrase from the user. Like `ssh-add -x`, locking asks for
+/// the passphrase twice.
+fn lock(pageant: &PageantClient, lock: bool) -> Result<()> {
+    let req = if lock {
+        let passphrase = prompt::passphrase("Enter a passphrase to lock the agent with")?;
+        if prompt::passphrase("Enter the passphrase again")? != passphrase {
+            return Err(Error::PassphraseMismatch);
+        }
+        agent_proto::Request::Lock { passphrase }
+    } else {
+        let passphrase = prompt::passphrase("Enter the passphrase to unlock the agent")?;
+        agent_proto::Request::Unlock { passphrase }
+    };
+    expect_success(&pageant.request(&agent_proto::frame::frame(&req.encode()))?)?;
+    println!("Agent {}.", if lock { "locked" } else { "unlocked" });
+    Ok(())
+}
 
-    eprintln!("Created file mapping: {:?}", file_mapping_handle);
+fn expect_success(rsp: &[u8]) -> Result<()> {
+    match agent_proto::Response::parse(&rsp[4..]) {
+        Ok(agent_proto::Response::Success) => Ok(()),
+        Ok(agent_proto::Response::Failure) => Err(Error::Refused),
+        Ok(rsp) => Err(Error::UnexpectedResponse(rsp.message_type())),
+        Err(e) => Err(Error::MalformedResponse(e)),
+    }
+}
 
-    let mut shm = ViewOfFile(unsafe {
-        windows::Win32::System::Memory::MapViewOfFile(
-            file_mapping_handle.0,
-            windows::Win32::System::Memory::FILE_MAP_WRITE,
-            0,
-            0,
-            0,
-        )
+fn main() {
+    let args = <Args as structopt::StructOpt>::from_args();
+    let level = args
+        .log_level
+        .clone()
+        .or_else(|| std::env::var(tracing_subscriber::EnvFilter::DEFAULT_ENV).ok())
+        .unwrap_or_else(|| "warn".to_owned());
+    let filter = match tracing_subscriber::EnvFilter::try_new(&level) {
+        // The wire traces are logged whatever the log level.
+        Ok(filter) if args.trace_wire => {
+            filter.add_directive("wire=trace".parse().expect("valid directive"))
+        }
+        Ok(filter) => filter,
+        Err(e) => {
+            eprintln!("Invalid log level {:?}: {}", level, e);
+            std::process::exit(1);
+        }
+    };
+    let event_log = match args.event_log.then(eventlog::EventLog::register) {
+        Some(Ok(event_log)) => Some(event_log),
+        Some(Err(e)) => {
+            eprintln!("Failed to register with the Event Log: {}", e);
+            None
+        }
+        None => None,
+    };
+    // Log to the file if asked, else stderr (stdout is carrying the agent protocol).
+    let writer = match &args.log_file {
+        Some(path) => match log_file(path) {
+            Ok(appender) => tracing_subscriber::fmt::writer::BoxMakeWriter::new(appender),
+            Err(e) => {
+                eprintln!("Failed to open log file {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        },
+        None => tracing_subscriber::fmt::writer::BoxMakeWriter::new(std::io::stderr),
+    };
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(args.log_file.is_none())
+        .with_writer(writer);
+    {
+        use tracing_subscriber::layer::SubscriberExt as _;
+        use tracing_subscriber::util::SubscriberInitExt as _;
+
+        match args.log_format {
+            LogFormat::Text => subscriber.finish().with(event_log).init(),
+            LogFormat::Json => subscriber
+                .json()
+                .flatten_event(true)
+                .finish()
+                .with(event_log)
+                .init(),
+        }
+    }
+    LOG_SECRETS.store(args.log_secrets, std::sync::atomic::Ordering::Relaxed);
+    let wait = match args.wait {
+        None => std::time::Duration::ZERO,
+        Some(None) => std::time::Duration::MAX,
+        Some(Some(secs)) => std::time::Duration::from_secs(secs),
+    };
+    let timeout = match args.timeout {
+        None => pageant_client::Options::DEFAULT_TIMEOUT,
+        Some(0) => std::time::Duration::MAX,
+        Some(secs) => std::time::Duration::from_secs(secs),
+    };
+    let pageant = PageantClient::new(pageant_client::Options {
+        wait,
+        launch: args.launch.clone(),
+        timeout,
     });
 
-    eprintln!("Created view of file: {:?}", shm);
-    let shm = shm.as_slice();
+    if let Some(command) = &args.command {
+        let result = match command {
+            Command::Add { key_file } => add_key(&pageant, key_file),
+            Command::Fingerprint { key_file } => fingerprint(key_file),
+            Command::Convert {
+                key_file,
+                output,
+                to,
+                no_passphrase,
+            } => convert(key_file, output, *to, *no_passphrase),
+            Command::Remove { fingerprint } => remove_key(&pageant, fingerprint),
+            Command::Lock => lock(&pageant, true),
+            Command::Unlock => lock(&pageant, false),
+        };
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            let mut source = std::error::Error::source(&e);
+            while let Some(e) = source {
+                eprintln!("  caused by: {}", e);
+                source = e.source();
+            }
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    unsafe { std::ptr::copy(data.as_ptr().cast(), (&mut shm[..]).as_mut_ptr(), data.len()) };
+    tracing::debug!("Starting up!");
 
-    let copy_data = windows::Win32::System::DataExchange::COPYDATASTRUCT {
-        // https://github.com/Yasushi/putty/blob/31a2ad775f393aad1c31a983b0baea205d48e219/windows/winpgntc.c#L14
-        dwData: 0x804e50ba,
-        cbData: map_pcstr_len,
-        lpData: map_pcstr.0.cast_mut().cast(),
+    let cache = cache::IdentityCache::new(std::time::Duration::from_secs(args.cache_identities));
+    let audit = match &args.audit_log {
+        Some(path) => match audit::AuditLog::open(path) {
+            Ok(audit) => Some(audit),
+            Err(e) => {
+                tracing::error!(error = %e, path = %path.display(), "Failed to open audit log");
+                std::process::exit(1);
+            }
+        },
+        None => None,
     };
-
-    eprintln!("COPYDATASTRUCT: {:?}", copy_data);
-
-    let ret = unsafe {
-        windows::Win32::UI::WindowsAndMessaging::SendMessageA(
-            window_handle,
-            windows::Win32::UI::WindowsAndMessaging::WM_COPYDATA,
-            WPARAM(0),
-            LPARAM(&copy_data as *const _ as isize),
-        )
+    let log = Log {
+        audit: audit.as_ref(),
+        connection: None,
     };
 
-    eprintln!("SendMessage(WM_COPYDATA) returned: {:?}", ret);
-
-    if ret.0 == 0 {
-        return Err(Error::SendMessageFailed);
+    if args.service {
+        let serve = |pipe: &service::Pipe, id| {
+            let log = Log {
+                connection: Some(id),
+                ..log
+            };
+            serve(
+                &mut &*pipe,
+                &mut &*pipe,
+                &pageant,
+                &args,
+                cache.as_ref(),
+                log,
+            )
+        };
+        if let Err(e) = service::run(&args.pipe, serve) {
+            tracing::error!(error = %e, "Failed to run as a service");
+            std::process::exit(1);
+        }
+        return;
     }
 
-    let rsp_len = &shm[0..4];
-    let rsp_len: &[u8] = unsafe { std::mem::transmute(rsp_len) };
-    let rsp_len = BigEndian::read_u32(rsp_len) as usize;
+    if let Err(e) = serve(
+        &mut std::io::stdin().lock(),
+        &mut std::io::stdout().lock(),
+        &pageant,
+        &args,
+        cache.as_ref(),
+        log,
+    ) {
+        tracing::error!(error = %e, "Failed to read request");
+        report(agent_proto::report::Kind::Io, &e, true);
+        std::process::exit(1);
+    }
+}
 
-    eprintln!("Response length is: {}", rsp_len);
+/// How many days of `--log-file` logs are kept.
+const LOG_FILES_KEPT: usize = 7;
 
-    let mut rsp = Vec::with_capacity(rsp_len as usize);
-    unsafe {
-        rsp.extend_from_slice(std::mem::transmute(&shm[0..rsp_len + 4])); // Remember to include
-                                                                          // the length field of
-                                                                          // the response...
+/// The daily rotating log files for `--log-file path`.
+fn log_file(
+    path: &std::path::Path,
+) -> Result<tracing_appender::rolling::RollingFileAppender, tracing_appender::rolling::InitError> {
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => std::path::Path::new("."),
+    };
+    let mut builder = tracing_appender::rolling::Builder::new()
+        .rotation(tracing_appender::rolling::Rotation::DAILY)
+        .max_log_files(LOG_FILES_KEPT);
+    if let Some(name) = path.file_name() {
+        builder = builder.filename_prefix(name.to_string_lossy());
     }
-
-    Ok(rsp)
+    builder.build(directory)
 }
 
-fn main() {
-    use std::io::{Write as _, Read as _};
+/// Log the agent message with `body` (in `direction`), if `--trace-wire` was passed.
+fn trace_wire(args: &Args, direction: &str, body: &[u8]) {
+    if !args.trace_wire || body.is_empty() {
+        return;
+    }
+    tracing::trace!(
+        target: "wire",
+        direction,
+        message_type = %agent_proto::MessageType(body[0]),
+        len = body.len(),
+        "\n{}",
+        agent_proto::dump::message(body, !args.unsafe_trace)
+    );
+}
 
-    eprintln!("Starting up!");
+/// Where a connection's signatures are recorded, if `--audit-log` was passed.
+#[derive(Clone, Copy)]
+struct Log<'a> {
+    audit: Option<&'a audit::AuditLog>,
+    /// The `--service` connection, which each pageant.exe on stdin/stdout only has one of.
+    connection: Option<u64>,
+}
 
+/// Forward agent requests read from `input` to Pageant, writing the responses to `output`, until
+/// the client closes the connection.
+fn serve(
+    input: &mut impl std::io::Read,
+    output: &mut impl std::io::Write,
+    pageant: &PageantClient,
+    args: &Args,
+    cache: Option<&cache::IdentityCache>,
+    log: Log,
+) -> std::io::Result<()> {
+    let mut session = session::Session::default();
     loop {
-        let req = {
-            let mut stdin = std::io::stdin().lock();
-            let mut len_buf = [0;4];
-            match stdin.read_exact(&mut len_buf) {
-                Ok(_) => {}
-                Err(e) => {
-                    if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                        return;
-                    } else {
-                        panic!("stdin is unreadable");
+        let req = agent_proto::read_frame(input, AGENT_MAX_MSGLEN - 4);
+        let rsp = match req {
+            Ok(Some(agent_proto::Frame::Message(req))) => {
+                tracing::debug!(
+                    len = req.len(),
+                    message_type = ?agent_proto::MessageType::of(&req).ok(),
+                    "Received request"
+                );
+                trace_secret!("Request: {:?}", req);
+                trace_wire(args, "->", &req);
+                // Keep the session alive if Pageant is missing or misbehaving, the client can
+                // decide whether a failure to e.g. list keys is fatal.
+                let answer = handle(pageant, &req, args, cache, &mut session);
+                let client = args.client.as_deref();
+                if let Some(audit) = log.audit {
+                    let outcome = match &answer {
+                        Ok(Ok(rsp)) => audit::Outcome::Answered(rsp),
+                        Ok(Err(refusal)) => audit::Outcome::Refused(*refusal),
+                        Err(e) => audit::Outcome::Failed(e),
+                    };
+                    audit.record(&req, outcome, log.connection, client);
+                }
+                let rsp = answer.map(|answer| answer.unwrap_or_else(|_| agent_failure()));
+                if args.notify {
+                    if let Ok(rsp) = &rsp {
+                        let app_id = args.notify_app_id.as_deref();
+                        notify::signed(app_id.unwrap_or(notify::DEFAULT_APP_ID), &req, rsp, client);
+                    }
+                }
+                match rsp {
+                    Ok(rsp) => rsp,
+                    Err(e) => {
+                        tracing::error!(error = %e, "Failed to forward request to Pageant");
+                        report(e.kind(), &e, false);
+                        // Pageant may have gone away or been restarted with different keys.
+                        if let Some(cache) = cache {
+                            cache.invalidate();
+                        }
+                        agent_failure()
                     }
                 }
             }
-            let req_len = BigEndian::read_u32(&len_buf);
-            eprintln!("Request length: {}", req_len);
-
-            let mut req = Vec::with_capacity(req_len as usize + 4);
-            req.extend_from_slice(&len_buf);
-            stdin.take(req_len as u64).read_to_end(&mut req).expect("should be able to read len bytes");
-
-            req
+            Ok(Some(agent_proto::Frame::Oversized(len))) => {
+                tracing::warn!(len, "Discarded request longer than AGENT_MAX_MSGLEN");
+                agent_failure()
+            }
+            Ok(None) => return Ok(()),
+            Err(e) => return Err(e),
         };
 
-        eprintln!("Request: {:?}", req);
-
-        let rsp = send_to_pageant(&req).unwrap();
-
-        let mut stdout = std::io::stdout().lock();
-        for chunk in rsp.chunks(16) {
-            eprintln!("Response chunk: {:?}", chunk);
-            stdout.write_all(&chunk).expect("writes to stdout can't fail");
-            stdout.flush().expect("can flush stdout");
+        trace_secret!("Response: {:?}", rsp);
+        trace_wire(args, "<-", &rsp[4..]);
+        if let Err(e) = output.write_all(&rsp).and_then(|()| output.flush()) {
+            tracing::info!(error = %e, "Client went away");
+            return Ok(());
         }
     }
 }
@@ -0,0 +1,65 @@
+//! `--notify`: a Windows notification for each signature, like the blink of a hardware key.
+//!
+//! Unlike `--confirm` nothing waits for the user, the notification just makes it visible when
+//! (and from where) the keys are being used, so an unexpected signature stands out.
+//!
+//! Windows only shows notifications from apps it knows about, by their AppUserModelID, and
+//! pageant.exe isn't one, so by default they're shown as coming from PowerShell (which every
+//! Windows has). `--notify-app-id` picks another, e.g. one given to a Start menu shortcut.
+
+use agent_proto::{MessageType, Request};
+use windows::core::HSTRING;
+use windows::Data::Xml::Dom::XmlDocument;
+use windows::UI::Notifications::{ToastNotification, ToastNotificationManager};
+
+/// Windows PowerShell's AppUserModelID.
+pub const DEFAULT_APP_ID: &str =
+    r"{1AC14E77-02E7-4E5D-B744-2EB1AE5198B7}\WindowsPowerShell\v1.0\powershell.exe";
+
+/// Show a notification if `rsp` (framed) is a signature in answer to `req` (unframed).
+pub fn signed(app_id: &str, req: &[u8], rsp: &[u8], client: Option<&str>) {
+    let Ok(Request::SignRequest { key_blob, .. }) = Request::parse(req) else {
+        return;
+    };
+    if rsp.get(4) != Some(&MessageType::SIGN_RESPONSE.0) {
+        return;
+    }
+    let fingerprint = agent_proto::fingerprint(&key_blob);
+    let mut text = format!("<text>Key {} used for signing from WSL</text>", fingerprint);
+    if let Some(client) = client {
+        let client = crate::confirm::describe_client(client);
+        text.push_str(&format!("<text>Requested by {}</text>", escape(&client)));
+    }
+    let xml = format!(
+        "<toast><visual><binding template=\"ToastGeneric\">\
+         <text>Pageant (WSL)</text>{}</binding></visual></toast>",
+        text
+    );
+    // The signature's been made either way, so failing to say so is only logged.
+    if let Err(e) = show(app_id, &xml) {
+        tracing::warn!(error = %e, %fingerprint, "Failed to show a notification");
+    }
+}
+
+fn show(app_id: &str, xml: &str) -> windows::core::Result<()> {
+    let content = XmlDocument::new()?;
+    content.LoadXml(&HSTRING::from(xml))?;
+    let toast = ToastNotification::CreateToastNotification(&content)?;
+    ToastNotificationManager::CreateToastNotifierWithId(&HSTRING::from(app_id))?.Show(&toast)
+}
+
+/// `text` with XML's special characters escaped.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
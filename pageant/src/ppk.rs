@@ -0,0 +1,547 @@
+//! PuTTY's private key files (`.ppk`), as described in "PPK file format" in PuTTY's manual:
+//!
+//! ```text
+//! PuTTY-User-Key-File-3: ssh-ed25519
+//! Encryption: none
+//! Comment: me@example
+//! Public-Lines: 2
+//! <the public key in SSH wire format, base64 encoded over that many lines>
+//! Private-Lines: 1
+//! <the key type's private fields, likewise>
+//! Private-MAC: <HMAC-SHA-256 of the type, encryption, comment and both blobs, in hex>
+//! ```
+//!
+//! With a passphrase, `Encryption` is `aes256-cbc` and the private blob is padded to a whole
+//! number of AES blocks and encrypted. In version 3 files the AES key and IV and the MAC's key
+//! come from Argon2, whose parameters are in headers before `Private-Lines`, and the MAC's key is
+//! empty if there's no passphrase. Version 2 files (PuTTY before 0.75) have no Argon2 headers:
+//! the AES key is made of SHA-1 hashes of the passphrase, the IV is zero, and the MAC is
+//! HMAC-SHA-1 keyed with another hash of it. Either way the MAC covers the decrypted private blob,
+//! so a wrong passphrase shows up as it not matching. Files are written as unencrypted version 3.
+//!
+//! The fields are split differently from the agent protocol's (which is also OpenSSH's): the
+//! public ones go in the public blob, in the order `ssh-keygen` prints them, and only the rest go
+//! in the private blob. Ed25519's private blob holds just the 32 byte seed, where OpenSSH's
+//! follows it with the public key.
+
+use std::path::Path;
+
+use agent_proto::wire::{Reader, Writer};
+use hmac::{Hmac, Mac as _};
+use sha1::{Digest as _, Sha1};
+use sha2::Sha256;
+
+use crate::keyfile::{Error, PrivateKey};
+
+/// The start of the first line, followed by the version.
+pub const MAGIC: &str = "PuTTY-User-Key-File-";
+
+/// How many base64 characters PuTTY puts on each line.
+const LINE_LEN: usize = 64;
+
+const ED25519_SEED_LEN: usize = 32;
+
+const AES_BLOCK_SIZE: usize = 16;
+const AES_KEY_LEN: usize = 32;
+/// The length of a version 3 file's MAC key.
+const MAC_KEY_LEN: usize = 32;
+
+/// What version 2 files hash along with the passphrase to get the MAC's key.
+const V2_MAC_KEY_PREFIX: &[u8] = b"putty-private-key-file-mac-key";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Version {
+    /// SHA-1 based, before PuTTY 0.75.
+    Two,
+    /// Argon2 and SHA-256 based.
+    Three,
+}
+
+/// How a version 3 file's keys are derived from its passphrase.
+struct Argon2Params {
+    algorithm: argon2::Algorithm,
+    /// In KiB.
+    memory: u32,
+    passes: u32,
+    parallelism: u32,
+    salt: Vec<u8>,
+}
+
+/// The private key in `text`, the contents of the `.ppk` file at `path`. If it's encrypted,
+/// `passphrase` is called (as for [`crate::keyfile::load`]) to get the passphrase.
+pub fn parse<P, E>(path: &Path, text: &str, passphrase: P) -> Result<PrivateKey, Error>
+where
+    P: FnOnce(&str) -> Result<Vec<u8>, E>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let malformed = |what: &str| Error::MalformedPpk(path.to_owned(), what.to_owned());
+    let mut lines = text.lines().map(str::trim_end);
+
+    let first = lines.next().unwrap_or_default();
+    let (version, algorithm) = first
+        .strip_prefix(MAGIC)
+        .and_then(|rest| rest.split_once(": "))
+        .ok_or_else(|| malformed("no PuTTY-User-Key-File line"))?;
+    let version = match version {
+        "2" => Version::Two,
+        "3" => Version::Three,
+        _ => return Err(Error::PpkVersion(path.to_owned(), version.to_owned())),
+    };
+    let encryption = header(&mut lines, "Encryption").ok_or_else(|| malformed("Encryption"))?;
+    let encrypted = match encryption {
+        "none" => false,
+        "aes256-cbc" => true,
+        _ => {
+            return Err(Error::UnsupportedCipher(
+                path.to_owned(),
+                encryption.to_owned(),
+            ))
+        }
+    };
+    let comment = header(&mut lines, "Comment").ok_or_else(|| malformed("Comment"))?;
+    let public = blob(&mut lines, "Public-Lines").ok_or_else(|| malformed("Public-Lines"))?;
+    let argon2 = match version {
+        Version::Three if encrypted => Some(argon2_params(path, &mut lines)?),
+        _ => None,
+    };
+    let mut private =
+        blob(&mut lines, "Private-Lines").ok_or_else(|| malformed("Private-Lines"))?;
+    let expected = header(&mut lines, "Private-MAC")
+        .and_then(unhex)
+        .ok_or_else(|| malformed("Private-MAC"))?;
+
+    let mac_key = if encrypted {
+        use cbc::cipher::block_padding::NoPadding;
+        use cbc::cipher::{BlockDecryptMut as _, KeyIvInit as _};
+
+        if !private.len().is_multiple_of(AES_BLOCK_SIZE) {
+            return Err(malformed("Private-Lines"));
+        }
+        let mut passphrase = crate::keyfile::read_passphrase(path, passphrase)?;
+        let keys = derive_keys(path, argon2.as_ref(), &passphrase);
+        passphrase.fill(0);
+        let keys = keys?;
+        let (key, rest) = keys.split_at(AES_KEY_LEN);
+        let (iv, mac_key) = rest.split_at(AES_BLOCK_SIZE);
+        cbc::Decryptor::<aes::Aes256>::new(key.into(), iv.into())
+            .decrypt_padded_mut::<NoPadding>(&mut private)
+            .map_err(|_| malformed("Private-Lines"))?;
+        mac_key.to_vec()
+    } else {
+        match version {
+            Version::Two => v2_mac_key(b""),
+            Version::Three => Vec::new(),
+        }
+    };
+    let data = mac_data(algorithm, encryption, comment, &public, &private);
+    let verified = match version {
+        Version::Two => Hmac::<Sha1>::new_from_slice(&mac_key)
+            .expect("HMAC accepts keys of any size")
+            .chain_update(&data)
+            .verify_slice(&expected),
+        Version::Three => Hmac::<Sha256>::new_from_slice(&mac_key)
+            .expect("HMAC accepts keys of any size")
+            .chain_update(&data)
+            .verify_slice(&expected),
+    };
+    verified.map_err(|_| match encrypted {
+        true => Error::WrongPassphrase(path.to_owned()),
+        false => Error::CheckMismatch(path.to_owned()),
+    })?;
+
+    // An encrypted private blob's padding is left on the end, after the fields.
+    let contents = to_agent(&public, &private, comment).map_err(|e| match e {
+        agent_proto::Error::UnknownKeyType(key_type) => {
+            Error::UnsupportedType(path.to_owned(), key_type)
+        }
+        e => Error::Malformed(path.to_owned(), e),
+    })?;
+    Ok(PrivateKey {
+        public_key: public,
+        comment: comment.to_owned(),
+        contents,
+    })
+}
+
+/// `key` as an unencrypted version 3 `.ppk` file, `path` being where it was read from.
+pub fn encode(path: &Path, key: &PrivateKey) -> Result<String, Error> {
+    let (public, private) = from_agent(&key.contents).map_err(|e| match e {
+        agent_proto::Error::UnknownKeyType(key_type) => {
+            Error::UnsupportedType(path.to_owned(), key_type)
+        }
+        e => Error::Malformed(path.to_owned(), e),
+    })?;
+    let algorithm = Reader::new(&public)
+        .text()
+        .map_err(|e| Error::Malformed(path.to_owned(), e))?;
+    // Without a passphrase the MAC's key is empty.
+    let mac = Hmac::<Sha256>::new_from_slice(&[])
+        .expect("HMAC accepts keys of any size")
+        .chain_update(mac_data(
+            &algorithm,
+            "none",
+            &key.comment,
+            &public,
+            &private,
+        ))
+        .finalize();
+
+    let mut text = format!(
+        "{}3: {}\nEncryption: none\nComment: {}\n",
+        MAGIC, algorithm, key.comment
+    );
+    for (name, blob) in [("Public-Lines", &public), ("Private-Lines", &private)] {
+        let lines = crate::keyfile::base64_lines(blob, LINE_LEN);
+        text.push_str(&format!("{}: {}\n{}", name, lines.lines().count(), lines));
+    }
+    let mac: String = mac
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    text.push_str(&format!("Private-MAC: {}\n", mac));
+    Ok(text)
+}
+
+/// The value of the next line, which should be the header `name`.
+fn header<'a>(lines: &mut impl Iterator<Item = &'a str>, name: &str) -> Option<&'a str> {
+    lines.next()?.strip_prefix(name)?.strip_prefix(": ")
+}
+
+/// The blob in the lines after the header `name`, which says how many there are.
+fn blob<'a>(lines: &mut impl Iterator<Item = &'a str>, name: &str) -> Option<Vec<u8>> {
+    use base64::Engine as _;
+
+    let count: usize = header(lines, name)?.parse().ok()?;
+    let mut encoded = String::new();
+    for _ in 0..count {
+        encoded.push_str(lines.next()?);
+    }
+    base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .ok()
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// The Argon2 headers of an encrypted version 3 file.
+fn argon2_params<'a>(
+    path: &Path,
+    lines: &mut impl Iterator<Item = &'a str>,
+) -> Result<Argon2Params, Error> {
+    let malformed = |what: &str| Error::MalformedPpk(path.to_owned(), what.to_owned());
+
+    let kdf = header(lines, "Key-Derivation").ok_or_else(|| malformed("Key-Derivation"))?;
+    let algorithm = match kdf {
+        "Argon2d" => argon2::Algorithm::Argon2d,
+        "Argon2i" => argon2::Algorithm::Argon2i,
+        "Argon2id" => argon2::Algorithm::Argon2id,
+        _ => return Err(Error::UnsupportedKdf(path.to_owned(), kdf.to_owned())),
+    };
+    let mut number = |name: &str| {
+        header(lines, name)
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| malformed(name))
+    };
+    let memory = number("Argon2-Memory")?;
+    let passes = number("Argon2-Passes")?;
+    let parallelism = number("Argon2-Parallelism")?;
+    let salt = header(lines, "Argon2-Salt")
+        .and_then(unhex)
+        .ok_or_else(|| malformed("Argon2-Salt"))?;
+    Ok(Argon2Params {
+        algorithm,
+        memory,
+        passes,
+        parallelism,
+        salt,
+    })
+}
+
+/// The AES key, IV and MAC key, one after the other, for the file at `path` encrypted with
+/// `passphrase`: from Argon2 in version 3 files (with `argon2`), otherwise from SHA-1.
+fn derive_keys(
+    path: &Path,
+    argon2: Option<&Argon2Params>,
+    passphrase: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let Some(argon2) = argon2 else {
+        let mut keys = Vec::new();
+        for sequence in 0u32..2 {
+            keys.extend(
+                Sha1::new()
+                    .chain_update(sequence.to_be_bytes())
+                    .chain_update(passphrase)
+                    .finalize(),
+            );
+        }
+        keys.truncate(AES_KEY_LEN);
+        keys.extend([0; AES_BLOCK_SIZE]);
+        keys.extend(v2_mac_key(passphrase));
+        return Ok(keys);
+    };
+    let malformed = || Error::MalformedPpk(path.to_owned(), "Argon2 parameters".to_owned());
+    let mut keys = vec![0; AES_KEY_LEN + AES_BLOCK_SIZE + MAC_KEY_LEN];
+    let params = argon2::Params::new(
+        argon2.memory,
+        argon2.passes,
+        argon2.parallelism,
+        Some(keys.len()),
+    )
+    .map_err(|_| malformed())?;
+    argon2::Argon2::new(argon2.algorithm, argon2::Version::V0x13, params)
+        .hash_password_into(passphrase, &argon2.salt, &mut keys)
+        .map_err(|_| malformed())?;
+    Ok(keys)
+}
+
+/// A version 2 file's MAC key.
+fn v2_mac_key(passphrase: &[u8]) -> Vec<u8> {
+    Sha1::new()
+        .chain_update(V2_MAC_KEY_PREFIX)
+        .chain_update(passphrase)
+        .finalize()
+        .to_vec()
+}
+
+/// What the MAC is over: everything the file holds, with the private blob decrypted.
+fn mac_data(
+    algorithm: &str,
+    encryption: &str,
+    comment: &str,
+    public: &[u8],
+    private: &[u8],
+) -> Vec<u8> {
+    let mut data = Writer::new();
+    data.string(algorithm.as_bytes())
+        .string(encryption.as_bytes())
+        .string(comment.as_bytes())
+        .string(public)
+        .string(private);
+    data.into_inner()
+}
+
+/// The agent protocol's encoding of the key (type, fields and comment) from PuTTY's blobs.
+fn to_agent(public: &[u8], private: &[u8], comment: &str) -> Result<Vec<u8>, agent_proto::Error> {
+    let mut public = Reader::new(public);
+    let mut private = Reader::new(private);
+    let key_type = public.text()?;
+    let mut contents = Writer::new();
+    contents.string(key_type.as_bytes());
+    match key_type.as_str() {
+        "ssh-rsa" => {
+            let [e, n] = read_strings(&mut public)?;
+            let [d, p, q, iqmp] = read_strings(&mut private)?;
+            contents
+                .string(n)
+                .string(e)
+                .string(d)
+                .string(iqmp)
+                .string(p)
+                .string(q);
+        }
+        "ssh-dss" => {
+            let [p, q, g, y] = read_strings(&mut public)?;
+            let [x] = read_strings(&mut private)?;
+            contents.string(p).string(q).string(g).string(y).string(x);
+        }
+        "ecdsa-sha2-nistp256" | "ecdsa-sha2-nistp384" | "ecdsa-sha2-nistp521" => {
+            let [curve, point] = read_strings(&mut public)?;
+            let [scalar] = read_strings(&mut private)?;
+            contents.string(curve).string(point).string(scalar);
+        }
+        "ssh-ed25519" => {
+            let [public_key] = read_strings(&mut public)?;
+            let [seed] = read_strings(&mut private)?;
+            if seed.len() != ED25519_SEED_LEN {
+                return Err(agent_proto::Error::Truncated);
+            }
+            contents
+                .string(public_key)
+                .string(&[seed, public_key].concat());
+        }
+        _ => return Err(agent_proto::Error::UnknownKeyType(key_type)),
+    }
+    contents.string(comment.as_bytes());
+    Ok(contents.into_inner())
+}
+
+/// PuTTY's public and private blobs from the agent protocol's encoding of a key.
+fn from_agent(contents: &[u8]) -> Result<(Vec<u8>, Vec<u8>), agent_proto::Error> {
+    let mut contents = Reader::new(contents);
+    let key_type = contents.text()?;
+    let mut public = Writer::new();
+    let mut private = Writer::new();
+    public.string(key_type.as_bytes());
+    match key_type.as_str() {
+        "ssh-rsa" => {
+            let [n, e, d, iqmp, p, q] = read_strings(&mut contents)?;
+            public.string(e).string(n);
+            private.string(d).string(p).string(q).string(iqmp);
+        }
+        "ssh-dss" => {
+            let [p, q, g, y, x] = read_strings(&mut contents)?;
+            public.string(p).string(q).string(g).string(y);
+            private.string(x);
+        }
+        "ecdsa-sha2-nistp256" | "ecdsa-sha2-nistp384" | "ecdsa-sha2-nistp521" => {
+            let [curve, point, scalar] = read_strings(&mut contents)?;
+            public.string(curve).string(point);
+            private.string(scalar);
+        }
+        "ssh-ed25519" => {
+            let [public_key, secret] = read_strings(&mut contents)?;
+            let seed = secret
+                .get(..ED25519_SEED_LEN)
+                .ok_or(agent_proto::Error::Truncated)?;
+            public.string(public_key);
+            private.string(seed);
+        }
+        _ => return Err(agent_proto::Error::UnknownKeyType(key_type)),
+    }
+    Ok((public.into_inner(), private.into_inner()))
+}
+
+/// The next `N` strings.
+fn read_strings<'a, const N: usize>(
+    reader: &mut Reader<'a>,
+) -> Result<[&'a [u8]; N], agent_proto::Error> {
+    let mut strings = [&[][..]; N];
+    for string in &mut strings {
+        *string = reader.string()?;
+    }
+    Ok(strings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyfile::tests::{check, key_file, no_passphrase, passphrase, PASSPHRASE};
+
+    /// The test `.ppk` files (written independently of this module, following PuTTY's manual),
+    /// the OpenSSH key file each was made from and its fingerprint.
+    const KEYS: [(&str, &str, &str); 4] = [
+        (
+            "ed25519.ppk",
+            "ed25519",
+            "SHA256:OaNUmgH932qohEK5UcyXCI0kXJY7bO3D0SfilYQ6yGo",
+        ),
+        (
+            "ecdsa-v2.ppk",
+            "ecdsa-aes256-ctr",
+            "SHA256:oL23wBJJgJ2B1R5Bb4D0gV8aHl005m7lmXoF+hjc4rw",
+        ),
+        (
+            "rsa-v2-aes256-cbc.ppk",
+            "rsa-aes256-cbc",
+            "SHA256:zQev8E2XYzsoysut8d/4gLXCiVmsheZ16VBPMCPWQQY",
+        ),
+        (
+            "ed25519-argon2id.ppk",
+            "ed25519-aes256-gcm",
+            "SHA256:JB0F0gvGk9FfUiuok2RmYSuhWpSZXpQUeNdrprMao9k",
+        ),
+    ];
+
+    fn load(name: &str) -> Result<PrivateKey, Error> {
+        let path = key_file(name);
+        parse(
+            &path,
+            &std::fs::read_to_string(&path).unwrap(),
+            passphrase(PASSPHRASE),
+        )
+    }
+
+    /// Check that `key`, read from the test file `ppk`, is the key it was made from.
+    fn check_against_openssh(key: &PrivateKey, ppk: &str) {
+        let (_, openssh, fingerprint) = KEYS.iter().find(|(name, ..)| *name == ppk).unwrap();
+        check(key, openssh, fingerprint);
+        let expected = crate::keyfile::load(&key_file(openssh), passphrase(PASSPHRASE)).unwrap();
+        assert_eq!(key.contents, expected.contents);
+    }
+
+    #[test]
+    fn parses_version_3() {
+        let path = key_file("ed25519.ppk");
+        let key = parse(
+            &path,
+            &std::fs::read_to_string(&path).unwrap(),
+            no_passphrase,
+        )
+        .unwrap();
+        check_against_openssh(&key, "ed25519.ppk");
+    }
+
+    #[test]
+    fn parses_version_2() {
+        let path = key_file("ecdsa-v2.ppk");
+        let key = parse(
+            &path,
+            &std::fs::read_to_string(&path).unwrap(),
+            no_passphrase,
+        )
+        .unwrap();
+        check_against_openssh(&key, "ecdsa-v2.ppk");
+    }
+
+    #[test]
+    fn decrypts_version_2() {
+        let key = load("rsa-v2-aes256-cbc.ppk").unwrap();
+        check_against_openssh(&key, "rsa-v2-aes256-cbc.ppk");
+    }
+
+    #[test]
+    fn decrypts_version_3() {
+        let key = load("ed25519-argon2id.ppk").unwrap();
+        check_against_openssh(&key, "ed25519-argon2id.ppk");
+    }
+
+    #[test]
+    fn rejects_a_wrong_passphrase() {
+        for name in ["rsa-v2-aes256-cbc.ppk", "ed25519-argon2id.ppk"] {
+            let path = key_file(name);
+            let text = std::fs::read_to_string(&path).unwrap();
+            let result = parse(&path, &text, passphrase("wrong horse"));
+            assert!(matches!(result, Err(Error::WrongPassphrase(_))), "{}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_a_modified_file() {
+        let path = key_file("ed25519.ppk");
+        let text = std::fs::read_to_string(&path)
+            .unwrap()
+            .replace("test@wsl-systemd", "someone@else");
+        let result = parse(&path, &text, no_passphrase);
+        assert!(matches!(result, Err(Error::CheckMismatch(_))));
+    }
+
+    #[test]
+    fn encodes_unencrypted_version_3() {
+        let path = key_file("ed25519");
+        let key = crate::keyfile::load(&path, no_passphrase).unwrap();
+        let expected = std::fs::read_to_string(key_file("ed25519.ppk")).unwrap();
+        assert_eq!(encode(&path, &key).unwrap(), expected);
+    }
+
+    #[test]
+    fn round_trips_through_ppk_format() {
+        for (name, ..) in KEYS {
+            let path = key_file(name);
+            let key = load(name).unwrap();
+            let text = encode(&path, &key).unwrap();
+            let decoded = parse(&path, &text, no_passphrase).unwrap();
+            assert_eq!(decoded.public_key, key.public_key);
+            assert_eq!(decoded.comment, key.comment);
+            assert_eq!(decoded.contents, key.contents);
+        }
+    }
+}
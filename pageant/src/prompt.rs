@@ -0,0 +1,67 @@
+//! Asking the Windows user for a passphrase, e.g. to decrypt a key file or lock the agent with.
+//!
+//! The passphrase is typed into a Windows credentials dialog rather than the terminal that ran
+//! us, which is usually inside WSL, so it's never echoed there or seen by anything between.
+
+use windows::core::HSTRING;
+use windows::Win32::Foundation::{ERROR_CANCELLED, HWND, NO_ERROR, WIN32_ERROR};
+use windows::Win32::Security::Credentials::{
+    CredUIPromptForCredentialsW, CREDUI_FLAGS_ALWAYS_SHOW_UI, CREDUI_FLAGS_DO_NOT_PERSIST,
+    CREDUI_FLAGS_GENERIC_CREDENTIALS, CREDUI_FLAGS_KEEP_USERNAME, CREDUI_INFOW,
+};
+
+/// The longest passphrase the dialog takes (`CREDUI_MAX_PASSWORD_LENGTH`), plus the terminator.
+const MAX_PASSPHRASE: usize = 256 + 1;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Cancelled by the user")]
+    Cancelled,
+    #[error("Failed to show the passphrase dialog")]
+    Dialog(#[source] windows::core::Error),
+    #[error("The passphrase isn't valid UTF-16")]
+    Encoding,
+}
+
+/// Ask for a passphrase, explaining what it's for with `message`.
+pub fn passphrase(message: &str) -> Result<Vec<u8>, Error> {
+    let message = HSTRING::from(message);
+    let caption = HSTRING::from("Pageant (WSL)");
+    let info = CREDUI_INFOW {
+        cbSize: std::mem::size_of::<CREDUI_INFOW>() as u32,
+        hwndParent: HWND(0),
+        pszMessageText: windows::core::PCWSTR(message.as_ptr()),
+        pszCaptionText: windows::core::PCWSTR(caption.as_ptr()),
+        ..Default::default()
+    };
+    // The dialog insists on a user name, so give it one it won't let the user change.
+    let mut user: Vec<u16> = "Pageant".encode_utf16().chain([0; 1]).collect();
+    let mut passphrase = [0u16; MAX_PASSPHRASE];
+    let result = unsafe {
+        CredUIPromptForCredentialsW(
+            Some(&info as *const CREDUI_INFOW),
+            &HSTRING::from("wsl-systemd-pageant"),
+            None,
+            0,
+            &mut user,
+            &mut passphrase,
+            None,
+            CREDUI_FLAGS_GENERIC_CREDENTIALS
+                | CREDUI_FLAGS_KEEP_USERNAME
+                | CREDUI_FLAGS_DO_NOT_PERSIST
+                | CREDUI_FLAGS_ALWAYS_SHOW_UI,
+        )
+    };
+    let result = match WIN32_ERROR(result) {
+        NO_ERROR => {
+            let len = passphrase.iter().position(|&c| c == 0).unwrap_or(0);
+            String::from_utf16(&passphrase[..len])
+                .map(String::into_bytes)
+                .map_err(|_| Error::Encoding)
+        }
+        ERROR_CANCELLED => Err(Error::Cancelled),
+        error => Err(Error::Dialog(error.into())),
+    };
+    passphrase.fill(0);
+    result
+}
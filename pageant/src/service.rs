@@ -0,0 +1,171 @@
+//! Serving agent connections on a named pipe from a long-lived background process.
+//!
+//! Spawning pageant.exe through interop for every connection is slow, and ties it to the WSL
+//! session that spawned it. `pageant.exe --service` instead detaches from its console and serves
+//! each client of a named pipe on its own thread, exactly as it would serve stdin/stdout. WSL
+//! reaches the pipe through wsl-systemd (a `named-pipe` target behind `wsl-systemd.exe hyperv`,
+//! say), and Windows programs can use it directly. To have it outlive WSL, start it at logon:
+//!
+//! ```text
+//! schtasks /create /sc onlogon /tn pageant-wsl /tr "C:\Users\me\bin\pageant.exe --service"
+//! ```
+//!
+//! Only one process serves a given pipe, so starting it again (e.g. as a `launch` command) just
+//! exits. It also exits if accepting connections keeps failing.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use windows::core::HSTRING;
+use windows::Win32::Foundation::{
+    ERROR_ACCESS_DENIED, ERROR_BROKEN_PIPE, ERROR_PIPE_CONNECTED, HANDLE, INVALID_HANDLE_VALUE,
+};
+use windows::Win32::Storage::FileSystem::{
+    FlushFileBuffers, ReadFile, WriteFile, FILE_FLAG_FIRST_PIPE_INSTANCE, PIPE_ACCESS_DUPLEX,
+};
+use windows::Win32::System::Pipes::{
+    ConnectNamedPipe, CreateNamedPipeW, DisconnectNamedPipe, PIPE_REJECT_REMOTE_CLIENTS,
+    PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+};
+
+use crate::{Error, Result};
+
+/// Source of the IDs that tell clients apart in the log.
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// How many times in a row accepting a connection can fail before giving up on the pipe.
+const MAX_ACCEPT_FAILURES: u32 = 5;
+
+/// The pipe served if `--pipe` isn't given.
+pub const DEFAULT_PIPE: &str = r"\\.\pipe\wsl-systemd-pageant";
+
+/// Serve connections to `pipe` until the process is killed, each with `serve` (given the ID it's
+/// logged with).
+pub fn run<F>(pipe: &str, serve: F) -> Result<()>
+where
+    F: Fn(&Pipe, u64) -> std::io::Result<()> + Sync,
+{
+    let mut instance = match create_instance(pipe, true) {
+        Ok(instance) => instance,
+        Err(e) if e.code() == ERROR_ACCESS_DENIED.to_hresult() => {
+            tracing::info!(pipe, "Another pageant.exe is already serving the pipe");
+            return Ok(());
+        }
+        Err(e) => return Err(Error::Pipe(pipe.to_owned(), e)),
+    };
+    tracing::info!(pipe, "Listening");
+
+    // Nothing else needs the console, and leaving it means closing it doesn't end the service.
+    if let Err(e) = unsafe { windows::Win32::System::Console::FreeConsole() } {
+        tracing::debug!(error = %e, "Failed to detach from the console");
+    }
+
+    let mut failures = 0;
+    std::thread::scope(|scope| loop {
+        match unsafe { ConnectNamedPipe(instance.0, None) } {
+            Ok(()) => {}
+            // The client connected between creating the instance and waiting for it.
+            Err(e) if e.code() == ERROR_PIPE_CONNECTED.to_hresult() => {}
+            Err(e) => {
+                failures += 1;
+                if failures == MAX_ACCEPT_FAILURES {
+                    return Err(Error::Pipe(pipe.to_owned(), e));
+                }
+                tracing::error!(error = %e, failures, "Failed to accept a connection");
+                // Waiting on the same instance again would likely fail the same way, so replace
+                // it, and pause in case the failure isn't the instance's.
+                std::thread::sleep(Duration::from_millis(100) * 2u32.pow(failures - 1));
+                instance =
+                    create_instance(pipe, false).map_err(|e| Error::Pipe(pipe.to_owned(), e))?;
+                continue;
+            }
+        }
+        failures = 0;
+        // Have the next client connect to a fresh instance while this one's served.
+        let next = create_instance(pipe, false).map_err(|e| Error::Pipe(pipe.to_owned(), e))?;
+        let client = Pipe(std::mem::replace(&mut instance, next));
+        let serve = &serve;
+        let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        scope.spawn(move || {
+            let _span = tracing::info_span!("connection", id).entered();
+            tracing::debug!("Accepted a connection");
+            if let Err(e) = serve(&client, id) {
+                tracing::error!(error = %e, "Failed to read request");
+            }
+            let _ = unsafe { DisconnectNamedPipe(client.handle()) };
+            tracing::debug!("Connection closed");
+        });
+    })
+}
+
+fn create_instance(pipe: &str, first: bool) -> windows::core::Result<DroppableHandle> {
+    let mut open_mode = PIPE_ACCESS_DUPLEX;
+    if first {
+        // Fail, rather than share the pipe, if another process has already created it.
+        open_mode |= FILE_FLAG_FIRST_PIPE_INSTANCE;
+    }
+    let handle = unsafe {
+        CreateNamedPipeW(
+            &HSTRING::from(pipe),
+            open_mode,
+            PIPE_TYPE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+            PIPE_UNLIMITED_INSTANCES,
+            crate::AGENT_MAX_MSGLEN as u32,
+            crate::AGENT_MAX_MSGLEN as u32,
+            0,
+            None,
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(windows::core::Error::from_win32());
+    }
+    Ok(DroppableHandle(handle))
+}
+
+struct DroppableHandle(HANDLE);
+
+impl std::ops::Drop for DroppableHandle {
+    fn drop(&mut self) {
+        if !self.0.is_invalid() {
+            tracing::trace!("Closing {:?}", self.0);
+            unsafe {
+                windows::Win32::Foundation::CloseHandle(self.0).expect("can close valid handles");
+            }
+        }
+    }
+}
+
+/// A connected instance of the named pipe.
+pub struct Pipe(DroppableHandle);
+
+impl Pipe {
+    fn handle(&self) -> HANDLE {
+        self.0 .0
+    }
+}
+
+// Like `File`, reading and writing don't need exclusive access.
+impl std::io::Read for &Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut read = 0;
+        match unsafe { ReadFile(self.handle(), Some(buf), Some(&mut read), None) } {
+            Ok(()) => Ok(read as usize),
+            // The client has closed its end.
+            Err(e) if e.code() == ERROR_BROKEN_PIPE.to_hresult() => Ok(0),
+            Err(e) => Err(std::io::Error::other(e)),
+        }
+    }
+}
+
+impl std::io::Write for &Pipe {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut written = 0;
+        unsafe { WriteFile(self.handle(), Some(buf), Some(&mut written), None) }
+            .map_err(std::io::Error::other)?;
+        Ok(written as usize)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        unsafe { FlushFileBuffers(self.handle()) }.map_err(std::io::Error::other)
+    }
+}
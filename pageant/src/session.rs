@@ -0,0 +1,182 @@
+//! Keeping track of what a client says about where it's using the agent.
+//!
+//! OpenSSH 8.9+ binds each agent connection to the SSH session(s) it's used for, with the
+//! `session-bind@openssh.com` extension, and `ssh-add -h` can restrict keys to particular
+//! destinations. Pageant implements neither, so requests are still forwarded as they are, but
+//! they're logged here and `--require-session-bind` can refuse signatures on connections that
+//! haven't been bound. As in OpenSSH's agent, a binding only counts if it's signed by the host
+//! key it names, so a client can't make one up.
+
+use agent_proto::constraint::Constraint;
+use agent_proto::extension::{SessionBind, SESSION_BIND};
+use agent_proto::Request;
+use signature::Verifier as _;
+
+#[derive(thiserror::Error, Debug)]
+enum Error {
+    #[error("Malformed host key or signature")]
+    Malformed(#[from] ssh_key::Error),
+    #[error("The host key's signature over the session ID doesn't verify")]
+    BadSignature(#[from] signature::Error),
+}
+
+/// What's known about one client connection.
+#[derive(Default)]
+pub struct Session {
+    /// Fingerprints of the host keys of the sessions the connection has been bound to.
+    hosts: Vec<String>,
+}
+
+impl Session {
+    /// Whether the connection has been bound to at least one SSH session.
+    pub fn is_bound(&self) -> bool {
+        !self.hosts.is_empty()
+    }
+
+    /// Log `request` if it says anything about where keys are being used, and remember any
+    /// session binding.
+    pub fn observe(&mut self, request: &Request) {
+        match request {
+            Request::Extension { name, contents } if name == SESSION_BIND => {
+                let bind = match SessionBind::parse(contents) {
+                    Ok(bind) => bind,
+                    Err(e) => return tracing::warn!(error = %e, "Malformed session-bind request"),
+                };
+                let host_key = agent_proto::fingerprint(&bind.host_key);
+                if let Err(e) = verify(&bind) {
+                    return tracing::warn!(%host_key, error = %e, "Ignoring session-bind request");
+                }
+                tracing::info!(
+                    %host_key,
+                    forwarding = bind.forwarding,
+                    hops = self.hosts.len() + 1,
+                    "Connection bound to an SSH session"
+                );
+                self.hosts.push(host_key);
+            }
+            Request::Extension { name, .. } => tracing::debug!(%name, "Extension request"),
+            Request::AddIdentity {
+                constrained: true,
+                contents,
+            } => match agent_proto::constraint::parse(contents) {
+                Ok((key, constraints)) => log_constraints(&key.comment, &constraints),
+                Err(e) => tracing::warn!(error = %e, "Malformed key constraints"),
+            },
+            _ => {}
+        }
+    }
+}
+
+/// Check that the binding was signed by the host key it names, as the server's signature over the
+/// session ID it shares with the client.
+fn verify(bind: &SessionBind) -> Result<(), Error> {
+    let host_key = ssh_key::PublicKey::from_bytes(&bind.host_key)?;
+    let signature = ssh_key::Signature::try_from(&bind.signature[..])?;
+    host_key.key_data().verify(&bind.session_id, &signature)?;
+    Ok(())
+}
+
+fn log_constraints(comment: &str, constraints: &[Constraint]) {
+    for constraint in constraints {
+        match constraint {
+            Constraint::Lifetime(seconds) => {
+                tracing::info!(%comment, seconds, "Adding a key with a lifetime")
+            }
+            Constraint::Confirm => {
+                tracing::info!(%comment, "Adding a key that needs confirmation")
+            }
+            Constraint::RestrictDestination(destinations) => {
+                let destinations: Vec<_> = destinations.iter().map(ToString::to_string).collect();
+                tracing::info!(
+                    %comment,
+                    destinations = %destinations.join(","),
+                    "Adding a key restricted to destinations"
+                );
+            }
+            Constraint::Extension { name, .. } => {
+                tracing::info!(%comment, %name, "Adding a key with a constraint extension")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine as _;
+
+    use super::*;
+
+    /// A session ID, and host keys with their signatures over it.
+    const SESSION_ID: &str = "e94DthdLBqwi2QKtpYfgL0axXoLpb8w02VG4bv4GQvg=";
+    const ED25519_KEY: &str =
+        "AAAAC3NzaC1lZDI1NTE5AAAAINCU1GsKvrWXMLszI/VQuganOe1UN3vf3QNeOaecGTA7";
+    const ED25519_SIGNATURE: &str = "AAAAC3NzaC1lZDI1NTE5AAAAQCBX8qhi24OyvXGelzqVZoU/m3044JMKTTExahRcO/rvT4Z1rr8nZ4/vnkMvySNRw98pJK5B5raYsTIPNRy5aQ0=";
+    const ECDSA_KEY: &str = "AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBLWBdAUeHyx4MB4FDqnJe5mQ+kjN0eyXuhyFAkwvMWPudMTKbrQ6g+JGacQJr8lP6ulRdrRd6mU+bH4UQmhfQqs=";
+    const ECDSA_SIGNATURE: &str = "AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAABKAAAAIQDRAoDoYcFGUBGqucq33J6VUxR3Vma73QJHVo+QySEnsQAAACEAsPsJOemDJJ2j61zZTpusya3a5NaAFwQleBu50M24/SQ=";
+
+    fn decode(base64: &str) -> Vec<u8> {
+        base64::engine::general_purpose::STANDARD
+            .decode(base64)
+            .unwrap()
+    }
+
+    fn bind(host_key: &str, signature: &str) -> SessionBind {
+        SessionBind {
+            host_key: decode(host_key),
+            session_id: decode(SESSION_ID),
+            signature: decode(signature),
+            forwarding: false,
+        }
+    }
+
+    fn observe(bind: &SessionBind) -> Session {
+        let mut session = Session::default();
+        session.observe(&Request::Extension {
+            name: SESSION_BIND.to_owned(),
+            contents: bind.encode(),
+        });
+        session
+    }
+
+    #[test]
+    fn starts_unbound() {
+        assert!(!Session::default().is_bound());
+    }
+
+    #[test]
+    fn is_bound_by_a_signed_binding() {
+        for bind in [
+            bind(ED25519_KEY, ED25519_SIGNATURE),
+            bind(ECDSA_KEY, ECDSA_SIGNATURE),
+        ] {
+            assert!(observe(&bind).is_bound());
+        }
+    }
+
+    #[test]
+    fn ignores_a_binding_signed_by_another_key() {
+        assert!(!observe(&bind(ED25519_KEY, ECDSA_SIGNATURE)).is_bound());
+        assert!(!observe(&bind(ECDSA_KEY, ED25519_SIGNATURE)).is_bound());
+    }
+
+    #[test]
+    fn ignores_a_binding_to_another_session() {
+        let mut bind = bind(ED25519_KEY, ED25519_SIGNATURE);
+        bind.session_id[0] ^= 1;
+        assert!(!observe(&bind).is_bound());
+    }
+
+    #[test]
+    fn ignores_a_malformed_binding() {
+        let mut bind = bind(ED25519_KEY, ED25519_SIGNATURE);
+        bind.signature.truncate(20);
+        assert!(!observe(&bind).is_bound());
+
+        let mut session = Session::default();
+        session.observe(&Request::Extension {
+            name: SESSION_BIND.to_owned(),
+            contents: b"\0\0\0\x05short".to_vec(),
+        });
+        assert!(!session.is_bound());
+    }
+}
@@ -0,0 +1,147 @@
+//! A stand-in for PuTTY's Pageant, for testing pageant.exe without a PuTTY install.
+//!
+//! It's a hidden window of class and title `Pageant`, owned by the test process, that answers
+//! `WM_COPYDATA` requests through the named file mapping exactly as Pageant does, holding the
+//! keys in [`identities`]. Sign requests are "signed" by echoing the data back, so tests can tell
+//! which request was answered.
+
+use std::sync::OnceLock;
+
+use agent_proto::{Identity, Request, Response};
+use windows::core::{s, PCSTR};
+use windows::Win32::Foundation::{CloseHandle, HWND, LPARAM, LRESULT, WPARAM};
+use windows::Win32::System::DataExchange::COPYDATASTRUCT;
+use windows::Win32::System::Memory::{
+    MapViewOfFile, OpenFileMappingA, UnmapViewOfFile, FILE_MAP_ALL_ACCESS,
+};
+use windows::Win32::UI::WindowsAndMessaging::{
+    CreateWindowExA, DefWindowProcA, DispatchMessageA, GetMessageA, RegisterClassA, MSG,
+    WINDOW_EX_STYLE, WM_COPYDATA, WNDCLASSA, WS_OVERLAPPEDWINDOW,
+};
+
+/// The `dwData` Pageant expects on its `WM_COPYDATA` requests.
+const AGENT_COPYDATA_ID: usize = 0x804e50ba;
+
+/// The size of the file mapping pageant.exe creates.
+const AGENT_MAX_MSGLEN: usize = 8192;
+
+/// The keys the mock Pageant holds.
+pub fn identities() -> Vec<Identity> {
+    ["work-laptop", "personal"]
+        .into_iter()
+        .enumerate()
+        .map(|(i, comment)| {
+            let mut key_blob = agent_proto::wire::Writer::new();
+            key_blob.string(b"ssh-ed25519").string(&[i as u8 + 1; 32]);
+            Identity {
+                key_blob: key_blob.into_inner(),
+                comment: comment.to_owned(),
+            }
+        })
+        .collect()
+}
+
+/// The signature the mock Pageant produces for `data`.
+pub fn signature(data: &[u8]) -> Vec<u8> {
+    let mut signature = agent_proto::wire::Writer::new();
+    signature.string(b"ssh-ed25519").string(data);
+    signature.into_inner()
+}
+
+/// Make sure the mock Pageant's window exists, creating it (once per test process) if not.
+pub fn start() {
+    static STARTED: OnceLock<()> = OnceLock::new();
+    STARTED.get_or_init(|| {
+        let (created, ready) = std::sync::mpsc::channel();
+        std::thread::spawn(move || unsafe {
+            let instance = windows::Win32::System::LibraryLoader::GetModuleHandleA(None)
+                .expect("can get the module handle");
+            let class = WNDCLASSA {
+                lpfnWndProc: Some(window_proc),
+                hInstance: instance.into(),
+                lpszClassName: s!("Pageant"),
+                ..Default::default()
+            };
+            assert_ne!(RegisterClassA(&class), 0, "can register the window class");
+            let window = CreateWindowExA(
+                WINDOW_EX_STYLE::default(),
+                s!("Pageant"),
+                s!("Pageant"),
+                WS_OVERLAPPEDWINDOW,
+                0,
+                0,
+                0,
+                0,
+                None,
+                None,
+                instance,
+                None,
+            );
+            assert_ne!(window.0, 0, "can create the window");
+            created.send(()).unwrap();
+
+            // The window must be pumped by the thread that created it.
+            let mut message = MSG::default();
+            while GetMessageA(&mut message, None, 0, 0).as_bool() {
+                DispatchMessageA(&message);
+            }
+        });
+        ready.recv().expect("mock Pageant started");
+    });
+}
+
+unsafe extern "system" fn window_proc(
+    window: HWND,
+    message: u32,
+    wparam: WPARAM,
+    lparam: LPARAM,
+) -> LRESULT {
+    if message != WM_COPYDATA {
+        return DefWindowProcA(window, message, wparam, lparam);
+    }
+    let copy_data = &*(lparam.0 as *const COPYDATASTRUCT);
+    if copy_data.dwData != AGENT_COPYDATA_ID {
+        return LRESULT(0);
+    }
+    LRESULT(answer(PCSTR(copy_data.lpData.cast())) as isize)
+}
+
+/// Answer the request in the file mapping called `name`, returning whether it was understood.
+unsafe fn answer(name: PCSTR) -> bool {
+    let Ok(mapping) = OpenFileMappingA(FILE_MAP_ALL_ACCESS.0, false, name) else {
+        return false;
+    };
+    let view = MapViewOfFile(mapping, FILE_MAP_ALL_ACCESS, 0, 0, 0);
+    let answered = !view.Value.is_null() && {
+        let shm = std::slice::from_raw_parts_mut(view.Value.cast::<u8>(), AGENT_MAX_MSGLEN);
+        match agent_proto::read_frame(&mut &shm[..], AGENT_MAX_MSGLEN - 4) {
+            Ok(Some(agent_proto::Frame::Message(request))) => {
+                let response = agent_proto::frame::frame(&respond(&request).encode());
+                shm[..response.len()].copy_from_slice(&response);
+                true
+            }
+            _ => false,
+        }
+    };
+    if !view.Value.is_null() {
+        let _ = UnmapViewOfFile(view);
+    }
+    let _ = CloseHandle(mapping);
+    answered
+}
+
+fn respond(request: &[u8]) -> Response {
+    match Request::parse(request) {
+        Ok(Request::RequestIdentities) => Response::IdentitiesAnswer(identities()),
+        Ok(Request::SignRequest { key_blob, data, .. })
+            if identities()
+                .iter()
+                .any(|identity| identity.key_blob == key_blob) =>
+        {
+            Response::SignResponse {
+                signature: signature(&data),
+            }
+        }
+        _ => Response::Failure,
+    }
+}
@@ -0,0 +1,125 @@
+//! The full request/response path of pageant.exe, against the mock Pageant.
+
+#![cfg(windows)]
+
+mod mock_pageant;
+
+use std::io::Write as _;
+
+use agent_proto::{Request, Response};
+
+/// Run pageant.exe with `args`, send it `requests` and return its responses.
+fn session(args: &[&str], requests: &[Vec<u8>]) -> Vec<Response> {
+    mock_pageant::start();
+    let mut pageant = std::process::Command::new(env!("CARGO_BIN_EXE_pageant"))
+        .args(args)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .expect("can run pageant.exe");
+    let mut stdin = pageant.stdin.take().unwrap();
+    for request in requests {
+        stdin
+            .write_all(&agent_proto::frame::frame(request))
+            .unwrap();
+    }
+    drop(stdin);
+
+    let output = pageant.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "pageant.exe failed: {}",
+        output.status
+    );
+    let mut stdout = output.stdout.as_slice();
+    let mut responses = Vec::new();
+    while let Some(frame) = agent_proto::read_frame(&mut stdout, usize::MAX).unwrap() {
+        let agent_proto::Frame::Message(body) = frame else {
+            unreachable!("no size limit")
+        };
+        responses.push(Response::parse(&body).unwrap());
+    }
+    responses
+}
+
+fn sign(key_blob: &[u8], data: &[u8]) -> Vec<u8> {
+    Request::SignRequest {
+        key_blob: key_blob.to_vec(),
+        data: data.to_vec(),
+        flags: 0,
+    }
+    .encode()
+}
+
+#[test]
+fn lists_identities() {
+    let responses = session(&[], &[Request::RequestIdentities.encode()]);
+    assert_eq!(
+        responses,
+        [Response::IdentitiesAnswer(mock_pageant::identities())]
+    );
+}
+
+#[test]
+fn forwards_sign_requests() {
+    let key = &mock_pageant::identities()[0];
+    let responses = session(
+        &[],
+        &[
+            sign(&key.key_blob, b"first"),
+            sign(&key.key_blob, b"second"),
+        ],
+    );
+    assert_eq!(
+        responses,
+        [
+            Response::SignResponse {
+                signature: mock_pageant::signature(b"first")
+            },
+            Response::SignResponse {
+                signature: mock_pageant::signature(b"second")
+            },
+        ]
+    );
+}
+
+#[test]
+fn passes_on_failures() {
+    let responses = session(&[], &[sign(b"not a key Pageant has", b"data")]);
+    assert_eq!(responses, [Response::Failure]);
+}
+
+#[test]
+fn filters_keys() {
+    let identities = mock_pageant::identities();
+    let responses = session(
+        &["--deny-comment", "person*"],
+        &[
+            Request::RequestIdentities.encode(),
+            sign(&identities[1].key_blob, b"hidden"),
+            sign(&identities[0].key_blob, b"visible"),
+        ],
+    );
+    assert_eq!(
+        responses,
+        [
+            Response::IdentitiesAnswer(identities[..1].to_vec()),
+            Response::Failure,
+            Response::SignResponse {
+                signature: mock_pageant::signature(b"visible")
+            },
+        ]
+    );
+}
+
+#[test]
+fn survives_oversized_requests() {
+    let responses = session(&[], &[vec![0; 10_000], Request::RequestIdentities.encode()]);
+    assert_eq!(
+        responses,
+        [
+            Response::Failure,
+            Response::IdentitiesAnswer(mock_pageant::identities())
+        ]
+    );
+}
@@ -0,0 +1,57 @@
+//! Listening sockets passed in by systemd's socket activation.
+//!
+//! A `.socket` unit with `Accept = No` listens on a bridge's `listen` path and starts
+//! `wsl-systemd daemon` when the first client connects, handing it the listening socket (see
+//! `sd_listen_fds(3)`). The daemon then serves that socket rather than binding its own, matching
+//! sockets to bridges by path. Together with `--exit-idle-time` this lets the daemon (and any
+//! helpers it keeps running) go away when it's not needed, and come back on demand.
+
+use std::os::fd::{FromRawFd as _, RawFd};
+use std::path::PathBuf;
+
+/// The first file descriptor passed by systemd, the rest follow in order.
+const FIRST_FD: RawFd = 3;
+
+/// Take the Unix sockets systemd passed to this process, with their paths. Anything else passed
+/// is closed.
+pub fn take() -> Vec<(PathBuf, std::os::unix::net::UnixListener)> {
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    // Not for any children.
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+    if !for_us {
+        return Vec::new();
+    }
+
+    (FIRST_FD..FIRST_FD + count)
+        .filter_map(|fd| {
+            // Safety: systemd passes these descriptors for this process to own.
+            let socket = unsafe { socket2::Socket::from_raw_fd(fd) };
+            let path = socket
+                .local_addr()
+                .ok()
+                .and_then(|addr| addr.as_pathname().map(ToOwned::to_owned));
+            match path {
+                Some(path) => {
+                    tracing::debug!(fd, path = %path.display(), "Received a socket from systemd");
+                    Some((path, socket.into()))
+                }
+                None => {
+                    tracing::warn!(
+                        fd,
+                        "Ignoring a socket from systemd that isn't a Unix socket"
+                    );
+                    None
+                }
+            }
+        })
+        .collect()
+}
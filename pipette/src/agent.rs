@@ -0,0 +1,84 @@
+//! Talking to an ssh-agent at the far end of a bridge, rather than relaying a client to it.
+//!
+//! This is how the subcommands that manage the agent (e.g. `list-keys`) work, and doubles as a
+//! check that a bridge is actually reaching an agent.
+
+use std::time::Duration;
+
+use agent_proto::{Identity, MessageType, Request, Response};
+use bridge_core::endpoint::{Endpoint, Stream};
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+use crate::config::{Launch, Target};
+
+/// How long to wait for the agent to answer. Long enough for it to ask for a passphrase or
+/// confirmation.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The longest response that will be accepted (OpenSSH's `AGENT_MAX_LEN`).
+const MAX_RESPONSE_LEN: usize = 256 * 1024;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Failed to talk to the agent")]
+    IO(#[from] std::io::Error),
+    #[error("The bridge's target is gpg-agent's Assuan socket, not an ssh-agent")]
+    NotSshAgent,
+    #[error("The agent didn't answer within {0:?}")]
+    Timeout(Duration),
+    #[error("The agent sent an implausibly long ({0} byte) response")]
+    TooLong(usize),
+    #[error("The agent sent a malformed response")]
+    Malformed(#[source] agent_proto::Error),
+    #[error("The agent refused the request")]
+    Failure,
+    #[error("The agent sent an unexpected {0} response")]
+    Unexpected(MessageType),
+}
+
+/// A connection to an ssh-agent.
+pub struct Client {
+    stream: Box<dyn Stream>,
+}
+
+impl Client {
+    /// Connect to the agent behind `target`, launching it if needed.
+    pub async fn connect(target: &Target, launch: Option<&Launch>) -> Result<Self, crate::Error> {
+        match crate::endpoint::connect_or_launch(target, launch).await? {
+            Endpoint::Stream(stream) => Ok(Self { stream }),
+            Endpoint::Assuan(_) => Err(Error::NotSshAgent.into()),
+        }
+    }
+
+    /// Send `request` and wait for the agent's response.
+    pub async fn request(&mut self, request: &Request) -> Result<Response, Error> {
+        tracing::debug!(message_type = %request.message_type(), "Sending request");
+        let body = agent_proto::frame::frame(&request.encode());
+        self.stream.write_all(&body).await?;
+        let body = tokio::time::timeout(REQUEST_TIMEOUT, self.read_response())
+            .await
+            .map_err(|_| Error::Timeout(REQUEST_TIMEOUT))??;
+        let response = Response::parse(&body).map_err(Error::Malformed)?;
+        tracing::debug!(message_type = %response.message_type(), "Received response");
+        Ok(response)
+    }
+
+    async fn read_response(&mut self) -> Result<Vec<u8>, Error> {
+        let len = self.stream.read_u32().await? as usize;
+        if len > MAX_RESPONSE_LEN {
+            return Err(Error::TooLong(len));
+        }
+        let mut body = vec![0; len];
+        self.stream.read_exact(&mut body).await?;
+        Ok(body)
+    }
+
+    /// The keys the agent holds.
+    pub async fn identities(&mut self) -> Result<Vec<Identity>, Error> {
+        match self.request(&Request::RequestIdentities).await? {
+            Response::IdentitiesAnswer(identities) => Ok(identities),
+            Response::Failure => Err(Error::Failure),
+            response => Err(Error::Unexpected(response.message_type())),
+        }
+    }
+}
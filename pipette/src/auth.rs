@@ -0,0 +1,214 @@
+//! Mutual authentication of the two halves of a bridge over TCP, with a pre-shared key.
+//!
+//! A `tcp` target is usually a localhost port that anything on the Windows host can connect to
+//! (and, if the listener isn't running, listen on), so a `listen-tcp` socket requires both ends
+//! to prove they hold the same key before any bridged traffic flows:
+//!
+//! 1. The listener sends a random 32 byte nonce.
+//! 2. The connecting side replies with its own nonce and `HMAC-SHA256(key, "client" || both
+//!    nonces)`.
+//! 3. The listener checks that, and answers with `HMAC-SHA256(key, "server" || both nonces)`,
+//!    which the connecting side checks in turn.
+//!
+//! The traffic itself isn't encrypted, it's only ever meant to cross the host's own (virtual)
+//! network. Generate a key with e.g. `head -c 32 /dev/urandom | base64 > bridge.key` and make it
+//! readable only by you on both sides (or, inside WSL, pass it to the service as a systemd
+//! credential, see the `credentials` module).
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use hmac::{Hmac, Mac as _};
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+
+/// How long either side waits for the other to complete the handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Keys shorter than this are too easily guessed to be worth accepting.
+const MIN_KEY_LEN: usize = 16;
+
+const NONCE_LEN: usize = 32;
+const MAC_LEN: usize = 32;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Failed to read the key file {0}")]
+    IO(PathBuf, #[source] std::io::Error),
+    #[error("The key in {0} is too short (at least {MIN_KEY_LEN} bytes are needed)")]
+    TooShort(PathBuf),
+}
+
+/// A pre-shared key, as read from a key file.
+pub struct Key(Vec<u8>);
+
+impl Key {
+    /// Read the key from `path` (or the systemd credential it names), ignoring any trailing
+    /// whitespace (e.g. a final newline).
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let path = &*crate::credentials::resolve(path);
+        let mut key = std::fs::read(path).map_err(|e| Error::IO(path.to_owned(), e))?;
+        while key.last().is_some_and(u8::is_ascii_whitespace) {
+            key.pop();
+        }
+        if key.len() < MIN_KEY_LEN {
+            return Err(Error::TooShort(path.to_owned()));
+        }
+        Ok(Self(key))
+    }
+
+    fn mac(&self, role: &[u8], server_nonce: &[u8], client_nonce: &[u8]) -> Hmac<sha2::Sha256> {
+        let mut mac =
+            Hmac::<sha2::Sha256>::new_from_slice(&self.0).expect("HMAC accepts keys of any size");
+        mac.update(role);
+        mac.update(server_nonce);
+        mac.update(client_nonce);
+        mac
+    }
+}
+
+/// Authenticate a connection made to a `listen-tcp` socket.
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    sock: &mut S,
+    key: &Key,
+) -> std::io::Result<()> {
+    let server_nonce = nonce()?;
+    sock.write_all(&server_nonce).await?;
+
+    let mut client_nonce = [0; NONCE_LEN];
+    let mut client_mac = [0; MAC_LEN];
+    sock.read_exact(&mut client_nonce).await?;
+    sock.read_exact(&mut client_mac).await?;
+    key.mac(b"client", &server_nonce, &client_nonce)
+        .verify_slice(&client_mac)
+        .map_err(|_| rejected("the client used a different key"))?;
+
+    let server_mac = key.mac(b"server", &server_nonce, &client_nonce);
+    sock.write_all(&server_mac.finalize().into_bytes()).await?;
+    sock.flush().await
+}
+
+/// Authenticate a connection made to a `tcp` target with a `key-file`.
+pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+    sock: &mut S,
+    key: &Key,
+) -> std::io::Result<()> {
+    let mut server_nonce = [0; NONCE_LEN];
+    sock.read_exact(&mut server_nonce).await?;
+
+    let client_nonce = nonce()?;
+    let client_mac = key.mac(b"client", &server_nonce, &client_nonce);
+    let mut response = client_nonce.to_vec();
+    response.extend_from_slice(&client_mac.finalize().into_bytes());
+    sock.write_all(&response).await?;
+    sock.flush().await?;
+
+    let mut server_mac = [0; MAC_LEN];
+    sock.read_exact(&mut server_mac)
+        .await
+        // The listener just hangs up on a client it doesn't trust.
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => rejected("the listener refused the key"),
+            _ => e,
+        })?;
+    key.mac(b"server", &server_nonce, &client_nonce)
+        .verify_slice(&server_mac)
+        .map_err(|_| rejected("the listener used a different key"))
+}
+
+fn nonce() -> std::io::Result<[u8; NONCE_LEN]> {
+    let mut nonce = [0; NONCE_LEN];
+    getrandom::getrandom(&mut nonce).map_err(std::io::Error::from)?;
+    Ok(nonce)
+}
+
+fn rejected(reason: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::PermissionDenied,
+        format!("authentication failed: {}", reason),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(contents: &[u8]) -> Key {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bridge.key");
+        std::fs::write(&path, contents).unwrap();
+        Key::load(&path).unwrap()
+    }
+
+    /// The results of the two sides of a handshake, the listener's first.
+    async fn handshake(listener: &Key, client: &Key) -> (std::io::Result<()>, std::io::Result<()>) {
+        let (mut listening, mut connecting) = tokio::io::duplex(1024);
+        let accept = async {
+            let result = accept(&mut listening, listener).await;
+            // Hang up, as the listener does.
+            drop(listening);
+            result
+        };
+        tokio::join!(accept, connect(&mut connecting, client))
+    }
+
+    #[test]
+    fn loads_keys_without_trailing_whitespace() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bridge.key");
+        std::fs::write(&path, "0123456789abcdef\r\n").unwrap();
+        assert_eq!(Key::load(&path).unwrap().0, b"0123456789abcdef");
+
+        // Too short once the newline is gone.
+        std::fs::write(&path, "0123456789abcde\n").unwrap();
+        assert!(matches!(Key::load(&path), Err(Error::TooShort(_))));
+
+        let missing = dir.path().join("missing.key");
+        assert!(matches!(Key::load(&missing), Err(Error::IO(path, _)) if path == missing));
+    }
+
+    #[tokio::test]
+    async fn the_same_key_authenticates_both_sides() {
+        let (accepted, connected) =
+            handshake(&key(b"0123456789abcdef"), &key(b"0123456789abcdef")).await;
+        accepted.unwrap();
+        connected.unwrap();
+    }
+
+    #[tokio::test]
+    async fn different_keys_are_refused() {
+        let (accepted, connected) =
+            handshake(&key(b"0123456789abcdef"), &key(b"fedcba9876543210")).await;
+        assert_eq!(
+            accepted.unwrap_err().kind(),
+            std::io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            connected.unwrap_err().kind(),
+            std::io::ErrorKind::PermissionDenied
+        );
+    }
+
+    #[tokio::test]
+    async fn a_listener_with_a_different_key_is_refused() {
+        // A listener that doesn't check the client, e.g. one impersonating the real one.
+        let (mut listening, mut connecting) = tokio::io::duplex(1024);
+        let impostor = key(b"fedcba9876543210");
+        let listener = async {
+            let server_nonce = [0; NONCE_LEN];
+            listening.write_all(&server_nonce).await.unwrap();
+            let mut response = [0; NONCE_LEN + MAC_LEN];
+            listening.read_exact(&mut response).await.unwrap();
+            let client_nonce = &response[..NONCE_LEN];
+            let mac = impostor.mac(b"server", &server_nonce, client_nonce);
+            listening
+                .write_all(&mac.finalize().into_bytes())
+                .await
+                .unwrap();
+        };
+        let key = key(b"0123456789abcdef");
+        let ((), connected) = tokio::join!(listener, connect(&mut connecting, &key));
+        let e = connected.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(e.to_string().contains("the listener used a different key"));
+    }
+}
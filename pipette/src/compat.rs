@@ -0,0 +1,121 @@
+//! Standing in for the helpers wsl-systemd replaces, so that setups built around them keep working
+//! without rewriting their unit files and shell scripts.
+//!
+//! Put wsl-systemd in place of the helper, under its name (a copy, or inside WSL a symlink), and
+//! its arguments are understood:
+//!
+//! - `wsl2-ssh-pageant.exe`, run through interop as socat's `EXEC` target: relays stdin/stdout to
+//!   Pageant, to the gpg-agent socket file named by `--gpg` (e.g. `S.gpg-agent`) in
+//!   `--gpgConfigBasepath` or gpg-agent's socket directory, or to the OpenSSH agent's named pipe
+//!   `\\.\pipe\<--winssh>`. `--verbose` logs at debug level; `--logfile`, `--systray` and
+//!   `--force` are accepted but ignored. Flags may be spelt with one dash, as Go allows.
+//! - `ssh-pageant`, as in `eval $(ssh-pageant -r -a /tmp/.ssh-pageant-$USER)`: listens on the
+//!   socket in the background, relaying each connection to Pageant, and prints the commands
+//!   setting `SSH_AUTH_SOCK` and `SSH_PAGEANT_PID`. `-r` reuses a socket that's already being
+//!   served, `-d` stays in the foreground, `-k` stops the one in `SSH_PAGEANT_PID`.
+//!
+//! Both are subcommands too (`wsl-systemd wsl2-ssh-pageant --gpg S.gpg-agent`), for scripts where
+//! only the program's easy to change. Anything else that runs a program to talk to through its
+//! stdin/stdout (socat's `EXEC`, ssh's `ProxyCommand`) can run `agent`, `gpg`, `tcp` and so on.
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use crate::config::Target;
+
+/// The helpers, named as they're run and as the subcommands standing in for them.
+const HELPERS: &[&str] = &[
+    "wsl2-ssh-pageant",
+    #[cfg(unix)]
+    "ssh-pageant",
+];
+
+/// If we were run as one of the helpers (i.e. through a copy or symlink named after it), the
+/// command line for the subcommand standing in for it.
+pub fn args() -> Option<Vec<OsString>> {
+    let mut args = std::env::args_os();
+    let argv0 = args.next()?;
+    let name = Path::new(&argv0)
+        .file_name()?
+        .to_string_lossy()
+        .to_lowercase();
+    let &subcommand = HELPERS.iter().find(|helper| name.starts_with(*helper))?;
+    let rest = args.map(|arg| match subcommand {
+        "wsl2-ssh-pageant" => go_flag(arg),
+        _ => arg,
+    });
+    Some([argv0, subcommand.into()].into_iter().chain(rest).collect())
+}
+
+/// Go's flags can be given with a single dash (`-gpg`), which structopt would take as a cluster of
+/// short flags.
+fn go_flag(arg: OsString) -> OsString {
+    match arg.to_str() {
+        Some(flag) if flag.len() > 2 && flag.starts_with('-') && !flag.starts_with("--") => {
+            format!("-{}", flag).into()
+        }
+        _ => arg,
+    }
+}
+
+/// wsl2-ssh-pageant.exe's arguments.
+#[derive(structopt::StructOpt, Debug)]
+pub struct Wsl2SshPageant {
+    /// Relay to gpg-agent, through the socket file of this name (e.g. `S.gpg-agent` or
+    /// `S.gpg-agent.extra`)
+    #[structopt(long, value_name = "SOCKET")]
+    gpg: Option<String>,
+    /// The directory with `--gpg`'s socket file [default: gpg-agent's socket directory]
+    #[structopt(long = "gpgConfigBasepath", value_name = "DIR", parse(from_os_str))]
+    gpg_config_basepath: Option<PathBuf>,
+    /// Relay to the named pipe `\\.\pipe\<NAME>`, e.g. `openssh-ssh-agent`
+    #[structopt(long, value_name = "NAME", conflicts_with = "gpg")]
+    winssh: Option<String>,
+    /// Log at debug level
+    #[structopt(long)]
+    verbose: bool,
+    /// Ignored, wsl-systemd logs to stderr
+    #[structopt(long, value_name = "FILE")]
+    logfile: Option<String>,
+    /// Ignored
+    #[structopt(long)]
+    systray: bool,
+    /// Ignored
+    #[structopt(long)]
+    force: bool,
+}
+
+impl Wsl2SshPageant {
+    /// The log level `--verbose` asks for.
+    pub fn log_level(&self) -> Option<&'static str> {
+        self.verbose.then_some("debug")
+    }
+
+    /// What to relay stdin/stdout to.
+    pub fn target(&self) -> Target {
+        if self.logfile.is_some() || self.systray || self.force {
+            tracing::debug!("Ignoring --logfile, --systray and --force");
+        }
+        if let Some(socket) = &self.gpg {
+            let dir = match &self.gpg_config_basepath {
+                Some(dir) => dir.clone(),
+                None => crate::gnupg::agent_socket(None)
+                    .parent()
+                    .map(ToOwned::to_owned)
+                    .unwrap_or_default(),
+            };
+            return Target::Assuan {
+                path: dir.join(socket),
+            };
+        }
+        if let Some(pipe) = &self.winssh {
+            return Target::NamedPipe {
+                path: format!(r"\\.\pipe\{}", pipe).into(),
+            };
+        }
+        Target::Pageant {
+            program: None,
+            args: Vec::new(),
+        }
+    }
+}
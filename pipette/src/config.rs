@@ -0,0 +1,483 @@
+//! The bridge configuration file.
+//!
+//! By default this is read from `wsl-systemd/bridges.toml` under the platform's configuration
+//! directory (`~/.config` inside WSL, `%APPDATA%` on Windows) and looks like:
+//!
+//! ```toml
+//! log-level = "info"
+//! log-format = "json"
+//!
+//! [bridges.gpg-agent]
+//! listen = "/run/user/1000/gnupg/S.gpg-agent"
+//! target = { type = "assuan", path = 'C:\Users\me\AppData\Local\gnupg\S.gpg-agent' }
+//! launch = { program = "gpgconf", args = ["--launch", "gpg-agent"] }
+//! idle-timeout = 3600
+//! max-connections = 32
+//! rate-limit = 10
+//!
+//! [bridges.docker]
+//! listen = "/run/user/1000/docker.sock"
+//! target = { type = "named-pipe", path = '\\.\pipe\docker_engine' }
+//! buffer-size = 262144
+//!
+//! # In $XDG_RUNTIME_DIR/wsl-systemd, which is only accessible to the user.
+//! [bridges.ssh-agent]
+//! listen = "ssh-agent.sock"
+//! env = "SSH_AUTH_SOCK"
+//! target = { type = "pageant", args = ["--confirm"] }
+//!
+//! [bridges.language-server]
+//! target = { type = "tcp", address = "127.0.0.1:9257" }
+//!
+//! [bridges.keyring]
+//! listen = "/run/user/1000/keyring.sock"
+//! target = { type = "unix", path = 'C:\Users\me\AppData\Local\keyring\agent.sock' }
+//!
+//! # Served by `wsl-systemd.exe hyperv` on Windows, and reached from WSL2 by the bridge below.
+//! [bridges.openssh-agent]
+//! target = { type = "named-pipe", path = '\\.\pipe\openssh-ssh-agent' }
+//! hyperv = { port = 0x5000 }
+//!
+//! [bridges.openssh-agent-vsock]
+//! listen = "/run/user/1000/openssh-agent.sock"
+//! target = { type = "vsock", port = 0x5000 }
+//!
+//! # Served by `wsl-systemd.exe daemon` on Windows, to the bridge below in WSL, with the same key
+//! # file on both sides.
+//! [bridges.pageant]
+//! target = { type = "command", program = 'C:\Users\me\bin\pageant.exe' }
+//! listen-tcp = { address = "127.0.0.1:5222", key-file = 'C:\Users\me\bridge.key' }
+//!
+//! [bridges.pageant-tcp]
+//! listen = "/run/user/1000/pageant.sock"
+//! target = { type = "tcp", address = "127.0.0.1:5222", key-file = "/home/me/bridge.key" }
+//!
+//! # With the key passed to the daemon's unit as a systemd credential (see the `credentials`
+//! # module), e.g. `LoadCredential = bridge.key:/home/me/bridge.key`.
+//! [bridges.pageant-tcp-credential]
+//! listen = "/run/user/1000/pageant-credential.sock"
+//! target = { type = "tcp", address = "127.0.0.1:5222", key-file = "bridge.key" }
+//!
+//! # Reaches the `pageant` bridge of a single long-lived `wsl-systemd.exe mux`.
+//! [bridges.pageant-mux]
+//! listen = "/run/user/1000/pageant-mux.sock"
+//!
+//! [bridges.pageant-mux.target]
+//! type = "mux"
+//! program = "/mnt/c/Users/me/bin/wsl-systemd.exe"
+//! args = ["mux"]
+//! bridge = "pageant"
+//!
+//! # Served by `wsl-systemd.exe daemon` on Windows, relaying Windows applications' connections to
+//! # the `wsl-ssh-agent` bridge of `wsl-systemd mux` inside WSL (which could have a `unix` target).
+//! [bridges.wsl-ssh-agent]
+//! listen-pipe = '\\.\pipe\wsl-ssh-agent'
+//! listen-tcp = { address = "127.0.0.1:5223" }
+//!
+//! [bridges.wsl-ssh-agent.target]
+//! type = "mux"
+//! program = "wsl.exe"
+//! args = ["--exec", "wsl-systemd", "mux"]
+//! bridge = "wsl-ssh-agent"
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("An IO Error occurred reading the configuration file {0}")]
+    IO(PathBuf, #[source] std::io::Error),
+    #[error("Failed to parse the configuration file {0}")]
+    Parse(PathBuf, #[source] toml::de::Error),
+    #[cfg(unix)]
+    #[error("Bridge {0:?}'s `listen` path is relative, but XDG_RUNTIME_DIR isn't set")]
+    NoRuntimeDir(String),
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Log filter (in `RUST_LOG` syntax) used if neither `--log-level` nor `RUST_LOG` is set.
+    pub log_level: Option<String>,
+    /// Log output format used if `--log-format` isn't set.
+    pub log_format: Option<LogFormat>,
+    #[serde(default)]
+    pub bridges: BTreeMap<String, Bridge>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Bridge {
+    /// The Unix socket inside WSL that clients of this bridge connect to, in
+    /// `$XDG_RUNTIME_DIR/wsl-systemd` if it's relative (see the `runtime_dir` module).
+    pub listen: Option<PathBuf>,
+    /// The environment variable that `wsl-systemd env` points at `listen`, e.g. `SSH_AUTH_SOCK`.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub env: Option<String>,
+    /// A TCP socket that `wsl-systemd daemon` accepts connections on, authenticated if it has a key
+    /// (e.g. from a `tcp` target with the same key on the other side of WSL).
+    pub listen_tcp: Option<ListenTcp>,
+    /// A Windows named pipe that `wsl-systemd.exe daemon` accepts connections on from Windows
+    /// applications, e.g. to reach a service inside WSL through a `mux` target.
+    pub listen_pipe: Option<PathBuf>,
+    /// Where on the Windows side connections are forwarded to.
+    pub target: Target,
+    /// Size of the buffers used when relaying in each direction [default: 64 KiB].
+    pub buffer_size: Option<std::num::NonZeroUsize>,
+    /// Close connections that have carried no traffic for this many seconds.
+    pub idle_timeout: Option<u64>,
+    /// Serve at most this many connections at once when listening.
+    pub max_connections: Option<std::num::NonZeroUsize>,
+    /// Accept at most this many new connections per second from each client when listening.
+    pub rate_limit: Option<std::num::NonZeroU32>,
+    /// A program that starts the target (e.g. `pageant.exe`), run if it can't be reached.
+    pub launch: Option<Launch>,
+    /// The Hyper-V socket that `wsl-systemd.exe hyperv` accepts connections from WSL2 on.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub hyperv: Option<HyperV>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ListenTcp {
+    /// The address to listen on, e.g. `127.0.0.1:5222`.
+    pub address: String,
+    /// The pre-shared key that connecting clients must also have. Only loopback addresses may
+    /// leave it out, accepting connections from any local application.
+    pub key_file: Option<PathBuf>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+#[cfg_attr(not(windows), allow(dead_code))]
+pub struct HyperV {
+    /// The vsock port, i.e. the first part of the service ID.
+    pub port: u32,
+    /// The ID of the VM allowed to connect (e.g. from `hcsdiag list`) [default: any]
+    pub vm_id: Option<String>,
+}
+
+impl Bridge {
+    /// The limits on the connections a listening bridge accepts.
+    pub fn limits(&self) -> crate::limit::Limits {
+        crate::limit::Limits {
+            max_connections: self.max_connections,
+            rate_limit: self.rate_limit,
+        }
+    }
+
+    /// How connections to this bridge are relayed.
+    pub fn relay_options(&self) -> bridge_core::relay::Options {
+        let defaults = bridge_core::relay::Options::default();
+        bridge_core::relay::Options {
+            buffer_size: self
+                .buffer_size
+                .map_or(defaults.buffer_size, std::num::NonZeroUsize::get),
+            idle_timeout: self
+                .idle_timeout
+                .map(std::time::Duration::from_secs)
+                .or(defaults.idle_timeout),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, tag = "type", rename_all = "kebab-case")]
+pub enum Target {
+    /// A GnuPG Assuan socket file, containing the localhost port and nonce (or, if relative, the
+    /// systemd credential of that name).
+    Assuan { path: PathBuf },
+    /// A Windows named pipe, e.g. `\\.\pipe\docker_engine`.
+    NamedPipe { path: PathBuf },
+    /// A TCP socket, authenticated with a pre-shared key if it's a bridge's `listen-tcp` socket.
+    Tcp {
+        address: String,
+        /// The key shared with the `listen-tcp` socket at `address` (see the `auth` module), or if
+        /// relative, the systemd credential of that name
+        #[serde(rename = "key-file")]
+        key_file: Option<PathBuf>,
+    },
+    /// A Unix domain socket, which Windows (10 1803 onwards) supports too.
+    Unix { path: PathBuf },
+    /// A Hyper-V socket on the Windows host (`wsl-systemd.exe hyperv`), reached over `AF_VSOCK`
+    /// from inside a WSL2 VM.
+    Vsock {
+        port: u32,
+        /// The CID to connect to [default: the host]
+        cid: Option<u32>,
+    },
+    /// A helper program (e.g. `pageant.exe`, launched through interop) speaking the protocol on
+    /// its stdin/stdout.
+    Command {
+        program: PathBuf,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    /// This project's `pageant.exe`, found automatically unless `program` is given and restarted
+    /// if it dies (see the `pageant` module).
+    Pageant {
+        program: Option<PathBuf>,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    /// A Windows pinentry, found automatically unless `program` is given, for gpg-agent's
+    /// passphrase prompts (see the `pinentry` module).
+    Pinentry {
+        program: Option<PathBuf>,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    /// A bridge on the other side of WSL, reached through a shared `wsl-systemd.exe mux` helper
+    /// (see the `mux` module).
+    Mux {
+        program: PathBuf,
+        #[serde(default)]
+        args: Vec<String>,
+        /// The name of the bridge in the helper's configuration file.
+        bridge: String,
+    },
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Launch {
+    pub program: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+impl Launch {
+    /// `gpgconf --launch gpg-agent`, which starts the agent (for `homedir`, if given) if it
+    /// isn't already running.
+    pub fn gpg_agent(homedir: Option<&Path>) -> Self {
+        let mut args: Vec<String> = crate::gnupg::homedir_args(homedir)
+            .into_iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        args.extend(["--launch".to_owned(), "gpg-agent".to_owned()]);
+        Self {
+            program: crate::gnupg::gpgconf(),
+            args,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    /// Human readable lines.
+    #[default]
+    Text,
+    /// One JSON object per event, for feeding into journald/ELK.
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "unknown log format {:?} (expected text or json)",
+                s
+            )),
+        }
+    }
+}
+
+impl Config {
+    /// The configuration file used when none is specified on the command line.
+    pub fn default_path() -> Option<PathBuf> {
+        let dirs = directories::BaseDirs::new()?;
+        Some(dirs.config_dir().join("wsl-systemd").join("bridges.toml"))
+    }
+
+    /// Load the configuration from `path`, or from the default location if `None`.
+    ///
+    /// It's not an error for the default configuration file to be missing, but an explicitly
+    /// requested one must exist.
+    pub fn load(path: Option<&Path>) -> Result<Self, Error> {
+        let (path, required) = match path {
+            Some(path) => (path.to_owned(), true),
+            None => match Self::default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Self::default()),
+            },
+        };
+
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default())
+            }
+            Err(e) => return Err(Error::IO(path, e)),
+        };
+
+        let mut config: Self = toml::from_str(&contents).map_err(|e| Error::Parse(path, e))?;
+        #[cfg(unix)]
+        for (name, bridge) in &mut config.bridges {
+            if let Some(listen) = &mut bridge.listen {
+                *listen = crate::runtime_dir::resolve(listen)
+                    .ok_or_else(|| Error::NoRuntimeDir(name.clone()))?;
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Load `contents` as a configuration file.
+    fn load(contents: &str) -> Result<Config, Error> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bridges.toml");
+        std::fs::write(&path, contents).unwrap();
+        Config::load(Some(&path))
+    }
+
+    #[test]
+    fn reads_bridges() {
+        let config = load(
+            r#"
+            log-level = "debug"
+            log-format = "json"
+
+            [bridges.ssh-agent]
+            listen = "/run/user/1000/ssh-agent.sock"
+            target = { type = "pageant", args = ["--confirm"] }
+            idle-timeout = 60
+            max-connections = 4
+
+            [bridges.docker]
+            target = { type = "named-pipe", path = '\\.\pipe\docker_engine' }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.log_level.as_deref(), Some("debug"));
+        assert!(matches!(config.log_format, Some(LogFormat::Json)));
+        let names: Vec<_> = config.bridges.keys().map(String::as_str).collect();
+        assert_eq!(names, ["docker", "ssh-agent"]);
+
+        let bridge = &config.bridges["ssh-agent"];
+        assert_eq!(
+            bridge.listen.as_deref(),
+            Some(Path::new("/run/user/1000/ssh-agent.sock"))
+        );
+        match &bridge.target {
+            Target::Pageant { program, args } => {
+                assert!(program.is_none());
+                assert_eq!(args, &["--confirm"]);
+            }
+            target => panic!("unexpected target {:?}", target),
+        }
+        let options = bridge.relay_options();
+        assert_eq!(
+            options.idle_timeout,
+            Some(std::time::Duration::from_secs(60))
+        );
+        assert_eq!(
+            bridge.limits().max_connections.map(|max| max.get()),
+            Some(4)
+        );
+    }
+
+    #[test]
+    fn leaves_out_optional_settings() {
+        let config = load(
+            r#"
+            [bridges.language-server]
+            target = { type = "tcp", address = "127.0.0.1:9257" }
+            "#,
+        )
+        .unwrap();
+        assert!(config.log_level.is_none());
+        assert!(config.log_format.is_none());
+
+        let bridge = &config.bridges["language-server"];
+        assert!(bridge.listen.is_none() && bridge.env.is_none() && bridge.launch.is_none());
+        let options = bridge.relay_options();
+        assert_eq!(
+            options.buffer_size,
+            bridge_core::relay::Options::DEFAULT_BUFFER_SIZE
+        );
+        assert!(options.idle_timeout.is_none());
+        let limits = bridge.limits();
+        assert!(limits.max_connections.is_none() && limits.rate_limit.is_none());
+        match &bridge.target {
+            Target::Tcp { address, key_file } => {
+                assert_eq!(address, "127.0.0.1:9257");
+                assert!(key_file.is_none());
+            }
+            target => panic!("unexpected target {:?}", target),
+        }
+    }
+
+    #[test]
+    fn an_empty_file_has_no_bridges() {
+        let config = load("").unwrap();
+        assert!(config.bridges.is_empty());
+        assert!(matches!(LogFormat::default(), LogFormat::Text));
+    }
+
+    #[test]
+    fn rejects_malformed_bridges() {
+        for contents in [
+            // Not TOML.
+            "[bridges.ssh-agent",
+            // No target.
+            "[bridges.ssh-agent]\nlisten = \"/tmp/agent.sock\"",
+            // An unknown target type.
+            "[bridges.ssh-agent]\ntarget = { type = \"carrier-pigeon\" }",
+            // A target missing its path.
+            "[bridges.ssh-agent]\ntarget = { type = \"unix\" }",
+            // A misspelt setting.
+            "[bridges.ssh-agent]\ntarget = { type = \"pageant\" }\nidle_timeout = 60",
+            // Limits must be positive.
+            "[bridges.ssh-agent]\ntarget = { type = \"pageant\" }\nmax-connections = 0",
+            // An unknown log format.
+            "log-format = \"xml\"",
+        ] {
+            match load(contents) {
+                Err(Error::Parse(path, _)) => assert!(path.ends_with("bridges.toml")),
+                other => panic!("{:?} parsed as {:?}", contents, other),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_duplicate_bridges() {
+        let contents = r#"
+            [bridges.ssh-agent]
+            target = { type = "pageant" }
+
+            [bridges.ssh-agent]
+            target = { type = "tcp", address = "127.0.0.1:5222" }
+            "#;
+        assert!(matches!(load(contents), Err(Error::Parse(..))));
+    }
+
+    #[test]
+    fn a_requested_file_must_exist() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing.toml");
+        match Config::load(Some(&path)) {
+            Err(Error::IO(missing, e)) => {
+                assert_eq!(missing, path);
+                assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+            }
+            other => panic!("expected an IO error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_log_formats() {
+        assert!(matches!("text".parse(), Ok(LogFormat::Text)));
+        assert!(matches!("json".parse(), Ok(LogFormat::Json)));
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+}
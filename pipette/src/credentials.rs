@@ -0,0 +1,56 @@
+//! Reading secrets from systemd credentials, rather than the environment or files anyone can read.
+//!
+//! A unit's `LoadCredential=` (or `SetCredential=`) has systemd put each credential in a directory
+//! only the service can read, named by `$CREDENTIALS_DIRECTORY`. When that's set:
+//!
+//! - a relative `key-file` (or `--key-file`) is looked for there first, e.g.
+//!   `LoadCredential = relay.key:/home/me/.config/wsl-systemd/relay.key` with
+//!   `key-file = "relay.key"`;
+//! - likewise a relative `path` of an `assuan` target, for a socket file (port and nonce) kept
+//!   out of reach of other users. systemd copies it when the service starts, so this only suits
+//!   an agent that isn't restarted while the service runs;
+//! - the `wsl-systemd.helper` credential holds the path of `pageant.exe`, in place of
+//!   `$WSL_SYSTEMD_HELPER`.
+//!
+//! Anything that isn't a credential is read as before, so the same configuration file works
+//! outside the service.
+
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+/// Set by systemd to the directory holding the service's credentials.
+const ENV: &str = "CREDENTIALS_DIRECTORY";
+
+/// The credential holding the path of the `pageant.exe` helper.
+pub const HELPER: &str = "wsl-systemd.helper";
+
+fn directory() -> Option<PathBuf> {
+    std::env::var_os(ENV).map(PathBuf::from)
+}
+
+/// The credential `path` names if it's relative and there is one, otherwise `path` itself.
+pub fn resolve(path: &Path) -> Cow<'_, Path> {
+    if path.is_relative() {
+        if let Some(credential) = directory().map(|dir| dir.join(path)) {
+            if credential.is_file() {
+                tracing::debug!(path = %credential.display(), "Using credential");
+                return Cow::Owned(credential);
+            }
+        }
+    }
+    Cow::Borrowed(path)
+}
+
+/// The contents of the credential `name` (without trailing whitespace), if there's one that can
+/// be read.
+pub fn read(name: &str) -> Option<String> {
+    let path = directory()?.join(name);
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => Some(contents.trim_end().to_owned()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => {
+            tracing::warn!(path = %path.display(), error = %e, "Failed to read credential");
+            None
+        }
+    }
+}
@@ -0,0 +1,913 @@
+//! Running several bridges from a single long-lived process, inside WSL or on Windows.
+//!
+//! Each socket a bridge listens on (`listen`, which is Unix only, `listen-tcp`, or `listen-pipe`,
+//! which is Windows only) gets an accept loop task, and every client connection gets a task with a
+//! fresh connection to the bridge's target, relayed until either end closes, subject to the
+//! bridge's [limits](crate::limit). On SIGTERM/SIGINT the listening sockets are closed
+//! (and Unix sockets removed), and open connections are given [`GRACE_PERIOD`] to wind down.
+//!
+//! Unix sockets are only accessible to the user (mode 0600, as systemd's socket units make them),
+//! and relative `listen` paths are in `$XDG_RUNTIME_DIR/wsl-systemd` (see the `runtime_dir`
+//! module), which is created as needed. Each is guarded by a lock file alongside it
+//! (`<listen>.lock`), held for as long as the socket is listened on and removed with it. A bridge
+//! whose socket is locked, or whose socket answers a connection, is already being served by
+//! another instance, so is skipped (and if that leaves nothing to do, the daemon exits
+//! successfully). A socket file that's neither is left over from an instance that didn't get to
+//! clean up (e.g. when WSL was terminated), so is replaced.
+//!
+//! Named pipes and keyless TCP sockets are for Windows applications to reach services inside WSL
+//! (the reverse of the usual direction), so are only reachable locally.
+//!
+//! On SIGHUP the configuration file is read again: bridges that have been added start listening,
+//! those that have been removed stop (their connections getting the grace period), and new
+//! connections to the others get their new settings. Open connections are left alone, as are the
+//! sockets that haven't changed, so nothing in flight is lost. If a new socket can't be bound,
+//! the bridges are left as they were. The log level is updated too (unless `--log-level` or
+//! `RUST_LOG` is overriding it).
+//!
+//! Unix sockets can also be passed in by systemd (see the `activation` module), in which case
+//! systemd owns them. With `--exit-idle-time` the daemon exits once it's had no connections for a
+//! while, for systemd to start it again when the next client connects.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bridge_core::relay::Options;
+use bridge_core::shutdown::GRACE_PERIOD;
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::{oneshot, watch};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument as _;
+
+use crate::auth::{Key, HANDSHAKE_TIMEOUT};
+use crate::config::{Bridge, Config, Launch, Target};
+use crate::limit::{ClientId, Limits, RateLimiter, Verdict, FULL_WARNING_INTERVAL};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("No bridge named {0:?} in the configuration file")]
+    UnknownBridge(String),
+    #[error("Bridge {0:?} has no `listen`, `listen-tcp` or `listen-pipe` socket configured")]
+    NoListenSocket(String),
+    #[cfg(not(unix))]
+    #[error("Bridge {0:?} has a `listen` socket, which is only supported inside WSL")]
+    UnixUnsupported(String),
+    #[cfg(not(windows))]
+    #[error("Bridge {0:?} has a `listen-pipe` socket, which is only supported on Windows")]
+    PipeUnsupported(String),
+    #[error("Bridge {0:?} has no `key-file` for its non-loopback `listen-tcp` address {1}")]
+    NoKey(String, std::net::SocketAddr),
+    #[error("No bridges with a `listen`, `listen-tcp` or `listen-pipe` socket are configured")]
+    NoBridges,
+    #[error("Failed to listen on {0}")]
+    Bind(String, #[source] std::io::Error),
+    #[cfg(unix)]
+    #[error("Failed to lock {0}")]
+    Lock(String, #[source] std::io::Error),
+    #[cfg(unix)]
+    #[error("Failed to create the directory for {0}")]
+    SocketDir(String, #[source] std::io::Error),
+    #[error(transparent)]
+    Key(#[from] crate::auth::Error),
+    #[error(transparent)]
+    Metrics(#[from] crate::metrics::Error),
+    #[cfg(unix)]
+    #[error("Failed to handle SIGHUP")]
+    Signal(#[source] std::io::Error),
+}
+
+/// How a bridge's connections are served, replaced when the configuration is reloaded.
+struct Settings {
+    target: Target,
+    launch: Option<Launch>,
+    options: Options,
+    limits: Limits,
+}
+
+/// A socket a bridge listens on, to tell which are unchanged when the configuration is reloaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Address {
+    #[cfg(unix)]
+    Unix(PathBuf),
+    Tcp {
+        address: String,
+        key_file: Option<PathBuf>,
+    },
+    #[cfg(windows)]
+    Pipe(PathBuf),
+}
+
+impl Address {
+    /// The sockets that the bridge called `name` listens on.
+    fn of(name: &str, bridge: &Bridge) -> Result<Vec<Self>, Error> {
+        let mut addresses = Vec::new();
+        #[cfg(unix)]
+        if let Some(path) = &bridge.listen {
+            addresses.push(Self::Unix(path.clone()));
+        }
+        #[cfg(not(unix))]
+        if bridge.listen.is_some() {
+            return Err(Error::UnixUnsupported(name.to_owned()));
+        }
+        #[cfg(windows)]
+        if let Some(path) = &bridge.listen_pipe {
+            addresses.push(Self::Pipe(path.clone()));
+        }
+        #[cfg(not(windows))]
+        if bridge.listen_pipe.is_some() {
+            return Err(Error::PipeUnsupported(name.to_owned()));
+        }
+        if let Some(listen_tcp) = &bridge.listen_tcp {
+            addresses.push(Self::Tcp {
+                address: listen_tcp.address.clone(),
+                key_file: listen_tcp.key_file.clone(),
+            });
+        }
+        if addresses.is_empty() {
+            return Err(Error::NoListenSocket(name.to_owned()));
+        }
+        Ok(addresses)
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            #[cfg(unix)]
+            Self::Unix(path) => path.display().fmt(f),
+            Self::Tcp { address, .. } => f.write_str(address),
+            #[cfg(windows)]
+            Self::Pipe(path) => path.display().fmt(f),
+        }
+    }
+}
+
+struct Listener {
+    name: String,
+    socket: Socket,
+    settings: watch::Receiver<Arc<Settings>>,
+}
+
+enum Socket {
+    /// `path` and `lock` are only set for sockets the daemon bound itself, rather than being
+    /// passed them by systemd.
+    #[cfg(unix)]
+    Unix {
+        listener: UnixListener,
+        path: Option<PathBuf>,
+        /// Locked for as long as the socket is listened on.
+        _lock: Option<std::fs::File>,
+    },
+    /// Connections are only relayed once they've proved they have `key`, if there is one.
+    Tcp {
+        listener: TcpListener,
+        key: Option<Arc<Key>>,
+    },
+    /// The instance of the pipe waiting for the next client, replaced as each one connects.
+    #[cfg(windows)]
+    Pipe {
+        next: tokio::sync::Mutex<tokio::net::windows::named_pipe::NamedPipeServer>,
+        path: PathBuf,
+    },
+}
+
+/// Whether `bridge` has a socket that this platform can listen on.
+fn listens(bridge: &Bridge) -> bool {
+    (cfg!(unix) && bridge.listen.is_some())
+        || bridge.listen_tcp.is_some()
+        || (cfg!(windows) && bridge.listen_pipe.is_some())
+}
+
+/// Run the bridges named in `names` (or all bridges with a socket to listen on if empty) until
+/// the process is asked to shut down, or has had no connections for `exit_idle_time`. `limits`
+/// override those in the configuration file, which is reloaded from `config_path` on SIGHUP.
+pub async fn run(
+    config: &Config,
+    config_path: Option<&Path>,
+    names: &[String],
+    limits: Limits,
+    metrics: &crate::metrics::Options,
+    exit_idle_time: Option<Duration>,
+) -> Result<(), Error> {
+    let stop = bridge_core::shutdown::on_signal();
+    let mut daemon = Daemon {
+        names: names.to_owned(),
+        limits,
+        bridges: BTreeMap::new(),
+        binder: Binder {
+            #[cfg(unix)]
+            activated: crate::activation::take(),
+        },
+        stop: stop.clone(),
+        open: Arc::new(watch::Sender::new(0)),
+        tasks: tokio::task::JoinSet::new(),
+    };
+    // Bind everything up-front so a misconfigured bridge stops the daemon from starting rather
+    // than leaving it half-working.
+    let already_running = daemon.apply(config).await?;
+    if daemon.bridges.is_empty() && already_running > 0 {
+        tracing::info!("All bridges are already served by other instances");
+        return Ok(());
+    }
+
+    #[cfg(unix)]
+    for (path, _) in daemon.binder.activated.drain(..) {
+        tracing::warn!(path = %path.display(), "No bridge listens on a socket from systemd");
+    }
+
+    crate::metrics::start(metrics, &stop).await?;
+    if let Some(idle) = exit_idle_time {
+        tokio::spawn(exit_when_idle(daemon.open.subscribe(), idle, stop.clone()));
+    }
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .map_err(Error::Signal)?;
+    loop {
+        #[cfg(unix)]
+        let reload = hangup.recv();
+        #[cfg(not(unix))]
+        let reload = std::future::pending::<Option<()>>();
+        tokio::select! {
+            () = stop.cancelled() => break,
+            Some(()) = reload => daemon.reload(config_path).await,
+        }
+    }
+    while daemon.tasks.join_next().await.is_some() {}
+
+    Ok(())
+}
+
+/// The bridges being served.
+struct Daemon {
+    /// The bridges to serve, or empty for all those with a socket to listen on.
+    names: Vec<String>,
+    limits: Limits,
+    bridges: BTreeMap<String, Served>,
+    binder: Binder,
+    stop: CancellationToken,
+    /// The number of connections open across all bridges.
+    open: Arc<watch::Sender<usize>>,
+    tasks: tokio::task::JoinSet<()>,
+}
+
+/// A bridge being served.
+struct Served {
+    settings: watch::Sender<Arc<Settings>>,
+    listeners: Vec<Running>,
+}
+
+/// A socket being listened on.
+struct Running {
+    address: Address,
+    /// Stops the listener, and (after the grace period) its connections.
+    stop: CancellationToken,
+    /// Completes once the listener has closed its socket.
+    closed: oneshot::Receiver<()>,
+}
+
+impl Daemon {
+    /// Serve the bridges selected from `config`, returning how many sockets were skipped because
+    /// another instance is serving them.
+    ///
+    /// Bridges that were already being served carry on, and their open connections are left
+    /// alone, but new connections get the new settings. Sockets that are no longer wanted are
+    /// closed, and their connections given the grace period to finish, but only once every new
+    /// socket has been bound: if one can't be, nothing changes.
+    async fn apply(&mut self, config: &Config) -> Result<usize, Error> {
+        while self.tasks.try_join_next().is_some() {}
+
+        let selected: Vec<_> = if self.names.is_empty() {
+            config
+                .bridges
+                .iter()
+                .filter(|(_, bridge)| listens(bridge))
+                .collect()
+        } else {
+            self.names
+                .iter()
+                .map(|name| {
+                    config
+                        .bridges
+                        .get_key_value(name)
+                        .ok_or_else(|| Error::UnknownBridge(name.clone()))
+                })
+                .collect::<Result<_, _>>()?
+        };
+        if selected.is_empty() {
+            return Err(Error::NoBridges);
+        }
+        let wanted = selected
+            .into_iter()
+            .map(|(name, bridge)| Ok((name, bridge, Address::of(name, bridge)?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        // Bind the new sockets before closing any, so a configuration that can't be bound
+        // leaves the bridges as they were. A socket moving to another bridge can only be bound
+        // once the old one has closed it, so is bound last.
+        let mut already_running = 0;
+        let mut bound = Vec::new();
+        let mut moved = Vec::new();
+        for (name, _, addresses) in &wanted {
+            for address in addresses {
+                let owner = self.bridges.iter().find(|(_, served)| {
+                    served
+                        .listeners
+                        .iter()
+                        .any(|running| running.address == *address)
+                });
+                match owner {
+                    Some((owner, _)) if owner == *name => {}
+                    Some(_) => moved.push((*name, address.clone())),
+                    None => match self.binder.bind(name, address).await {
+                        Ok(Some(socket)) => bound.push((*name, address.clone(), socket)),
+                        Ok(None) => {
+                            skipped(name, address);
+                            already_running += 1;
+                        }
+                        Err(e) => {
+                            for (name, _, socket) in bound {
+                                socket.close(name);
+                            }
+                            return Err(e);
+                        }
+                    },
+                }
+            }
+        }
+
+        let mut closing = Vec::new();
+        for (name, served) in &mut self.bridges {
+            let addresses = wanted
+                .iter()
+                .find(|(wanted, _, _)| *wanted == name)
+                .map_or(&[][..], |(_, _, addresses)| addresses);
+            let (keep, stop) = served
+                .listeners
+                .drain(..)
+                .partition(|running| addresses.contains(&running.address));
+            served.listeners = keep;
+            for running in stop {
+                tracing::info!(bridge = %name, address = %running.address, "No longer listening");
+                running.stop.cancel();
+                closing.push(running.closed);
+            }
+        }
+        self.bridges
+            .retain(|_, served| !served.listeners.is_empty());
+        for closed in closing {
+            let _ = closed.await;
+        }
+
+        // The old bridges are gone by now, so a moved socket failing to bind can't leave things
+        // as they were: the rest of the configuration is applied anyway.
+        let mut failed = None;
+        for (name, address) in moved {
+            match self.binder.bind(name, &address).await {
+                Ok(Some(socket)) => bound.push((name, address, socket)),
+                Ok(None) => {
+                    skipped(name, &address);
+                    already_running += 1;
+                }
+                Err(e) => failed = Some(e),
+            }
+        }
+
+        for (name, bridge, _) in &wanted {
+            let settings = Arc::new(Settings {
+                target: bridge.target.clone(),
+                launch: bridge.launch.clone(),
+                options: bridge.relay_options(),
+                limits: self.limits.or(bridge.limits()),
+            });
+            match self.bridges.get(*name) {
+                Some(served) => {
+                    served.settings.send_replace(settings);
+                }
+                None if bound.iter().any(|(bound, _, _)| bound == name) => {
+                    let served = Served {
+                        settings: watch::Sender::new(settings),
+                        listeners: Vec::new(),
+                    };
+                    self.bridges.insert((*name).clone(), served);
+                }
+                None => {}
+            }
+        }
+        for (name, address, socket) in bound {
+            tracing::info!(bridge = %name, %address, "Listening");
+            let served = self.bridges.get_mut(name).expect("bridge set up above");
+            let listener = Listener {
+                name: name.clone(),
+                socket,
+                settings: served.settings.subscribe(),
+            };
+            let stop = self.stop.child_token();
+            let (closed_tx, closed) = oneshot::channel();
+            self.tasks
+                .spawn(listener.accept_loop(stop.clone(), Arc::clone(&self.open), closed_tx));
+            served.listeners.push(Running {
+                address,
+                stop,
+                closed,
+            });
+        }
+
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(already_running),
+        }
+    }
+
+    /// Re-read the configuration file and apply it, keeping the current configuration if it
+    /// can't be read.
+    async fn reload(&mut self, config_path: Option<&Path>) {
+        tracing::info!("Reloading the configuration");
+        let config = match Config::load(config_path) {
+            Ok(config) => config,
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to reload the configuration, keeping the old one");
+                return;
+            }
+        };
+        crate::logging::reload(config.log_level.as_deref());
+        if let Err(e) = self.apply(&config).await {
+            tracing::error!(error = %e, "Failed to apply the reloaded configuration");
+        }
+    }
+}
+
+/// Log that the bridge called `name` isn't listening on `address` because another instance
+/// already is.
+fn skipped(name: &str, address: &Address) {
+    tracing::info!(
+        bridge = %name,
+        %address,
+        "Already served by another instance, skipping"
+    );
+}
+
+/// Binds the sockets bridges listen on.
+struct Binder {
+    /// Sockets passed in by systemd that haven't been claimed by a bridge yet.
+    #[cfg(unix)]
+    activated: Vec<(PathBuf, std::os::unix::net::UnixListener)>,
+}
+
+impl Binder {
+    /// Listen on `address` for the bridge called `name`, unless another instance already is
+    /// (`None`).
+    async fn bind(&mut self, name: &str, address: &Address) -> Result<Option<Socket>, Error> {
+        match address {
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                let activated = self
+                    .activated
+                    .iter()
+                    .position(|(activated, _)| activated == path)
+                    .map(|i| self.activated.swap_remove(i).1);
+                match activated {
+                    Some(listener) => from_systemd(listener, path).map(Some),
+                    None => bind_unix(path),
+                }
+            }
+            Address::Tcp { address, key_file } => {
+                let key = key_file.as_deref().map(Key::load).transpose()?;
+                let listener = TcpListener::bind(address)
+                    .await
+                    .map_err(|e| Error::Bind(address.clone(), e))?;
+                let local = listener
+                    .local_addr()
+                    .map_err(|e| Error::Bind(address.clone(), e))?;
+                if key.is_none() && !local.ip().is_loopback() {
+                    return Err(Error::NoKey(name.to_owned(), local));
+                }
+                tracing::debug!(bridge = %name, address = %local, authenticated = key.is_some(), "Bound");
+                Ok(Some(Socket::Tcp {
+                    listener,
+                    key: key.map(Arc::new),
+                }))
+            }
+            #[cfg(windows)]
+            Address::Pipe(path) => {
+                let next = crate::pipe::listen(path, true)
+                    .map_err(|e| Error::Bind(path.display().to_string(), e))?;
+                Ok(next.map(|next| Socket::Pipe {
+                    next: tokio::sync::Mutex::new(next),
+                    path: path.clone(),
+                }))
+            }
+        }
+    }
+}
+
+/// Cancel `stop` once there have been no connections `open` for `idle`.
+async fn exit_when_idle(mut open: watch::Receiver<usize>, idle: Duration, stop: CancellationToken) {
+    loop {
+        if open.wait_for(|&open| open == 0).await.is_err() {
+            return;
+        }
+        tokio::select! {
+            () = tokio::time::sleep(idle) => {
+                tracing::info!(?idle, "No connections for a while, exiting");
+                stop.cancel();
+                return;
+            }
+            _ = open.wait_for(|&open| open > 0) => {}
+            () = stop.cancelled() => return,
+        }
+    }
+}
+
+/// Counts a connection in the number open across all bridges, until dropped.
+struct OpenConnection(Arc<watch::Sender<usize>>);
+
+impl OpenConnection {
+    fn new(open: &Arc<watch::Sender<usize>>) -> Self {
+        open.send_modify(|open| *open += 1);
+        Self(Arc::clone(open))
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.send_modify(|open| *open -= 1);
+    }
+}
+
+impl Listener {
+    async fn accept_loop(
+        self,
+        stop: CancellationToken,
+        open: Arc<watch::Sender<usize>>,
+        closed: oneshot::Sender<()>,
+    ) {
+        let Self {
+            name,
+            socket,
+            mut settings,
+        } = self;
+        let metrics = crate::metrics::bridge(&name);
+        let mut connections = tokio::task::JoinSet::new();
+        let mut rate_limit = None;
+        let mut rate_limiter = None;
+        let mut warned_full: Option<std::time::Instant> = None;
+        loop {
+            let current = Arc::clone(&settings.borrow_and_update());
+            let limits = current.limits;
+            if limits.rate_limit != rate_limit {
+                rate_limit = limits.rate_limit;
+                rate_limiter = rate_limit.map(RateLimiter::new);
+            }
+            let has_room = limits.has_room(connections.len());
+            if !has_room && warned_full.is_none_or(|at| at.elapsed() >= FULL_WARNING_INTERVAL) {
+                warned_full = Some(std::time::Instant::now());
+                tracing::warn!(
+                    bridge = %name,
+                    connections = connections.len(),
+                    "Too many connections, not accepting more until some close"
+                );
+            }
+            tokio::select! {
+                () = stop.cancelled() => break,
+                // The limits may have changed.
+                Ok(()) = settings.changed() => {}
+                accepted = socket.accept(), if has_room => match accepted {
+                    Ok(client) => {
+                        let verdict = rate_limiter
+                            .as_mut()
+                            .map_or(Verdict::Allow, |limiter| limiter.check(client.id()));
+                        if let Verdict::Refuse { first } = verdict {
+                            if first {
+                                tracing::warn!(
+                                    bridge = %name,
+                                    client = ?client.id(),
+                                    "Client is opening connections too quickly, refusing some"
+                                );
+                            }
+                            continue;
+                        }
+                        let id = crate::relay::next_id();
+                        let span = tracing::info_span!("connection", bridge = %name, id);
+                        let stop = stop.clone();
+                        let metrics = Arc::clone(&metrics);
+                        let open = OpenConnection::new(&open);
+                        connections.spawn(
+                            async move {
+                                let _open = open;
+                                let Settings {
+                                    target,
+                                    launch,
+                                    options,
+                                    ..
+                                } = &*current;
+                                client
+                                    .serve(id, target, launch.as_ref(), *options, &stop, &metrics)
+                                    .await
+                            }
+                            .instrument(span),
+                        );
+                    }
+                    Err(e) => {
+                        tracing::error!(bridge = %name, error = %e, "Failed to accept a connection")
+                    }
+                },
+                // Reap finished connections as we go.
+                Some(_) = connections.join_next() => {}
+            }
+        }
+
+        socket.close(&name);
+        let _ = closed.send(());
+        if !connections.is_empty() {
+            tracing::info!(
+                bridge = %name,
+                connections = connections.len(),
+                grace_period = ?GRACE_PERIOD,
+                "Waiting for connections to close"
+            );
+        }
+        // Anything still open after the grace period is aborted as `connections` is dropped.
+        bridge_core::shutdown::with_grace_period(&stop, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+    }
+}
+
+/// Listen on the Unix socket at `path`, unless another instance already is (`None`), replacing
+/// a stale socket file.
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path) -> Result<Option<Socket>, Error> {
+    use std::os::unix::fs::{FileTypeExt as _, PermissionsExt as _};
+
+    crate::runtime_dir::create_for(path)
+        .map_err(|e| Error::SocketDir(path.display().to_string(), e))?;
+    let lock_path = lock_path(path);
+    let lock = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .map_err(|e| Error::Lock(lock_path.display().to_string(), e))?;
+    match lock.try_lock() {
+        Ok(()) => {}
+        Err(std::fs::TryLockError::WouldBlock) => return Ok(None),
+        Err(std::fs::TryLockError::Error(e)) => {
+            return Err(Error::Lock(lock_path.display().to_string(), e))
+        }
+    }
+
+    // Nothing else holds the lock, but something that doesn't take it (e.g. an older wsl-systemd,
+    // or systemd's socket activation) may still be listening.
+    let is_socket = std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket());
+    if is_socket {
+        match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => return Ok(None),
+            Err(e) => {
+                tracing::info!(path = %path.display(), error = %e, "Replacing stale socket");
+                std::fs::remove_file(path)
+                    .map_err(|e| Error::Bind(path.display().to_string(), e))?;
+            }
+        }
+    }
+
+    let listener =
+        UnixListener::bind(path).map_err(|e| Error::Bind(path.display().to_string(), e))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+        .map_err(|e| Error::Bind(path.display().to_string(), e))?;
+    Ok(Some(Socket::Unix {
+        listener,
+        path: Some(path.to_owned()),
+        _lock: Some(lock),
+    }))
+}
+
+/// The lock file guarding the Unix socket at `path`.
+#[cfg(unix)]
+fn lock_path(path: &Path) -> PathBuf {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    PathBuf::from(lock_path)
+}
+
+/// Listen on a socket passed in by systemd.
+#[cfg(unix)]
+fn from_systemd(
+    listener: std::os::unix::net::UnixListener,
+    path: &std::path::Path,
+) -> Result<Socket, Error> {
+    let listener = listener
+        .set_nonblocking(true)
+        .and_then(|()| UnixListener::from_std(listener))
+        .map_err(|e| Error::Bind(path.display().to_string(), e))?;
+    Ok(Socket::Unix {
+        listener,
+        path: None,
+        _lock: None,
+    })
+}
+
+/// A connection accepted on a [`Socket`].
+enum Client {
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+    Tcp(
+        tokio::net::TcpStream,
+        std::net::SocketAddr,
+        Option<Arc<Key>>,
+    ),
+    #[cfg(windows)]
+    Pipe(tokio::net::windows::named_pipe::NamedPipeServer),
+}
+
+impl Client {
+    fn id(&self) -> ClientId {
+        match self {
+            #[cfg(unix)]
+            Client::Unix(client) => client
+                .peer_cred()
+                .map_or(ClientId::Unknown, |cred| ClientId::User(cred.uid())),
+            Client::Tcp(_, peer, _) => ClientId::Address(peer.ip()),
+            #[cfg(windows)]
+            Client::Pipe(_) => ClientId::Unknown,
+        }
+    }
+
+    async fn serve(
+        self,
+        id: u64,
+        target: &Target,
+        launch: Option<&Launch>,
+        options: Options,
+        stop: &CancellationToken,
+        metrics: &Arc<crate::metrics::Bridge>,
+    ) {
+        match self {
+            #[cfg(unix)]
+            Client::Unix(client) => {
+                crate::relay::serve(client, id, target, launch, options, stop, metrics).await
+            }
+            Client::Tcp(mut client, peer, key) => {
+                if let Some(key) = key {
+                    let handshake = tokio::time::timeout(
+                        HANDSHAKE_TIMEOUT,
+                        crate::auth::accept(&mut client, &key),
+                    )
+                    .await
+                    .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()));
+                    if let Err(e) = handshake {
+                        tracing::warn!(%peer, error = %e, "Rejected an unauthenticated connection");
+                        return;
+                    }
+                }
+                crate::relay::serve(client, id, target, launch, options, stop, metrics).await
+            }
+            #[cfg(windows)]
+            Client::Pipe(client) => {
+                crate::relay::serve(client, id, target, launch, options, stop, metrics).await
+            }
+        }
+    }
+}
+
+impl Socket {
+    async fn accept(&self) -> std::io::Result<Client> {
+        match self {
+            #[cfg(unix)]
+            Socket::Unix { listener, .. } => {
+                let (client, _) = listener.accept().await?;
+                Ok(Client::Unix(client))
+            }
+            Socket::Tcp { listener, key } => {
+                let (client, peer) = listener.accept().await?;
+                bridge_core::endpoint::enable_keepalive(&client);
+                Ok(Client::Tcp(client, peer, key.clone()))
+            }
+            #[cfg(windows)]
+            Socket::Pipe { next, path } => {
+                // Holding the lock across the wait is fine, only the accept loop takes it.
+                let mut next = next.lock().await;
+                next.connect().await?;
+                let waiting = crate::pipe::listen(path, false)?.expect("not the first instance");
+                Ok(Client::Pipe(std::mem::replace(&mut *next, waiting)))
+            }
+        }
+    }
+
+    /// Stop listening, removing the socket file (and lock file) of a Unix socket.
+    fn close(self, bridge: &str) {
+        match self {
+            #[cfg(unix)]
+            Socket::Unix {
+                listener,
+                path,
+                _lock: lock,
+            } => {
+                drop(listener);
+                // systemd's sockets are left for systemd to listen on.
+                let Some(path) = path else {
+                    return;
+                };
+                if let Err(e) = std::fs::remove_file(&path) {
+                    tracing::warn!(
+                        bridge,
+                        path = %path.display(),
+                        error = %e,
+                        "Failed to remove socket"
+                    );
+                }
+                // While it's still locked, so no other instance is using it.
+                let _ = std::fs::remove_file(lock_path(&path));
+                drop(lock);
+            }
+            Socket::Tcp { listener, .. } => drop(listener),
+            // The pipe goes away with its last instance.
+            #[cfg(windows)]
+            Socket::Pipe { next, .. } => drop(next),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn daemon() -> Daemon {
+        Daemon {
+            names: Vec::new(),
+            limits: Limits::default(),
+            bridges: BTreeMap::new(),
+            binder: Binder {
+                activated: Vec::new(),
+            },
+            stop: CancellationToken::new(),
+            open: Arc::new(watch::Sender::new(0)),
+            tasks: tokio::task::JoinSet::new(),
+        }
+    }
+
+    /// A bridge called `echo` listening on `listen`, and on `listen_tcp` if it's set.
+    fn config(listen: &Path, listen_tcp: Option<std::net::SocketAddr>) -> Config {
+        let mut config = format!(
+            "[bridges.echo]\nlisten = {:?}\ntarget = {{ type = \"tcp\", address = \"127.0.0.1:1\" }}\n",
+            listen
+        );
+        if let Some(address) = listen_tcp {
+            config.push_str(&format!("listen-tcp = {{ address = \"{}\" }}\n", address));
+        }
+        toml::from_str(&config).unwrap()
+    }
+
+    fn addresses(daemon: &Daemon) -> Vec<String> {
+        daemon
+            .bridges
+            .values()
+            .flat_map(|served| &served.listeners)
+            .map(|running| running.address.to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn moves_a_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old, new) = (dir.path().join("old.sock"), dir.path().join("new.sock"));
+        let mut daemon = daemon();
+        assert_eq!(daemon.apply(&config(&old, None)).await.unwrap(), 0);
+        assert!(old.exists());
+
+        daemon.apply(&config(&new, None)).await.unwrap();
+        assert_eq!(addresses(&daemon), [new.display().to_string()]);
+        assert!(new.exists() && !old.exists());
+        assert!(!lock_path(&old).exists());
+
+        daemon.stop.cancel();
+        while daemon.tasks.join_next().await.is_some() {}
+        assert!(!new.exists());
+    }
+
+    #[tokio::test]
+    async fn keeps_the_old_sockets_if_a_new_one_fails_to_bind() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old, new) = (dir.path().join("old.sock"), dir.path().join("new.sock"));
+        let mut daemon = daemon();
+        daemon.apply(&config(&old, None)).await.unwrap();
+
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let reloaded = config(&new, Some(taken.local_addr().unwrap()));
+        assert!(matches!(
+            daemon.apply(&reloaded).await,
+            Err(Error::Bind(..))
+        ));
+        assert_eq!(addresses(&daemon), [old.display().to_string()]);
+        std::os::unix::net::UnixStream::connect(&old).unwrap();
+        // The socket that was bound is cleaned up.
+        assert!(!new.exists() && !lock_path(&new).exists());
+
+        daemon.stop.cancel();
+        while daemon.tasks.join_next().await.is_some() {}
+    }
+}
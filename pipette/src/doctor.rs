@@ -0,0 +1,177 @@
+//! `wsl-systemd doctor`: checking what the bridges depend on, and saying what's wrong.
+//!
+//! A broken bridge usually shows up as a client failing with "connection refused" or hanging,
+//! which says nothing about why. This goes through each thing a bridge needs in turn (the
+//! configuration, the environment, the Windows helpers, each target and, inside WSL, the
+//! variables pointing clients at the sockets) and reports on each one.
+//!
+//! Targets are connected to but nothing is sent, and launching a target isn't attempted, so a
+//! target that's only started on demand is reported as a warning rather than a failure.
+
+use std::fmt::Display;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::config::{Config, Target};
+
+/// How long to wait for each target to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("{0} check(s) failed")]
+    Failed(usize),
+}
+
+/// The outcome of the checks so far, printed as they're made.
+#[derive(Default)]
+struct Report {
+    failures: usize,
+}
+
+impl Report {
+    fn ok(&mut self, check: &str, detail: impl Display) {
+        println!("ok    {}: {}", check, detail);
+    }
+
+    /// Something that may well be intended, but is worth knowing about.
+    fn warn(&mut self, check: &str, detail: impl Display) {
+        println!("warn  {}: {}", check, detail);
+    }
+
+    fn fail(&mut self, check: &str, detail: impl Display) {
+        self.failures += 1;
+        println!("FAIL  {}: {}", check, detail);
+    }
+}
+
+/// Run the checks, failing if any did.
+pub async fn run(config: &Config, config_path: Option<&Path>) -> Result<(), Error> {
+    let mut report = Report::default();
+    match config_path {
+        Some(path) if path.is_file() => report.ok(
+            "configuration",
+            format_args!("{} ({} bridge(s))", path.display(), config.bridges.len()),
+        ),
+        Some(path) => report.warn(
+            "configuration",
+            format_args!(
+                "{} doesn't exist, only the built-in bridges",
+                path.display()
+            ),
+        ),
+        None => report.warn("configuration", "no configuration directory"),
+    }
+
+    #[cfg(target_os = "linux")]
+    match crate::wsl::environment() {
+        crate::wsl::Environment::None => report.warn(
+            "environment",
+            "not WSL, Windows has to be reached over the network",
+        ),
+        environment => report.ok("environment", format_args!("{:?}", environment)),
+    }
+
+    helper(&mut report, "pageant helper", &crate::pageant::locate(None));
+    helper(&mut report, "pinentry", &crate::pinentry::locate(None));
+    let socket = crate::gnupg::agent_socket(None);
+    if socket.is_file() {
+        report.ok("gpg-agent socket", socket.display());
+    } else {
+        report.warn(
+            "gpg-agent socket",
+            format_args!("{} doesn't exist, is gpg-agent running?", socket.display()),
+        );
+    }
+
+    for (name, bridge) in &config.bridges {
+        let check = format!("bridge {}", name);
+        if let Target::Pinentry { program, .. } = &bridge.target {
+            // Connecting would start the pinentry, to no purpose.
+            helper(
+                &mut report,
+                &check,
+                &crate::pinentry::locate(program.as_deref()),
+            );
+        } else {
+            let connected =
+                tokio::time::timeout(CONNECT_TIMEOUT, crate::endpoint::connect(&bridge.target))
+                    .await;
+            match connected {
+                Ok(Ok(_)) => report.ok(&check, "target reached"),
+                Ok(Err(e)) if bridge.launch.is_some() => report.warn(
+                    &check,
+                    format_args!("{} (it's launched when a client connects)", chain(&e)),
+                ),
+                Ok(Err(e)) => report.fail(&check, chain(&e)),
+                Err(_) => report.fail(
+                    &check,
+                    format_args!("target didn't answer within {:?}", CONNECT_TIMEOUT),
+                ),
+            }
+        }
+        #[cfg(unix)]
+        if let Some(listen) = &bridge.listen {
+            if listen.exists() {
+                report.ok(&check, format_args!("listening on {}", listen.display()));
+            } else {
+                report.fail(
+                    &check,
+                    format_args!(
+                        "nothing at {}, is `wsl-systemd daemon` (or its socket unit) running?",
+                        listen.display()
+                    ),
+                );
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    match crate::env::variables(config) {
+        Ok(variables) => {
+            for (variable, value) in variables {
+                match std::env::var(&variable) {
+                    Ok(current) if current == value => report.ok(&variable, value),
+                    Ok(current) => report.warn(
+                        &variable,
+                        format_args!("is {}, not {} (see `wsl-systemd env`)", current, value),
+                    ),
+                    Err(_) => report.warn(
+                        &variable,
+                        format_args!("not set, should be {} (see `wsl-systemd env`)", value),
+                    ),
+                }
+            }
+        }
+        Err(e) => report.fail("environment variables", chain(&e)),
+    }
+
+    match report.failures {
+        0 => Ok(()),
+        failures => Err(Error::Failed(failures)),
+    }
+}
+
+/// Check a helper program can be found, though it may yet be on `PATH`.
+fn helper(report: &mut Report, check: &str, program: &Path) {
+    if program.is_file() {
+        report.ok(check, program.display());
+    } else {
+        report.warn(
+            check,
+            format_args!("not found, relying on {} being on PATH", program.display()),
+        );
+    }
+}
+
+/// An error and its causes, on one line.
+fn chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(e) = source {
+        message.push_str(": ");
+        message.push_str(&e.to_string());
+        source = e.source();
+    }
+    message
+}
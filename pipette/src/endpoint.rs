@@ -0,0 +1,316 @@
+//! Connecting to the targets in the configuration file.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use bridge_core::endpoint::Endpoint;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::Instrument as _;
+
+use crate::config::{Launch, Target};
+
+/// How long to keep trying to reach a target after launching it.
+const LAUNCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Held while launching a target, so a burst of connections to a stopped target only starts it
+/// once.
+static LAUNCHING: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Connect to `target`.
+pub async fn connect(target: &Target) -> Result<Endpoint, crate::Error> {
+    let endpoint = match target {
+        Target::Assuan { path } => Endpoint::assuan(&crate::credentials::resolve(path)).await?,
+        #[cfg(windows)]
+        Target::NamedPipe { path } => Endpoint::stream(
+            crate::pipe::connect(path)
+                .await
+                .map_err(|e| crate::Error::Connect(path.display().to_string(), e))?,
+        ),
+        #[cfg(not(windows))]
+        Target::NamedPipe { path } => return Err(crate::Error::NamedPipeUnsupported(path.clone())),
+        Target::Tcp { address, key_file } => {
+            let key = key_file
+                .as_deref()
+                .map(crate::auth::Key::load)
+                .transpose()?;
+            #[cfg(target_os = "linux")]
+            let address = &*crate::wsl::windows_address(address);
+            let mut sock = bridge_core::endpoint::connect_tcp(address)
+                .await
+                .map_err(|e| crate::Error::Connect(address.to_owned(), e))?;
+            if let Some(key) = key {
+                tokio::time::timeout(
+                    crate::auth::HANDSHAKE_TIMEOUT,
+                    crate::auth::connect(&mut sock, &key),
+                )
+                .await
+                .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()))
+                .map_err(|e| crate::Error::Connect(address.to_owned(), e))?;
+            }
+            Endpoint::stream(sock)
+        }
+        #[cfg(unix)]
+        Target::Unix { path } => Endpoint::unix(path)
+            .await
+            .map_err(|e| crate::Error::Connect(path.display().to_string(), e))?,
+        // tokio can't create Unix sockets on Windows, but it can drive one once it's
+        // connected.
+        #[cfg(windows)]
+        Target::Unix { path } => {
+            let unix_path = path.clone();
+            let sock =
+                tokio::task::spawn_blocking(move || crate::winsock::connect_unix(&unix_path))
+                    .await
+                    .map_err(std::io::Error::from)
+                    .and_then(|connected| connected)
+                    .and_then(tokio::net::TcpStream::from_std)
+                    .map_err(|e| crate::Error::Connect(path.display().to_string(), e))?;
+            Endpoint::stream(sock)
+        }
+        #[cfg(target_os = "linux")]
+        Target::Vsock { port, cid: None }
+            if crate::wsl::environment() == crate::wsl::Environment::Wsl1 =>
+        {
+            return Err(crate::Error::VsockUnsupported(*port))
+        }
+        #[cfg(target_os = "linux")]
+        Target::Vsock { port, cid } => Endpoint::stream(
+            crate::vsock::VsockStream::connect(cid.unwrap_or(crate::vsock::HOST_CID), *port)
+                .await
+                .map_err(|e| crate::Error::Connect(format!("vsock port {:#x}", port), e))?,
+        ),
+        #[cfg(not(target_os = "linux"))]
+        Target::Vsock { port, .. } => return Err(crate::Error::VsockUnsupported(*port)),
+        Target::Pageant { program, args } => {
+            let program = crate::pageant::locate(program.as_deref());
+            Endpoint::stream(
+                crate::pageant::spawn(&program, args)
+                    .map_err(|e| crate::Error::Connect(program.display().to_string(), e))?,
+            )
+        }
+        Target::Pinentry { program, args } => {
+            let program = crate::pinentry::locate(program.as_deref());
+            Endpoint::stream(
+                ChildProcess::spawn(&program, args)
+                    .map_err(|e| crate::Error::Connect(program.display().to_string(), e))?,
+            )
+        }
+        Target::Command { program, args } => Endpoint::stream(
+            ChildProcess::spawn(program, args)
+                .map_err(|e| crate::Error::Connect(program.display().to_string(), e))?,
+        ),
+        Target::Mux {
+            program,
+            args,
+            bridge,
+        } => Endpoint::stream(
+            crate::mux::connect(program, args, bridge)
+                .await
+                .map_err(|e| crate::Error::Connect(program.display().to_string(), e))?,
+        ),
+    };
+    Ok(endpoint)
+}
+
+/// Connect to `target`, running `launch` to start it if it can't be reached and then
+/// retrying (with backoff) for up to [`LAUNCH_TIMEOUT`].
+pub async fn connect_or_launch(
+    target: &Target,
+    launch: Option<&Launch>,
+) -> Result<Endpoint, crate::Error> {
+    let launch = match (connect(target).await, launch) {
+        (Ok(endpoint), _) => return Ok(endpoint),
+        (Err(e), None) => return Err(e),
+        (Err(e), Some(launch)) => {
+            tracing::info!(error = %e, "Target unavailable, launching it");
+            launch
+        }
+    };
+
+    let _launching = LAUNCHING.lock().await;
+    // Another connection may have launched it while we waited for the lock.
+    if let Ok(endpoint) = connect(target).await {
+        return Ok(endpoint);
+    }
+
+    let program = launch.program.display().to_string();
+    tracing::info!(%program, args = ?launch.args, "Launching target");
+    // stdout may be carrying the bridged stream, so keep the launcher off it. Once it's
+    // dropped, tokio takes care of reaping the launcher whenever it exits.
+    let mut launcher = tokio::process::Command::new(&launch.program)
+        .args(&launch.args)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .map_err(|e| crate::Error::Launch(program.clone(), e))?;
+
+    let deadline = Instant::now() + LAUNCH_TIMEOUT;
+    let mut delay = Duration::from_millis(100);
+    loop {
+        // Launchers like `gpgconf --launch` exit once the target is up, while others (e.g.
+        // `pageant.exe`) are the target and keep running.
+        if let Ok(Some(status)) = launcher.try_wait() {
+            if !status.success() {
+                return Err(crate::Error::LaunchFailed(program, status));
+            }
+        }
+        match connect(target).await {
+            Ok(endpoint) => return Ok(endpoint),
+            Err(e) if Instant::now() >= deadline => return Err(e),
+            Err(e) => {
+                tracing::debug!(error = %e, "Target not ready yet");
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(Duration::from_secs(1));
+            }
+        }
+    }
+}
+
+/// A helper process (typically a Windows executable launched through interop) whose stdin and
+/// stdout carry the forwarded stream.
+pub struct ChildProcess {
+    child: tokio::process::Child,
+    stdin: Option<tokio::process::ChildStdin>,
+    stdout: tokio::process::ChildStdout,
+}
+
+impl ChildProcess {
+    fn spawn(program: &std::path::Path, args: &[String]) -> std::io::Result<Self> {
+        let (child, stdin, stdout) = spawn_helper(program, args)?;
+        Ok(Self {
+            child,
+            stdin: Some(stdin),
+            stdout,
+        })
+    }
+}
+
+/// A running helper, and its stdin and stdout.
+pub type Helper = (
+    tokio::process::Child,
+    tokio::process::ChildStdin,
+    tokio::process::ChildStdout,
+);
+
+/// Start a helper with its stdin and stdout piped, asking it to report errors (see
+/// [`agent_proto::report`]) on its stderr, which is passed on to ours.
+pub fn spawn_helper(program: &std::path::Path, args: &[String]) -> std::io::Result<Helper> {
+    tracing::debug!(program = %program.display(), ?args, "Spawning helper");
+    // Pass the variable through to Windows programs launched through WSL interop.
+    let report = agent_proto::report::ENV;
+    let wslenv = match std::env::var("WSLENV") {
+        Ok(wslenv) if !wslenv.is_empty() => format!("{}:{}", wslenv, report),
+        _ => report.to_owned(),
+    };
+    let mut child = tokio::process::Command::new(program)
+        .args(args)
+        .env(report, "1")
+        .env("WSLENV", wslenv)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+    let stdin = child.stdin.take().expect("stdin is piped");
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+    // Attributing the helper's errors to the connection it's serving.
+    tokio::spawn(
+        forward_stderr(program.display().to_string(), stderr).instrument(tracing::Span::current()),
+    );
+    Ok((child, stdin, stdout))
+}
+
+/// Pass the helper's stderr on to ours, logging any errors it reports (see
+/// [`agent_proto::report`]) instead.
+async fn forward_stderr(program: String, stderr: tokio::process::ChildStderr) {
+    use agent_proto::report::Report;
+    use tokio::io::{AsyncBufReadExt as _, AsyncWriteExt as _};
+
+    let mut stderr = tokio::io::BufReader::new(stderr);
+    let mut output = tokio::io::stderr();
+    let mut line = Vec::new();
+    // Keep reading whatever the helper writes, so it never blocks on a full pipe.
+    while let Ok(len) = stderr.read_until(b'\n', &mut line).await {
+        if len == 0 {
+            break;
+        }
+        let report = std::str::from_utf8(&line).ok().and_then(Report::parse);
+        let Some(report) = report else {
+            let _ = output.write_all(&line).await;
+            line.clear();
+            continue;
+        };
+        line.clear();
+        let Report {
+            kind,
+            message,
+            fatal,
+        } = report;
+        match (fatal, kind.is_transient()) {
+            (true, _) => tracing::error!(%program, %kind, "Helper failed: {}", message),
+            (false, true) => tracing::warn!(
+                %program,
+                %kind,
+                "Helper failed a request, later requests may succeed: {}",
+                message
+            ),
+            (false, false) => {
+                tracing::warn!(%program, %kind, "Helper failed a request: {}", message)
+            }
+        }
+    }
+}
+
+impl AsyncRead for ChildProcess {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ChildProcess {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match &mut self.stdin {
+            Some(stdin) => Pin::new(stdin).poll_write(cx, buf),
+            None => Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut self.stdin {
+            Some(stdin) => Pin::new(stdin).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    // Closing the pipe is the only way to signal EOF to the helper.
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.stdin.take();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl std::ops::Drop for ChildProcess {
+    fn drop(&mut self) {
+        // Closing stdin normally lets the helper exit by itself, but don't leave it running if
+        // it's ignoring that. tokio reaps it in the background either way.
+        self.stdin.take();
+        match self.child.try_wait() {
+            Ok(Some(status)) => tracing::debug!(%status, "Helper exited"),
+            Ok(None) => {
+                if let Err(e) = self.child.start_kill() {
+                    tracing::warn!(error = %e, "Failed to kill helper");
+                }
+            }
+            Err(e) => tracing::warn!(error = %e, "Failed to reap helper"),
+        }
+    }
+}
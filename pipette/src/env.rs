@@ -0,0 +1,240 @@
+//! Printing the environment variables that point clients at the bridges' sockets, for
+//! `eval "$(wsl-systemd env)"` in a shell's startup file.
+//!
+//! The built-in bridges (see the `install` module) set `SSH_AUTH_SOCK` and `GPG_AGENT_INFO`.
+//! Configured bridges with a `listen` socket replace the built-in bridge of the same name and
+//! export the variable named by their `env` setting, or `GPG_AGENT_INFO` for `assuan` targets and
+//! `SSH_AUTH_SOCK` for `pageant` targets. A bridge named `docker` exports `DOCKER_HOST`.
+
+use std::path::{Path, PathBuf};
+
+use crate::config::{Config, Target};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Could not determine the user's runtime directory (is XDG_RUNTIME_DIR set?)")]
+    NoRuntimeDir,
+}
+
+/// The shell syntax to print.
+#[derive(Debug, Clone, Copy)]
+pub enum Shell {
+    /// `export NAME='value';`, for bash, zsh and other POSIX shells.
+    Posix,
+    /// `set -gx NAME 'value';`
+    Fish,
+}
+
+impl std::str::FromStr for Shell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bash" | "zsh" | "sh" => Ok(Self::Posix),
+            "fish" => Ok(Self::Fish),
+            _ => Err(format!(
+                "unknown shell {:?} (expected bash, zsh or fish)",
+                s
+            )),
+        }
+    }
+}
+
+impl Shell {
+    /// The user's login shell, from `$SHELL`.
+    pub fn detect() -> Self {
+        let shell = std::env::var_os("SHELL").map(PathBuf::from);
+        match shell.as_deref().and_then(|shell| shell.file_name()) {
+            Some(name) if name == "fish" => Self::Fish,
+            _ => Self::Posix,
+        }
+    }
+
+    fn export(self, name: &str, value: &str) -> String {
+        match self {
+            Self::Posix => format!("export {}='{}';", name, value.replace('\'', r"'\''")),
+            Self::Fish => format!(
+                "set -gx {} '{}';",
+                name,
+                value.replace('\\', r"\\").replace('\'', r"\'")
+            ),
+        }
+    }
+}
+
+/// The variables to set, and their values.
+pub fn variables(config: &Config) -> Result<Vec<(String, String)>, Error> {
+    let runtime_dir = directories::BaseDirs::new()
+        .and_then(|dirs| dirs.runtime_dir().map(ToOwned::to_owned))
+        .ok_or(Error::NoRuntimeDir)?;
+    Ok(variables_in(config, &runtime_dir))
+}
+
+/// The variables to set with the built-in bridges' sockets in `runtime_dir`.
+fn variables_in(config: &Config, runtime_dir: &Path) -> Vec<(String, String)> {
+    let mut sockets: Vec<_> = crate::install::builtin_sockets(runtime_dir)
+        .into_iter()
+        .filter(|(name, _)| {
+            config
+                .bridges
+                .get(name)
+                .is_none_or(|bridge| bridge.listen.is_none())
+        })
+        .filter_map(|(name, listen)| Some((builtin_variable(&name)?.to_owned(), listen)))
+        .collect();
+    for (name, bridge) in &config.bridges {
+        let Some(listen) = &bridge.listen else {
+            continue;
+        };
+        let variable = match (&bridge.env, &bridge.target) {
+            (Some(variable), _) => variable.as_str(),
+            (None, Target::Assuan { .. }) => "GPG_AGENT_INFO",
+            (None, Target::Pageant { .. }) => "SSH_AUTH_SOCK",
+            (None, _) => match builtin_variable(name) {
+                Some(variable) => variable,
+                None => continue,
+            },
+        };
+        // A configured bridge takes the variable over from a built-in one.
+        sockets.retain(|(existing, _)| existing != variable);
+        sockets.push((variable.to_owned(), listen.clone()));
+    }
+
+    sockets
+        .into_iter()
+        .map(|(variable, listen)| {
+            let listen = listen.display().to_string();
+            // The pre-2.1 format, `socket:pid:protocol version`, which is all anything still
+            // reading it needs.
+            let value = match variable.as_str() {
+                "GPG_AGENT_INFO" => format!("{}:0:1", listen),
+                "DOCKER_HOST" => format!("unix://{}", listen),
+                _ => listen,
+            };
+            (variable, value)
+        })
+        .collect()
+}
+
+fn builtin_variable(name: &str) -> Option<&'static str> {
+    match name {
+        "ssh-agent" => Some("SSH_AUTH_SOCK"),
+        "gpg-agent" => Some("GPG_AGENT_INFO"),
+        "docker" => Some("DOCKER_HOST"),
+        _ => None,
+    }
+}
+
+/// Print the commands setting each variable in `shell`'s syntax.
+pub fn print(config: &Config, shell: Shell) -> Result<(), Error> {
+    for (name, value) in variables(config)? {
+        println!("{}", shell.export(&name, &value));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables(config: &str) -> Vec<(String, String)> {
+        let config: Config = toml::from_str(config).unwrap();
+        variables_in(&config, Path::new("/run/user/1000"))
+    }
+
+    fn pairs(variables: &[(String, String)]) -> Vec<(&str, &str)> {
+        variables
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn points_at_the_builtin_bridges() {
+        assert_eq!(
+            pairs(&variables("")),
+            [
+                ("GPG_AGENT_INFO", "/run/user/1000/gnupg/S.gpg-agent:0:1"),
+                ("SSH_AUTH_SOCK", "/run/user/1000/ssh-agent.sock"),
+            ]
+        );
+    }
+
+    #[test]
+    fn configured_bridges_replace_builtin_ones() {
+        let variables = variables(
+            r#"
+            [bridges.keys]
+            listen = "/tmp/keys.sock"
+            target = { type = "pageant" }
+
+            [bridges.gpg-agent]
+            listen = "/tmp/gpg.sock"
+            target = { type = "assuan", path = 'C:\gnupg\S.gpg-agent' }
+
+            [bridges.docker]
+            listen = "/var/run/docker.sock"
+            target = { type = "named-pipe", path = '\\.\pipe\docker_engine' }
+            "#,
+        );
+        let mut variables = pairs(&variables);
+        variables.sort();
+        assert_eq!(
+            variables,
+            [
+                ("DOCKER_HOST", "unix:///var/run/docker.sock"),
+                ("GPG_AGENT_INFO", "/tmp/gpg.sock:0:1"),
+                ("SSH_AUTH_SOCK", "/tmp/keys.sock"),
+            ]
+        );
+    }
+
+    #[test]
+    fn exports_the_configured_variable() {
+        let variables = variables(
+            r#"
+            [bridges.language-server]
+            listen = "/tmp/language-server.sock"
+            env = "LANGUAGE_SERVER_SOCKET"
+            target = { type = "tcp", address = "127.0.0.1:9257" }
+
+            [bridges.database]
+            listen = "/tmp/database.sock"
+            target = { type = "tcp", address = "127.0.0.1:5432" }
+
+            [bridges.ssh-agent]
+            target = { type = "pageant" }
+            "#,
+        );
+        assert_eq!(
+            pairs(&variables),
+            [
+                ("GPG_AGENT_INFO", "/run/user/1000/gnupg/S.gpg-agent:0:1"),
+                ("SSH_AUTH_SOCK", "/run/user/1000/ssh-agent.sock"),
+                ("LANGUAGE_SERVER_SOCKET", "/tmp/language-server.sock"),
+            ]
+        );
+    }
+
+    #[test]
+    fn quotes_values() {
+        let value = r"/tmp/it's \here";
+        assert_eq!(
+            Shell::Posix.export("NAME", value),
+            r"export NAME='/tmp/it'\''s \here';"
+        );
+        assert_eq!(
+            Shell::Fish.export("NAME", value),
+            r"set -gx NAME '/tmp/it\'s \\here';"
+        );
+    }
+
+    #[test]
+    fn parses_shell_names() {
+        for name in ["bash", "zsh", "sh"] {
+            assert!(matches!(name.parse(), Ok(Shell::Posix)));
+        }
+        assert!(matches!("fish".parse(), Ok(Shell::Fish)));
+        assert!("csh".parse::<Shell>().is_err());
+    }
+}
@@ -0,0 +1,161 @@
+//! Locating the Windows gpg-agent's Assuan socket file.
+//!
+//! The socket lives in the GnuPG home directory, which differs between installs (Gpg4win uses
+//! `%APPDATA%\gnupg`, other builds `%LOCALAPPDATA%\gnupg`, and either can be moved through the
+//! registry). `gpgconf` knows the answer, so ask it first and only guess if it can't be run.
+
+use std::path::{Path, PathBuf};
+
+/// The name of the agent's socket file within the socket directory.
+const AGENT_SOCKET: &str = "S.gpg-agent";
+
+/// `gpgconf` executables to try, in order of preference.
+fn gpgconf_candidates() -> Vec<PathBuf> {
+    let mut candidates = vec![PathBuf::from("gpgconf")];
+    for var in ["ProgramFiles(x86)", "ProgramFiles"] {
+        if let Some(dir) = std::env::var_os(var) {
+            candidates.push(
+                Path::new(&dir)
+                    .join("GnuPG")
+                    .join("bin")
+                    .join("gpgconf.exe"),
+            );
+        }
+    }
+    candidates
+}
+
+/// The first `gpgconf` that can be run, falling back to relying on `PATH`.
+pub fn gpgconf() -> PathBuf {
+    gpgconf_candidates()
+        .into_iter()
+        .find(|gpgconf| {
+            std::process::Command::new(gpgconf)
+                .arg("--version")
+                .stdin(std::process::Stdio::null())
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::null())
+                .status()
+                .is_ok_and(|status| status.success())
+        })
+        .unwrap_or_else(|| PathBuf::from("gpgconf"))
+}
+
+/// The path of the agent's Assuan socket file, for the default GnuPG home directory or
+/// `homedir`.
+///
+/// With a `homedir` this is the socket file in that directory if there is one, otherwise (or if
+/// there's no `homedir`) `gpgconf --list-dirs agent-socket` if any `gpgconf` can be run. Failing
+/// that it's a guess: the socket file in `homedir`, or the first of `%APPDATA%\gnupg` and
+/// `%LOCALAPPDATA%\gnupg` that contains a socket file (or the latter if neither does, to give a
+/// sensible error message).
+pub fn agent_socket(homedir: Option<&Path>) -> PathBuf {
+    if let Some(homedir) = homedir {
+        let path = homedir.join(AGENT_SOCKET);
+        if path.exists() {
+            tracing::debug!(path = %path.display(), "Found agent socket in homedir");
+            return path;
+        }
+    }
+
+    for gpgconf in gpgconf_candidates() {
+        if let Some(path) = list_dirs(&gpgconf, homedir, "agent-socket") {
+            tracing::debug!(
+                gpgconf = %gpgconf.display(),
+                path = %path.display(),
+                "Found agent socket"
+            );
+            return path;
+        }
+    }
+
+    if let Some(homedir) = homedir {
+        let path = homedir.join(AGENT_SOCKET);
+        tracing::warn!(path = %path.display(), "Could not locate the agent socket, trying homedir");
+        return path;
+    }
+
+    let dirs = directories::BaseDirs::new();
+    let homedirs: Vec<PathBuf> = dirs
+        .iter()
+        .flat_map(|dirs| [dirs.data_dir(), dirs.data_local_dir()])
+        .map(|dir| dir.join("gnupg"))
+        .collect();
+    for homedir in &homedirs {
+        let path = homedir.join(AGENT_SOCKET);
+        if path.exists() {
+            tracing::debug!(path = %path.display(), "Guessed agent socket");
+            return path;
+        }
+    }
+    let path = homedirs.last().map_or_else(
+        || PathBuf::from(AGENT_SOCKET),
+        |homedir| homedir.join(AGENT_SOCKET),
+    );
+    tracing::warn!(path = %path.display(), "Could not locate the agent socket, trying the default");
+    path
+}
+
+/// Run `gpgconf [--homedir <homedir>] --list-dirs <name>`, returning `None` if it can't be run
+/// or fails.
+fn list_dirs(gpgconf: &Path, homedir: Option<&Path>, name: &str) -> Option<PathBuf> {
+    let output = std::process::Command::new(gpgconf)
+        .args(homedir_args(homedir))
+        .args(["--list-dirs", name])
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .output();
+    let output = match output {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            tracing::debug!(
+                gpgconf = %gpgconf.display(),
+                status = %output.status,
+                "gpgconf failed"
+            );
+            return None;
+        }
+        Err(e) => {
+            tracing::debug!(gpgconf = %gpgconf.display(), error = %e, "Could not run gpgconf");
+            return None;
+        }
+    };
+    let line = String::from_utf8_lossy(&output.stdout);
+    let line = line.lines().next()?.trim();
+    if line.is_empty() {
+        return None;
+    }
+    Some(PathBuf::from(unescape(line)))
+}
+
+/// The arguments that point GnuPG's tools at `homedir`, if any.
+pub fn homedir_args(homedir: Option<&Path>) -> Vec<std::ffi::OsString> {
+    match homedir {
+        Some(homedir) => vec!["--homedir".into(), homedir.into()],
+        None => Vec::new(),
+    }
+}
+
+/// Undo gpgconf's percent-escaping (e.g. `C%3a\Users` is `C:\Users`).
+fn unescape(escaped: &str) -> String {
+    let mut bytes = Vec::with_capacity(escaped.len());
+    let mut rest = escaped.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let decoded = (byte == b'%')
+            .then(|| tail.get(..2))
+            .flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match decoded {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
@@ -0,0 +1,279 @@
+//! Accepting connections from WSL2 over Hyper-V sockets.
+//!
+//! WSL2 distributions run in a lightweight VM that can connect straight to an `AF_HYPERV` socket
+//! listening on the Windows host (with `AF_VSOCK`, see the `vsock` target). Running
+//! `wsl-systemd.exe hyperv` and pointing the WSL side's bridges at it saves spawning a Windows
+//! process through interop for every connection.
+//!
+//! vsock port `N` is Hyper-V service ID `N-facb-11e6-bd58-64006a7986d3` (with `N` as 8 hex
+//! digits), which has to be registered, as an administrator, before a VM may connect to it:
+//!
+//! ```text
+//! $services = "HKLM:\SOFTWARE\Microsoft\Windows NT\CurrentVersion\Virtualization\" +
+//!     "GuestCommunicationServices"
+//! New-Item -Path $services -Name 00005000-facb-11e6-bd58-64006a7986d3 |
+//!     New-ItemProperty -Name ElementName -Value wsl-systemd
+//! ```
+//!
+//! Accepting is done on a dedicated thread per bridge (std/tokio's listeners can't make sense of
+//! an `AF_HYPERV` peer address), with the accepted sockets relayed on the tokio runtime.
+
+use bridge_core::shutdown::GRACE_PERIOD;
+use tokio::sync::mpsc;
+use tracing::Instrument as _;
+use windows::core::GUID;
+use windows::Win32::Networking::WinSock;
+use windows::Win32::System::Hypervisor::{
+    HV_GUID_VSOCK_TEMPLATE, HV_GUID_ZERO, HV_PROTOCOL_RAW, SOCKADDR_HV,
+};
+
+use crate::config::{Config, HyperV};
+use crate::limit::{ClientId, Limits, RateLimiter, Verdict, FULL_WARNING_INTERVAL};
+use crate::winsock::{self, last_error};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("No bridge named {0:?} in the configuration file")]
+    UnknownBridge(String),
+    #[error("Bridge {0:?} has no `hyperv` socket configured")]
+    NoHyperVSocket(String),
+    #[error("No bridges with a `hyperv` socket are configured")]
+    NoBridges,
+    #[error("Invalid VM ID {0:?} (expected a GUID)")]
+    VmId(String),
+    #[error("Failed to initialize Winsock")]
+    Startup(#[source] std::io::Error),
+    #[error("Failed to listen on Hyper-V socket {0:#x}")]
+    Bind(u32, #[source] std::io::Error),
+    #[error(transparent)]
+    Metrics(#[from] crate::metrics::Error),
+}
+
+/// Run the bridges named in `names` (or all bridges with a `hyperv` socket if empty) until the
+/// process is asked to shut down. `limits` override those in the configuration file.
+pub async fn run(
+    config: &Config,
+    names: &[String],
+    limits: Limits,
+    metrics: &crate::metrics::Options,
+) -> Result<(), Error> {
+    let selected: Vec<_> = if names.is_empty() {
+        config
+            .bridges
+            .iter()
+            .filter(|(_, bridge)| bridge.hyperv.is_some())
+            .collect()
+    } else {
+        names
+            .iter()
+            .map(|name| {
+                config
+                    .bridges
+                    .get_key_value(name)
+                    .ok_or_else(|| Error::UnknownBridge(name.clone()))
+            })
+            .collect::<Result<_, _>>()?
+    };
+    if selected.is_empty() {
+        return Err(Error::NoBridges);
+    }
+
+    winsock::startup().map_err(Error::Startup)?;
+    let stop = bridge_core::shutdown::on_signal();
+    crate::metrics::start(metrics, &stop).await?;
+    let mut tasks = tokio::task::JoinSet::new();
+    for (name, bridge) in selected {
+        let hyperv = bridge
+            .hyperv
+            .as_ref()
+            .ok_or_else(|| Error::NoHyperVSocket(name.clone()))?;
+        let listener = Listener::bind(hyperv)?;
+        tracing::info!(bridge = %name, port = hyperv.port, "Listening");
+
+        // Accepted sockets are passed from the accept thread to the runtime.
+        let (accepted, mut incoming) = mpsc::channel(16);
+        let socket = listener.0;
+        std::thread::spawn(move || listener.accept_loop(accepted));
+
+        let name = name.clone();
+        let target = std::sync::Arc::new((bridge.target.clone(), bridge.launch.clone()));
+        let options = bridge.relay_options();
+        let limits = limits.or(bridge.limits());
+        let metrics = crate::metrics::bridge(&name);
+        let stop = stop.clone();
+        tasks.spawn(async move {
+            let mut connections = tokio::task::JoinSet::new();
+            // Peer addresses aren't collected when accepting, so all connections count as coming
+            // from the same client.
+            let mut rate_limiter = limits.rate_limit.map(RateLimiter::new);
+            let mut warned_full: Option<std::time::Instant> = None;
+            loop {
+                let has_room = limits.has_room(connections.len());
+                if !has_room && warned_full.is_none_or(|at| at.elapsed() >= FULL_WARNING_INTERVAL) {
+                    warned_full = Some(std::time::Instant::now());
+                    tracing::warn!(
+                        bridge = %name,
+                        connections = connections.len(),
+                        "Too many connections, not accepting more until some close"
+                    );
+                }
+                tokio::select! {
+                    () = stop.cancelled() => break,
+                    client = incoming.recv(), if has_room => match client {
+                        Some(client) => {
+                            let verdict = rate_limiter
+                                .as_mut()
+                                .map_or(Verdict::Allow, |limiter| limiter.check(ClientId::Unknown));
+                            if let Verdict::Refuse { first } = verdict {
+                                if first {
+                                    tracing::warn!(
+                                        bridge = %name,
+                                        "Connections are being opened too quickly, refusing some"
+                                    );
+                                }
+                                continue;
+                            }
+                            let client = match tokio::net::TcpStream::from_std(client) {
+                                Ok(client) => client,
+                                Err(e) => {
+                                    tracing::error!(
+                                        bridge = %name,
+                                        error = %e,
+                                        "Failed to register a connection"
+                                    );
+                                    continue;
+                                }
+                            };
+                            let id = crate::relay::next_id();
+                            let span = tracing::info_span!("connection", bridge = %name, id);
+                            let target = std::sync::Arc::clone(&target);
+                            let stop = stop.clone();
+                            let metrics = std::sync::Arc::clone(&metrics);
+                            connections.spawn(
+                                async move {
+                                    let (target, launch) = &*target;
+                                    crate::relay::serve(
+                                        client,
+                                        id,
+                                        target,
+                                        launch.as_ref(),
+                                        options,
+                                        &stop,
+                                        &metrics,
+                                    )
+                                    .await
+                                }
+                                .instrument(span),
+                            );
+                        }
+                        None => break,
+                    },
+                    Some(_) = connections.join_next() => {}
+                }
+            }
+
+            // Closing the socket wakes the accept thread up, which then exits.
+            unsafe { WinSock::closesocket(socket) };
+            if !connections.is_empty() {
+                tracing::info!(
+                    bridge = %name,
+                    connections = connections.len(),
+                    grace_period = ?GRACE_PERIOD,
+                    "Waiting for connections to close"
+                );
+            }
+            bridge_core::shutdown::with_grace_period(&stop, async {
+                while connections.join_next().await.is_some() {}
+            })
+            .await;
+        });
+    }
+    while tasks.join_next().await.is_some() {}
+
+    Ok(())
+}
+
+/// A listening `AF_HYPERV` socket. It's closed by the task consuming its connections rather than
+/// when this is dropped, to interrupt a blocked `accept`.
+struct Listener(WinSock::SOCKET);
+
+impl Listener {
+    fn bind(hyperv: &HyperV) -> Result<Self, Error> {
+        let vm_id = match &hyperv.vm_id {
+            Some(vm_id) => parse_guid(vm_id).ok_or_else(|| Error::VmId(vm_id.clone()))?,
+            // Accept connections from any VM.
+            None => HV_GUID_ZERO,
+        };
+        let address = SOCKADDR_HV {
+            Family: WinSock::ADDRESS_FAMILY(WinSock::AF_HYPERV),
+            Reserved: 0,
+            VmId: vm_id,
+            ServiceId: service_id(hyperv.port),
+        };
+        unsafe {
+            let socket = WinSock::socket(
+                WinSock::AF_HYPERV.into(),
+                WinSock::SOCK_STREAM,
+                HV_PROTOCOL_RAW as i32,
+            );
+            if socket == WinSock::INVALID_SOCKET {
+                return Err(Error::Bind(hyperv.port, last_error()));
+            }
+            let bound = WinSock::bind(
+                socket,
+                &address as *const SOCKADDR_HV as *const WinSock::SOCKADDR,
+                std::mem::size_of::<SOCKADDR_HV>() as i32,
+            );
+            if bound == WinSock::SOCKET_ERROR
+                || WinSock::listen(socket, WinSock::SOMAXCONN as i32) == WinSock::SOCKET_ERROR
+            {
+                let e = last_error();
+                WinSock::closesocket(socket);
+                return Err(Error::Bind(hyperv.port, e));
+            }
+            Ok(Self(socket))
+        }
+    }
+
+    fn accept_loop(self, accepted: mpsc::Sender<std::net::TcpStream>) {
+        loop {
+            let socket = unsafe { WinSock::accept(self.0, None, None) };
+            if socket == WinSock::INVALID_SOCKET {
+                // Most likely the listener was closed to shut down.
+                tracing::debug!(error = %last_error(), "Stopped accepting connections");
+                return;
+            }
+            let client = unsafe { winsock::into_stream(socket) };
+            if let Err(e) = client.set_nonblocking(true) {
+                tracing::error!(error = %e, "Failed to make a connection non-blocking");
+                continue;
+            }
+            if accepted.blocking_send(client).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// The Hyper-V service ID corresponding to vsock port `port`.
+fn service_id(port: u32) -> GUID {
+    GUID::from_values(
+        port,
+        HV_GUID_VSOCK_TEMPLATE.data2,
+        HV_GUID_VSOCK_TEMPLATE.data3,
+        HV_GUID_VSOCK_TEMPLATE.data4,
+    )
+}
+
+/// Parse a GUID written as 32 hex digits, optionally with hyphens and braces.
+fn parse_guid(guid: &str) -> Option<GUID> {
+    let hex: String = guid
+        .trim_matches(|c| c == '{' || c == '}')
+        .chars()
+        .filter(|&c| c != '-')
+        .collect();
+    if hex.len() != 32 {
+        return None;
+    }
+    u128::from_str_radix(&hex, 16).ok().map(GUID::from_u128)
+}
@@ -0,0 +1,464 @@
+//! Generation of the systemd user units that expose the Windows-side helpers as sockets inside
+//! WSL.
+//!
+//! Each bridge is a pair of units: a `.socket` listening on a path under the user's runtime
+//! directory with `Accept = Yes`, and a templated `@.service` that systemd instantiates per
+//! connection with the accepted socket wired to the helper's stdin/stdout. With `--docker`, Docker
+//! Desktop's engine is exposed the same way.
+//!
+//! With `--daemon`, the configured bridges are instead served by a single `wsl-systemd.service`,
+//! socket-activated by a `wsl-systemd-<bridge>.socket` unit per bridge.
+
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+
+/// Where Docker Desktop's engine is exposed by default, which is where Docker clients look.
+const DOCKER_SOCKET: &str = "/run/docker-desktop.sock";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("An IO Error occurred writing {0}")]
+    IO(PathBuf, #[source] std::io::Error),
+    #[error("Could not determine the user's configuration directory")]
+    NoConfigDir,
+    #[error("Helper executable {0} does not exist (use --force to install anyway)")]
+    MissingHelper(PathBuf),
+    #[error("{0} already exists and differs from the generated unit (use --force to overwrite)")]
+    UnitExists(PathBuf),
+    #[error("Could not determine the path to wsl-systemd for the daemon unit")]
+    CurrentExe(#[source] std::io::Error),
+    #[error("Failed to run `systemctl --user daemon-reload`")]
+    DaemonReloadSpawn(#[source] std::io::Error),
+    #[error("`systemctl --user daemon-reload` failed: {0}")]
+    DaemonReload(std::process::ExitStatus),
+}
+
+#[derive(structopt::StructOpt, Debug)]
+pub struct Options {
+    /// Directory (as seen from inside WSL) containing `wsl-systemd.exe` and `pageant.exe`, e.g.
+    /// `/mnt/c/Users/me/bin`
+    #[structopt(long, parse(from_os_str))]
+    helper_dir: PathBuf,
+    /// Directory to write the units into [default: ~/.config/systemd/user]
+    #[structopt(long, parse(from_os_str))]
+    unit_dir: Option<PathBuf>,
+    /// Overwrite existing units and skip checking the helpers exist
+    #[structopt(long)]
+    force: bool,
+    /// Run `systemctl --user daemon-reload` once the units are written
+    #[structopt(long)]
+    daemon_reload: bool,
+    /// Serve the configured bridges from a single `wsl-systemd daemon` service, rather than a
+    /// socket unit per bridge
+    #[structopt(long)]
+    daemon: bool,
+    /// Have the daemon exit after this many seconds without any connections, to be started again
+    /// by its sockets on demand
+    #[structopt(long, value_name = "SECONDS", requires = "daemon")]
+    exit_idle_time: Option<u64>,
+    /// Also expose Docker Desktop's engine inside WSL, for `DOCKER_HOST=unix://<socket>`
+    #[structopt(long)]
+    docker: bool,
+    /// Where to expose Docker Desktop's engine, may use systemd specifiers (e.g. `%t/docker.sock`
+    /// if `/run` isn't writable by the user) [default: /run/docker-desktop.sock]
+    #[structopt(long, requires = "docker")]
+    docker_socket: Option<String>,
+}
+
+/// A forwarded agent socket and the helper invocation that services it.
+struct Bridge {
+    name: String,
+    description: String,
+    /// Value of `ListenStream`, may use systemd specifiers (e.g. `%t` for the runtime directory).
+    listen: String,
+    helper: &'static str,
+    args: String,
+    environment: Vec<String>,
+}
+
+impl Bridge {
+    fn builtin() -> Vec<Self> {
+        vec![
+            Self {
+                name: "gpg-agent".into(),
+                description: "GPG Agent".into(),
+                listen: "%t/gnupg/S.gpg-agent".into(),
+                helper: "wsl-systemd.exe",
+                args: " gpg".into(),
+                environment: Vec::new(),
+            },
+            Self {
+                name: "ssh-agent".into(),
+                description: "SSH Agent".into(),
+                listen: "%t/ssh-agent.sock".into(),
+                helper: "pageant.exe",
+                // The instance name identifies the peer, for `--confirm` dialogs.
+                args: " --client %i".into(),
+                environment: Vec::new(),
+            },
+        ]
+    }
+
+    /// Docker Desktop's engine, at `listen` rather than in the runtime directory so that it's
+    /// where Docker clients expect.
+    fn docker(listen: &str) -> Self {
+        Self {
+            name: "docker".into(),
+            description: "Docker Desktop".into(),
+            listen: listen.to_owned(),
+            helper: "wsl-systemd.exe",
+            args: " docker".into(),
+            environment: Vec::new(),
+        }
+    }
+
+    /// A bridge from the configuration file, which wsl-systemd.exe will need to read too, so pass
+    /// the path across interop (translated via `WSLENV`).
+    fn configured(name: &str, listen: &Path, config_path: &Path) -> Self {
+        Self {
+            name: name.to_owned(),
+            description: format!("{} Bridge", name),
+            listen: listen.display().to_string(),
+            helper: "wsl-systemd.exe",
+            args: format!(" bridge {}", name),
+            environment: vec![
+                format!("WSL_SYSTEMD_CONFIG={}", config_path.display()),
+                "WSLENV=WSL_SYSTEMD_CONFIG/p".to_owned(),
+            ],
+        }
+    }
+
+    fn socket_unit(&self) -> String {
+        format!(
+            "[Unit]
+Description = {description} Socket
+
+[Socket]
+ListenStream = {listen}
+SocketMode = 0600
+DirectoryMode = 0700
+Accept = Yes
+
+[Install]
+WantedBy = sockets.target
+",
+            description = self.description,
+            listen = self.listen,
+        )
+    }
+
+    fn service_unit(&self, helper_dir: &Path) -> String {
+        let environment: String = self
+            .environment
+            .iter()
+            .map(|var| format!("Environment = \"{}\"\n", var))
+            .collect();
+        // Instances are spawned per-connection with the accepted socket as stdio, so restarting
+        // one after a failure would just hand a closed socket to the new helper. Let the
+        // `.socket` unit spawn a fresh instance for the next client instead.
+        format!(
+            r#"[Unit]
+Description = {description} Socket Forwarder
+CollectMode = inactive-or-failed
+
+[Service]
+ExecStart = "{helper}"{args}
+{environment}StandardInput = socket
+StandardOutput = socket
+StandardError = journal
+Restart = no
+"#,
+            description = self.description,
+            helper = helper_dir.join(self.helper).display(),
+            args = self.args,
+            environment = environment,
+        )
+    }
+}
+
+/// The names and sockets of the built-in bridges, with the runtime directory (`%t`) expanded.
+pub fn builtin_sockets(runtime_dir: &Path) -> Vec<(String, PathBuf)> {
+    Bridge::builtin()
+        .into_iter()
+        .map(|bridge| {
+            let listen = runtime_dir.join(bridge.listen.trim_start_matches("%t/"));
+            (bridge.name, listen)
+        })
+        .collect()
+}
+
+/// Install units for the built-in bridges, plus any bridges in `config` that have a `listen`
+/// socket (which replace a built-in bridge of the same name).
+pub fn install(
+    options: &Options,
+    config: &Config,
+    config_path: Option<&Path>,
+) -> Result<(), Error> {
+    let unit_dir = match &options.unit_dir {
+        Some(dir) => dir.clone(),
+        None => directories::BaseDirs::new()
+            .ok_or(Error::NoConfigDir)?
+            .config_dir()
+            .join("systemd")
+            .join("user"),
+    };
+
+    let mut bridges = Bridge::builtin();
+    if options.docker {
+        let listen = options.docker_socket.as_deref();
+        bridges.push(Bridge::docker(listen.unwrap_or(DOCKER_SOCKET)));
+    }
+    let mut daemon_sockets = Vec::new();
+    if let Some(config_path) = config_path {
+        for (name, bridge) in &config.bridges {
+            if let Some(listen) = &bridge.listen {
+                bridges.retain(|b| &b.name != name);
+                if options.daemon {
+                    daemon_sockets.push((name, listen));
+                } else {
+                    bridges.push(Bridge::configured(name, listen, config_path));
+                }
+            }
+        }
+    }
+
+    if !options.force {
+        for bridge in &bridges {
+            let helper = options.helper_dir.join(bridge.helper);
+            if !helper.is_file() {
+                return Err(Error::MissingHelper(helper));
+            }
+        }
+    }
+
+    std::fs::create_dir_all(&unit_dir).map_err(|e| Error::IO(unit_dir.clone(), e))?;
+
+    for bridge in &bridges {
+        let socket = unit_dir.join(format!("{}.socket", bridge.name));
+        write_unit(&socket, &bridge.socket_unit(), options.force)?;
+        let service = unit_dir.join(format!("{}@.service", bridge.name));
+        write_unit(
+            &service,
+            &bridge.service_unit(&options.helper_dir),
+            options.force,
+        )?;
+    }
+
+    if options.daemon {
+        let exe = std::env::current_exe().map_err(Error::CurrentExe)?;
+        let service = unit_dir.join("wsl-systemd.service");
+        let unit = daemon_unit(&exe, config_path, options.exit_idle_time);
+        write_unit(&service, &unit, options.force)?;
+        for (name, listen) in daemon_sockets {
+            let socket = unit_dir.join(format!("wsl-systemd-{}.socket", name));
+            write_unit(&socket, &daemon_socket_unit(name, listen), options.force)?;
+        }
+    }
+
+    if options.daemon_reload {
+        let status = std::process::Command::new("systemctl")
+            .args(["--user", "daemon-reload"])
+            .status()
+            .map_err(Error::DaemonReloadSpawn)?;
+        if !status.success() {
+            return Err(Error::DaemonReload(status));
+        }
+    }
+
+    Ok(())
+}
+
+/// A long-running service for `wsl-systemd daemon`, which unlike the per-connection helpers is
+/// worth restarting if it falls over.
+fn daemon_unit(exe: &Path, config_path: Option<&Path>, exit_idle_time: Option<u64>) -> String {
+    let config = match config_path {
+        Some(path) => format!(" --config \"{}\"", path.display()),
+        None => String::new(),
+    };
+    let exit_idle_time = match exit_idle_time {
+        Some(secs) => format!(" --exit-idle-time {}", secs),
+        None => String::new(),
+    };
+    format!(
+        r#"[Unit]
+Description = WSL Bridge Daemon
+
+[Service]
+ExecStart = "{exe}"{config} daemon{exit_idle_time}
+ExecReload = kill -HUP $MAINPID
+StandardError = journal
+Restart = on-failure
+RestartSec = 1
+
+[Install]
+WantedBy = default.target
+"#,
+        exe = exe.display(),
+        config = config,
+        exit_idle_time = exit_idle_time,
+    )
+}
+
+/// A socket passed to `wsl-systemd.service` by systemd, starting it if it isn't running.
+fn daemon_socket_unit(name: &str, listen: &Path) -> String {
+    format!(
+        "[Unit]
+Description = {name} Bridge Socket
+
+[Socket]
+ListenStream = {listen}
+SocketMode = 0600
+DirectoryMode = 0700
+Service = wsl-systemd.service
+
+[Install]
+WantedBy = sockets.target
+",
+        name = name,
+        listen = listen.display(),
+    )
+}
+
+fn write_unit(path: &Path, contents: &str, force: bool) -> Result<(), Error> {
+    match std::fs::read_to_string(path) {
+        Ok(existing) if existing == contents => {
+            eprintln!("{} is up to date", path.display());
+            return Ok(());
+        }
+        Ok(_) if !force => return Err(Error::UnitExists(path.to_owned())),
+        _ => {}
+    }
+    eprintln!("Writing {}", path.display());
+    std::fs::write(path, contents).map_err(|e| Error::IO(path.to_owned(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt as _;
+
+    use super::*;
+
+    fn options(helper_dir: &Path, unit_dir: &Path, extra: &[&str]) -> Options {
+        let args = ["install", "--helper-dir"]
+            .into_iter()
+            .map(Into::into)
+            .chain([helper_dir.as_os_str().to_owned()])
+            .chain(["--unit-dir".into(), unit_dir.as_os_str().to_owned()])
+            .chain(extra.iter().map(Into::into));
+        Options::from_iter(args)
+    }
+
+    fn read(unit_dir: &Path, name: &str) -> String {
+        std::fs::read_to_string(unit_dir.join(name)).unwrap()
+    }
+
+    #[test]
+    fn writes_a_socket_and_service_per_builtin_bridge() {
+        let dir = tempfile::tempdir().unwrap();
+        let helper_dir = Path::new("/mnt/c/Users/me/bin");
+        let unit_dir = dir.path().join("units");
+        install(
+            &options(helper_dir, &unit_dir, &["--force"]),
+            &Config::default(),
+            None,
+        )
+        .unwrap();
+
+        let socket = read(&unit_dir, "ssh-agent.socket");
+        assert!(socket.contains("ListenStream = %t/ssh-agent.sock\n"));
+        assert!(socket.contains("Accept = Yes\n"));
+        let service = read(&unit_dir, "ssh-agent@.service");
+        assert!(service.contains("ExecStart = \"/mnt/c/Users/me/bin/pageant.exe\" --client %i\n"));
+        assert!(service.contains("StandardInput = socket\n"));
+        let service = read(&unit_dir, "gpg-agent@.service");
+        assert!(service.contains("ExecStart = \"/mnt/c/Users/me/bin/wsl-systemd.exe\" gpg\n"));
+        assert!(!unit_dir.join("docker.socket").exists());
+        assert!(!unit_dir.join("wsl-systemd.service").exists());
+    }
+
+    #[test]
+    fn adds_docker_and_configured_bridges_in_place_of_builtin_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let config: Config = toml::from_str(
+            r#"
+            [bridges.ssh-agent]
+            listen = "/run/user/1000/agent.sock"
+            target = { type = "pageant" }
+            "#,
+        )
+        .unwrap();
+        let config_path = Path::new("/home/me/bridges.toml");
+        install(
+            &options(dir.path(), dir.path(), &["--force", "--docker"]),
+            &config,
+            Some(config_path),
+        )
+        .unwrap();
+
+        let socket = read(dir.path(), "ssh-agent.socket");
+        assert!(socket.contains("ListenStream = /run/user/1000/agent.sock\n"));
+        let service = read(dir.path(), "ssh-agent@.service");
+        assert!(service.contains("\" bridge ssh-agent\n"));
+        assert!(service.contains("Environment = \"WSL_SYSTEMD_CONFIG=/home/me/bridges.toml\"\n"));
+        let socket = read(dir.path(), "docker.socket");
+        assert!(socket.contains("ListenStream = /run/docker-desktop.sock\n"));
+    }
+
+    #[test]
+    fn daemon_serves_configured_bridges() {
+        let dir = tempfile::tempdir().unwrap();
+        let config: Config = toml::from_str(
+            r#"
+            [bridges.docker]
+            listen = "/run/user/1000/docker.sock"
+            target = { type = "named-pipe", path = '\\.\pipe\docker_engine' }
+            "#,
+        )
+        .unwrap();
+        let extra = ["--force", "--daemon", "--exit-idle-time", "60"];
+        install(
+            &options(dir.path(), dir.path(), &extra),
+            &config,
+            Some(Path::new("/home/me/bridges.toml")),
+        )
+        .unwrap();
+
+        let service = read(dir.path(), "wsl-systemd.service");
+        assert!(
+            service.contains(" --config \"/home/me/bridges.toml\" daemon --exit-idle-time 60\n")
+        );
+        let socket = read(dir.path(), "wsl-systemd-docker.socket");
+        assert!(socket.contains("ListenStream = /run/user/1000/docker.sock\n"));
+        assert!(socket.contains("Service = wsl-systemd.service\n"));
+        assert!(!dir.path().join("docker@.service").exists());
+    }
+
+    #[test]
+    fn leaves_up_to_date_units_and_refuses_to_overwrite_changed_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let helper_dir = dir.path().join("bin");
+        let unit_dir = dir.path().join("units");
+        let options = options(&helper_dir, &unit_dir, &[]);
+        match install(&options, &Config::default(), None) {
+            Err(Error::MissingHelper(helper)) => assert!(helper.starts_with(&helper_dir)),
+            other => panic!("expected a missing helper, got {:?}", other),
+        }
+
+        std::fs::create_dir(&helper_dir).unwrap();
+        for helper in ["wsl-systemd.exe", "pageant.exe"] {
+            std::fs::write(helper_dir.join(helper), "").unwrap();
+        }
+        install(&options, &Config::default(), None).unwrap();
+        // Running again finds every unit up to date.
+        install(&options, &Config::default(), None).unwrap();
+
+        let socket = unit_dir.join("ssh-agent.socket");
+        std::fs::write(&socket, "edited").unwrap();
+        match install(&options, &Config::default(), None) {
+            Err(Error::UnitExists(path)) => assert_eq!(path, socket),
+            other => panic!("expected an existing unit, got {:?}", other),
+        }
+        assert_eq!(std::fs::read_to_string(&socket).unwrap(), "edited");
+    }
+}
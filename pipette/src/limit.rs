@@ -0,0 +1,209 @@
+//! Limits on the connections a listening bridge accepts, so a runaway client can't open an
+//! unbounded number of connections to the target (or spawn unbounded helper processes).
+//!
+//! `max-connections` caps the connections open at once: beyond it, new clients wait in the
+//! listen backlog until others close. `rate-limit` caps how many connections each client (a user,
+//! for Unix sockets, or an address, for TCP) may open per second, allowing bursts of up to that
+//! many. Connections over the rate are closed as soon as they're accepted.
+
+use std::collections::HashMap;
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// How often to repeat the warning that a bridge has hit `max-connections`, while it's at it.
+pub const FULL_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(structopt::StructOpt, Debug, Default, Clone, Copy)]
+pub struct Limits {
+    /// Serve at most this many connections to each bridge at once, overriding `max-connections`
+    /// in the configuration file [default: unlimited]
+    #[structopt(long)]
+    pub max_connections: Option<NonZeroUsize>,
+    /// Accept at most this many new connections per second from each client, overriding
+    /// `rate-limit` in the configuration file [default: unlimited]
+    #[structopt(long, value_name = "PER_SECOND")]
+    pub rate_limit: Option<NonZeroU32>,
+}
+
+impl Limits {
+    /// These limits, falling back to `defaults` for any that aren't set.
+    pub fn or(self, defaults: Self) -> Self {
+        Self {
+            max_connections: self.max_connections.or(defaults.max_connections),
+            rate_limit: self.rate_limit.or(defaults.rate_limit),
+        }
+    }
+
+    /// Whether `open` connections leave room for another.
+    pub fn has_room(&self, open: usize) -> bool {
+        self.max_connections.is_none_or(|max| open < max.get())
+    }
+}
+
+/// Who a connection came from, as far as rate limiting goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ClientId {
+    User(u32),
+    Address(std::net::IpAddr),
+    /// A client that can't be told apart from any other, e.g. a VM over a Hyper-V socket.
+    Unknown,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// Over the limit, `first` if the client was within it until now.
+    Refuse {
+        first: bool,
+    },
+}
+
+/// A token bucket per client, each holding up to `rate` tokens and refilled at `rate` per
+/// second.
+pub struct RateLimiter {
+    rate: f64,
+    buckets: HashMap<ClientId, Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    refused: bool,
+}
+
+/// Past this many clients, those that are back to a full bucket are forgotten.
+const MAX_BUCKETS: usize = 256;
+
+impl RateLimiter {
+    pub fn new(rate: NonZeroU32) -> Self {
+        Self {
+            rate: rate.get().into(),
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Decide whether `client` may open another connection now.
+    pub fn check(&mut self, client: ClientId) -> Verdict {
+        let now = Instant::now();
+        let rate = self.rate;
+        let refill = |bucket: &mut Bucket| {
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+            bucket.updated = now;
+        };
+        if self.buckets.len() >= MAX_BUCKETS {
+            self.buckets.retain(|_, bucket| {
+                refill(bucket);
+                bucket.tokens < rate
+            });
+        }
+
+        let bucket = self.buckets.entry(client).or_insert(Bucket {
+            tokens: rate,
+            updated: now,
+            refused: false,
+        });
+        refill(bucket);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.refused = false;
+            Verdict::Allow
+        } else {
+            let first = !bucket.refused;
+            bucket.refused = true;
+            Verdict::Refuse { first }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: ClientId = ClientId::User(1000);
+    const BOB: ClientId = ClientId::User(1001);
+
+    fn limiter(rate: u32) -> RateLimiter {
+        RateLimiter::new(NonZeroU32::new(rate).unwrap())
+    }
+
+    #[test]
+    fn falls_back_to_defaults() {
+        let defaults = Limits {
+            max_connections: NonZeroUsize::new(4),
+            rate_limit: NonZeroU32::new(10),
+        };
+        let limits = Limits {
+            max_connections: NonZeroUsize::new(2),
+            rate_limit: None,
+        }
+        .or(defaults);
+        assert_eq!(limits.max_connections, NonZeroUsize::new(2));
+        assert_eq!(limits.rate_limit, NonZeroU32::new(10));
+        assert!(limits.has_room(1));
+        assert!(!limits.has_room(2));
+        assert!(Limits::default().has_room(usize::MAX));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn allows_a_burst_then_refuses() {
+        let mut limiter = limiter(3);
+        for _ in 0..3 {
+            assert_eq!(limiter.check(ALICE), Verdict::Allow);
+        }
+        assert_eq!(limiter.check(ALICE), Verdict::Refuse { first: true });
+        assert_eq!(limiter.check(ALICE), Verdict::Refuse { first: false });
+        // Other clients have their own buckets.
+        assert_eq!(limiter.check(BOB), Verdict::Allow);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refills_at_the_rate() {
+        let mut limiter = limiter(2);
+        assert_eq!(limiter.check(ALICE), Verdict::Allow);
+        assert_eq!(limiter.check(ALICE), Verdict::Allow);
+        assert_eq!(limiter.check(ALICE), Verdict::Refuse { first: true });
+
+        tokio::time::advance(Duration::from_millis(400)).await;
+        assert_eq!(limiter.check(ALICE), Verdict::Refuse { first: false });
+        tokio::time::advance(Duration::from_millis(100)).await;
+        assert_eq!(limiter.check(ALICE), Verdict::Allow);
+        assert_eq!(limiter.check(ALICE), Verdict::Refuse { first: true });
+
+        // A long wait only refills the bucket to the rate.
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(limiter.check(ALICE), Verdict::Allow);
+        assert_eq!(limiter.check(ALICE), Verdict::Allow);
+        assert_eq!(limiter.check(ALICE), Verdict::Refuse { first: true });
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn forgets_clients_with_full_buckets() {
+        let mut limiter = limiter(1);
+        for user in 0..MAX_BUCKETS as u32 {
+            assert_eq!(limiter.check(ClientId::User(user)), Verdict::Allow);
+        }
+        assert_eq!(limiter.buckets.len(), MAX_BUCKETS);
+
+        // Once their buckets are full again, they're dropped to make room.
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(limiter.check(ALICE), Verdict::Allow);
+        assert_eq!(limiter.buckets.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn remembers_clients_still_being_limited() {
+        let mut limiter = limiter(1);
+        for user in 0..MAX_BUCKETS as u32 {
+            assert_eq!(limiter.check(ClientId::User(user)), Verdict::Allow);
+        }
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert_eq!(
+            limiter.check(ClientId::User(0)),
+            Verdict::Refuse { first: true }
+        );
+        assert_eq!(limiter.buckets.len(), MAX_BUCKETS);
+    }
+}
@@ -0,0 +1,82 @@
+//! Logging to stderr (stdout may well be carrying the bridged stream), with a filter that can be
+//! changed when `wsl-systemd daemon` reloads its configuration.
+
+use std::sync::OnceLock;
+
+use tracing_subscriber::layer::SubscriberExt as _;
+use tracing_subscriber::util::SubscriberInitExt as _;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::config::LogFormat;
+
+/// What's needed to rebuild the filter with a new level from the configuration file.
+struct Reload {
+    handle: reload::Handle<EnvFilter, Registry>,
+    /// `--log-level` or `RUST_LOG`, which take precedence over the configuration file.
+    overridden: bool,
+    trace_wire: bool,
+}
+
+static RELOAD: OnceLock<Reload> = OnceLock::new();
+
+/// Start logging, filtered by the first of `--log-level`, `RUST_LOG` or the configuration file
+/// that's set, plus the wire traces if `trace_wire`.
+pub fn init(
+    cli_level: Option<&str>,
+    config_level: Option<&str>,
+    format: LogFormat,
+    trace_wire: bool,
+) -> Result<(), crate::Error> {
+    let env_level = std::env::var(EnvFilter::DEFAULT_ENV).ok();
+    let overriding = cli_level.or(env_level.as_deref());
+    let (filter, handle) = reload::Layer::new(filter(overriding.or(config_level), trace_wire)?);
+    let text = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    let json = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .json()
+        .flatten_event(true);
+    let (text, json) = match format {
+        LogFormat::Text => (Some(text), None),
+        LogFormat::Json => (None, Some(json)),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(text)
+        .with(json)
+        .init();
+    let _ = RELOAD.set(Reload {
+        handle,
+        overridden: overriding.is_some(),
+        trace_wire,
+    });
+    Ok(())
+}
+
+/// Switch to the configuration file's new log level, if nothing's overriding it.
+pub fn reload(config_level: Option<&str>) {
+    let Some(reload) = RELOAD.get() else {
+        return;
+    };
+    if reload.overridden {
+        return;
+    }
+    match filter(config_level, reload.trace_wire) {
+        Ok(filter) => {
+            if let Err(e) = reload.handle.reload(filter) {
+                tracing::warn!(error = %e, "Failed to change the log level");
+            }
+        }
+        Err(e) => tracing::warn!(error = %e, "Keeping the old log level"),
+    }
+}
+
+fn filter(level: Option<&str>, trace_wire: bool) -> Result<EnvFilter, crate::Error> {
+    let level = level.unwrap_or("warn");
+    let mut filter =
+        EnvFilter::try_new(level).map_err(|e| crate::Error::LogLevel(level.to_owned(), e))?;
+    if trace_wire {
+        let directive = format!("{}=trace", crate::trace::TARGET);
+        filter = filter.add_directive(directive.parse().expect("valid directive"));
+    }
+    Ok(filter)
+}
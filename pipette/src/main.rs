@@ -1,149 +1,485 @@
-use std::io::Read as _;
-use std::io::Write as _;
-use std::sync::Arc;
+use std::path::PathBuf;
+
+use bridge_core::{relay::Options, shutdown};
+
+#[cfg(unix)]
+mod activation;
+mod agent;
+mod auth;
+mod compat;
+mod config;
+mod credentials;
+mod daemon;
+mod doctor;
+mod endpoint;
+#[cfg(unix)]
+mod env;
+mod gnupg;
+#[cfg(windows)]
+mod hyperv;
+#[cfg(unix)]
+mod install;
+mod limit;
+mod logging;
+mod metrics;
+mod mux;
+mod pageant;
+mod pinentry;
+#[cfg(windows)]
+mod pipe;
+mod relay;
+#[cfg(unix)]
+mod runtime_dir;
+#[cfg(unix)]
+mod ssh_pageant;
+mod trace;
+#[cfg(target_os = "linux")]
+mod vsock;
+#[cfg(windows)]
+mod winsock;
+#[cfg(target_os = "linux")]
+mod wsl;
+
+#[derive(thiserror::Error, Debug)]
+enum Error {
+    #[error(transparent)]
+    Config(#[from] config::Error),
+    #[error(transparent)]
+    Assuan(#[from] bridge_core::assuan::Error),
+    #[error(transparent)]
+    Agent(#[from] agent::Error),
+    #[error(transparent)]
+    Auth(#[from] auth::Error),
+    #[cfg(unix)]
+    #[error(transparent)]
+    SshPageant(#[from] ssh_pageant::Error),
+    #[cfg(unix)]
+    #[error(transparent)]
+    Install(#[from] install::Error),
+    #[error(transparent)]
+    Daemon(#[from] daemon::Error),
+    #[error(transparent)]
+    Doctor(#[from] doctor::Error),
+    #[cfg(unix)]
+    #[error(transparent)]
+    Env(#[from] env::Error),
+    #[cfg(windows)]
+    #[error(transparent)]
+    HyperV(#[from] hyperv::Error),
+    #[error("Invalid log level {0:?}")]
+    LogLevel(String, #[source] tracing_subscriber::filter::ParseError),
+    #[error("No bridge named {0:?} in the configuration file")]
+    UnknownBridge(String),
+    #[error("Failed to connect to {0}")]
+    Connect(String, #[source] std::io::Error),
+    #[error("Named pipe {0} can only be reached from Windows")]
+    NamedPipeUnsupported(PathBuf),
+    #[error("vsock port {0:#x} can only be reached from inside WSL2")]
+    VsockUnsupported(u32),
+    #[error("Failed to launch {0}")]
+    Launch(String, #[source] std::io::Error),
+    #[error("{0} failed to start the target ({1})")]
+    LaunchFailed(String, std::process::ExitStatus),
+}
 
 #[derive(structopt::StructOpt, Debug)]
 struct Args {
+    /// Path to the bridge configuration file [default: <config dir>/wsl-systemd/bridges.toml]
+    ///
+    /// When wsl-systemd.exe is launched from inside WSL, add `WSL_SYSTEMD_CONFIG/p` to `WSLENV` to
+    /// have the path translated for the Windows side.
+    #[structopt(long, env = "WSL_SYSTEMD_CONFIG", parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Log filter, e.g. `debug` or `wsl_systemd::daemon=trace` (overrides `RUST_LOG` and
+    /// `log-level` in the configuration file) [default: warn]
+    #[structopt(long)]
+    log_level: Option<String>,
+    /// Log output format, `text` or `json` (overrides `log-format` in the configuration file)
+    /// [default: text]
+    #[structopt(long)]
+    log_format: Option<config::LogFormat>,
+    /// Include secret material (nonces) in trace-level logs
+    #[structopt(long)]
+    log_secrets: bool,
+    #[structopt(flatten)]
+    trace: trace::Options,
+    /// Where wsl-systemd is running, which decides how Windows is reached: `none`, `wsl1`, `wsl2`
+    /// or `wsl2-mirrored` (networking) [default: detected]
+    #[cfg(target_os = "linux")]
+    #[structopt(long)]
+    wsl: Option<wsl::Environment>,
     #[structopt(subcommand)]
     mode: Mode,
 }
 
+/// Where Docker Desktop serves the Docker API on Windows.
+const DOCKER_PIPE: &str = r"\\.\pipe\docker_engine";
+
+/// Image layers, build contexts and `docker cp` archives are streamed through the relay, so a
+/// bigger buffer than the default saves a lot of round trips.
+const DOCKER_BUFFER_SIZE: usize = 1024 * 1024;
+
 #[derive(structopt::StructOpt, Debug)]
 enum Mode {
-    GpgAgent,
+    /// Relay stdin/stdout to Pageant, through the `pageant.exe` helper (found as for `pageant`
+    /// targets)
+    Agent {
+        /// The helper to run [default: found in the usual places]
+        #[structopt(long, parse(from_os_str))]
+        program: Option<PathBuf>,
+        /// Arguments for the helper, e.g. `-- --confirm`
+        #[structopt(last = true)]
+        args: Vec<String>,
+        #[structopt(flatten)]
+        relay: RelayArgs,
+    },
+    /// Relay stdin/stdout to gpg-agent, through its Assuan socket file
+    #[structopt(alias = "gpg-agent")]
+    Gpg {
+        /// Run `gpgconf --launch gpg-agent` if the agent isn't running
+        #[structopt(long)]
+        launch: bool,
+        /// GnuPG home (or socket) directory of the agent to connect to [default: as reported by
+        /// gpgconf]
+        #[structopt(long, env = "GNUPGHOME", parse(from_os_str))]
+        gnupg_home: Option<PathBuf>,
+        #[structopt(flatten)]
+        relay: RelayArgs,
+    },
+    /// Relay stdin/stdout to a Windows pinentry, for gpg-agent's `pinentry-program` (also run
+    /// through a symlink named `pinentry...`)
+    Pinentry {
+        /// The Windows pinentry to run [default: Gpg4win's or GnuPG's]
+        #[structopt(long, env = pinentry::ENV, parse(from_os_str))]
+        program: Option<PathBuf>,
+        #[structopt(flatten)]
+        relay: RelayArgs,
+    },
+    /// Stand in for wsl2-ssh-pageant.exe, taking its arguments (also run through a copy or symlink
+    /// named `wsl2-ssh-pageant...`)
+    Wsl2SshPageant(compat::Wsl2SshPageant),
+    /// Stand in for ssh-pageant, taking its arguments (also run through a symlink named
+    /// `ssh-pageant...`)
+    #[cfg(unix)]
+    SshPageant(ssh_pageant::SshPageant),
+    /// Relay stdin/stdout to Docker Desktop's engine, for a Docker socket inside WSL
+    Docker {
+        /// The engine's named pipe
+        #[structopt(long, default_value = DOCKER_PIPE, parse(from_os_str))]
+        pipe: PathBuf,
+        #[structopt(flatten)]
+        relay: RelayArgs,
+    },
+    /// Relay stdin/stdout to a Windows named pipe (from the Windows side of WSL, e.g. run through
+    /// interop)
+    Pipe {
+        /// The pipe, e.g. `\\.\pipe\openssh-ssh-agent`
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        #[structopt(flatten)]
+        relay: RelayArgs,
+    },
+    /// Relay stdin/stdout to a TCP socket, e.g. a language server or debugger listening on
+    /// Windows
+    Tcp {
+        /// The address to connect to, as `host:port`
+        #[structopt(long)]
+        target: String,
+        /// Authenticate to a `listen-tcp` socket with this pre-shared key
+        #[structopt(long, parse(from_os_str))]
+        key_file: Option<PathBuf>,
+        #[structopt(flatten)]
+        relay: RelayArgs,
+    },
+    /// Relay stdin/stdout to a bridge declared in the configuration file
+    Bridge {
+        name: String,
+        #[structopt(flatten)]
+        relay: RelayArgs,
+    },
+    /// Listen on the `listen` (Unix only) and `listen-tcp` sockets of each configured bridge,
+    /// relaying every connection to the bridge's target
+    Daemon {
+        /// The bridges to run [default: all bridges with a socket to listen on]
+        bridges: Vec<String>,
+        #[structopt(flatten)]
+        limits: limit::Limits,
+        #[structopt(flatten)]
+        metrics: metrics::Options,
+        /// Exit after this many seconds without any connections, for a socket-activated daemon
+        /// that systemd starts again on demand [default: never]
+        #[structopt(long, value_name = "SECONDS")]
+        exit_idle_time: Option<u64>,
+    },
+    /// Listen on the Hyper-V socket of each bridge with `hyperv` configured, relaying
+    /// connections from WSL2 VMs to the bridge's target
+    #[cfg(windows)]
+    Hyperv {
+        /// The bridges to run [default: all bridges with `hyperv` configured]
+        bridges: Vec<String>,
+        #[structopt(flatten)]
+        limits: limit::Limits,
+        #[structopt(flatten)]
+        metrics: metrics::Options,
+    },
+    /// Print shell commands pointing `SSH_AUTH_SOCK` (etc.) at the bridges' sockets, for
+    /// `eval "$(wsl-systemd env)"`
+    #[cfg(unix)]
+    Env {
+        /// The shell to print commands for, `bash`, `zsh` or `fish` [default: from `$SHELL`]
+        #[structopt(long)]
+        shell: Option<env::Shell>,
+    },
+    /// List the keys held by the ssh-agent behind a bridge, to check the bridge works
+    ListKeys { name: String },
+    /// Serve the bridges in the configuration file to channels multiplexed over stdin/stdout, for
+    /// `mux` targets on the other side of WSL
+    Mux,
+    /// Check the configuration, the environment and each bridge's target, reporting what's wrong
+    Doctor,
+    /// Write systemd user units that expose the helpers as sockets inside WSL
+    #[cfg(unix)]
+    Install(install::Options),
 }
 
-fn main() {
-    let args = <Args as structopt::StructOpt>::from_args();
-    eprintln!("{:?}", args);
-
-    let sock = match args.mode {
-        Mode::GpgAgent => {
-            let dirs = directories::BaseDirs::new().unwrap();
-            let app_data = dirs.data_local_dir();
-            let gnupg_data = app_data.join("gnupg");
-            let assuan = gnupg_data.join("S.gpg-agent");
-            assuan::Assuan::new(&assuan).unwrap()
-        }
-    };
-    attach_to_tty(sock);
-}
-
-trait Split {
-    type Read: Send + Sync + 'static;
-    type Write: Send + Sync + 'static;
-    fn split(self) -> (Arc<Self::Read>, Arc<Self::Write>);
+// Relay options, overriding those in the configuration file. (Not a doc comment, structopt would
+// use it as the description of the subcommands it's flattened into.)
+#[derive(structopt::StructOpt, Debug)]
+struct RelayArgs {
+    /// Size of the relay buffers [default: 65536]
+    #[structopt(long)]
+    buffer_size: Option<std::num::NonZeroUsize>,
+    /// Close connections that have carried no traffic for this many seconds [default: never]
+    #[structopt(long, value_name = "SECONDS")]
+    idle_timeout: Option<u64>,
 }
 
-fn attach_to_tty<S: Split>(splittable: S)
-where
-    for<'a> &'a S::Read: std::io::Read,
-    for<'a> &'a S::Write: std::io::Write,
-{
-    let (read, write) = splittable.split();
-    let terminated = Arc::new(std::sync::atomic::AtomicBool::new(false));
-    let bob = std::thread::spawn({
-        let terminated = Arc::clone(&terminated);
-        move || loop {
-            if terminated.load(std::sync::atomic::Ordering::Relaxed) {
-                break;
-            }
-            let mut buf = [0; 128];
-            match read.as_ref().read(&mut buf) {
-                Ok(0) => {
-                    eprintln!("sock closed");
-                    std::process::exit(0);
-                }
-                Ok(len) => {
-                    std::io::stdout().write_all(&buf[..len]).unwrap();
-                }
-                Err(e) => eprintln!("{}", e),
-            };
+impl RelayArgs {
+    fn apply(&self, mut options: Options) -> Options {
+        if let Some(buffer_size) = self.buffer_size {
+            options.buffer_size = buffer_size.get();
         }
-    });
-    let fred = std::thread::spawn(move || loop {
-        if terminated.load(std::sync::atomic::Ordering::Relaxed) {
-            break;
+        if let Some(idle_timeout) = self.idle_timeout {
+            options.idle_timeout = Some(std::time::Duration::from_secs(idle_timeout));
         }
-        let mut buf = [0; 128];
-        match std::io::stdin().read(&mut buf) {
-            Ok(0) => {
-                eprintln!("stdin closed");
-                std::process::exit(0);
-            }
-            Ok(len) => {
-                write.as_ref().write_all(&buf[..len]).unwrap();
-            }
-            Err(e) => panic!("{}", e),
-        };
-    });
-    bob.join().unwrap();
-    fred.join().unwrap();
+        options
+    }
 }
 
-mod assuan {
-    use std::io::BufRead as _;
-    use std::io::Read as _;
-    use std::io::Write as _;
-    use std::sync::Arc;
-
-    #[derive(thiserror::Error, Debug)]
-    pub enum Error {
-        #[error("An IO Error occurred opening the Assuan file")]
-        IO(#[from] std::io::Error),
-        #[error("Failed to parse port from the Assan file")]
-        PortParse(#[from] std::num::ParseIntError),
-        #[error("Failed to parse nonce from the Assuan file")]
-        NonceParse,
+fn main() {
+    // gpg-agent runs its pinentry with its own arguments (e.g. `--display`), which don't apply.
+    let args = if pinentry::invoked_as() {
+        let argv0 = std::env::args_os().next().unwrap_or_default();
+        <Args as structopt::StructOpt>::from_iter([argv0, "pinentry".into()])
+    } else if let Some(args) = compat::args() {
+        <Args as structopt::StructOpt>::from_iter(args)
+    } else {
+        <Args as structopt::StructOpt>::from_args()
+    };
+
+    if let Err(e) = run(args) {
+        eprintln!("{}", e);
+        let mut source = std::error::Error::source(&e);
+        while let Some(e) = source {
+            eprintln!("  caused by: {}", e);
+            source = e.source();
+        }
+        std::process::exit(1);
     }
+}
 
-    pub struct Assuan {
-        sock: std::net::TcpStream,
+fn run(args: Args) -> Result<(), Error> {
+    let config = config::Config::load(args.config.as_deref())?;
+    // The helpers' own ways of asking for more logging.
+    let compat_level = match &args.mode {
+        Mode::Wsl2SshPageant(options) => options.log_level(),
+        #[cfg(unix)]
+        Mode::SshPageant(options) => options.log_level(),
+        _ => None,
+    };
+    logging::init(
+        args.log_level.as_deref().or(compat_level),
+        config.log_level.as_deref(),
+        args.log_format.or(config.log_format).unwrap_or_default(),
+        args.trace.trace_wire,
+    )?;
+    bridge_core::log_secrets(args.log_secrets);
+    trace::set(args.trace);
+    #[cfg(target_os = "linux")]
+    if let Some(environment) = args.wsl {
+        wsl::set(environment);
     }
+    tracing::debug!("{:?}", args);
 
-    impl Assuan {
-        pub fn new(path: &std::path::Path) -> Result<Self, Error> {
-            // Open the Assuan file
-            eprintln!("Opening {}", path.display());
-            let data_file = std::fs::File::open(path)?;
-            let mut data_file = std::io::BufReader::new(data_file);
-
-            // Format is:
-            //
-            // ```text
-            // aaaa
-            // bbbbbbbbbbbbbbbb
-            // ```
-            //
-            // Where `aaaa` is the port on localhost to connect to and `bbbbbbbbbbbb` is a 16-byte
-            // nonce to authenticate the connection.
-            let mut port = String::new();
-            data_file.read_line(&mut port)?;
-            let mut nonce = [0u8; 16];
-            let nonce_len = data_file.read(&mut nonce)?;
-            if nonce_len != 16 {
-                return Err(Error::NonceParse);
-            }
-            let port: u16 = port.trim().parse()?;
-
-            eprintln!(
-                "Discovered assuan socket at 127.0.0.1:{} ({:?})",
-                port, nonce
-            );
-
-            let mut sock = std::net::TcpStream::connect(("127.0.0.1", port))?;
-            sock.write_all(&nonce[..])?;
-
-            Ok(Self { sock })
+    match args.mode {
+        Mode::Agent {
+            program,
+            args,
+            relay,
+        } => block_on(connect(
+            &config::Target::Pageant { program, args },
+            None,
+            relay.apply(Options::default()),
+        )),
+        Mode::Gpg {
+            launch,
+            gnupg_home,
+            relay,
+        } => {
+            let homedir = gnupg_home.as_deref();
+            block_on(connect(
+                &config::Target::Assuan {
+                    path: gnupg::agent_socket(homedir),
+                },
+                launch.then(|| config::Launch::gpg_agent(homedir)).as_ref(),
+                relay.apply(Options::default()),
+            ))
+        }
+        Mode::Pinentry { program, relay } => block_on(connect(
+            &config::Target::Pinentry {
+                program,
+                args: Vec::new(),
+            },
+            None,
+            relay.apply(Options::default()),
+        )),
+        Mode::Wsl2SshPageant(options) => {
+            block_on(connect(&options.target(), None, Options::default()))
+        }
+        #[cfg(unix)]
+        Mode::SshPageant(options) => Ok(options.run()?),
+        // Each connection gets its own instance of the pipe (waiting for one if the engine's are
+        // all busy), so the client can open as many as it likes, e.g. to attach to a container
+        // while waiting for it to exit.
+        Mode::Docker { pipe, relay } => block_on(connect(
+            &config::Target::NamedPipe { path: pipe },
+            None,
+            relay.apply(Options {
+                buffer_size: DOCKER_BUFFER_SIZE,
+                ..Options::default()
+            }),
+        )),
+        Mode::Pipe { path, relay } => block_on(connect(
+            &config::Target::NamedPipe { path },
+            None,
+            relay.apply(Options::default()),
+        )),
+        Mode::Tcp {
+            target,
+            key_file,
+            relay,
+        } => block_on(connect(
+            &config::Target::Tcp {
+                address: target,
+                key_file,
+            },
+            None,
+            relay.apply(Options::default()),
+        )),
+        Mode::Daemon {
+            bridges,
+            limits,
+            metrics,
+            exit_idle_time,
+        } => Ok(block_on(daemon::run(
+            &config,
+            args.config.as_deref(),
+            &bridges,
+            limits,
+            &metrics,
+            exit_idle_time.map(std::time::Duration::from_secs),
+        ))?),
+        #[cfg(windows)]
+        Mode::Hyperv {
+            bridges,
+            limits,
+            metrics,
+        } => Ok(block_on(hyperv::run(&config, &bridges, limits, &metrics))?),
+        Mode::Mux => {
+            block_on(mux::run(&config));
+            Ok(())
+        }
+        Mode::Bridge { name, relay } => {
+            let bridge = find_bridge(&config, &name)?;
+            block_on(connect(
+                &bridge.target,
+                bridge.launch.as_ref(),
+                relay.apply(bridge.relay_options()),
+            ))
+        }
+        #[cfg(unix)]
+        Mode::Env { shell } => Ok(env::print(
+            &config,
+            shell.unwrap_or_else(env::Shell::detect),
+        )?),
+        Mode::ListKeys { name } => block_on(list_keys(find_bridge(&config, &name)?)),
+        Mode::Doctor => {
+            let config_path = args.config.or_else(config::Config::default_path);
+            Ok(block_on(doctor::run(&config, config_path.as_deref()))?)
+        }
+        #[cfg(unix)]
+        Mode::Install(options) => {
+            let config_path = args.config.or_else(config::Config::default_path);
+            Ok(install::install(&options, &config, config_path.as_deref())?)
         }
     }
+}
 
-    impl super::Split for Assuan {
-        type Read = std::net::TcpStream;
-        type Write = std::net::TcpStream;
-        fn split(self) -> (Arc<Self::Read>, Arc<Self::Write>) {
-            let arc = Arc::new(self.sock);
-            (Arc::clone(&arc) as Arc<_>, arc as Arc<_>)
-        }
+fn find_bridge<'a>(config: &'a config::Config, name: &str) -> Result<&'a config::Bridge, Error> {
+    config
+        .bridges
+        .get(name)
+        .ok_or_else(|| Error::UnknownBridge(name.to_owned()))
+}
+
+/// Run `future` to completion on a fresh tokio runtime.
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to start the tokio runtime");
+    let output = runtime.block_on(future);
+    // Reading stdin happens on a blocking thread that can't be interrupted, so don't wait for it
+    // to notice the client has gone.
+    runtime.shutdown_background();
+    output
+}
+
+/// Connect to `target` (launching it if needed) and relay it to stdin/stdout.
+async fn connect(
+    target: &config::Target,
+    launch: Option<&config::Launch>,
+    options: Options,
+) -> Result<(), Error> {
+    let stop = shutdown::on_signal();
+    let endpoint = tokio::select! {
+        endpoint = endpoint::connect_or_launch(target, launch) => endpoint?,
+        () = stop.cancelled() => return Ok(()),
+    };
+    let hooks = trace::connection(target);
+    let relay = bridge_core::relay::attach_to_tty(endpoint, options, &stop, &hooks);
+    shutdown::with_grace_period(&stop, relay).await;
+    Ok(())
+}
+
+/// Print the type, fingerprint and comment of each key the bridge's agent holds, like
+/// `ssh-add -l`.
+async fn list_keys(bridge: &config::Bridge) -> Result<(), Error> {
+    let mut agent = agent::Client::connect(&bridge.target, bridge.launch.as_ref()).await?;
+    let identities = agent.identities().await?;
+    if identities.is_empty() {
+        println!("The agent has no identities.");
+    }
+    for identity in identities {
+        let key_type = identity
+            .key_type()
+            .unwrap_or_else(|_| "(malformed key)".to_owned());
+        println!(
+            "{} {} {}",
+            key_type,
+            identity.fingerprint(),
+            identity.comment
+        );
     }
+    Ok(())
 }
@@ -0,0 +1,573 @@
+//! Per-bridge statistics, for diagnosing "ssh is slow sometimes".
+//!
+//! Every bridge served by this process counts its connections, failures, bytes relayed and
+//! backend reconnects. Bridges carrying the ssh-agent protocol also count agent requests and
+//! time sign requests (from the request reaching the bridge to the response leaving it). The
+//! protocol is recognised by its framing, so other bridges just don't get those.
+//!
+//! `--stats` prints the statistics to stderr on SIGUSR1, and `--metrics-listen` serves them over
+//! HTTP in Prometheus' text format.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio_util::sync::CancellationToken;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Failed to listen for metrics requests on {0}")]
+    Bind(String, #[source] std::io::Error),
+    #[cfg(unix)]
+    #[error("Failed to handle SIGUSR1")]
+    Signal(#[source] std::io::Error),
+}
+
+// Flattened into the subcommands that serve bridges. (Not a doc comment, structopt would use it
+// as their description.)
+#[derive(structopt::StructOpt, Debug, Default)]
+pub struct Options {
+    /// Print statistics for each bridge to stderr on SIGUSR1
+    #[cfg(unix)]
+    #[structopt(long)]
+    stats: bool,
+    /// Serve Prometheus metrics over HTTP on this address, e.g. `127.0.0.1:9464`
+    #[structopt(long, value_name = "ADDRESS")]
+    metrics_listen: Option<String>,
+}
+
+/// The upper bounds of the sign latency histogram's buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// The statistics of one bridge.
+#[derive(Default)]
+pub struct Bridge {
+    connections: AtomicU64,
+    open_connections: AtomicU64,
+    connect_failures: AtomicU64,
+    relay_failures: AtomicU64,
+    idle_timeouts: AtomicU64,
+    reconnects: AtomicU64,
+    bytes_to_target: AtomicU64,
+    bytes_to_client: AtomicU64,
+    agent_requests: AtomicU64,
+    sign_latency: Histogram,
+    /// The open connections' own counts, by connection ID.
+    open: Mutex<BTreeMap<u64, Arc<Totals>>>,
+}
+
+/// What one connection has relayed so far.
+struct Totals {
+    opened: Instant,
+    to_target: AtomicU64,
+    to_client: AtomicU64,
+}
+
+/// What a connection relayed, and for how long.
+pub struct Summary {
+    pub duration: Duration,
+    pub to_target: u64,
+    pub to_client: u64,
+}
+
+/// Cumulative counts of observations at or below each of [`LATENCY_BUCKETS`].
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, value: Duration) {
+        let seconds = value.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// The bridges that have had any connections, by name.
+static BRIDGES: Mutex<BTreeMap<String, Arc<Bridge>>> = Mutex::new(BTreeMap::new());
+
+/// The statistics of the bridge called `name`.
+pub fn bridge(name: &str) -> Arc<Bridge> {
+    let mut bridges = BRIDGES.lock().expect("metrics lock poisoned");
+    Arc::clone(bridges.entry(name.to_owned()).or_default())
+}
+
+impl Bridge {
+    /// A connection failed to reach the target.
+    pub fn connect_failed(&self) {
+        self.connect_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Start tracking a newly accepted connection called `id`, to be relayed with the returned
+    /// hooks.
+    pub fn connection(self: &Arc<Self>, id: u64) -> Connection {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+        let totals = Arc::new(Totals {
+            opened: Instant::now(),
+            to_target: AtomicU64::new(0),
+            to_client: AtomicU64::new(0),
+        });
+        self.open
+            .lock()
+            .expect("metrics lock poisoned")
+            .insert(id, Arc::clone(&totals));
+        Connection {
+            bridge: Arc::clone(self),
+            id,
+            totals,
+            agent: Mutex::new(AgentTap::default()),
+        }
+    }
+}
+
+/// The [`Hooks`](bridge_core::relay::Hooks) recording a connection's statistics.
+pub struct Connection {
+    bridge: Arc<Bridge>,
+    id: u64,
+    totals: Arc<Totals>,
+    agent: Mutex<AgentTap>,
+}
+
+impl Connection {
+    /// What the connection has relayed so far.
+    pub fn totals(&self) -> Summary {
+        Summary {
+            duration: self.totals.opened.elapsed(),
+            to_target: self.totals.to_target.load(Ordering::Relaxed),
+            to_client: self.totals.to_client.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.bridge.open_connections.fetch_sub(1, Ordering::Relaxed);
+        self.bridge
+            .open
+            .lock()
+            .expect("metrics lock poisoned")
+            .remove(&self.id);
+    }
+}
+
+impl bridge_core::relay::Hooks for Connection {
+    fn to_backend(&self, data: &[u8]) {
+        let bridge = &self.bridge;
+        bridge
+            .bytes_to_target
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        self.totals
+            .to_target
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        let requests = self
+            .agent
+            .lock()
+            .expect("metrics lock poisoned")
+            .requests(data);
+        bridge.agent_requests.fetch_add(requests, Ordering::Relaxed);
+    }
+
+    fn to_client(&self, data: &[u8]) {
+        self.bridge
+            .bytes_to_client
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        self.totals
+            .to_client
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        let mut agent = self.agent.lock().expect("metrics lock poisoned");
+        agent.responses(data, |message_type, latency| {
+            if message_type == agent_proto::MessageType::SIGN_REQUEST.0 {
+                self.bridge.sign_latency.observe(latency);
+            }
+        });
+    }
+
+    fn reconnected(&self) {
+        self.bridge.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn idle(&self) {
+        self.bridge.idle_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn failed(&self, _error: &std::io::Error) {
+        self.bridge.relay_failures.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Follows the ssh-agent protocol's framing in both directions, to pair requests with their
+/// responses. It gives up as soon as the traffic doesn't look like agent messages.
+#[derive(Default)]
+struct AgentTap {
+    requests: agent_proto::frame::Boundaries,
+    responses: agent_proto::frame::Boundaries,
+    /// The type of each request that hasn't been answered yet, and when it was seen.
+    pending: VecDeque<(u8, Instant)>,
+    not_agent: bool,
+}
+
+impl AgentTap {
+    /// Follow `data` sent to the agent, returning how many requests it started.
+    fn requests(&mut self, data: &[u8]) -> u64 {
+        if self.not_agent {
+            return 0;
+        }
+        let before = self.pending.len();
+        let pending = &mut self.pending;
+        let framed = self.requests.feed(data, |message_type| {
+            pending.push_back((message_type, Instant::now()));
+        });
+        self.not_agent = !framed;
+        self.pending.len().saturating_sub(before) as u64
+    }
+
+    /// Follow `data` sent by the agent, calling `answered` with the type of each request it
+    /// responds to and how long that took.
+    fn responses(&mut self, data: &[u8], mut answered: impl FnMut(u8, Duration)) {
+        if self.not_agent {
+            return;
+        }
+        let pending = &mut self.pending;
+        let framed = self.responses.feed(data, |_| {
+            if let Some((message_type, at)) = pending.pop_front() {
+                answered(message_type, at.elapsed());
+            }
+        });
+        self.not_agent = !framed;
+    }
+}
+
+/// Picks one of a bridge's counters.
+type Counter = fn(&Bridge) -> &AtomicU64;
+
+/// The statistics of every bridge, in Prometheus' text format.
+fn prometheus() -> String {
+    let bridges = BRIDGES.lock().expect("metrics lock poisoned");
+    let mut out = String::new();
+    let counters: [(&str, &str, Counter); 9] = [
+        ("connections_total", "Connections accepted", |b| {
+            &b.connections
+        }),
+        ("open_connections", "Connections currently open", |b| {
+            &b.open_connections
+        }),
+        (
+            "connect_failures_total",
+            "Connections that failed to reach the target",
+            |b| &b.connect_failures,
+        ),
+        (
+            "relay_failures_total",
+            "Connections that failed part way through",
+            |b| &b.relay_failures,
+        ),
+        (
+            "idle_timeouts_total",
+            "Connections closed for being idle",
+            |b| &b.idle_timeouts,
+        ),
+        (
+            "reconnects_total",
+            "Reconnections to a restarted target",
+            |b| &b.reconnects,
+        ),
+        (
+            "bytes_to_target_total",
+            "Bytes relayed to the target",
+            |b| &b.bytes_to_target,
+        ),
+        ("bytes_to_client_total", "Bytes relayed to clients", |b| {
+            &b.bytes_to_client
+        }),
+        ("agent_requests_total", "ssh-agent requests relayed", |b| {
+            &b.agent_requests
+        }),
+    ];
+    for (name, help, counter) in counters {
+        let kind = if name.ends_with("_total") {
+            "counter"
+        } else {
+            "gauge"
+        };
+        let _ = writeln!(out, "# HELP pipette_{} {}.", name, help);
+        let _ = writeln!(out, "# TYPE pipette_{} {}", name, kind);
+        for (bridge_name, bridge) in bridges.iter() {
+            let value = counter(bridge).load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "pipette_{}{{bridge={:?}}} {}",
+                name, bridge_name, value
+            );
+        }
+    }
+
+    let name = "pipette_sign_latency_seconds";
+    let _ = writeln!(
+        out,
+        "# HELP {} Time taken to answer ssh-agent sign requests.",
+        name
+    );
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for (bridge_name, bridge) in bridges.iter() {
+        let histogram = &bridge.sign_latency;
+        let count = histogram.count.load(Ordering::Relaxed);
+        for (bucket, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+            let value = bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{bridge={:?},le=\"{}\"}} {}",
+                name, bridge_name, bound, value
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{bridge={:?},le=\"+Inf\"}} {}",
+            name, bridge_name, count
+        );
+        let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum{{bridge={:?}}} {}", name, bridge_name, sum);
+        let _ = writeln!(out, "{}_count{{bridge={:?}}} {}", name, bridge_name, count);
+    }
+    out
+}
+
+/// A line per bridge, followed by one per open connection, for `--stats`.
+fn summary() -> String {
+    let bridges = BRIDGES.lock().expect("metrics lock poisoned");
+    let mut out = String::new();
+    for (name, bridge) in bridges.iter() {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let signs = load(&bridge.sign_latency.count);
+        let mean_sign = Duration::from_micros(load(&bridge.sign_latency.sum_micros))
+            .checked_div(signs.max(1) as u32)
+            .unwrap_or_default();
+        let _ = writeln!(
+            out,
+            "{}: {} connections ({} open, {} failed to connect, {} failed, {} idle), \
+             {} reconnects, {} bytes to target, {} bytes to clients, {} agent requests, \
+             {} signs (mean {:?})",
+            name,
+            load(&bridge.connections),
+            load(&bridge.open_connections),
+            load(&bridge.connect_failures),
+            load(&bridge.relay_failures),
+            load(&bridge.idle_timeouts),
+            load(&bridge.reconnects),
+            load(&bridge.bytes_to_target),
+            load(&bridge.bytes_to_client),
+            load(&bridge.agent_requests),
+            signs,
+            mean_sign,
+        );
+        let open = bridge.open.lock().expect("metrics lock poisoned");
+        for (id, totals) in open.iter() {
+            let _ = writeln!(
+                out,
+                "  connection {}: open {:?}, {} bytes to target, {} bytes to client",
+                id,
+                totals.opened.elapsed(),
+                load(&totals.to_target),
+                load(&totals.to_client),
+            );
+        }
+    }
+    if out.is_empty() {
+        out.push_str("No connections yet\n");
+    }
+    out
+}
+
+/// Start reporting the statistics as `options` asks, until `stop` is cancelled.
+pub async fn start(options: &Options, stop: &CancellationToken) -> Result<(), Error> {
+    #[cfg(unix)]
+    if options.stats {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut signals = signal(SignalKind::user_defined1()).map_err(Error::Signal)?;
+        let stop = stop.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    () = stop.cancelled() => return,
+                    _ = signals.recv() => eprint!("{}", summary()),
+                }
+            }
+        });
+    }
+
+    if let Some(address) = &options.metrics_listen {
+        let listener = tokio::net::TcpListener::bind(address)
+            .await
+            .map_err(|e| Error::Bind(address.clone(), e))?;
+        tracing::info!(%address, "Serving metrics");
+        let stop = stop.clone();
+        tokio::spawn(async move {
+            loop {
+                let client = tokio::select! {
+                    () = stop.cancelled() => return,
+                    accepted = listener.accept() => accepted,
+                };
+                match client {
+                    Ok((client, _)) => {
+                        tokio::spawn(async move {
+                            if let Err(e) = serve_metrics(client).await {
+                                tracing::debug!(error = %e, "Failed to serve metrics");
+                            }
+                        });
+                    }
+                    Err(e) => tracing::warn!(error = %e, "Failed to accept a metrics request"),
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Answer an HTTP request (whatever it's for) with the metrics.
+async fn serve_metrics(mut client: tokio::net::TcpStream) -> std::io::Result<()> {
+    // Read (and ignore) the request, up to the blank line ending its headers.
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < 16 * 1024 {
+        let len = tokio::time::timeout(Duration::from_secs(10), client.read(&mut buf))
+            .await
+            .map_err(|_| std::io::ErrorKind::TimedOut)??;
+        if len == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..len]);
+    }
+    let body = prometheus();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {}",
+        body.len(),
+        body
+    );
+    client.write_all(response.as_bytes()).await?;
+    client.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use bridge_core::relay::Hooks as _;
+
+    use super::*;
+
+    /// A framed agent message with no contents beyond its type.
+    fn message(message_type: u8) -> [u8; 5] {
+        [0, 0, 0, 1, message_type]
+    }
+
+    #[test]
+    fn fills_histogram_buckets_cumulatively() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(20));
+        histogram.observe(Duration::from_secs(60));
+        let counts: Vec<_> = histogram
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        assert_eq!(counts, [0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1]);
+        assert_eq!(histogram.count.load(Ordering::Relaxed), 2);
+        assert_eq!(histogram.sum_micros.load(Ordering::Relaxed), 60_020_000);
+    }
+
+    #[test]
+    fn pairs_agent_requests_with_responses() {
+        let mut tap = AgentTap::default();
+        let identities = agent_proto::MessageType::REQUEST_IDENTITIES.0;
+        let sign = agent_proto::MessageType::SIGN_REQUEST.0;
+        let requests = [message(identities), message(sign)].concat();
+        // Split mid-frame, as reads can be.
+        assert_eq!(tap.requests(&requests[..7]), 1);
+        assert_eq!(tap.requests(&requests[7..]), 1);
+
+        let mut answered = Vec::new();
+        let responses = [message(12), message(14)].concat();
+        tap.responses(&responses, |message_type, _| answered.push(message_type));
+        assert_eq!(answered, [identities, sign]);
+    }
+
+    #[test]
+    fn stops_following_other_protocols() {
+        let mut tap = AgentTap::default();
+        assert_eq!(tap.requests(b"GET / HTTP/1.1\r\n\r\n"), 0);
+        assert_eq!(tap.requests(&message(11)), 0);
+        tap.responses(&message(12), |_, _| panic!("not an agent"));
+    }
+
+    #[test]
+    fn counts_connections() {
+        let bridge = bridge("metrics-test-counts-connections");
+        let connection = bridge.connection(1);
+        connection.to_backend(&message(agent_proto::MessageType::SIGN_REQUEST.0));
+        connection.to_client(&message(14));
+        connection.to_client(b"!");
+        connection.reconnected();
+        let totals = connection.totals();
+        assert_eq!((totals.to_target, totals.to_client), (5, 6));
+        assert_eq!(bridge.open_connections.load(Ordering::Relaxed), 1);
+        assert_eq!(bridge.open.lock().unwrap().len(), 1);
+
+        drop(connection);
+        bridge.connect_failed();
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        assert_eq!(load(&bridge.connections), 1);
+        assert_eq!(load(&bridge.open_connections), 0);
+        assert!(bridge.open.lock().unwrap().is_empty());
+        assert_eq!(load(&bridge.connect_failures), 1);
+        assert_eq!(load(&bridge.reconnects), 1);
+        assert_eq!(load(&bridge.bytes_to_target), 5);
+        assert_eq!(load(&bridge.bytes_to_client), 6);
+        assert_eq!(load(&bridge.agent_requests), 1);
+        assert_eq!(load(&bridge.sign_latency.count), 1);
+    }
+
+    #[test]
+    fn exports_prometheus_metrics() {
+        let bridge = bridge("metrics-test-prometheus");
+        bridge
+            .connection(1)
+            .failed(&std::io::ErrorKind::BrokenPipe.into());
+        bridge.sign_latency.observe(Duration::from_millis(30));
+
+        let metrics = prometheus();
+        for line in [
+            "# TYPE pipette_connections_total counter",
+            "# TYPE pipette_open_connections gauge",
+            "pipette_connections_total{bridge=\"metrics-test-prometheus\"} 1",
+            "pipette_open_connections{bridge=\"metrics-test-prometheus\"} 0",
+            "pipette_relay_failures_total{bridge=\"metrics-test-prometheus\"} 1",
+            "# TYPE pipette_sign_latency_seconds histogram",
+            "pipette_sign_latency_seconds_bucket{bridge=\"metrics-test-prometheus\",le=\"0.025\"} 0",
+            "pipette_sign_latency_seconds_bucket{bridge=\"metrics-test-prometheus\",le=\"0.05\"} 1",
+            "pipette_sign_latency_seconds_bucket{bridge=\"metrics-test-prometheus\",le=\"+Inf\"} 1",
+            "pipette_sign_latency_seconds_sum{bridge=\"metrics-test-prometheus\"} 0.03",
+            "pipette_sign_latency_seconds_count{bridge=\"metrics-test-prometheus\"} 1",
+        ] {
+            assert!(metrics.lines().any(|l| l == line), "missing {:?}", line);
+        }
+        assert!(summary().contains("metrics-test-prometheus: 1 connections (0 open"));
+    }
+}
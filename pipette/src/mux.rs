@@ -0,0 +1,714 @@
+//! Carrying many connections over a single helper process's stdin/stdout.
+//!
+//! A `command` target spawns a helper for every connection, which is slow through interop. A `mux`
+//! target instead starts one long-lived `wsl-systemd.exe mux` per program, and opens a channel to
+//! it for each connection; the helper relays each channel to the (Windows side) bridge it was
+//! opened for.
+//!
+//! Both directions carry frames of:
+//!
+//! | bytes | field                                                                |
+//! |-------|----------------------------------------------------------------------|
+//! | 1     | kind: `0` open (payload is the bridge name), `1` data, `2` close,    |
+//! |       | `3` window (payload is a count of bytes read, 4 bytes big endian)    |
+//! | 4     | channel ID, chosen by the side that opened it (big endian)           |
+//! | 4     | payload length (big endian), at most [`MAX_PAYLOAD`]                 |
+//!
+//! followed by the payload. Close is a half-close, it means the sender won't send any more data
+//! on that channel; a channel is finished once both sides have closed it. A channel the helper
+//! can't connect to the target of is simply closed straight away.
+//!
+//! Each side may have at most [`WINDOW`] bytes of data on a channel that the other hasn't read
+//! yet, and window frames say how much more has been read, so a client that stops reading only
+//! holds up its own channel rather than every channel of the session. Data for a channel that's
+//! gone away is thrown away, but counted as read.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll, Waker};
+
+use bridge_core::shutdown::GRACE_PERIOD;
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+use tokio::sync::mpsc;
+use tokio_util::sync::{CancellationToken, PollSender};
+use tracing::Instrument as _;
+
+use crate::config::Config;
+
+/// The largest payload a frame may carry.
+pub const MAX_PAYLOAD: usize = 64 * 1024;
+
+/// How much data may be sent on a channel that the other side hasn't read yet.
+pub const WINDOW: usize = 16 * MAX_PAYLOAD;
+
+/// How many frames can be queued for sending (and opened channels for accepting) before those
+/// sending them wait.
+const QUEUE_LEN: usize = 16;
+
+const OPEN: u8 = 0;
+const DATA: u8 = 1;
+const CLOSE: u8 = 2;
+const WINDOW_UPDATE: u8 = 3;
+
+struct Frame {
+    kind: u8,
+    channel: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Tell the other side that `read` more bytes of `channel`'s data have been read.
+    fn window_update(channel: u32, read: usize) -> Self {
+        Self {
+            kind: WINDOW_UPDATE,
+            channel,
+            payload: (read as u32).to_be_bytes().to_vec(),
+        }
+    }
+}
+
+/// The state shared between the channels of a session and its reader task.
+struct Shared {
+    /// The open channels.
+    channels: Mutex<HashMap<u32, Route>>,
+    frames: mpsc::Sender<Frame>,
+    /// Window frames, sent ahead of the other frames since those may be waiting for them.
+    updates: mpsc::UnboundedSender<Frame>,
+}
+
+/// Where the reader task delivers a channel's frames.
+struct Route {
+    /// Received data, until the other side closes the channel.
+    data: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    window: Arc<Mutex<Window>>,
+}
+
+/// A channel's flow control.
+struct Window {
+    /// How much more data can be sent before the other side reads some.
+    credit: usize,
+    /// The channel's writer, waiting for more credit.
+    waiting: Option<Waker>,
+    /// How much data has been received but not yet reported read.
+    unread: usize,
+    /// Set when the session ends, so the writer stops waiting.
+    ended: bool,
+}
+
+/// The program and arguments that start a helper.
+type Helper = (PathBuf, Vec<String>);
+
+/// Helper processes that `mux` targets share.
+static SESSIONS: Mutex<Vec<(Helper, Arc<Session>)>> = Mutex::new(Vec::new());
+
+/// A helper process that channels can be opened to.
+struct Session {
+    shared: Arc<Shared>,
+    next_id: std::sync::atomic::AtomicU32,
+    /// Cleared when the helper's stdout closes.
+    alive: Arc<std::sync::atomic::AtomicBool>,
+    _child: tokio::process::Child,
+}
+
+/// Open a channel to `bridge` through the helper started by `program` (starting it if it isn't
+/// already running).
+pub async fn connect(program: &Path, args: &[String], bridge: &str) -> std::io::Result<Channel> {
+    let session = {
+        let mut sessions = SESSIONS.lock().unwrap();
+        sessions.retain(|(_, session)| session.alive.load(std::sync::atomic::Ordering::Relaxed));
+        let helper = (program.to_owned(), args.to_owned());
+        match sessions.iter().find(|(h, _)| *h == helper) {
+            Some((_, session)) => Arc::clone(session),
+            None => {
+                let session = Arc::new(Session::spawn(program, args)?);
+                sessions.push((helper, Arc::clone(&session)));
+                session
+            }
+        }
+    };
+
+    let id = session
+        .next_id
+        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    session.shared.open(id, bridge).await
+}
+
+impl Session {
+    fn spawn(program: &Path, args: &[String]) -> std::io::Result<Self> {
+        tracing::info!(program = %program.display(), ?args, "Spawning multiplexing helper");
+        let mut child = tokio::process::Command::new(program)
+            .args(args)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+
+        let shared = start(stdout, stdin, None);
+        let alive = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let finished = Arc::clone(&alive);
+        let frames = shared.frames.clone();
+        tokio::spawn(async move {
+            // The writer stops (dropping the receiving end) once the reader finishes.
+            frames.closed().await;
+            finished.store(false, std::sync::atomic::Ordering::Relaxed);
+            tracing::warn!("Multiplexing helper exited");
+        });
+        Ok(Self {
+            shared,
+            next_id: std::sync::atomic::AtomicU32::new(0),
+            alive,
+            _child: child,
+        })
+    }
+}
+
+impl Shared {
+    /// Open the channel `id` to `bridge` on the other side.
+    async fn open(self: &Arc<Self>, id: u32, bridge: &str) -> std::io::Result<Channel> {
+        tracing::debug!(channel = id, bridge, "Opening channel");
+        let channel = Channel::new(id, self);
+        self.frames
+            .send(Frame {
+                kind: OPEN,
+                channel: id,
+                payload: bridge.as_bytes().to_vec(),
+            })
+            .await
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        Ok(channel)
+    }
+
+    /// Deliver `data` received on `channel`.
+    fn received(&self, channel: u32, data: Vec<u8>) -> std::io::Result<()> {
+        let channels = self.channels.lock().unwrap();
+        let Some((sender, window)) = channels
+            .get(&channel)
+            .and_then(|route| Some((route.data.as_ref()?, &route.window)))
+        else {
+            // Counted as read, so the other side doesn't wait for room to send the rest.
+            let _ = self.updates.send(Frame::window_update(channel, data.len()));
+            return Ok(());
+        };
+        let mut window = window.lock().unwrap();
+        window.unread += data.len();
+        if window.unread > WINDOW {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("channel {} overran its window", channel),
+            ));
+        }
+        let _ = sender.send(data);
+        Ok(())
+    }
+
+    /// Let `channel`'s writer send `read` more bytes.
+    fn grant(&self, channel: u32, read: usize) {
+        if let Some(route) = self.channels.lock().unwrap().get(&channel) {
+            let mut window = route.window.lock().unwrap();
+            window.credit += read;
+            if let Some(writer) = window.waiting.take() {
+                writer.wake();
+            }
+        }
+    }
+
+    /// Every channel sees EOF, and any writer waiting for credit gives up.
+    fn end(&self) {
+        for (_, route) in self.channels.lock().unwrap().drain() {
+            let mut window = route.window.lock().unwrap();
+            window.ended = true;
+            if let Some(writer) = window.waiting.take() {
+                writer.wake();
+            }
+        }
+    }
+}
+
+/// Start reading and writing frames on a session. Channels the other side opens are passed to
+/// `accept`, with the name of the bridge they're for.
+fn start<R, W>(reader: R, writer: W, accept: Option<mpsc::Sender<(Channel, String)>>) -> Arc<Shared>
+where
+    R: AsyncRead + Send + Unpin + 'static,
+    W: AsyncWrite + Send + Unpin + 'static,
+{
+    let (frames, outgoing) = mpsc::channel(QUEUE_LEN);
+    let (updates, outgoing_updates) = mpsc::unbounded_channel();
+    let shared = Arc::new(Shared {
+        channels: Mutex::new(HashMap::new()),
+        frames,
+        updates,
+    });
+    let writer = tokio::spawn(write_frames(writer, outgoing, outgoing_updates));
+    let reading = Arc::clone(&shared);
+    tokio::spawn(async move {
+        if let Err(e) = read_frames(reader, &reading, accept).await {
+            tracing::error!(error = %e, "Failed to read from the multiplexed stream");
+        }
+        // The session stops sending too.
+        reading.end();
+        writer.abort();
+    });
+    shared
+}
+
+async fn read_frames<R: AsyncRead + Unpin>(
+    mut reader: R,
+    shared: &Arc<Shared>,
+    accept: Option<mpsc::Sender<(Channel, String)>>,
+) -> std::io::Result<()> {
+    loop {
+        let mut header = [0; 9];
+        match reader.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let kind = header[0];
+        let channel = u32::from_be_bytes(header[1..5].try_into().unwrap());
+        let len = u32::from_be_bytes(header[5..9].try_into().unwrap()) as usize;
+        if len > MAX_PAYLOAD {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("frame of {} bytes is too large", len),
+            ));
+        }
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload).await?;
+
+        match kind {
+            OPEN => {
+                let Some(accept) = &accept else {
+                    tracing::warn!(channel, "Ignoring a channel opened by the helper");
+                    continue;
+                };
+                let bridge = String::from_utf8_lossy(&payload).into_owned();
+                if accept
+                    .send((Channel::new(channel, shared), bridge))
+                    .await
+                    .is_err()
+                {
+                    return Ok(());
+                }
+            }
+            DATA => shared.received(channel, payload)?,
+            CLOSE => {
+                if let Some(route) = shared.channels.lock().unwrap().get_mut(&channel) {
+                    route.data = None;
+                }
+            }
+            WINDOW_UPDATE => {
+                let read = <[u8; 4]>::try_from(payload.as_slice()).map_err(|_| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, "malformed window frame")
+                })?;
+                shared.grant(channel, u32::from_be_bytes(read) as usize);
+            }
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("unknown frame kind {}", kind),
+                ))
+            }
+        }
+    }
+}
+
+async fn write_frames<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    mut frames: mpsc::Receiver<Frame>,
+    mut updates: mpsc::UnboundedReceiver<Frame>,
+) {
+    let mut buf = Vec::new();
+    loop {
+        let frame = tokio::select! {
+            biased;
+            Some(frame) = updates.recv() => frame,
+            Some(frame) = frames.recv() => frame,
+            else => return,
+        };
+        // Batch up whatever's queued into one write.
+        let mut next = Some(frame);
+        while let Some(frame) = next {
+            buf.push(frame.kind);
+            buf.extend_from_slice(&frame.channel.to_be_bytes());
+            buf.extend_from_slice(&(frame.payload.len() as u32).to_be_bytes());
+            buf.extend_from_slice(&frame.payload);
+            next = updates.try_recv().or_else(|_| frames.try_recv()).ok();
+        }
+        if let Err(e) = async {
+            writer.write_all(&buf).await?;
+            writer.flush().await
+        }
+        .await
+        {
+            tracing::error!(error = %e, "Failed to write to the multiplexed stream");
+            return;
+        }
+        buf.clear();
+    }
+}
+
+/// One connection carried over a session.
+pub struct Channel {
+    id: u32,
+    incoming: mpsc::UnboundedReceiver<Vec<u8>>,
+    /// Received data that didn't fit in the reader's buffer.
+    pending: Vec<u8>,
+    /// How much has been read since the other side was last told.
+    read: usize,
+    window: Arc<Mutex<Window>>,
+    outgoing: PollSender<Frame>,
+    closed: bool,
+    shared: Arc<Shared>,
+}
+
+impl Channel {
+    fn new(id: u32, shared: &Arc<Shared>) -> Self {
+        let (sender, incoming) = mpsc::unbounded_channel();
+        let window = Arc::new(Mutex::new(Window {
+            credit: WINDOW,
+            waiting: None,
+            unread: 0,
+            ended: false,
+        }));
+        let route = Route {
+            data: Some(sender),
+            window: Arc::clone(&window),
+        };
+        shared.channels.lock().unwrap().insert(id, route);
+        Self {
+            id,
+            incoming,
+            pending: Vec::new(),
+            read: 0,
+            window,
+            outgoing: PollSender::new(shared.frames.clone()),
+            closed: false,
+            shared: Arc::clone(shared),
+        }
+    }
+
+    fn poll_send(
+        &mut self,
+        cx: &mut Context<'_>,
+        kind: u8,
+        payload: &[u8],
+    ) -> Poll<std::io::Result<()>> {
+        let broken = |_| std::io::Error::from(std::io::ErrorKind::BrokenPipe);
+        ready!(self.outgoing.poll_reserve(cx)).map_err(broken)?;
+        self.outgoing
+            .send_item(Frame {
+                kind,
+                channel: self.id,
+                payload: payload.to_vec(),
+            })
+            .map_err(broken)?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for Channel {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.pending.is_empty() {
+            match ready!(self.incoming.poll_recv(cx)) {
+                Some(data) => self.pending = data,
+                // Closed by the other side.
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let len = self.pending.len().min(buf.remaining());
+        buf.put_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+        // Once half the window's been read, let the other side fill it up again.
+        self.read += len;
+        if self.read >= WINDOW / 2 {
+            self.window.lock().unwrap().unread -= self.read;
+            let _ = self
+                .shared
+                .updates
+                .send(Frame::window_update(self.id, self.read));
+            self.read = 0;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Channel {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let len = {
+            let mut window = self.window.lock().unwrap();
+            if window.ended {
+                return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+            }
+            if window.credit == 0 {
+                window.waiting = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            buf.len().min(MAX_PAYLOAD).min(window.credit)
+        };
+        ready!(self.poll_send(cx, DATA, &buf[..len]))?;
+        self.window.lock().unwrap().credit -= len;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        // The writer task flushes whenever it runs out of frames to send.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if !self.closed {
+            ready!(self.poll_send(cx, CLOSE, &[]))?;
+            self.closed = true;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        self.shared.channels.lock().unwrap().remove(&self.id);
+        if self.closed {
+            return;
+        }
+        // Let the other side know it won't get anything more, without blocking here.
+        let frames = self.shared.frames.clone();
+        let close = Frame {
+            kind: CLOSE,
+            channel: self.id,
+            payload: Vec::new(),
+        };
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move { frames.send(close).await });
+        }
+    }
+}
+
+/// Serve the bridges in `config` to channels opened over stdin/stdout, until stdin closes or the
+/// process is asked to shut down.
+pub async fn run(config: &Config) {
+    let stop = bridge_core::shutdown::on_signal();
+    serve(tokio::io::stdin(), tokio::io::stdout(), config, &stop).await
+}
+
+/// Serve the bridges in `config` to channels opened over `reader` and `writer`, until `reader`
+/// closes or `stop` is cancelled.
+async fn serve<R, W>(reader: R, writer: W, config: &Config, stop: &CancellationToken)
+where
+    R: AsyncRead + Send + Unpin + 'static,
+    W: AsyncWrite + Send + Unpin + 'static,
+{
+    let (accepted, mut incoming) = mpsc::channel(QUEUE_LEN);
+    start(reader, writer, Some(accepted));
+
+    let mut connections = tokio::task::JoinSet::new();
+    loop {
+        tokio::select! {
+            () = stop.cancelled() => break,
+            channel = incoming.recv() => match channel {
+                Some((channel, name)) => {
+                    let id = crate::relay::next_id();
+                    let span = tracing::info_span!(
+                        "connection",
+                        bridge = %name,
+                        id,
+                        channel = channel.id
+                    );
+                    let Some(bridge) = config.bridges.get(&name) else {
+                        span.in_scope(|| tracing::error!("No such bridge"));
+                        continue;
+                    };
+                    let target = bridge.target.clone();
+                    let launch = bridge.launch.clone();
+                    let options = bridge.relay_options();
+                    let stop = stop.clone();
+                    let metrics = crate::metrics::bridge(&name);
+                    connections.spawn(
+                        async move {
+                            crate::relay::serve(
+                                channel,
+                                id,
+                                &target,
+                                launch.as_ref(),
+                                options,
+                                &stop,
+                                &metrics,
+                            )
+                            .await
+                        }
+                        .instrument(span),
+                    );
+                }
+                // stdin closed, the other side has gone away.
+                None => break,
+            },
+            Some(_) = connections.join_next() => {}
+        }
+    }
+
+    if !connections.is_empty() {
+        tracing::info!(
+            connections = connections.len(),
+            grace_period = ?GRACE_PERIOD,
+            "Waiting for channels to close"
+        );
+    }
+    bridge_core::shutdown::with_grace_period(stop, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::time::Duration;
+
+    use super::*;
+
+    /// `future`'s output, unless it takes so long that it must be stuck.
+    async fn within<F: Future>(future: F) -> F::Output {
+        tokio::time::timeout(Duration::from_secs(5), future)
+            .await
+            .expect("timed out")
+    }
+
+    /// A session accepting the channels opened to it, and the other end of its stream.
+    fn accepting() -> (tokio::io::DuplexStream, mpsc::Receiver<(Channel, String)>) {
+        let (stream, session) = tokio::io::duplex(MAX_PAYLOAD);
+        let (reader, writer) = tokio::io::split(session);
+        let (accepted, incoming) = mpsc::channel(QUEUE_LEN);
+        start(reader, writer, Some(accepted));
+        (stream, incoming)
+    }
+
+    /// A session opening channels to one accepting them.
+    fn pair() -> (Arc<Shared>, mpsc::Receiver<(Channel, String)>) {
+        let (stream, incoming) = accepting();
+        let (reader, writer) = tokio::io::split(stream);
+        (start(reader, writer, None), incoming)
+    }
+
+    fn header(kind: u8, channel: u32, len: usize) -> Vec<u8> {
+        let mut header = vec![kind];
+        header.extend_from_slice(&channel.to_be_bytes());
+        header.extend_from_slice(&(len as u32).to_be_bytes());
+        header
+    }
+
+    async fn read_to_end(channel: &mut Channel) -> Vec<u8> {
+        let mut data = Vec::new();
+        within(channel.read_to_end(&mut data)).await.unwrap();
+        data
+    }
+
+    #[tokio::test]
+    async fn relays_both_ways_until_both_sides_close() {
+        let (client, mut incoming) = pair();
+        let mut opened = within(client.open(0, "agent")).await.unwrap();
+        let (mut accepted, bridge) = within(incoming.recv()).await.unwrap();
+        assert_eq!(bridge, "agent");
+
+        let mut buf = [0; 4];
+        opened.write_all(b"ping").await.unwrap();
+        within(accepted.read_exact(&mut buf)).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        accepted.write_all(b"pong").await.unwrap();
+        within(opened.read_exact(&mut buf)).await.unwrap();
+        assert_eq!(&buf, b"pong");
+
+        // Closing one direction leaves the other open.
+        opened.shutdown().await.unwrap();
+        assert!(read_to_end(&mut accepted).await.is_empty());
+        accepted.write_all(b"reply").await.unwrap();
+        accepted.shutdown().await.unwrap();
+        assert_eq!(read_to_end(&mut opened).await, b"reply");
+    }
+
+    #[tokio::test]
+    async fn refills_the_window_as_data_is_read() {
+        let (client, mut incoming) = pair();
+        let mut opened = within(client.open(0, "agent")).await.unwrap();
+        let (mut accepted, _) = within(incoming.recv()).await.unwrap();
+        let reader = tokio::spawn(async move { read_to_end(&mut accepted).await.len() });
+        within(opened.write_all(&vec![0; 3 * WINDOW]))
+            .await
+            .unwrap();
+        opened.shutdown().await.unwrap();
+        assert_eq!(reader.await.unwrap(), 3 * WINDOW);
+    }
+
+    #[tokio::test]
+    async fn a_stalled_channel_does_not_hold_up_the_others() {
+        let (client, mut incoming) = pair();
+        let mut stalled = within(client.open(0, "agent")).await.unwrap();
+        let _unread = within(incoming.recv()).await.unwrap();
+        let mut other = within(client.open(1, "agent")).await.unwrap();
+        let (mut other_accepted, _) = within(incoming.recv()).await.unwrap();
+
+        // Nothing reads the stalled channel, so its writer runs out of window...
+        let data = vec![0; WINDOW + 1];
+        let write = tokio::time::timeout(Duration::from_millis(200), stalled.write_all(&data));
+        assert!(write.await.is_err());
+        // ...without stopping the other channel's data getting through.
+        let mut buf = [0; 4];
+        other.write_all(b"ping").await.unwrap();
+        within(other_accepted.read_exact(&mut buf)).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn rejects_frames_larger_than_the_maximum() {
+        let (mut stream, mut incoming) = accepting();
+        stream.write_all(&header(OPEN, 0, 5)).await.unwrap();
+        stream.write_all(b"agent").await.unwrap();
+        let (mut channel, _) = within(incoming.recv()).await.unwrap();
+
+        stream
+            .write_all(&header(DATA, 0, MAX_PAYLOAD + 1))
+            .await
+            .unwrap();
+        // The session ends.
+        assert!(read_to_end(&mut channel).await.is_empty());
+        assert!(channel.write_all(b"late").await.is_err());
+        assert!(within(incoming.recv()).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_frame_kinds() {
+        let (mut stream, mut incoming) = accepting();
+        stream.write_all(&header(OPEN, 0, 5)).await.unwrap();
+        stream.write_all(b"agent").await.unwrap();
+        let (mut channel, _) = within(incoming.recv()).await.unwrap();
+
+        stream.write_all(&header(42, 0, 0)).await.unwrap();
+        assert!(read_to_end(&mut channel).await.is_empty());
+        assert!(within(incoming.recv()).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn closes_channels_to_unknown_bridges() {
+        let (stream, session) = tokio::io::duplex(MAX_PAYLOAD);
+        let (reader, writer) = tokio::io::split(session);
+        tokio::spawn(async move {
+            let stop = CancellationToken::new();
+            serve(reader, writer, &Config::default(), &stop).await
+        });
+        let (reader, writer) = tokio::io::split(stream);
+        let client = start(reader, writer, None);
+
+        let mut channel = within(client.open(0, "no-such-bridge")).await.unwrap();
+        assert!(read_to_end(&mut channel).await.is_empty());
+    }
+}
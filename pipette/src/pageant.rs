@@ -0,0 +1,323 @@
+//! `pageant` targets: running `pageant.exe` (this project's, not PuTTY's) for each connection,
+//! without the configuration having to say where it is.
+//!
+//! The helper is found at the target's `program`, else the `wsl-systemd.helper` systemd credential
+//! or `$WSL_SYSTEMD_HELPER` (the executable, or the directory containing it), else `pageant.exe`
+//! in one of the usual places on the Windows drive (e.g. `C:\Users\<user>\bin`), else on `PATH`.
+//!
+//! The helper is supervised: if it dies (e.g. interop hiccups, or it's killed) before answering
+//! the client's latest request, it's started again and the request replayed, a few times before
+//! giving up, so the client never sees the failure. That's only done for requests that are safe
+//! to repeat: listing keys, and signing unless the helper's arguments have it confirm
+//! (`--confirm`), notify (`--notify`) or audit (`--audit-log`) each signature, which could
+//! otherwise happen twice. For anything else (e.g. adding or removing keys, or locking the
+//! agent) the connection is closed, as it would be without supervision.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use bridge_core::outbox::{earliest, Outbox};
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _, DuplexStream};
+use tracing::Instrument as _;
+
+/// The helper's file name.
+const PROGRAM: &str = "pageant.exe";
+
+/// Set to the helper (or the directory containing it) to use it for `pageant` targets.
+const HELPER_ENV: &str = "WSL_SYSTEMD_HELPER";
+
+/// How many times a connection's helper is restarted before the connection is dropped.
+const MAX_RESTARTS: u32 = 3;
+
+/// The most data held in each direction between the client and the helper.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// The most unanswered data that's kept to replay: a request of the largest size.
+const MAX_UNANSWERED: usize = agent_proto::frame::MAX_MESSAGE_LEN + 4;
+
+/// Arguments of the helper that make a signature more than a computation, so not safe to repeat.
+const SIGN_SIDE_EFFECTS: &[&str] = &["--confirm", "--notify", "--audit-log"];
+
+/// Where to look for the helper on the Windows drive, relative to each user's profile.
+#[cfg(target_os = "linux")]
+const PROFILE_DIRS: &[&str] = &["bin", ".cargo/bin", "AppData/Local/wsl-systemd"];
+
+/// Find the helper, see the module documentation for where.
+pub fn locate(configured: Option<&Path>) -> PathBuf {
+    if let Some(program) = configured {
+        return program.to_owned();
+    }
+    let credential = crate::credentials::read(crate::credentials::HELPER).map(PathBuf::from);
+    if let Some(helper) = credential.or_else(|| std::env::var_os(HELPER_ENV).map(PathBuf::from)) {
+        return if helper.is_dir() {
+            helper.join(PROGRAM)
+        } else {
+            helper
+        };
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(found) = scan() {
+        tracing::debug!(program = %found.display(), "Found the helper");
+        return found;
+    }
+    PathBuf::from(PROGRAM)
+}
+
+/// Look for the helper in each user's profile on `C:`, preferring the current user's.
+#[cfg(target_os = "linux")]
+fn scan() -> Option<PathBuf> {
+    let users = Path::new("/mnt/c/Users");
+    let mut profiles: Vec<_> = std::fs::read_dir(users)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+    // Linux and Windows user names often match.
+    let user = std::env::var_os("USER");
+    profiles.sort_by_key(|profile| profile.file_name() != user.as_deref());
+    let program_files = Path::new("/mnt/c/Program Files/wsl-systemd").join(PROGRAM);
+    profiles
+        .iter()
+        .flat_map(|profile| {
+            PROFILE_DIRS
+                .iter()
+                .map(move |dir| profile.join(dir).join(PROGRAM))
+        })
+        .chain(std::iter::once(program_files))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Start a supervised helper for a connection, returning the stream to relay the client to.
+pub fn spawn(program: &Path, args: &[String]) -> std::io::Result<DuplexStream> {
+    let helper = crate::endpoint::spawn_helper(program, args)?;
+    let (stream, supervisor) = tokio::io::duplex(BUFFER_SIZE);
+    tokio::spawn(
+        supervise(supervisor, program.to_owned(), args.to_owned(), helper)
+            .instrument(tracing::Span::current()),
+    );
+    Ok(stream)
+}
+
+/// Relay `client` to the helper, restarting it if it dies before answering a request.
+async fn supervise(
+    client: DuplexStream,
+    program: PathBuf,
+    args: Vec<String>,
+    helper: crate::endpoint::Helper,
+) {
+    let (mut child, stdin, mut stdout) = helper;
+    let mut stdin = Some(stdin);
+    let (mut client_reader, mut client_writer) = tokio::io::split(client);
+    let mut to_helper = Outbox::new(BUFFER_SIZE, "helper");
+    let mut to_client = Outbox::new(BUFFER_SIZE, "client");
+    // What the client has sent that the helper hasn't finished answering, i.e. its unanswered
+    // requests, if the traffic looks like agent messages.
+    let mut unanswered = Some(Vec::new());
+    let mut responses = agent_proto::frame::Boundaries::default();
+    // Responses started since the helper's output was last at a boundary.
+    let mut answered = 0;
+    let mut client_open = true;
+    let mut restarts = 0;
+    loop {
+        if !client_open && to_helper.is_empty() {
+            // Closing stdin lets the helper finish and exit, and closing the pipe is the only way
+            // to do that.
+            stdin = None;
+        }
+        let stall = earliest(to_helper.stall_deadline(), to_client.stall_deadline());
+        let (from_client, for_helper) = to_helper.buffers();
+        let (from_helper, for_client) = to_client.buffers();
+        let read = tokio::select! {
+            read = client_reader.read(from_client), if client_open && !from_client.is_empty() => {
+                match read {
+                    Ok(len) if len > 0 => {
+                        let data = to_helper.filled(len);
+                        if let Some(request) = &mut unanswered {
+                            request.extend_from_slice(data);
+                            // Too much in flight to be worth keeping to replay.
+                            if request.len() > MAX_UNANSWERED {
+                                unanswered = None;
+                            }
+                        }
+                    }
+                    _ => client_open = false,
+                }
+                continue;
+            }
+            written = async {
+                let stdin = stdin.as_mut().expect("stdin is only closed once there's nothing left");
+                stdin.write(for_helper).await
+            }, if !for_helper.is_empty() => {
+                // If the helper's gone, reading its stdout notices.
+                let len = written.unwrap_or(to_helper.pending().len());
+                to_helper.consumed(len);
+                continue;
+            }
+            written = client_writer.write(for_client), if !for_client.is_empty() => {
+                match written {
+                    Ok(len) => to_client.consumed(len),
+                    Err(_) => return,
+                }
+                continue;
+            }
+            () = tokio::time::sleep_until(stall.unwrap_or_else(tokio::time::Instant::now)),
+                if stall.is_some() =>
+            {
+                to_helper.check_stall();
+                to_client.check_stall();
+                continue;
+            }
+            read = stdout.read(from_helper), if !from_helper.is_empty() => read,
+        };
+        match read {
+            Ok(len) if len > 0 => {
+                let data = to_client.filled(len);
+                if !responses.feed(data, |_| answered += 1) {
+                    unanswered = None;
+                } else if responses.at_boundary() {
+                    // The client may have pipelined more requests behind those answered.
+                    if let Some(requests) = &mut unanswered {
+                        forget_answered(requests, answered);
+                    }
+                    answered = 0;
+                }
+            }
+            _ => {
+                let status = child.wait().await;
+                let crashed = !status.as_ref().is_ok_and(|status| status.success());
+                if !client_open || !crashed {
+                    client_writer.write_all(to_client.pending()).await.ok();
+                    return;
+                }
+                let request = match &unanswered {
+                    Some(request) if responses.at_boundary() && replayable(request, &args) => {
+                        request.clone()
+                    }
+                    Some(_) if responses.at_boundary() => {
+                        tracing::warn!(
+                            ?status,
+                            "Helper died on a request that isn't safe to repeat"
+                        );
+                        client_writer.write_all(to_client.pending()).await.ok();
+                        return;
+                    }
+                    _ => {
+                        tracing::warn!(?status, "Helper died part way through a response");
+                        client_writer.write_all(to_client.pending()).await.ok();
+                        return;
+                    }
+                };
+                if restarts == MAX_RESTARTS {
+                    tracing::error!(?status, restarts, "Helper keeps dying, giving up");
+                    client_writer.write_all(to_client.pending()).await.ok();
+                    return;
+                }
+                restarts += 1;
+                tracing::warn!(?status, restarts, "Helper died, restarting it");
+                tokio::time::sleep(Duration::from_millis(100) * 2u32.pow(restarts - 1)).await;
+                let (new_child, mut new_stdin, new_stdout) =
+                    match crate::endpoint::spawn_helper(&program, &args) {
+                        Ok(helper) => helper,
+                        Err(e) => {
+                            tracing::error!(error = %e, "Failed to restart helper");
+                            client_writer.write_all(to_client.pending()).await.ok();
+                            return;
+                        }
+                    };
+                // The unanswered request includes whatever hadn't been written to the old
+                // helper. If the new one's dead already, the next read notices.
+                to_helper = Outbox::new(BUFFER_SIZE, "helper");
+                new_stdin.write_all(&request).await.ok();
+                (child, stdin, stdout) = (new_child, Some(new_stdin), new_stdout);
+            }
+        }
+    }
+}
+
+/// Drop the first `count` requests from `unanswered`, once the helper has answered them.
+fn forget_answered(unanswered: &mut Vec<u8>, count: usize) {
+    for _ in 0..count {
+        match agent_proto::frame::split_frame(unanswered) {
+            Some((request, _)) => {
+                let len = request.len();
+                unanswered.drain(..len);
+            }
+            // A response to something the client hasn't finished sending.
+            None => unanswered.clear(),
+        }
+    }
+}
+
+/// Whether the requests in `unanswered` can be sent to a new helper, i.e. repeating those the
+/// old one may have acted on has no effect (see the module documentation). A request that hadn't
+/// been received in full can't have been acted on.
+fn replayable(mut unanswered: &[u8], args: &[String]) -> bool {
+    use agent_proto::MessageType;
+
+    let sign_side_effects = args.iter().any(|arg| {
+        SIGN_SIDE_EFFECTS.iter().any(|flag| {
+            arg.strip_prefix(flag)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('='))
+        })
+    });
+    while let Some((request, rest)) = agent_proto::frame::split_frame(unanswered) {
+        match request
+            .get(4)
+            .map(|&message_type| MessageType(message_type))
+        {
+            Some(MessageType::REQUEST_IDENTITIES) => {}
+            Some(MessageType::SIGN_REQUEST) if !sign_side_effects => {}
+            _ => return false,
+        }
+        unanswered = rest;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `REQUEST_IDENTITIES`.
+    const REQUEST: &[u8] = b"\0\0\0\x01\x0b";
+
+    /// `IDENTITIES_ANSWER`, with no keys.
+    const ANSWER: &[u8] = b"\0\0\0\x05\x0c\0\0\0\0";
+
+    #[tokio::test]
+    async fn replays_a_pipelined_request_the_helper_died_on() {
+        let dir = tempfile::tempdir().unwrap();
+        let started = dir.path().join("started");
+        // The first helper reads both requests, answers the first and dies, the next answers one.
+        let script = r#"
+            answer() { printf '\000\000\000\005\014\000\000\000\000'; }
+            if [ -e "$0" ]; then head -c 5 >/dev/null; answer; exit; fi
+            touch "$0"; head -c 10 >/dev/null; answer; exit 1
+        "#;
+        let args = vec![
+            "-c".to_owned(),
+            script.to_owned(),
+            started.display().to_string(),
+        ];
+        let mut client = spawn(Path::new("/bin/sh"), &args).unwrap();
+
+        client
+            .write_all(&[REQUEST, REQUEST].concat())
+            .await
+            .unwrap();
+        let mut responses = [0; 2 * ANSWER.len()];
+        tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut responses))
+            .await
+            .expect("timed out")
+            .unwrap();
+        assert_eq!(responses, *[ANSWER, ANSWER].concat());
+    }
+
+    #[test]
+    fn forgets_only_the_answered_requests() {
+        let mut unanswered = [REQUEST, REQUEST, &REQUEST[..2]].concat();
+        forget_answered(&mut unanswered, 1);
+        assert_eq!(unanswered, [REQUEST, &REQUEST[..2]].concat());
+        forget_answered(&mut unanswered, 2);
+        assert!(unanswered.is_empty());
+    }
+}
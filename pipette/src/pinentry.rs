@@ -0,0 +1,64 @@
+//! `pinentry` targets: showing gpg-agent's passphrase prompts inside WSL as Windows dialogs.
+//!
+//! gpg-agent runs its `pinentry-program` with the Assuan dialogue on its stdin/stdout, and a curses
+//! or tty pinentry fights whatever else is using the terminal. Point it at wsl-systemd instead
+//! (through a symlink whose name starts with `pinentry`, as gpg-agent won't pass arguments):
+//!
+//! ```text
+//! ln -s "$(command -v wsl-systemd)" ~/.local/bin/pinentry-wsl
+//! echo "pinentry-program $HOME/.local/bin/pinentry-wsl" >> ~/.gnupg/gpg-agent.conf
+//! ```
+//!
+//! and the dialogue is relayed to a Windows pinentry launched through interop:
+//! `$WSL_SYSTEMD_PINENTRY` if set, else Gpg4win's or GnuPG's, else `pinentry.exe` on `PATH`.
+
+use std::path::{Path, PathBuf};
+
+/// Set to the Windows pinentry to use.
+pub const ENV: &str = "WSL_SYSTEMD_PINENTRY";
+
+/// Where the usual Windows installs put their pinentry, in order of preference.
+#[cfg(unix)]
+const CANDIDATES: &[&str] = &[
+    "/mnt/c/Program Files (x86)/Gpg4win/bin/pinentry.exe",
+    "/mnt/c/Program Files (x86)/GnuPG/bin/pinentry-basic.exe",
+    "/mnt/c/Program Files/Gpg4win/bin/pinentry.exe",
+    "/mnt/c/Program Files/GnuPG/bin/pinentry-basic.exe",
+];
+
+/// Whether we were run as a pinentry, i.e. through a symlink named `pinentry...`.
+pub fn invoked_as() -> bool {
+    std::env::args_os()
+        .next()
+        .map(PathBuf::from)
+        .and_then(|argv0| argv0.file_name().map(ToOwned::to_owned))
+        .is_some_and(|name| name.to_string_lossy().starts_with("pinentry"))
+}
+
+/// Find the Windows pinentry, see the module documentation for where.
+pub fn locate(configured: Option<&Path>) -> PathBuf {
+    if let Some(program) = configured {
+        return program.to_owned();
+    }
+    #[cfg(unix)]
+    if let Some(found) = CANDIDATES.iter().map(Path::new).find(|path| path.is_file()) {
+        return found.to_owned();
+    }
+    #[cfg(windows)]
+    for var in ["ProgramFiles(x86)", "ProgramFiles"] {
+        let Some(dir) = std::env::var_os(var) else {
+            continue;
+        };
+        let dir = Path::new(&dir);
+        let found = [
+            dir.join("Gpg4win").join("bin").join("pinentry.exe"),
+            dir.join("GnuPG").join("bin").join("pinentry-basic.exe"),
+        ]
+        .into_iter()
+        .find(|path| path.is_file());
+        if let Some(found) = found {
+            return found;
+        }
+    }
+    PathBuf::from("pinentry.exe")
+}
@@ -0,0 +1,42 @@
+//! Both ends of a Windows named pipe.
+
+use std::time::Duration;
+
+use tokio::net::windows::named_pipe::{
+    ClientOptions, NamedPipeClient, NamedPipeServer, ServerOptions,
+};
+use windows::Win32::Foundation::{ERROR_ACCESS_DENIED, ERROR_PIPE_BUSY};
+
+/// How long to keep retrying while every instance of the pipe is in use.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Open the pipe, waiting for an instance to become free if the server's are all busy.
+pub async fn connect(path: &std::path::Path) -> std::io::Result<NamedPipeClient> {
+    let deadline = std::time::Instant::now() + BUSY_TIMEOUT;
+    loop {
+        match ClientOptions::new().open(path) {
+            Err(e)
+                if e.raw_os_error() == Some(ERROR_PIPE_BUSY.0 as i32)
+                    && std::time::Instant::now() < deadline =>
+            {
+                tracing::debug!("Named pipe busy, retrying");
+            }
+            result => return result,
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// Create an instance of the pipe at `path` for a client to connect to, or `None` if it's the
+/// `first` instance and the pipe already exists (i.e. it's already being served). Clients on
+/// other machines are refused.
+pub fn listen(path: &std::path::Path, first: bool) -> std::io::Result<Option<NamedPipeServer>> {
+    match ServerOptions::new()
+        .first_pipe_instance(first)
+        .reject_remote_clients(true)
+        .create(path)
+    {
+        Err(e) if first && e.raw_os_error() == Some(ERROR_ACCESS_DENIED.0 as i32) => Ok(None),
+        result => result.map(Some),
+    }
+}
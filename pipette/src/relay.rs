@@ -0,0 +1,57 @@
+//! Serving a bridge's clients.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use bridge_core::relay::Options;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::sync::CancellationToken;
+
+use crate::config::{Launch, Target};
+use crate::metrics;
+
+/// Source of the IDs that tell connections apart in log events, statistics and errors, unique
+/// across all of the process's bridges.
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The ID for a newly accepted connection, to record in its `connection` span.
+pub fn next_id() -> u64 {
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Serve a freshly accepted `client`, connecting to `target` (launching it if needed) and
+/// relaying until the connection closes, recording its statistics in `metrics` under `id`.
+pub async fn serve<C>(
+    mut client: C,
+    id: u64,
+    target: &Target,
+    launch: Option<&Launch>,
+    options: Options,
+    stop: &CancellationToken,
+    metrics: &Arc<metrics::Bridge>,
+) where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    tracing::info!("Connection opened");
+    let hooks = (metrics.connection(id), crate::trace::connection(target));
+    let endpoint = tokio::select! {
+        endpoint = crate::endpoint::connect_or_launch(target, launch) => endpoint,
+        () = stop.cancelled() => return,
+    };
+    match endpoint {
+        Ok(endpoint) => {
+            bridge_core::relay::relay(&mut client, endpoint, options, stop, &hooks).await
+        }
+        Err(e) => {
+            metrics.connect_failed();
+            tracing::error!(error = %e, "Failed to connect to target")
+        }
+    }
+    let totals = hooks.0.totals();
+    tracing::info!(
+        duration = ?totals.duration,
+        to_target = totals.to_target,
+        to_client = totals.to_client,
+        "Connection closed"
+    );
+}
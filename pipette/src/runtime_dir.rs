@@ -0,0 +1,85 @@
+//! `$XDG_RUNTIME_DIR/wsl-systemd`, where bridges' Unix sockets go unless they say otherwise.
+//!
+//! A relative `listen` path (e.g. `listen = "ssh-agent.sock"`) is taken to be in this directory,
+//! so there's no need to choose (and get the permissions right on) somewhere only the user can
+//! reach. `wsl-systemd daemon` creates the directory when it first listens there, readable only
+//! by the user (mode 0700), and takes away anyone else's access to it if it already exists. The
+//! same goes for any directories under it in the path (`listen = "gnupg/S.gpg-agent"`). systemd's
+//! socket units do the same with `DirectoryMode`.
+//!
+//! The directory is left behind when the daemon stops, for the next one, but is removed along with
+//! the rest of the runtime directory when the user's last session ends.
+
+use std::path::{Path, PathBuf};
+
+/// The name of our directory in the runtime directory.
+const NAME: &str = "wsl-systemd";
+
+/// Only the user can list the directory or reach the sockets in it.
+const MODE: u32 = 0o700;
+
+/// The directory, if the user has a runtime directory.
+pub fn dir() -> Option<PathBuf> {
+    let dirs = directories::BaseDirs::new()?;
+    Some(dirs.runtime_dir()?.join(NAME))
+}
+
+/// Where the `listen` socket `path` is: in the directory if it's relative (`None` if there's no
+/// runtime directory to put it in), otherwise `path` itself.
+pub fn resolve(path: &Path) -> Option<PathBuf> {
+    if path.is_absolute() {
+        return Some(path.to_owned());
+    }
+    Some(dir()?.join(path))
+}
+
+/// Make sure the directory exists, with the right permissions, if `socket` is to be in it, along
+/// with any directories under it that `socket` is in (e.g. `gnupg` for `gnupg/S.gpg-agent`).
+pub fn create_for(socket: &Path) -> std::io::Result<()> {
+    let Some(dir) = dir() else {
+        return Ok(());
+    };
+    let Some(subdirs) = socket
+        .parent()
+        .and_then(|parent| parent.strip_prefix(&dir).ok())
+    else {
+        return Ok(());
+    };
+    let mut path = dir;
+    create(&path)?;
+    for component in subdirs.components() {
+        // Anything else (`..`) is left for binding the socket to complain about.
+        let std::path::Component::Normal(name) = component else {
+            break;
+        };
+        path.push(name);
+        create(&path)?;
+    }
+    Ok(())
+}
+
+/// Create the directory at `path` with mode 0700, or if it already exists, take away anyone
+/// else's access to it.
+fn create(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt as _, PermissionsExt as _};
+
+    match std::fs::DirBuilder::new().mode(MODE).create(path) {
+        Ok(()) => {
+            tracing::debug!(path = %path.display(), "Created socket directory");
+            Ok(())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            let permissions = std::fs::metadata(path)?.permissions();
+            if permissions.mode() & 0o077 == 0 {
+                return Ok(());
+            }
+            tracing::warn!(
+                path = %path.display(),
+                mode = format_args!("{:o}", permissions.mode() & 0o777),
+                "Socket directory is accessible to other users, restricting it"
+            );
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(MODE))
+        }
+        Err(e) => Err(e),
+    }
+}
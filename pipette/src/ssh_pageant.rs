@@ -0,0 +1,242 @@
+//! `wsl-systemd ssh-pageant`: serving a socket inside WSL the way ssh-pageant does, for shell
+//! startup files written for it (see the `compat` module).
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bridge_core::relay::Options;
+use tracing::Instrument as _;
+
+use crate::config::Target;
+
+/// How long to wait for the background process to start listening.
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The name the connections are counted under.
+const BRIDGE: &str = "ssh-pageant";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Failed to create a directory for the socket")]
+    SocketDir(#[source] std::io::Error),
+    #[error("Failed to listen on {0}")]
+    Listen(PathBuf, #[source] std::io::Error),
+    #[error("Failed to start the background process")]
+    Spawn(#[source] std::io::Error),
+    #[error("The background process exited ({0})")]
+    Exited(std::process::ExitStatus),
+    #[error("The background process didn't start listening on {0}")]
+    NotListening(PathBuf),
+    #[error("SSH_PAGEANT_PID isn't set")]
+    NoPid,
+    #[error("Failed to stop process {0}")]
+    Kill(String, #[source] std::io::Error),
+}
+
+/// ssh-pageant's arguments.
+#[derive(structopt::StructOpt, Debug)]
+pub struct SshPageant {
+    /// Listen on this socket [default: a new one in a private directory under /tmp]
+    #[structopt(short = "a", value_name = "SOCKET", parse(from_os_str))]
+    socket: Option<PathBuf>,
+    /// If the socket's already being served, just print the commands pointing at it
+    #[structopt(short = "r", long)]
+    reuse: bool,
+    /// Stay in the foreground, logging at debug level
+    #[structopt(short = "d", long)]
+    debug: bool,
+    /// Don't print the command echoing the process ID
+    #[structopt(short = "q", long)]
+    quiet: bool,
+    /// Print C shell commands [default: if `$SHELL` is a csh]
+    #[structopt(short = "c", long, conflicts_with = "sh")]
+    csh: bool,
+    /// Print Bourne shell commands
+    #[structopt(short = "s", long)]
+    sh: bool,
+    /// Stop the ssh-pageant in `SSH_PAGEANT_PID`, printing commands unsetting the variables
+    #[structopt(short = "k", long)]
+    kill: bool,
+    /// Listen without printing anything, as the background process started for the above
+    #[structopt(long, hidden = true)]
+    serve: bool,
+}
+
+impl SshPageant {
+    /// The log level `-d` asks for.
+    pub fn log_level(&self) -> Option<&'static str> {
+        self.debug.then_some("debug")
+    }
+
+    /// Do as ssh-pageant would: stop the one running, or start one (unless it's already running)
+    /// and print the commands pointing clients at it.
+    pub fn run(&self) -> Result<(), Error> {
+        let csh = self.csh
+            || (!self.sh
+                && std::env::var_os("SHELL")
+                    .is_some_and(|shell| shell.to_string_lossy().ends_with("csh")));
+        if self.kill {
+            return kill(csh, self.quiet);
+        }
+
+        let socket = match &self.socket {
+            Some(socket) => socket.clone(),
+            None => default_socket()?,
+        };
+        if socket.exists() {
+            let served = std::os::unix::net::UnixStream::connect(&socket).is_ok();
+            if served && self.reuse {
+                print!(
+                    "{}",
+                    set(csh, "SSH_AUTH_SOCK", &socket.display().to_string())
+                );
+                return Ok(());
+            }
+            if served {
+                let e = std::io::ErrorKind::AddrInUse.into();
+                return Err(Error::Listen(socket, e));
+            }
+            // Left behind by one that's gone.
+            let _ = std::fs::remove_file(&socket);
+        }
+
+        if self.serve || self.debug {
+            if self.debug {
+                print_variables(csh, self.quiet, &socket, std::process::id());
+            }
+            return crate::block_on(serve(&socket));
+        }
+
+        let exe = std::env::current_exe().map_err(Error::Spawn)?;
+        let mut child = Command::new(exe)
+            .args(["ssh-pageant", "--serve", "-a"])
+            .arg(&socket)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(Error::Spawn)?;
+        // Connecting would have the background process start a helper for nothing.
+        let started = Instant::now();
+        while !socket.exists() {
+            if let Some(status) = child.try_wait().map_err(Error::Spawn)? {
+                return Err(Error::Exited(status));
+            }
+            if started.elapsed() > START_TIMEOUT {
+                let _ = child.kill();
+                return Err(Error::NotListening(socket));
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        print_variables(csh, self.quiet, &socket, child.id());
+        Ok(())
+    }
+}
+
+/// A socket in a new directory only we can use, like ssh-agent's
+/// `/tmp/ssh-XXXXXXXXXX/agent.<pid>`.
+fn default_socket() -> Result<PathBuf, Error> {
+    use std::os::unix::fs::DirBuilderExt as _;
+
+    let mut random = [0; 5];
+    getrandom::getrandom(&mut random).map_err(|e| Error::SocketDir(e.into()))?;
+    let suffix: String = random.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let dir = std::env::temp_dir().join(format!("ssh-{}", suffix));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .map_err(Error::SocketDir)?;
+    Ok(dir.join(format!("agent.{}", std::process::id())))
+}
+
+/// Relay each connection to `socket` to Pageant until we're asked to stop.
+async fn serve(socket: &Path) -> Result<(), Error> {
+    let listener =
+        tokio::net::UnixListener::bind(socket).map_err(|e| Error::Listen(socket.to_owned(), e))?;
+    tracing::info!(socket = %socket.display(), "Listening");
+    let stop = bridge_core::shutdown::on_signal();
+    let metrics = crate::metrics::bridge(BRIDGE);
+    let target = Target::Pageant {
+        program: None,
+        args: Vec::new(),
+    };
+    let mut connections = tokio::task::JoinSet::new();
+    loop {
+        tokio::select! {
+            () = stop.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((client, _)) => {
+                    let id = crate::relay::next_id();
+                    let span = tracing::info_span!("connection", id);
+                    let (target, stop, metrics) =
+                        (target.clone(), stop.clone(), Arc::clone(&metrics));
+                    connections.spawn(
+                        async move {
+                            let options = Options::default();
+                            crate::relay::serve(client, id, &target, None, options, &stop, &metrics)
+                                .await
+                        }
+                        .instrument(span),
+                    );
+                }
+                Err(e) => tracing::error!(error = %e, "Failed to accept a connection"),
+            },
+            // Reap finished connections as we go.
+            Some(_) = connections.join_next() => {}
+        }
+    }
+    let _ = std::fs::remove_file(socket);
+    bridge_core::shutdown::with_grace_period(&stop, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    Ok(())
+}
+
+/// Stop the ssh-pageant in `SSH_PAGEANT_PID` and print the commands unsetting its variables.
+fn kill(csh: bool, quiet: bool) -> Result<(), Error> {
+    let pid = std::env::var("SSH_PAGEANT_PID").map_err(|_| Error::NoPid)?;
+    let status = Command::new("kill")
+        .arg(&pid)
+        .status()
+        .map_err(|e| Error::Kill(pid.clone(), e))?;
+    if !status.success() {
+        let e = std::io::Error::other(format!("kill exited with {}", status));
+        return Err(Error::Kill(pid, e));
+    }
+    for variable in ["SSH_AUTH_SOCK", "SSH_PAGEANT_PID"] {
+        if csh {
+            println!("unsetenv {};", variable);
+        } else {
+            println!("unset {};", variable);
+        }
+    }
+    if !quiet {
+        println!("echo ssh-pageant pid {} killed;", pid);
+    }
+    Ok(())
+}
+
+/// Print the commands pointing clients at `socket`, served by `pid`, as ssh-pageant does.
+fn print_variables(csh: bool, quiet: bool, socket: &Path, pid: u32) {
+    print!(
+        "{}",
+        set(csh, "SSH_AUTH_SOCK", &socket.display().to_string())
+    );
+    print!("{}", set(csh, "SSH_PAGEANT_PID", &pid.to_string()));
+    if !quiet {
+        println!("echo ssh-pageant pid {};", pid);
+    }
+}
+
+/// The command setting (and exporting) `variable` to `value`, on a line of its own.
+fn set(csh: bool, variable: &str, value: &str) -> String {
+    let value = value.replace('\'', r"'\''");
+    if csh {
+        format!("setenv {} '{}';\n", variable, value)
+    } else {
+        format!("{}='{}'; export {};\n", variable, value, variable)
+    }
+}
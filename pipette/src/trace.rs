@@ -0,0 +1,383 @@
+//! `--trace-wire`: logging every message relayed, decoded and as a hex dump.
+//!
+//! Bridges to `assuan` targets are traced a line at a time, and all others as ssh-agent
+//! messages. Tracing stops on a connection as soon as its traffic doesn't look like agent
+//! messages (e.g. Docker's API), as there's nothing to decode.
+//!
+//! Secrets are redacted: private keys and passphrases sent to ssh-agent (see
+//! [`agent_proto::dump`]), and gpg-agent's data lines and passphrases (which carry decrypted
+//! session keys, PINs and so on). `--unsafe-trace` dumps everything.
+//!
+//! The messages are logged at trace level with the `wire` target, which `--trace-wire` enables
+//! whatever the log level.
+
+use std::fmt::Write as _;
+use std::sync::{Mutex, OnceLock};
+
+use crate::config::Target;
+
+/// The log target wire traces are logged with.
+pub const TARGET: &str = "wire";
+
+// Flattened into the global arguments. (Not a doc comment, structopt would use it as their
+// description.)
+#[derive(structopt::StructOpt, Debug, Default, Clone, Copy)]
+pub struct Options {
+    /// Log each ssh-agent or Assuan message relayed, with its type and a hex dump (with secrets
+    /// redacted)
+    #[structopt(long)]
+    pub trace_wire: bool,
+    /// Don't redact secrets (private keys, passphrases, decrypted data) from `--trace-wire`'s
+    /// dumps
+    #[structopt(long, requires = "trace-wire")]
+    pub unsafe_trace: bool,
+}
+
+static OPTIONS: OnceLock<Options> = OnceLock::new();
+
+/// Trace connections as `options` says, called before any are relayed.
+pub fn set(options: Options) {
+    // Only fails if it's already been set, and this is called before anything needs it.
+    let _ = OPTIONS.set(options);
+}
+
+/// The hooks tracing a connection to `target`, if `--trace-wire` is enabled.
+pub fn connection(target: &Target) -> Option<Tracer> {
+    let options = OPTIONS.get().copied().unwrap_or_default();
+    if !options.trace_wire {
+        return None;
+    }
+    let protocol = match target {
+        Target::Assuan { .. } | Target::Pinentry { .. } => Protocol::Assuan(Assuan::default()),
+        _ => Protocol::Agent(Agent::default()),
+    };
+    Some(Tracer {
+        redact: !options.unsafe_trace,
+        protocol: Mutex::new(protocol),
+    })
+}
+
+/// The [`Hooks`](bridge_core::relay::Hooks) tracing a connection.
+pub struct Tracer {
+    redact: bool,
+    protocol: Mutex<Protocol>,
+}
+
+enum Protocol {
+    Agent(Agent),
+    Assuan(Assuan),
+    /// Not a protocol that can be decoded, or not any more.
+    Opaque,
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    ToBackend,
+    ToClient,
+}
+
+impl Direction {
+    fn arrow(self) -> &'static str {
+        match self {
+            Self::ToBackend => "->",
+            Self::ToClient => "<-",
+        }
+    }
+}
+
+impl Tracer {
+    fn observe(&self, direction: Direction, data: &[u8]) {
+        let mut protocol = self.protocol.lock().expect("trace lock poisoned");
+        let decoded = match &mut *protocol {
+            Protocol::Agent(agent) => agent.feed(direction, data, |body| {
+                let message_type = agent_proto::MessageType(body[0]);
+                tracing::trace!(
+                    target: TARGET,
+                    direction = direction.arrow(),
+                    %message_type,
+                    len = body.len(),
+                    "\n{}",
+                    agent_proto::dump::message(body, self.redact)
+                );
+            }),
+            Protocol::Assuan(assuan) => {
+                assuan.feed(direction, data, self.redact, |line| {
+                    tracing::trace!(
+                        target: TARGET,
+                        direction = direction.arrow(),
+                        verb = %line.verb,
+                        len = line.len,
+                        "\n{}",
+                        line.dump
+                    );
+                });
+                true
+            }
+            Protocol::Opaque => true,
+        };
+        if !decoded {
+            tracing::trace!(
+                target: TARGET,
+                "Not the ssh-agent protocol, no longer tracing this connection"
+            );
+            *protocol = Protocol::Opaque;
+        }
+    }
+}
+
+impl bridge_core::relay::Hooks for Tracer {
+    fn to_backend(&self, data: &[u8]) {
+        self.observe(Direction::ToBackend, data);
+    }
+
+    fn to_client(&self, data: &[u8]) {
+        self.observe(Direction::ToClient, data);
+    }
+
+    fn reconnected(&self) {
+        tracing::trace!(target: TARGET, "Reconnected to the target");
+    }
+}
+
+/// The partial ssh-agent message in each direction.
+#[derive(Default)]
+struct Agent {
+    to_backend: Vec<u8>,
+    to_client: Vec<u8>,
+}
+
+impl Agent {
+    /// Pass the body of each message completed by `data` to `complete`, returning `false` if it
+    /// can't be agent messages.
+    fn feed(
+        &mut self,
+        direction: Direction,
+        mut data: &[u8],
+        mut complete: impl FnMut(&[u8]),
+    ) -> bool {
+        let buffer = match direction {
+            Direction::ToBackend => &mut self.to_backend,
+            Direction::ToClient => &mut self.to_client,
+        };
+        while !data.is_empty() {
+            let wanted = if buffer.len() < 4 {
+                4 - buffer.len()
+            } else {
+                let len = u32::from_be_bytes(buffer[..4].try_into().expect("4 bytes")) as usize;
+                if len == 0 || len > agent_proto::frame::MAX_MESSAGE_LEN {
+                    return false;
+                }
+                4 + len - buffer.len()
+            };
+            let taken = wanted.min(data.len());
+            buffer.extend_from_slice(&data[..taken]);
+            data = &data[taken..];
+            if buffer.len() > 4 && taken == wanted {
+                complete(&buffer[4..]);
+                buffer.clear();
+            }
+        }
+        true
+    }
+}
+
+/// Assuan commands whose arguments are secret.
+const SECRET_COMMANDS: &[&str] = &["PRESET_PASSPHRASE"];
+
+/// Assuan commands whose (`OK`) response is secret.
+const SECRET_RESPONSES: &[&str] = &["GET_PASSPHRASE"];
+
+/// Enough of an Assuan line to decode and dump, the rest is elided.
+const MAX_LINE: usize = 4096;
+
+/// The partial Assuan line in each direction, and the last command sent.
+#[derive(Default)]
+struct Assuan {
+    to_backend: Line,
+    to_client: Line,
+    command: String,
+}
+
+/// A complete Assuan line, ready to log.
+struct Dump {
+    verb: String,
+    /// The line's length, including anything elided.
+    len: usize,
+    dump: String,
+}
+
+#[derive(Default)]
+struct Line {
+    data: Vec<u8>,
+    /// How many bytes of the line didn't fit in `data`.
+    elided: usize,
+}
+
+impl Assuan {
+    /// Pass each line completed by `data` to `complete`, dumped and with secrets redacted if
+    /// `redact`.
+    fn feed(
+        &mut self,
+        direction: Direction,
+        data: &[u8],
+        redact: bool,
+        mut complete: impl FnMut(Dump),
+    ) {
+        for &byte in data {
+            let line = match direction {
+                Direction::ToBackend => &mut self.to_backend,
+                Direction::ToClient => &mut self.to_client,
+            };
+            if line.data.len() < MAX_LINE {
+                line.data.push(byte);
+            } else {
+                line.elided += 1;
+            }
+            if byte == b'\n' {
+                let line = std::mem::take(line);
+                complete(self.dump(direction, line, redact));
+            }
+        }
+    }
+
+    fn dump(&mut self, direction: Direction, line: Line, redact: bool) -> Dump {
+        let text = String::from_utf8_lossy(&line.data);
+        let verb = text
+            .split([' ', '\n'])
+            .next()
+            .unwrap_or_default()
+            .to_owned();
+        if let Direction::ToBackend = direction {
+            if verb != "D" && verb != "END" {
+                self.command = verb.to_ascii_uppercase();
+            }
+        }
+        let secret = match direction {
+            _ if verb == "D" => true,
+            Direction::ToBackend => SECRET_COMMANDS.contains(&self.command.as_str()),
+            Direction::ToClient => {
+                verb == "OK" && SECRET_RESPONSES.contains(&self.command.as_str())
+            }
+        };
+        let mut dump = if redact && secret {
+            let shown = verb.len().min(line.data.len());
+            format!(
+                "{}\n[{} bytes redacted]",
+                agent_proto::dump::hex(&line.data[..shown]),
+                line.data.len() + line.elided - shown
+            )
+        } else {
+            agent_proto::dump::hex(&line.data)
+        };
+        if line.elided > 0 && !(redact && secret) {
+            let _ = write!(dump, "\n[{} more bytes]", line.elided);
+        }
+        Dump {
+            verb,
+            len: line.data.len() + line.elided,
+            dump,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The bodies of the agent messages completed by each of `chunks`, or `None` once they
+    /// stop looking like agent messages.
+    fn agent_messages(chunks: &[&[u8]]) -> Option<Vec<Vec<u8>>> {
+        let mut agent = Agent::default();
+        let mut messages = Vec::new();
+        for chunk in chunks {
+            if !agent.feed(Direction::ToBackend, chunk, |body| {
+                messages.push(body.to_vec())
+            }) {
+                return None;
+            }
+        }
+        Some(messages)
+    }
+
+    /// The Assuan lines `exchange` (commands sent to the backend, and its responses) would log.
+    fn assuan_lines(exchange: &[(Direction, &str)], redact: bool) -> Vec<Dump> {
+        let mut assuan = Assuan::default();
+        let mut lines = Vec::new();
+        for (direction, data) in exchange {
+            assuan.feed(*direction, data.as_bytes(), redact, |line| lines.push(line));
+        }
+        lines
+    }
+
+    #[test]
+    fn follows_agent_messages_across_reads() {
+        let messages = agent_messages(&[b"\0\0", b"\0\x01\x0b\0\0\0\x03\x0d", b"ab"]);
+        assert_eq!(messages, Some(vec![vec![11], vec![13, b'a', b'b']]));
+    }
+
+    #[test]
+    fn gives_up_on_other_protocols() {
+        // The length is only checked once the body starts.
+        assert_eq!(agent_messages(&[b"\0\0\0\0", b"\x0b"]), None);
+        assert_eq!(agent_messages(&[b"GET / HTTP/1.1\r\n\r\n"]), None);
+    }
+
+    #[test]
+    fn redacts_assuan_secrets() {
+        use Direction::{ToBackend, ToClient};
+
+        let lines = assuan_lines(
+            &[
+                (ToBackend, "GETINFO version\n"),
+                (ToClient, "D 2.4.5\nOK\n"),
+                (ToBackend, "PRESET_PASSPHRASE 0123 -1 736563726574\n"),
+                (ToClient, "OK\n"),
+                (ToBackend, "GET_PASSPHRASE X X X X\n"),
+                (ToClient, "OK 736563726574\n"),
+            ],
+            true,
+        );
+        let verbs: Vec<_> = lines.iter().map(|line| line.verb.as_str()).collect();
+        assert_eq!(
+            verbs,
+            [
+                "GETINFO",
+                "D",
+                "OK",
+                "PRESET_PASSPHRASE",
+                "OK",
+                "GET_PASSPHRASE",
+                "OK"
+            ]
+        );
+        let redacted: Vec<_> = lines
+            .iter()
+            .map(|line| line.dump.contains("bytes redacted]"))
+            .collect();
+        assert_eq!(redacted, [false, true, false, true, false, false, true]);
+        assert!(lines[0].dump.contains("|GETINFO version.|"));
+        assert!(lines[1].dump.ends_with("[7 bytes redacted]"));
+        assert!(!lines[6].dump.contains("736563726574"));
+    }
+
+    #[test]
+    fn dumps_everything_unredacted() {
+        let lines = assuan_lines(&[(Direction::ToClient, "D secret\n")], false);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].dump.contains("|D secret.|"));
+    }
+
+    #[test]
+    fn elides_long_assuan_lines() {
+        let long = format!("D {}\n", "x".repeat(MAX_LINE));
+        let lines = assuan_lines(&[(Direction::ToClient, &long)], false);
+        assert_eq!(lines[0].len, MAX_LINE + 3);
+        assert!(lines[0].dump.ends_with("[3 more bytes]"));
+
+        let lines = assuan_lines(&[(Direction::ToClient, &long)], true);
+        assert!(lines[0]
+            .dump
+            .ends_with(&format!("[{} bytes redacted]", MAX_LINE + 2)));
+        assert!(!lines[0].dump.contains("more bytes"));
+    }
+}
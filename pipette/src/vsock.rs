@@ -0,0 +1,77 @@
+//! Connecting from a WSL2 VM to a Hyper-V socket on the Windows host, over `AF_VSOCK`.
+//!
+//! This avoids spawning a Windows process through interop for every connection, but needs
+//! `wsl-systemd.exe hyperv` listening on the Windows side (see the `hyperv` module).
+
+use std::io::{Read as _, Write as _};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// The CID that always refers to the host, from inside a VM.
+pub const HOST_CID: u32 = 2;
+
+/// A connected `AF_VSOCK` stream socket.
+pub struct VsockStream(AsyncFd<socket2::Socket>);
+
+impl VsockStream {
+    pub async fn connect(cid: u32, port: u32) -> std::io::Result<Self> {
+        tracing::debug!(cid, port, "Connecting over vsock");
+        // Connecting to the host either succeeds or is refused promptly, so it's not worth
+        // driving a non-blocking connect.
+        let socket = tokio::task::spawn_blocking(move || {
+            let socket = socket2::Socket::new(socket2::Domain::VSOCK, socket2::Type::STREAM, None)?;
+            socket.connect(&socket2::SockAddr::vsock(cid, port))?;
+            socket.set_nonblocking(true)?;
+            Ok::<_, std::io::Error>(socket)
+        })
+        .await??;
+        Ok(Self(AsyncFd::new(socket)?))
+    }
+}
+
+impl AsyncRead for VsockStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.0.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|socket| socket.get_ref().read(unfilled)) {
+                Ok(result) => {
+                    buf.advance(result?);
+                    return Poll::Ready(Ok(()));
+                }
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for VsockStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.0.poll_write_ready(cx))?;
+            match guard.try_io(|socket| socket.get_ref().write(buf)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(self.0.get_ref().shutdown(std::net::Shutdown::Write))
+    }
+}
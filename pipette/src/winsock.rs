@@ -0,0 +1,76 @@
+//! Winsock sockets that std doesn't know how to create (`AF_UNIX`, `AF_HYPERV`).
+//!
+//! Once connected, a stream socket of any family can be driven through std/tokio's
+//! `TcpStream`, which only ever sends and receives on it.
+
+use std::os::windows::io::{FromRawSocket as _, RawSocket};
+use std::path::Path;
+
+use windows::Win32::Networking::WinSock;
+
+/// Initialize Winsock, which std otherwise only does when it first creates a socket itself.
+pub fn startup() -> std::io::Result<()> {
+    static STARTUP: std::sync::OnceLock<i32> = std::sync::OnceLock::new();
+    let result = *STARTUP.get_or_init(|| {
+        let mut data = WinSock::WSADATA::default();
+        unsafe { WinSock::WSAStartup(0x0202, &mut data) }
+    });
+    match result {
+        0 => Ok(()),
+        e => Err(std::io::Error::from_raw_os_error(e)),
+    }
+}
+
+/// The error from the last failed Winsock call on this thread.
+pub fn last_error() -> std::io::Error {
+    std::io::Error::from_raw_os_error(unsafe { WinSock::WSAGetLastError() }.0)
+}
+
+/// Take ownership of a connected stream socket.
+///
+/// # Safety
+///
+/// `socket` must be a valid socket that nothing else will close.
+pub unsafe fn into_stream(socket: WinSock::SOCKET) -> std::net::TcpStream {
+    std::net::TcpStream::from_raw_socket(socket.0 as RawSocket)
+}
+
+/// Connect to the `AF_UNIX` socket at `path`, returning a non-blocking stream.
+pub fn connect_unix(path: &Path) -> std::io::Result<std::net::TcpStream> {
+    startup()?;
+
+    let mut address = WinSock::SOCKADDR_UN {
+        sun_family: WinSock::ADDRESS_FAMILY(WinSock::AF_UNIX),
+        sun_path: [0; 108],
+    };
+    let path_bytes = path
+        .to_str()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "non-UTF-8 path"))?
+        .as_bytes();
+    // Leave room for the nul terminator.
+    if path_bytes.len() >= address.sun_path.len() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "socket path too long",
+        ));
+    }
+    address.sun_path[..path_bytes.len()].copy_from_slice(path_bytes);
+
+    unsafe {
+        let socket = WinSock::socket(WinSock::AF_UNIX.into(), WinSock::SOCK_STREAM, 0);
+        if socket == WinSock::INVALID_SOCKET {
+            return Err(last_error());
+        }
+        let stream = into_stream(socket);
+        let connected = WinSock::connect(
+            socket,
+            &address as *const WinSock::SOCKADDR_UN as *const WinSock::SOCKADDR,
+            std::mem::size_of::<WinSock::SOCKADDR_UN>() as i32,
+        );
+        if connected == WinSock::SOCKET_ERROR {
+            return Err(last_error());
+        }
+        stream.set_nonblocking(true)?;
+        Ok(stream)
+    }
+}
@@ -0,0 +1,205 @@
+//! Telling WSL1 and WSL2 apart, and what that means for reaching Windows.
+//!
+//! WSL1 runs Linux processes on the Windows kernel, sharing its network stack, so `127.0.0.1` is
+//! Windows' loopback interface. WSL2 runs them in a VM: with its default (NAT) networking Windows
+//! is the VM's default gateway instead, and `vsock` targets only work there. With mirrored
+//! networking (`networkingMode=mirrored` in `.wslconfig`) WSL2 shares Windows' loopback again.
+//!
+//! So on WSL2 with NAT networking, `tcp` targets on the loopback interface are redirected to the
+//! gateway. The Windows side then has to listen on an address the VM can reach (e.g.
+//! `0.0.0.0:5222`), and be allowed through the firewall. `--wsl` overrides the detection, e.g.
+//! `--wsl none` to leave the addresses alone.
+
+use std::borrow::Cow;
+use std::net::Ipv4Addr;
+use std::sync::OnceLock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Environment {
+    /// Not WSL at all, e.g. a Linux VM or machine reaching Windows over the network.
+    None,
+    Wsl1,
+    /// WSL2 with NAT networking.
+    Wsl2,
+    /// WSL2 with mirrored networking.
+    Wsl2Mirrored,
+}
+
+impl std::str::FromStr for Environment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "wsl1" => Ok(Self::Wsl1),
+            "wsl2" => Ok(Self::Wsl2),
+            "wsl2-mirrored" => Ok(Self::Wsl2Mirrored),
+            _ => Err(format!(
+                "unknown environment {:?} (expected none, wsl1, wsl2 or wsl2-mirrored)",
+                s
+            )),
+        }
+    }
+}
+
+/// The environment, once it's been detected (or overridden).
+static ENVIRONMENT: OnceLock<Environment> = OnceLock::new();
+
+/// Use `environment` rather than detecting it.
+pub fn set(environment: Environment) {
+    // Only fails if it's already been set, and this is called before anything needs it.
+    let _ = ENVIRONMENT.set(environment);
+}
+
+/// The environment wsl-systemd is running in, detected the first time it's needed.
+pub fn environment() -> Environment {
+    *ENVIRONMENT.get_or_init(|| {
+        let environment = detect();
+        tracing::debug!(?environment, "Detected environment");
+        environment
+    })
+}
+
+/// WSL1's kernel release ends `-Microsoft` (e.g. `4.4.0-19041-Microsoft`), while WSL2's real
+/// Linux kernel is e.g. `5.15.153.1-microsoft-standard-WSL2`.
+fn detect() -> Environment {
+    let release = std::fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+    classify(
+        &release,
+        std::env::var_os("WSL_DISTRO_NAME").is_some(),
+        || networking_mode().as_deref() == Some("mirrored"),
+    )
+}
+
+/// The environment for the kernel `release`, only asking whether WSL2's networking is `mirrored`
+/// if it is WSL2.
+fn classify(release: &str, wsl_distro: bool, mirrored: impl FnOnce() -> bool) -> Environment {
+    if release.contains("-Microsoft") {
+        Environment::Wsl1
+    } else if release.contains("microsoft") || wsl_distro {
+        if mirrored() {
+            Environment::Wsl2Mirrored
+        } else {
+            Environment::Wsl2
+        }
+    } else {
+        Environment::None
+    }
+}
+
+/// WSL2's networking mode (`nat`, `mirrored`...), as reported by `wslinfo` in WSL 2.0+.
+fn networking_mode() -> Option<String> {
+    let output = std::process::Command::new("wslinfo")
+        .arg("--networking-mode")
+        .stderr(std::process::Stdio::null())
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// The address to connect to for the Windows-side `address` (`host:port`), redirecting the
+/// loopback interface to the Windows host on WSL2 with NAT networking.
+pub fn windows_address(address: &str) -> Cow<'_, str> {
+    let Some(port) = loopback_port(address) else {
+        return Cow::Borrowed(address);
+    };
+    if environment() != Environment::Wsl2 {
+        return Cow::Borrowed(address);
+    }
+    match windows_host() {
+        Ok(host) => {
+            tracing::debug!(%address, %host, "Redirecting loopback to the Windows host");
+            Cow::Owned(format!("{}:{}", host, port))
+        }
+        Err(e) => {
+            tracing::warn!(error = %e, "Failed to find the Windows host, using loopback");
+            Cow::Borrowed(address)
+        }
+    }
+}
+
+/// The port of `address` (`host:port`), if its host is the loopback interface.
+fn loopback_port(address: &str) -> Option<&str> {
+    let (host, port) = address.rsplit_once(':')?;
+    let loopback = matches!(host, "localhost" | "[::1]")
+        || host.parse::<Ipv4Addr>().is_ok_and(|ip| ip.is_loopback());
+    loopback.then_some(port)
+}
+
+/// The Windows host's address from inside the WSL2 VM, i.e. the default gateway.
+fn windows_host() -> std::io::Result<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route")?;
+    default_gateway(&routes)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no default route"))
+}
+
+/// The default gateway in `routes`, the contents of `/proc/net/route`.
+fn default_gateway(routes: &str) -> Option<Ipv4Addr> {
+    // Each line is the interface, destination, gateway, ..., with the addresses as native-endian
+    // hex.
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().skip(1);
+            let destination = fields.next()?;
+            let gateway = u32::from_str_radix(fields.next()?, 16).ok()?;
+            (destination == "00000000").then(|| Ipv4Addr::from(gateway.to_ne_bytes()))
+        })
+        .next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_wsl1_and_wsl2_apart() {
+        let never = || -> bool { panic!("asked for the networking mode") };
+        assert_eq!(
+            classify("4.4.0-19041-Microsoft", true, never),
+            Environment::Wsl1
+        );
+        let wsl2 = "5.15.153.1-microsoft-standard-WSL2";
+        assert_eq!(classify(wsl2, true, || false), Environment::Wsl2);
+        assert_eq!(classify(wsl2, false, || true), Environment::Wsl2Mirrored);
+        // A custom WSL2 kernel, which only `WSL_DISTRO_NAME` gives away.
+        assert_eq!(classify("6.6.36-custom", true, || false), Environment::Wsl2);
+        assert_eq!(classify("6.6.36-arch1-1", false, never), Environment::None);
+    }
+
+    #[test]
+    fn parses_environments() {
+        assert_eq!("wsl2-mirrored".parse(), Ok(Environment::Wsl2Mirrored));
+        assert_eq!("none".parse(), Ok(Environment::None));
+        assert!("wsl3".parse::<Environment>().is_err());
+    }
+
+    #[test]
+    fn recognises_loopback_addresses() {
+        assert_eq!(loopback_port("127.0.0.1:5222"), Some("5222"));
+        assert_eq!(loopback_port("127.1.2.3:5222"), Some("5222"));
+        assert_eq!(loopback_port("localhost:5222"), Some("5222"));
+        assert_eq!(loopback_port("[::1]:5222"), Some("5222"));
+        assert_eq!(loopback_port("192.168.1.10:5222"), None);
+        assert_eq!(loopback_port("windows.local:5222"), None);
+        assert_eq!(loopback_port("localhost"), None);
+    }
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn finds_the_default_gateway() {
+        let header =
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n";
+        let local = "eth0\t0010A8C0\t00000000\t0001\t0\t0\t0\t00F0FFFF\t0\t0\t0\n";
+        let default = "eth0\t00000000\t0110A8C0\t0003\t0\t0\t0\t00000000\t0\t0\t0\n";
+        assert_eq!(
+            default_gateway(&[header, local, default].concat()),
+            Some(Ipv4Addr::new(192, 168, 16, 1))
+        );
+        assert_eq!(default_gateway(&[header, local].concat()), None);
+    }
+}
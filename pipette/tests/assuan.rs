@@ -0,0 +1,294 @@
+//! Relaying gpg-agent's Assuan protocol, against the mock agent.
+
+mod mock_agent;
+
+use std::io::{BufRead as _, Write as _};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use mock_agent::{temp_dir, MockAgent, GREETING};
+
+/// `wsl-systemd` with an empty configuration file in `dir`, so the user's own isn't picked up.
+fn wsl_systemd(dir: &Path) -> Command {
+    let config = dir.join("bridges.toml");
+    if !config.exists() {
+        std::fs::write(&config, "").unwrap();
+    }
+    let mut wsl_systemd = Command::new(env!("CARGO_BIN_EXE_wsl-systemd"));
+    wsl_systemd
+        .arg("--config")
+        .arg(config)
+        .env_remove("RUST_LOG")
+        .env_remove("GNUPGHOME");
+    wsl_systemd
+}
+
+/// Run `command`, with `input` on its stdin, returning its stdout and stderr.
+fn run(command: &mut Command, input: &str) -> (bool, String, String) {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    (
+        output.status.success(),
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+#[test]
+fn finds_the_socket_in_the_homedir() {
+    let temp = temp_dir("homedir");
+    let dir = temp.path();
+    let _agent = MockAgent::start(dir);
+    let (success, stdout, stderr) = run(
+        wsl_systemd(dir).arg("gpg").arg("--gnupg-home").arg(dir),
+        "GETINFO version\nBYE\n",
+    );
+    assert!(success, "{}", stderr);
+    assert_eq!(
+        stdout,
+        format!(
+            "{}\nD GETINFO version\nOK\nOK closing connection\n",
+            GREETING
+        )
+    );
+}
+
+#[test]
+fn relays_an_assuan_bridge() {
+    let temp = temp_dir("bridge");
+    let dir = temp.path();
+    let agent = MockAgent::start(dir);
+    std::fs::write(
+        dir.join("bridges.toml"),
+        format!(
+            "[bridges.gpg]\ntarget = {{ type = \"assuan\", path = {:?} }}\n",
+            agent.path
+        ),
+    )
+    .unwrap();
+    let (success, stdout, stderr) = run(
+        wsl_systemd(dir).args(["bridge", "gpg"]),
+        "KEYINFO --list\nBYE\n",
+    );
+    assert!(success, "{}", stderr);
+    assert_eq!(
+        stdout,
+        format!(
+            "{}\nD KEYINFO --list\nOK\nOK closing connection\n",
+            GREETING
+        )
+    );
+}
+
+#[test]
+fn reports_a_wrong_nonce() {
+    let temp = temp_dir("nonce");
+    let dir = temp.path();
+    let agent = MockAgent::start(dir);
+    // Same port, different nonce.
+    let mut contents = std::fs::read(&agent.path).unwrap();
+    let len = contents.len();
+    contents[len - 16..].fill(0);
+    std::fs::write(&agent.path, contents).unwrap();
+
+    let (success, stdout, stderr) = run(
+        wsl_systemd(dir).arg("gpg").arg("--gnupg-home").arg(dir),
+        "BYE\n",
+    );
+    assert!(!success);
+    assert_eq!(stdout, "");
+    assert!(stderr.contains("hung up without greeting us"), "{}", stderr);
+}
+
+#[test]
+fn reports_a_refusal() {
+    let temp = temp_dir("refused");
+    let dir = temp.path();
+    let _agent = MockAgent::with_greeting(dir, "ERR 67108949 No pinentry <GPG Agent>");
+    let (success, _, stderr) = run(
+        wsl_systemd(dir).arg("gpg").arg("--gnupg-home").arg(dir),
+        "BYE\n",
+    );
+    assert!(!success);
+    assert!(
+        stderr.contains("gpg-agent refused the connection"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn reconnects_after_the_agent_restarts() {
+    let temp = temp_dir("restart");
+    let dir = temp.path();
+    let agent = MockAgent::start(dir);
+    let mut child = wsl_systemd(dir)
+        .arg("gpg-agent")
+        .arg("--gnupg-home")
+        .arg(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = std::io::BufReader::new(child.stdout.take().unwrap()).lines();
+    assert_eq!(stdout.next().unwrap().unwrap(), GREETING);
+    let mut exchange = |command: &str| {
+        writeln!(stdin, "{}", command).unwrap();
+        [stdout.next(), stdout.next()].map(|line| line.unwrap().unwrap())
+    };
+
+    assert_eq!(exchange("first"), ["D first", "OK"]);
+    agent.restart();
+    // The restart is only noticed when the client next says something, and the new agent's
+    // greeting isn't passed on (the client has already been greeted).
+    assert_eq!(exchange("second"), ["D second", "OK"]);
+    drop(stdin);
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn waits_for_the_agent_to_come_back() {
+    let temp = temp_dir("come-back");
+    let dir = temp.path();
+    let agent = MockAgent::start(dir);
+    let mut child = wsl_systemd(dir)
+        .arg("gpg")
+        .arg("--gnupg-home")
+        .arg(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = std::io::BufReader::new(child.stdout.take().unwrap()).lines();
+    assert_eq!(stdout.next().unwrap().unwrap(), GREETING);
+    let mut exchange = |command: &str| {
+        writeln!(stdin, "{}", command).unwrap();
+        [stdout.next(), stdout.next()].map(|line| line.unwrap().unwrap())
+    };
+
+    assert_eq!(exchange("first"), ["D first", "OK"]);
+    agent.stop();
+    // The agent's still gone when the client next says something, so the command waits for it.
+    let restarted = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        agent.listen();
+        agent
+    });
+    assert_eq!(exchange("second"), ["D second", "OK"]);
+    let _agent = restarted.join().unwrap();
+    drop(stdin);
+    assert!(child.wait().unwrap().success());
+}
+
+#[cfg(unix)]
+#[test]
+fn stands_in_for_wsl2_ssh_pageant() {
+    let temp = temp_dir("wsl2-ssh-pageant");
+    let dir = temp.path();
+    let _agent = MockAgent::start(dir);
+    let helper = dir.join("wsl2-ssh-pageant.exe");
+    std::os::unix::fs::symlink(env!("CARGO_BIN_EXE_wsl-systemd"), &helper).unwrap();
+    std::fs::write(dir.join("bridges.toml"), "").unwrap();
+    // As in wsl2-ssh-pageant's instructions, with Go's single dash flags.
+    let (success, stdout, stderr) = run(
+        Command::new(&helper)
+            .args(["-gpg", "S.gpg-agent", "-gpgConfigBasepath"])
+            .arg(dir)
+            .env("WSL_SYSTEMD_CONFIG", dir.join("bridges.toml"))
+            .env_remove("RUST_LOG"),
+        "GETINFO version\nBYE\n",
+    );
+    assert!(success, "{}", stderr);
+    assert_eq!(
+        stdout,
+        format!(
+            "{}\nD GETINFO version\nOK\nOK closing connection\n",
+            GREETING
+        )
+    );
+}
+
+#[test]
+fn reads_the_socket_file_from_a_credential() {
+    let temp = temp_dir("credential");
+    let dir = temp.path();
+    let _agent = MockAgent::start(dir);
+    std::fs::write(
+        dir.join("bridges.toml"),
+        "[bridges.gpg]\ntarget = { type = \"assuan\", path = \"S.gpg-agent\" }\n",
+    )
+    .unwrap();
+    let (success, stdout, stderr) = run(
+        wsl_systemd(dir)
+            .args(["bridge", "gpg"])
+            .env("CREDENTIALS_DIRECTORY", dir),
+        "BYE\n",
+    );
+    assert!(success, "{}", stderr);
+    assert_eq!(stdout, format!("{}\nOK closing connection\n", GREETING));
+}
+
+#[cfg(unix)]
+#[test]
+fn listens_in_the_runtime_directory() {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let temp = temp_dir("runtime-dir");
+    let dir = temp.path();
+    let agent = MockAgent::start(dir);
+    std::fs::write(
+        dir.join("bridges.toml"),
+        format!(
+            "[bridges.gpg]\nlisten = \"gnupg/gpg.sock\"\ntarget = {{ type = \"assuan\", path = {:?} }}\n",
+            agent.path
+        ),
+    )
+    .unwrap();
+    let mut daemon = wsl_systemd(dir)
+        .arg("daemon")
+        .env("XDG_RUNTIME_DIR", dir)
+        .env_remove("LISTEN_FDS")
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let socket = dir.join("wsl-systemd/gnupg/gpg.sock");
+    let started = std::time::Instant::now();
+    while !socket.exists() {
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+    assert_eq!(mode(&dir.join("wsl-systemd")), 0o700);
+    assert_eq!(mode(&dir.join("wsl-systemd/gnupg")), 0o700);
+    assert_eq!(mode(&socket), 0o600);
+
+    let client = std::os::unix::net::UnixStream::connect(&socket).unwrap();
+    let mut greeting = String::new();
+    std::io::BufReader::new(&client)
+        .read_line(&mut greeting)
+        .unwrap();
+    assert_eq!(greeting, format!("{}\n", GREETING));
+    drop(client);
+
+    let killed = Command::new("kill")
+        .args(["-TERM", &daemon.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(killed.success());
+    assert!(daemon.wait().unwrap().success());
+    assert!(!socket.exists());
+    assert!(!dir.join("wsl-systemd/gnupg/gpg.sock.lock").exists());
+}
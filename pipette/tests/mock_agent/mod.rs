@@ -0,0 +1,114 @@
+//! A stand-in for gpg-agent's Assuan socket on Windows, for testing wsl-systemd without GnuPG.
+//!
+//! Like gpg-agent, it listens on a localhost port and writes the port and a random nonce to an
+//! `S.gpg-agent` file, and hangs up on connections that don't start with the nonce. Once a client
+//! is greeted, every command line it sends is answered with `D <command>` and `OK`, until `BYE`.
+
+use std::io::{BufRead as _, Read as _, Write as _};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub const GREETING: &str = "OK Pleased to meet you";
+
+pub struct MockAgent {
+    /// The socket file.
+    pub path: PathBuf,
+    greeting: String,
+    /// Every connection that's been accepted, so a restart can drop them.
+    connections: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl MockAgent {
+    /// Start an agent with its socket file in `dir`.
+    pub fn start(dir: &Path) -> Self {
+        Self::with_greeting(dir, GREETING)
+    }
+
+    /// Start an agent that greets clients with `greeting` (e.g. an `ERR`) instead of `OK`.
+    pub fn with_greeting(dir: &Path, greeting: &str) -> Self {
+        let agent = Self {
+            path: dir.join("S.gpg-agent"),
+            greeting: greeting.to_owned(),
+            connections: Arc::default(),
+        };
+        agent.listen();
+        agent
+    }
+
+    /// Act as if gpg-agent had been restarted: drop every connection, and listen on a new port
+    /// with a new nonce.
+    pub fn restart(&self) {
+        self.stop();
+        self.listen();
+    }
+
+    /// Act as if gpg-agent had exited: drop every connection and remove the socket file, until
+    /// [`MockAgent::listen`] starts it again.
+    pub fn stop(&self) {
+        for connection in self.connections.lock().unwrap().drain(..) {
+            let _ = connection.shutdown(std::net::Shutdown::Both);
+        }
+        let _ = std::fs::remove_file(&self.path);
+    }
+
+    pub fn listen(&self) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut nonce = [0; 16];
+        getrandom::getrandom(&mut nonce).unwrap();
+        let mut contents = format!("{}\n", port).into_bytes();
+        contents.extend_from_slice(&nonce);
+        std::fs::write(&self.path, contents).unwrap();
+
+        let greeting = self.greeting.clone();
+        let connections = Arc::clone(&self.connections);
+        std::thread::spawn(move || {
+            for client in listener.incoming() {
+                let Ok(client) = client else { return };
+                connections
+                    .lock()
+                    .unwrap()
+                    .push(client.try_clone().unwrap());
+                let greeting = greeting.clone();
+                std::thread::spawn(move || {
+                    let _ = serve(&client, nonce, &greeting);
+                    // There's another handle to the connection in `connections`, so it has to be
+                    // closed explicitly.
+                    let _ = client.shutdown(std::net::Shutdown::Both);
+                });
+            }
+        });
+    }
+}
+
+fn serve(mut client: &TcpStream, nonce: [u8; 16], greeting: &str) -> std::io::Result<()> {
+    let mut received = [0; 16];
+    client.read_exact(&mut received)?;
+    if received != nonce {
+        // gpg-agent doesn't say why, it just hangs up.
+        return Ok(());
+    }
+    writeln!(client, "{}", greeting)?;
+    if !greeting.starts_with("OK") {
+        return Ok(());
+    }
+
+    let mut lines = std::io::BufReader::new(client).lines();
+    while let Some(line) = lines.next().transpose()? {
+        if line == "BYE" {
+            writeln!(client, "OK closing connection")?;
+            return Ok(());
+        }
+        write!(client, "D {}\nOK\n", line)?;
+    }
+    Ok(())
+}
+
+/// A fresh, empty directory for a test's files, removed when it's dropped.
+pub fn temp_dir(test: &str) -> tempfile::TempDir {
+    tempfile::Builder::new()
+        .prefix(&format!("wsl-systemd-test-{}-", test))
+        .tempdir()
+        .unwrap()
+}
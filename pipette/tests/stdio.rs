@@ -0,0 +1,54 @@
+//! Relaying stdin/stdout to a stream target, as the helper modes do.
+
+use std::io::{Read as _, Write as _};
+use std::net::TcpListener;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+/// The response has to reach the client while the connection's still open, even though it
+/// doesn't end in a newline (which would have stdout's line buffering flush it anyway).
+#[test]
+fn passes_on_responses_before_the_client_closes() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        let (mut client, _) = listener.accept().unwrap();
+        let mut request = [0; 4];
+        client.read_exact(&mut request).unwrap();
+        assert_eq!(&request, b"ping");
+        client.write_all(b"\x00\x04pong").unwrap();
+        // Stay connected until the client's done.
+        let _ = client.read_to_end(&mut Vec::new());
+    });
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("bridges.toml"), "").unwrap();
+    let mut wsl_systemd = Command::new(env!("CARGO_BIN_EXE_wsl-systemd"))
+        .arg("--config")
+        .arg(dir.path().join("bridges.toml"))
+        .args(["--wsl", "none", "tcp", "--target"])
+        .arg(address.to_string())
+        .env_remove("RUST_LOG")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = wsl_systemd.stdin.take().unwrap();
+    let mut stdout = wsl_systemd.stdout.take().unwrap();
+    stdin.write_all(b"ping").unwrap();
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let mut response = [0; 6];
+        let _ = sender.send(stdout.read_exact(&mut response).map(|()| response));
+    });
+    let response = receiver
+        .recv_timeout(Duration::from_secs(2))
+        .expect("no response while stdin is open")
+        .unwrap();
+    assert_eq!(&response, b"\x00\x04pong");
+
+    drop(stdin);
+    assert!(wsl_systemd.wait().unwrap().success());
+    server.join().unwrap();
+}